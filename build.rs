@@ -2,10 +2,35 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=Cargo.toml");
     println!("cargo:rerun-if-env-changed=CARGO_FEATURE_ONNXRUNTIME_FROM_SOURCE");
     build::build()?;
+    grpc()?;
     built::write_built_file()?;
+    git_sha();
     Ok(())
 }
 
+/// Generates the gRPC service from its proto file, with a bundled `protoc` so that
+/// it does not need to be installed.
+fn grpc() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/musicgpt.proto");
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::compile_protos("proto/musicgpt.proto")?;
+    Ok(())
+}
+
+/// Exposes the commit being built as `MUSICGPT_GIT_SHA`, if building from a git checkout.
+fn git_sha() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    let output = std::process::Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output();
+    if let Ok(output) = output {
+        if output.status.success() {
+            let sha = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=MUSICGPT_GIT_SHA={}", sha.trim());
+        }
+    }
+}
+
 #[cfg(not(feature = "onnxruntime-from-source"))]
 mod build {
     pub(crate) fn build() -> Result<(), Box<dyn std::error::Error>> {
@@ -7,7 +7,9 @@ use cpal::{
     ChannelCount, SampleFormat, SampleRate, Stream, SupportedBufferSize, SupportedStreamConfig,
 };
 
-const DEFAULT_SAMPLING_RATE: u32 = 32000;
+use crate::pcm::{to_pcm, BitDepth, ClipStats};
+
+pub const DEFAULT_SAMPLING_RATE: u32 = 32000;
 
 pub struct AudioManager {
     host: cpal::Host,
@@ -39,9 +41,21 @@ unsafe impl Send for AudioStream {}
 unsafe impl Sync for AudioStream {}
 
 impl AudioManager {
+    /// Same as the default, for audio with `n_channels` interleaved channels.
+    pub fn with_channels(n_channels: u16) -> Self {
+        Self {
+            n_channels: n_channels.max(1),
+            ..Self::default()
+        }
+    }
+
+    pub fn sampling_rate(&self) -> u32 {
+        self.sampling_rate
+    }
+
     pub fn play_from_queue(&self, mut v: VecDeque<f32>) -> anyhow::Result<AudioStream> {
-        let time = 1000 * v.len() / self.sampling_rate as usize;
         let channels = self.n_channels;
+        let time = 1000 * v.len() / channels as usize / self.sampling_rate as usize;
 
         let config = SupportedStreamConfig::new(
             ChannelCount::from(channels),
@@ -119,6 +133,36 @@ impl AudioManager {
 
         Ok(buffer)
     }
+
+    /// Same as [AudioManager::to_wav], but storing the samples as integer PCM
+    /// with the provided bit depth.
+    pub fn to_wav_pcm(
+        &self,
+        v: VecDeque<f32>,
+        bit_depth: BitDepth,
+        dither: bool,
+    ) -> hound::Result<(Vec<u8>, ClipStats)> {
+        let spec = hound::WavSpec {
+            channels: self.n_channels,
+            sample_rate: self.sampling_rate,
+            bits_per_sample: bit_depth.bits(),
+            sample_format: hound::SampleFormat::Int,
+        };
+        let (samples, stats) = to_pcm(v, bit_depth, dither);
+
+        let mut buffer = vec![];
+        let cursor = std::io::Cursor::new(&mut buffer);
+        let in_memory_file = std::io::BufWriter::new(cursor);
+        {
+            let mut writer = hound::WavWriter::new(in_memory_file, spec)?;
+            for sample in samples {
+                writer.write_sample(sample)?;
+            }
+            // <- we need writer to be dropped here.
+        }
+
+        Ok((buffer, stats))
+    }
 }
 
 #[cfg(test)]
@@ -139,4 +183,20 @@ mod tests {
         assert_eq!(wav_path_content, buff);
         Ok(())
     }
+
+    #[test]
+    fn saves_to_pcm_wav() -> anyhow::Result<()> {
+        let audio_manager = AudioManager::default();
+        let data = VecDeque::from([0.0, 0.5, -0.5, 1.0]);
+        let (buff, stats) = audio_manager.to_wav_pcm(data, BitDepth::I16, false)?;
+        assert_eq!(stats.clipped, 0);
+
+        let reader = hound::WavReader::new(std::io::Cursor::new(buff))?;
+        assert_eq!(reader.spec().bits_per_sample, 16);
+        let samples = reader
+            .into_samples::<i16>()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(samples, vec![0, 16384, -16384, 32767]);
+        Ok(())
+    }
 }
@@ -1,19 +1,24 @@
 use std::collections::VecDeque;
 use std::time::Duration;
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use tokio_util::sync::CancellationToken;
 
 use crate::backend::audio_generation_backend::{
     AudioGenerationRequest, BackendOutboundMsg, JobProcessor,
 };
+use crate::backend::bulk::BulkProgress;
+use crate::backend::errors::ErrorCode;
 use crate::backend::audio_generation_fanout::{
-    AudioGenerationError, AudioGenerationProgress, AudioGenerationResult, AudioGenerationStart,
-    GenerationMessage,
+    AudioGenerationCancelled, AudioGenerationError, AudioGenerationProgress, AudioGenerationResult,
+    AudioGenerationStart, AudioGenerationTokens, GenerationMessage,
 };
 use crate::backend::music_gpt_chat::{Chat, ChatEntry};
-use crate::backend::music_gpt_ws_handler::{Info, OutboundMsg};
+use crate::backend::music_gpt_ws_handler::{Info, OutboundMsg, Profiles};
+use crate::music_gen_config::GenerationConfig;
 use crate::storage::AppFs;
 
 impl OutboundMsg {
@@ -59,6 +64,34 @@ impl OutboundMsg {
         }
     }
 
+    pub(crate) fn cancelled(self) -> AudioGenerationCancelled {
+        match self {
+            OutboundMsg::Generation(GenerationMessage::Cancelled(p)) => p,
+            _ => panic!("msg was not GenerationMessage::Cancelled, it was {self:?}"),
+        }
+    }
+
+    pub(crate) fn tokens(self) -> AudioGenerationTokens {
+        match self {
+            OutboundMsg::Generation(GenerationMessage::Tokens(p)) => p,
+            _ => panic!("msg was not GenerationMessage::Tokens, it was {self:?}"),
+        }
+    }
+
+    pub(crate) fn profiles(self) -> Profiles {
+        match self {
+            OutboundMsg::Profiles(p) => p,
+            _ => panic!("msg was not OutboundMsg::Profiles, it was {self:?}"),
+        }
+    }
+
+    pub(crate) fn bulk_progress(self) -> BulkProgress {
+        match self {
+            OutboundMsg::BulkProgress(p) => p,
+            _ => panic!("msg was not OutboundMsg::BulkProgress, it was {self:?}"),
+        }
+    }
+
     pub(crate) fn chat(self) -> (Chat, Vec<ChatEntry>) {
         match self {
             OutboundMsg::Chat(p) => p,
@@ -84,17 +117,31 @@ impl BackendOutboundMsg {
 
     pub(crate) fn unwrap_response(self) -> (String, VecDeque<f32>) {
         match self {
-            BackendOutboundMsg::Response(p) => p,
+            BackendOutboundMsg::Response((id, audio, _)) => (id, audio),
             _ => panic!("msg was not Response, it was {self:?}"),
         }
     }
 
     pub(crate) fn unwrap_err(self) -> (String, String) {
         match self {
-            BackendOutboundMsg::Failure(p) => p,
+            BackendOutboundMsg::Failure((id, err)) => (id, err.message),
             _ => panic!("msg was not Failure, it was {self:?}"),
         }
     }
+
+    pub(crate) fn unwrap_cancelled(self) -> String {
+        match self {
+            BackendOutboundMsg::Cancelled(p) => p,
+            _ => panic!("msg was not Cancelled, it was {self:?}"),
+        }
+    }
+
+    pub(crate) fn unwrap_queued(self) -> (String, usize) {
+        match self {
+            BackendOutboundMsg::Queued(p) => p,
+            _ => panic!("msg was not Queued, it was {self:?}"),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -122,18 +169,28 @@ impl JobProcessor for DummyJobProcessor {
         &self,
         prompt: &str,
         secs: usize,
-        on_progress: Box<dyn Fn(f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>> {
+        _config: &GenerationConfig,
+        cancel: CancellationToken,
+        on_progress: Box<dyn Fn(f32) + Sync + Send + 'static>,
+        on_tokens: Box<dyn Fn(Vec<i64>) + Sync + Send + 'static>,
+        on_audio: Box<dyn Fn(VecDeque<f32>) + Sync + Send + 'static>,
+    ) -> anyhow::Result<VecDeque<f32>> {
         let mut result = VecDeque::new();
         for i in 0..secs {
             if prompt == format!("fail at {i}") {
-                return Err(ort::Error::new(format!("Failed at {i}")));
+                return Err(anyhow!("Failed at {i}"));
             }
             std::thread::sleep(self.wait_scale);
+            if prompt == "with tokens" {
+                on_tokens(vec![i as i64; 4]);
+            }
+            if prompt == "with audio" {
+                on_audio([i as f32].into());
+            }
             result.push_back(i as f32);
-            let should_exit = on_progress(result.len() as f32 / secs as f32);
-            if should_exit {
-                return Err(ort::Error::new("Aborted"));
+            on_progress(result.len() as f32 / secs as f32);
+            if cancel.is_cancelled() {
+                return Err(ErrorCode::Cancelled.err("Cancelled"));
             }
         }
 
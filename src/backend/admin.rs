@@ -0,0 +1,259 @@
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::backend::audio_generation_backend::BackendInboundMsg;
+use crate::backend::auth::constant_time_eq;
+use crate::backend::job_store::{JobState, JobStore};
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::stems::{gc_stems, BandSeparator, StemSeparator};
+use crate::fetch_remove_data_file::downloads_in_flight;
+use crate::music_gen_config::Secret;
+use crate::storage::{Storage, TEMP_DIR};
+
+/// Header carrying the admin token, which is separate from the API key so that
+/// the clients using the server cannot manage it.
+pub const ADMIN_HEADER: &str = "x-musicgpt-admin-token";
+/// Where the models are downloaded to, relative to the data dir.
+pub const MODELS_DIR: &str = "v1";
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct AdminJobsQuery {
+    /// Only the jobs in this state, all of them if not set.
+    #[serde(default)]
+    pub state: Option<JobState>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct PurgeReport {
+    /// Queued jobs that were asked to be cancelled.
+    pub cancelled: usize,
+    /// Finished jobs whose records were deleted.
+    pub deleted: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct EvictReport {
+    /// Model dirs removed from the data dir, they are downloaded again if needed.
+    pub removed: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct GcReport {
+    /// Stale stem cache entries removed.
+    pub stems: usize,
+    /// Leftovers of interrupted downloads removed.
+    pub temp_files: usize,
+}
+
+/// Maintenance endpoints under `/api/admin`, only available with the admin token.
+#[derive(Clone)]
+pub struct Admin<S: Storage> {
+    token: Option<Secret>,
+    storage: S,
+    jobs: JobStore,
+    ai_tx: Sender<BackendInboundMsg>,
+    /// Files of the models loaded by the server, which are never evicted.
+    model_files: Arc<Vec<String>>,
+}
+
+impl<S: Storage> Admin<S> {
+    pub fn new(
+        token: Option<Secret>,
+        storage: S,
+        jobs: JobStore,
+        ai_tx: Sender<BackendInboundMsg>,
+        model_files: Vec<String>,
+    ) -> Self {
+        Self {
+            token,
+            storage,
+            jobs,
+            ai_tx,
+            model_files: Arc::new(model_files),
+        }
+    }
+
+    /// Fails with the response to send back unless the request has the admin token.
+    /// The admin endpoints do not exist if no token was configured.
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), Response> {
+        let Some(token) = &self.token else {
+            return Err((StatusCode::NOT_FOUND, "The admin API is disabled").into_response());
+        };
+        let sent = headers.get(ADMIN_HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default();
+        if !constant_time_eq(token.expose().as_bytes(), sent.as_bytes()) {
+            return Err((StatusCode::FORBIDDEN, "Invalid admin token").into_response());
+        }
+        Ok(())
+    }
+
+    pub async fn jobs(&self, headers: HeaderMap, query: AdminJobsQuery) -> Response {
+        if let Err(res) = self.authorize(&headers) {
+            return res;
+        }
+        match self.jobs.list(query.state) {
+            Ok(jobs) => Json(jobs).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        }
+    }
+
+    /// Cancels the queued jobs and deletes the records of the finished ones, either
+    /// all of them or only the ones in `query.state`. Running jobs are left alone.
+    pub async fn purge(&self, headers: HeaderMap, query: AdminJobsQuery) -> Response {
+        if let Err(res) = self.authorize(&headers) {
+            return res;
+        }
+        if query.state == Some(JobState::Running) {
+            let msg = "Running jobs can only be cancelled one by one";
+            return (StatusCode::BAD_REQUEST, msg).into_response();
+        }
+        let result = || -> anyhow::Result<PurgeReport> {
+            let mut cancelled = 0;
+            if matches!(query.state, None | Some(JobState::Queued)) {
+                for status in self.jobs.list(Some(JobState::Queued))? {
+                    let id = IdPair(status.chat_id, status.id).to_string();
+                    self.ai_tx.send(BackendInboundMsg::Abort(id))?;
+                    cancelled += 1;
+                }
+            }
+            let deleted = match query.state {
+                Some(JobState::Queued) => 0,
+                state => self.jobs.delete_finished(state)?,
+            };
+            info!("Purged the jobs, {cancelled} cancelled and {deleted} deleted");
+            Ok(PurgeReport { cancelled, deleted })
+        };
+        match result() {
+            Ok(report) => Json(report).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        }
+    }
+
+    pub async fn usage(&self, headers: HeaderMap) -> Response {
+        if let Err(res) = self.authorize(&headers) {
+            return res;
+        }
+        match self.jobs.usage() {
+            Ok(usage) => Json(usage).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        }
+    }
+
+    pub async fn evict_models(&self, headers: HeaderMap) -> Response {
+        if let Err(res) = self.authorize(&headers) {
+            return res;
+        }
+        match evict_models(&self.storage, &self.model_files).await {
+            Ok(removed) => Json(EvictReport { removed }).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        }
+    }
+
+    pub async fn gc(&self, headers: HeaderMap) -> Response {
+        if let Err(res) = self.authorize(&headers) {
+            return res;
+        }
+        match gc(&self.storage).await {
+            Ok(report) => Json(report).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        }
+    }
+}
+
+/// Removes the downloaded models with no file in `in_use`, returning their dirs.
+async fn evict_models<S: Storage>(storage: &S, in_use: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut removed = vec![];
+    for dir in storage.list(MODELS_DIR).await? {
+        let prefix = format!("{dir}/");
+        if in_use.iter().any(|v| v.starts_with(&prefix)) {
+            continue;
+        }
+        storage.rm_rf(&dir).await?;
+        removed.push(dir);
+    }
+    if !removed.is_empty() {
+        info!("Evicted the models in {removed:?}");
+    }
+    Ok(removed)
+}
+
+/// Removes the stale stems and the leftovers of interrupted downloads. The downloads
+/// still in progress, like the ones of a model being switched to, are left alone.
+async fn gc<S: Storage>(storage: &S) -> anyhow::Result<GcReport> {
+    let stems = gc_stems(storage, Some(&BandSeparator::default().version())).await?;
+    let temp_files = rm_stale(storage, TEMP_DIR, &downloads_in_flight()).await?;
+    Ok(GcReport { stems, temp_files })
+}
+
+/// Removes the entries under `dir` with no file in `in_flight`, going into the dirs
+/// that have some. Returns the amount of removed entries.
+async fn rm_stale<S: Storage>(
+    storage: &S,
+    dir: &str,
+    in_flight: &[String],
+) -> anyhow::Result<usize> {
+    let mut removed = 0;
+    for entry in storage.list(dir).await? {
+        let prefix = format!("{entry}/");
+        if in_flight.contains(&entry) {
+            continue;
+        }
+        if in_flight.iter().any(|v| v.starts_with(&prefix)) {
+            removed += Box::pin(rm_stale(storage, &entry, in_flight)).await?;
+        } else {
+            storage.rm_rf(&entry).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn evicts_the_models_not_in_use() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let files = ["small/config.json", "small_fp32/text_encoder.onnx", "large_fp32/text_encoder.onnx"];
+        for file in files {
+            storage.write(&format!("{MODELS_DIR}/{file}"), "model").await?;
+        }
+        let in_use = vec![
+            format!("{MODELS_DIR}/small/config.json"),
+            format!("{MODELS_DIR}/small_fp32/text_encoder.onnx"),
+        ];
+        let removed = evict_models(&storage, &in_use).await?;
+        assert_eq!(removed, vec![format!("{MODELS_DIR}/large_fp32")]);
+        for file in in_use {
+            assert!(storage.exists(&file).await?);
+        }
+        assert_eq!(evict_models(&storage, &[]).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaves_the_downloads_in_progress() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let downloading = format!("{TEMP_DIR}/{MODELS_DIR}/small/decoder.onnx.temp");
+        let interrupted = format!("{TEMP_DIR}/{MODELS_DIR}/small/config.json.temp");
+        let stale = format!("{TEMP_DIR}/{MODELS_DIR}/large/config.json.temp");
+        for file in [&downloading, &interrupted, &stale] {
+            storage.write(file, "model").await?;
+        }
+        let removed = rm_stale(&storage, TEMP_DIR, &[downloading.clone()]).await?;
+        assert_eq!(removed, 2);
+        assert!(storage.exists(&downloading).await?);
+        assert!(!storage.exists(&interrupted).await?);
+        assert!(!storage.exists(&stale).await?);
+        Ok(())
+    }
+}
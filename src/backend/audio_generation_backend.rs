@@ -1,21 +1,88 @@
-use std::collections::VecDeque;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use anyhow::anyhow;
+use ndarray::Array2;
+use ort::value::DynValue;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sysinfo::System;
 use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use uuid::Uuid;
 
+use crate::audio_manager::DEFAULT_SAMPLING_RATE;
+use crate::backend::checkpoints::Checkpoints;
+use crate::backend::errors::{ApiError, ErrorCode};
+use crate::backend::melodies::{clip_paths, decode_wav, resample};
+use crate::backend::pipeline::Pipeline;
+use crate::backend::result_cache::ResultCache;
+use crate::config_units::HumanDuration;
+use crate::dsp::{crossfade, estimate_bpm, same_tempo};
+use crate::long_form::{LongForm, Timeline};
 use crate::music_gen_audio_encodec::MusicGenAudioEncodec;
+use crate::music_gen_config::{GenerationConfig, LimitsConfig, MusicGenConfig};
 use crate::music_gen_decoder::MusicGenDecoder;
-use crate::music_gen_text_encoder::MusicGenTextEncoder;
+use crate::music_gen_text_encoder::{melody_features, MusicGenTextEncoder};
+use crate::progress::{ProgressReporter, ProgressSink};
 
-const INPUT_IDS_BATCH_PER_SECOND: usize = 50;
+/// Tokens generated for each second of audio.
+pub const INPUT_IDS_BATCH_PER_SECOND: usize = 50;
+/// Generated tokens decoded into audio at a time while the job runs.
+const STREAMING_CHUNK_TOKENS: usize = 2 * INPUT_IDS_BATCH_PER_SECOND;
+/// The first chunk is shorter, so that something can be heard as soon as possible.
+const FIRST_STREAMING_CHUNK_TOKENS: usize = INPUT_IDS_BATCH_PER_SECOND / 2;
+/// Already streamed tokens decoded again before each chunk, so that the chunks
+/// join without clicks.
+const STREAMING_CONTEXT_TOKENS: usize = INPUT_IDS_BATCH_PER_SECOND;
+/// End of the continued audio that the decoder is prompted with, the rest is kept as is.
+const CONTINUATION_PROMPT_SECS: usize = 10;
+/// Crossfade from the continued audio into the generated one, which has the same content
+/// at that point but is not exactly the same signal.
+const CONTINUATION_CROSSFADE_SECS: f32 = 0.1;
+/// Crossfade from the generated audio into the audio it leads into, taken from the end
+/// of the generated audio.
+const LEAD_IN_CROSSFADE_SECS: f32 = 1.0;
+/// How far off the asked tempo the tempo of the audio can be, as a share of it.
+const BPM_TOLERANCE: f32 = 0.05;
+/// Audio quieter than this RMS, about -50 dBFS, is taken as silence.
+const SILENCE_RMS: f32 = 0.003;
+
+/// Weight of the last job in the average pace of the backend.
+const PACE_SMOOTHING: f32 = 0.3;
+
+/// Amount of jobs failed because their decode loop stopped making progress.
+pub static STALLED_JOBS: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates a new decoder with fresh sessions, used for replacing a stalled one.
+pub type DecoderFactory = Box<dyn Fn() -> anyhow::Result<Box<dyn MusicGenDecoder>> + Send + Sync>;
+
+/// How urgently a job is needed. Interactive jobs are always processed before batch
+/// ones, so that someone waiting in the chat is not stuck behind a bulk export.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Interactive,
+    Batch,
+}
 
 #[derive(Clone, Debug)]
 pub struct AudioGenerationRequest {
     pub id: String,
     pub prompt: String,
     pub secs: usize,
+    pub config: GenerationConfig,
+    pub priority: Priority,
+    /// Who submitted the job. Jobs of the same priority are shared fairly among owners.
+    pub owner: String,
 }
 
 #[derive(Clone, Debug)]
@@ -27,43 +94,270 @@ pub enum BackendInboundMsg {
 #[derive(Clone, Debug)]
 pub enum BackendOutboundMsg {
     Start(AudioGenerationRequest),
-    Response((String, VecDeque<f32>)),
-    Failure((String, String)),
+    /// The audio of the job, and whether it was taken from the result cache instead
+    /// of being generated.
+    Response((String, VecDeque<f32>, bool)),
+    /// The job failed, with the error carrying the code it is reported with.
+    Failure((String, ApiError)),
+    /// The job was cancelled, either while queued or while being processed.
+    Cancelled(String),
+    /// The job is waiting, with this many jobs to be started before it.
+    Queued((String, usize)),
     Progress((String, f32)),
+    Tokens((String, Vec<i64>)),
+    /// A chunk of the audio being generated, starting at this sample.
+    Audio((String, usize, VecDeque<f32>)),
 }
 
 #[derive(Clone, Debug)]
 struct Job {
     req: AudioGenerationRequest,
+    /// Of the request as submitted, before being given a seed.
+    cache_key: String,
     abort_token: CancellationToken,
+    /// Set by the worker that picked the job, so that no other worker takes it.
+    taken: Arc<AtomicBool>,
+    /// Last queue position sent for the job, so that only changes are reported.
+    reported_position: Option<usize>,
 }
 
 impl Job {
-    fn new(req: AudioGenerationRequest) -> Self {
+    fn new(
+        mut req: AudioGenerationRequest,
+        cache_key: String,
+        parent: &CancellationToken,
+    ) -> Self {
+        // Jobs without a seed get a random one, reported so that they can be repeated.
+        req.config.seed.get_or_insert_with(rand::random);
         Self {
             req,
-            abort_token: CancellationToken::new(),
+            cache_key,
+            // Shutting down the backend cancels all the jobs.
+            abort_token: parent.child_token(),
+            taken: Arc::new(AtomicBool::new(false)),
+            reported_position: None,
+        }
+    }
+
+    fn take(&self) -> bool {
+        !self.taken.swap(true, Ordering::SeqCst)
+    }
+
+    fn is_taken(&self) -> bool {
+        self.taken.load(Ordering::SeqCst)
+    }
+}
+
+/// Decides which waiting job goes next: interactive before batch, then the owner with
+/// the fewest jobs running, then the owner that was served the longest ago, and
+/// finally queue order.
+#[derive(Default)]
+struct Scheduler {
+    turn: u64,
+    last_served: HashMap<String, u64>,
+}
+
+impl Scheduler {
+    /// Indexes of the waiting jobs in `queue`, in the order they would be started if
+    /// nothing else was submitted.
+    fn order(&self, queue: &VecDeque<Job>) -> Vec<usize> {
+        let mut running = HashMap::<&str, usize>::new();
+        for job in queue.iter().filter(|job| job.is_taken()) {
+            *running.entry(&job.req.owner).or_default() += 1;
+        }
+        let mut last_served = self.last_served.clone();
+        let mut turn = self.turn;
+        let mut waiting: Vec<usize> = (0..queue.len()).filter(|&i| !queue[i].is_taken()).collect();
+        let mut order = Vec::with_capacity(waiting.len());
+        while let Some(w) = (0..waiting.len()).min_by_key(|&w| {
+            let req = &queue[waiting[w]].req;
+            (
+                req.priority,
+                running.get(req.owner.as_str()).copied().unwrap_or_default(),
+                last_served.get(&req.owner).copied().unwrap_or_default(),
+                waiting[w],
+            )
+        }) {
+            let i = waiting.remove(w);
+            turn += 1;
+            last_served.insert(queue[i].req.owner.clone(), turn);
+            order.push(i);
         }
+        order
+    }
+
+    fn served(&mut self, owner: &str) {
+        self.turn += 1;
+        self.last_served.insert(owner.to_string(), self.turn);
     }
 }
 
 pub trait JobProcessor: Send + Sync {
     fn name(&self) -> String;
     fn device(&self) -> String;
+    /// Channels of the generated audio, whose samples are interleaved when more than one.
+    fn channels(&self) -> u16 {
+        1
+    }
+    /// Generates `secs` of audio for `prompt`, stopping as soon as possible with an
+    /// error once `cancel` is cancelled. The audio can also be handed to `on_audio` in
+    /// consecutive chunks while it is generated, for playing it before it is done.
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+        on_progress: Box<dyn Fn(f32) + Sync + Send + 'static>,
+        on_tokens: Box<dyn Fn(Vec<i64>) + Sync + Send + 'static>,
+        on_audio: Box<dyn Fn(VecDeque<f32>) + Sync + Send + 'static>,
+    ) -> anyhow::Result<VecDeque<f32>>;
+    /// Runs every session once, so that the first job does not pay for setting them up.
+    fn warmup(&self) -> anyhow::Result<()> {
+        warmup_generation(self)
+    }
+}
+
+/// Generates a second of audio that nobody is waiting for.
+fn warmup_generation(processor: &(impl JobProcessor + ?Sized)) -> anyhow::Result<()> {
+    processor.process(
+        "warmup",
+        1,
+        &GenerationConfig::default(),
+        CancellationToken::new(),
+        Box::new(|_| {}),
+        Box::new(|_| {}),
+        Box::new(|_| {}),
+    )?;
+    Ok(())
+}
+
+impl JobProcessor for Box<dyn JobProcessor> {
+    fn name(&self) -> String {
+        self.as_ref().name()
+    }
+
+    fn device(&self) -> String {
+        self.as_ref().device()
+    }
+
+    fn channels(&self) -> u16 {
+        self.as_ref().channels()
+    }
+
     fn process(
         &self,
         prompt: &str,
         secs: usize,
-        on_progress: Box<dyn Fn(f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>>;
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+        on_progress: Box<dyn Fn(f32) + Sync + Send + 'static>,
+        on_tokens: Box<dyn Fn(Vec<i64>) + Sync + Send + 'static>,
+        on_audio: Box<dyn Fn(VecDeque<f32>) + Sync + Send + 'static>,
+    ) -> anyhow::Result<VecDeque<f32>> {
+        self.as_ref()
+            .process(prompt, secs, config, cancel, on_progress, on_tokens, on_audio)
+    }
+
+    fn warmup(&self) -> anyhow::Result<()> {
+        self.as_ref().warmup()
+    }
 }
 
 pub struct MusicGenJobProcessor {
     pub name: String,
     pub device: String,
     pub text_encoder: MusicGenTextEncoder,
-    pub decoder: Box<dyn MusicGenDecoder>,
+    pub decoder: RwLock<Box<dyn MusicGenDecoder>>,
+    pub decoder_factory: DecoderFactory,
     pub audio_encodec: MusicGenAudioEncodec,
+    pub config: Arc<RwLock<MusicGenConfig>>,
+    pub pipeline: Pipeline,
+    /// Where the clips used as melody or continued are, see [clip_paths].
+    pub data_dir: PathBuf,
+    /// Gets the progress of every job, for embedders that do not go through the backend.
+    pub progress_sink: Option<Arc<dyn ProgressSink>>,
+}
+
+impl MusicGenJobProcessor {
+    /// Fails the job if it went over the time limit, or the process over the memory one.
+    fn check_limits(
+        limits: &LimitsConfig,
+        started: Instant,
+        system: &mut System,
+    ) -> anyhow::Result<()> {
+        if let Some(timeout) = limits.job_timeout {
+            if started.elapsed() > timeout.0 {
+                return Err(ErrorCode::TimedOut.err(format!("Job timed out after {timeout}")));
+            }
+        }
+        if let Some(max_memory) = limits.max_memory {
+            let pid = sysinfo::get_current_pid().map_err(anyhow::Error::msg)?;
+            system.refresh_process(pid);
+            let used = system.process(pid).map(|p| p.memory()).unwrap_or_default();
+            if used > max_memory.0 {
+                let message = format!("The process went over the memory limit of {max_memory}");
+                return Err(ErrorCode::OutOfMemory.err(message));
+            }
+        }
+        Ok(())
+    }
+
+    /// The mono samples of the clip `id`, at the sampling rate of the model.
+    fn load_clip(&self, id: Uuid) -> ort::Result<Vec<f32>> {
+        let path = clip_paths(id)
+            .into_iter()
+            .map(|path| self.data_dir.join(path))
+            .find(|path| path.exists())
+            .ok_or_else(|| ort::Error::new(format!("Audio {id} not found")))?;
+        let bytes = std::fs::read(&path)
+            .map_err(|err| ort::Error::new(format!("Could not read audio {id}: {err}")))?;
+        let sampling_rate = self.config.read().unwrap().audio_encoder.sampling_rate;
+        decode_wav(&bytes, sampling_rate as u32)
+            .map_err(|err| ort::Error::new(format!("Invalid audio {id}: {err}")))
+    }
+
+    /// The hidden states of `prompt` and their attention mask, for the decoder.
+    fn encode_text(
+        &self,
+        prompt: &str,
+        melody: Option<Array2<f32>>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<(DynValue, DynValue)> {
+        let _permit = self.pipeline.text_encoder.acquire(cancel).ok_or_else(cancelled)?;
+        Ok(self.text_encoder.encode(prompt, melody)?)
+    }
+
+    /// The conditioning tensor of the melody `id`.
+    fn load_melody(&self, id: Uuid) -> ort::Result<Array2<f32>> {
+        let sampling_rate = self.config.read().unwrap().audio_encoder.sampling_rate;
+        Ok(melody_features(&self.load_clip(id)?, sampling_rate))
+    }
+
+    /// The audio `id`, and the tokens of its last [CONTINUATION_PROMPT_SECS] that the
+    /// decoder is prompted with.
+    fn load_continuation(
+        &self,
+        id: Uuid,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<(Vec<f32>, Vec<Vec<i64>>)> {
+        let sampling_rate = self.config.read().unwrap().audio_encoder.sampling_rate;
+        let clip = self.load_clip(id)?;
+        let tail = &clip[clip.len().saturating_sub(CONTINUATION_PROMPT_SECS * sampling_rate)..];
+        let _permit = self.pipeline.audio_encoder.acquire(cancel).ok_or_else(cancelled)?;
+        let tokens = self.audio_encodec.tokens(tail, self.channels() as usize)?;
+        Ok((clip, tokens))
+    }
+
+    /// Replaces the decoder with a new one. The stalled sessions are left to the
+    /// hung thread, and released whenever it finishes.
+    fn recreate_decoder(&self) {
+        info!("Recreating the decoder sessions");
+        match (self.decoder_factory)() {
+            Ok(decoder) => *self.decoder.write().unwrap() = decoder,
+            Err(err) => warn!("Could not recreate the decoder sessions: {err}"),
+        }
+    }
 }
 
 impl JobProcessor for MusicGenJobProcessor {
@@ -75,54 +369,525 @@ impl JobProcessor for MusicGenJobProcessor {
         self.device.clone()
     }
 
+    fn channels(&self) -> u16 {
+        self.config.read().unwrap().decoder.audio_channels as u16
+    }
+
+    fn warmup(&self) -> anyhow::Result<()> {
+        warmup_generation(self)?;
+        // The audio encoder only runs for the jobs that continue a clip.
+        let sampling_rate = self.config.read().unwrap().audio_encoder.sampling_rate;
+        self.audio_encodec.tokens(&vec![0.0; sampling_rate], self.channels() as usize)?;
+        Ok(())
+    }
+
     fn process(
         &self,
         prompt: &str,
         secs: usize,
-        on_progress: Box<dyn Fn(f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>> {
-        let max_len = secs * INPUT_IDS_BATCH_PER_SECOND;
-
-        let (lhs, am) = self.text_encoder.encode(prompt)?;
-        let token_stream = self.decoder.generate_tokens(lhs, am, max_len)?;
-
-        let mut data = VecDeque::new();
-        while let Ok(tokens) = token_stream.recv() {
-            data.push_back(tokens?);
-            let should_exit = on_progress(data.len() as f32 / max_len as f32);
-            if should_exit {
-                return Err(ort::Error::new("Aborted"));
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+        on_progress: Box<dyn Fn(f32) + Sync + Send + 'static>,
+        on_tokens: Box<dyn Fn(Vec<i64>) + Sync + Send + 'static>,
+        on_audio: Box<dyn Fn(VecDeque<f32>) + Sync + Send + 'static>,
+    ) -> anyhow::Result<VecDeque<f32>> {
+        let limits = self.config.read().unwrap().limits.clone();
+        if let Some(max) = limits.max_generation_length {
+            if secs as u64 > max.0.as_secs() {
+                return Err(ErrorCode::InvalidRequest.err(format!(
+                    "Cannot generate {secs}s of audio, the max generation length is {max}"
+                )));
+            }
+        }
+        let started = Instant::now();
+        let mut system = System::new();
+        // A process already over the memory limit takes no more jobs.
+        Self::check_limits(&limits, started, &mut system)?;
+        let max_len = secs
+            .checked_mul(INPUT_IDS_BATCH_PER_SECOND)
+            .ok_or_else(|| ort::Error::new(format!("Cannot generate {secs}s of audio")))?;
+        let channels = self.channels() as usize;
+        let sampling_rate = self.config.read().unwrap().audio_encoder.sampling_rate;
+        let on_audio =
+            move |audio: VecDeque<f32>| on_audio(to_output_rate(audio, sampling_rate, channels));
+        let decoder_config = self.config.read().unwrap().decoder.clone();
+        let long_form = LongForm {
+            context: decoder_config.context_secs * INPUT_IDS_BATCH_PER_SECOND,
+            overlap: decoder_config.long_form_overlap_secs * INPUT_IDS_BATCH_PER_SECOND,
+        };
+        let stop_after_silence =
+            decoder_config.stop_after_silence_secs.map(|v| v * INPUT_IDS_BATCH_PER_SECOND);
+
+        let melody = config.melody.map(|id| self.load_melody(id)).transpose()?;
+        let (continued, prompt_tokens) = match config.continuation {
+            Some(id) => self.load_continuation(id, &cancel)?,
+            None => (vec![], vec![]),
+        };
+        let lead_into = config.leads_into.map(|id| self.load_clip(id)).transpose()?;
+        let sections = config.sections.as_deref();
+        let timeline = Timeline::new(prompt, sections, INPUT_IDS_BATCH_PER_SECOND);
+        // The text is encoded again for every segment of long jobs, as the decoder takes it.
+        // So is the negative prompt, which takes the melody too for both to match.
+        let hints = config.hints.clone().unwrap_or_default();
+        let encode = |prompt: &str| -> anyhow::Result<_> {
+            let negative = config.negative_prompt.as_deref();
+            let negative = negative.map(|p| self.encode_text(p, melody.clone(), &cancel));
+            let negative = negative.transpose()?;
+            let positive = self.encode_text(&hints.describe(prompt), melody.clone(), &cancel)?;
+            Ok((positive, negative))
+        };
+        let mut encoded = Some(encode(timeline.at(0).0)?);
+        let decoder_permit = self.pipeline.decoder.acquire(&cancel).ok_or_else(cancelled)?;
+
+        // The prompt is decoded together with the new tokens, so that they join seamlessly,
+        // but only the new audio is streamed.
+        let mut data = VecDeque::from(prompt_tokens);
+        let prompt_len = data.len();
+        // The audio of the tokens generated before the job was interrupted is only in the
+        // final audio.
+        data.extend(config.resumed_tokens.clone().unwrap_or_default());
+        let mut windows = StreamingWindows { streamed: data.len() };
+        let mut silence = TrailingSilence::default();
+        let mut ended = false;
+        let progress = ProgressReporter::new(self.progress_sink.as_deref(), prompt, max_len);
+        // Jobs longer than the decoder context are generated in segments, each one
+        // continuing the end of the previous one. So are the sections of a timeline, which
+        // join like the segments of a job on a single prompt do.
+        loop {
+            let Some((prompted, new)) = long_form.next(data.len(), prompt_len, max_len) else {
+                break;
+            };
+            let (segment_prompt, section_left) = timeline.at(data.len() - prompt_len);
+            let new = section_left.map_or(new, |left| new.min(left));
+            // Each segment samples differently, and the same for the same seed of the job.
+            // It goes by the token the segment starts at, so that a job resumed from its
+            // checkpoint carries on sampling like it would have instead of starting over.
+            let start = data.len() - prompt_len;
+            let segment_config = GenerationConfig {
+                seed: config.seed.map(|seed| segment_seed(seed, start)),
+                ..config.clone()
+            };
+            let ((lhs, am), negative) = match encoded.take() {
+                Some(encoded) => encoded,
+                None => encode(segment_prompt)?,
+            };
+            let segment_tokens = data.range(data.len() - prompted..).cloned().collect();
+            // Stops the decoder when the audio ends early, without cancelling the job.
+            let segment_cancel = cancel.child_token();
+            let token_stream = self.decoder.read().unwrap().generate_tokens(
+                lhs,
+                am,
+                negative,
+                segment_tokens,
+                new,
+                &segment_config,
+                segment_cancel.clone(),
+            )?;
+            let before = data.len();
+            loop {
+                if cancel.is_cancelled() {
+                    return Err(cancelled());
+                }
+                let stall_timeout = limits.stall_timeout;
+                let next = next_tokens(&token_stream, stall_timeout, || self.recreate_decoder());
+                let Some(tokens) = next? else {
+                    break;
+                };
+                on_tokens(tokens.clone());
+                data.push_back(tokens);
+                // Checked once per second of generated audio, reading the process memory
+                // is not free.
+                if data.len() % INPUT_IDS_BATCH_PER_SECOND == 0 {
+                    Self::check_limits(&limits, started, &mut system)?;
+                }
+                let step = data.len() - prompt_len;
+                on_progress(step as f32 / max_len as f32);
+                let Some((window, context)) = windows.next(data.len()) else {
+                    progress.step(step, None);
+                    continue;
+                };
+                let _permit = self.pipeline.audio_encoder.acquire(&cancel).ok_or_else(cancelled)?;
+                let audio = self.audio_encodec.encode(data.range(window.clone()).cloned())?;
+                let audio = skip_tokens(&audio, context, window.len(), channels);
+                let silent = silence.push(&audio, window.len() - context);
+                progress.step(step, Some(&audio));
+                on_audio(audio);
+                if stop_after_silence.is_some_and(|v| silent >= v) {
+                    segment_cancel.cancel();
+                    ended = true;
+                    break;
+                }
+            }
+            // The decoder stops early when cancelled, which closes the token stream.
+            if cancel.is_cancelled() || data.len() == before || ended {
+                break;
+            }
+        }
+        if ended {
+            // The silence was already streamed, but it is left out of the final audio.
+            data.truncate((data.len() - silence.frames).max(prompt_len));
+        }
+
+        drop(decoder_permit);
+        // The decoder stops early when cancelled, which closes the token stream.
+        if cancel.is_cancelled() {
+            return Err(cancelled());
+        }
+
+        let _permit = self.pipeline.audio_encoder.acquire(&cancel).ok_or_else(cancelled)?;
+        let audio = self.audio_encodec.encode(data.iter().cloned())?;
+        let rest = skip_tokens(&audio, windows.streamed.min(data.len()), data.len(), channels);
+        if !rest.is_empty() {
+            on_audio(rest);
+        }
+        // The clips are mono, the same samples go to every channel.
+        let upmix = |clip: &[f32]| {
+            clip.iter()
+                .flat_map(|v| std::iter::repeat(*v).take(channels))
+                .collect::<Vec<_>>()
+        };
+        let lead_into = lead_into.unwrap_or_default();
+        let clips = Clips {
+            continued: &upmix(&continued),
+            lead_into: &upmix(&lead_into),
+            channels,
+        };
+        let mut audio = clips.join(Vec::from(audio), prompt_len, data.len(), sampling_rate);
+        if let (true, Some(bpm)) = (hints.check_bpm, hints.bpm) {
+            let mono: Vec<f32> = audio
+                .make_contiguous()
+                .chunks(channels)
+                .map(|v| v.iter().sum::<f32>() / channels as f32)
+                .collect();
+            match estimate_bpm(&mono, sampling_rate) {
+                Some(detected) if same_tempo(detected, bpm as f32, BPM_TOLERANCE) => {}
+                Some(detected) => {
+                    return Err(anyhow!("The audio is at {detected:.0} bpm instead of {bpm}"));
+                }
+                None => return Err(anyhow!("No tempo detected in the audio")),
             }
         }
+        Ok(to_output_rate(audio, sampling_rate, channels))
+    }
+}
+
+/// The `audio` of a model generating it at `sampling_rate`, like the 16kHz of AudioGen, at
+/// the rate of the rest of the pipeline, which is the same for every model.
+fn to_output_rate(audio: VecDeque<f32>, sampling_rate: usize, channels: usize) -> VecDeque<f32> {
+    let output_rate = DEFAULT_SAMPLING_RATE;
+    if sampling_rate as u32 == output_rate {
+        return audio;
+    }
+    let audio = Vec::from(audio);
+    let resampled = (0..channels)
+        .map(|c| {
+            let channel = audio.iter().skip(c).step_by(channels).copied().collect::<Vec<_>>();
+            resample(&channel, sampling_rate as u32, output_rate)
+        })
+        .collect::<Vec<_>>();
+    let frames = resampled.iter().map(|v| v.len()).min().unwrap_or_default();
+    (0..frames).flat_map(|i| resampled.iter().map(move |v| v[i])).collect()
+}
+
+/// Splits the tokens of a job into the rolling windows decoded into audio while it runs.
+#[derive(Default)]
+struct StreamingWindows {
+    /// Tokens whose audio was already handed out.
+    streamed: usize,
+}
+
+impl StreamingWindows {
+    /// The tokens to decode once there are `generated` of them, and how many of those
+    /// at the start were already streamed and are only there as context. Nothing until
+    /// the next chunk is complete.
+    fn next(&mut self, generated: usize) -> Option<(Range<usize>, usize)> {
+        let chunk = match self.streamed {
+            0 => FIRST_STREAMING_CHUNK_TOKENS,
+            _ => STREAMING_CHUNK_TOKENS,
+        };
+        if generated - self.streamed < chunk {
+            return None;
+        }
+        let from = self.streamed.saturating_sub(STREAMING_CONTEXT_TOKENS);
+        let context = self.streamed - from;
+        self.streamed = generated;
+        Some((from..generated, context))
+    }
+}
+
+/// The seed of the segment of a job that starts at its token `start`, which is the seed
+/// of the job for the first one.
+fn segment_seed(seed: u32, start: usize) -> u32 {
+    seed.wrapping_add((start as u32).wrapping_mul(0x9e37_79b9))
+}
+
+/// The next tokens the decoder sends, none once it is done. A decoder that sends none
+/// in `stall_timeout` is taken for hung, so `recreate` replaces it and the job fails.
+fn next_tokens(
+    token_stream: &Receiver<ort::Result<Vec<i64>>>,
+    stall_timeout: HumanDuration,
+    recreate: impl FnOnce(),
+) -> anyhow::Result<Option<Vec<i64>>> {
+    match token_stream.recv_timeout(stall_timeout.0) {
+        Ok(tokens) => Ok(Some(tokens?)),
+        Err(RecvTimeoutError::Disconnected) => Ok(None),
+        Err(RecvTimeoutError::Timeout) => {
+            STALLED_JOBS.fetch_add(1, Ordering::Relaxed);
+            warn!("Decoding made no progress in {stall_timeout}, failing the job");
+            recreate();
+            Err(ErrorCode::TimedOut.err(format!("Stalled: no progress in {stall_timeout}")))
+        }
+    }
+}
 
-        self.audio_encodec.encode(data)
+/// The error of the jobs stopped because they were cancelled.
+fn cancelled() -> anyhow::Error {
+    ErrorCode::Cancelled.err("Cancelled")
+}
+
+/// Counts the near silent frames, one per token, that the audio of a job ends in.
+#[derive(Default)]
+struct TrailingSilence {
+    frames: usize,
+}
+
+impl TrailingSilence {
+    /// Adds the `audio` that comes next, decoded from `tokens` tokens, and returns the
+    /// frames of silence that the audio ends in.
+    fn push(&mut self, audio: &VecDeque<f32>, tokens: usize) -> usize {
+        let samples = audio.iter().copied().collect::<Vec<_>>();
+        let frame = (samples.len() / tokens.max(1)).max(1);
+        for frame in samples.chunks(frame) {
+            let rms = (frame.iter().map(|v| v * v).sum::<f32>() / frame.len() as f32).sqrt();
+            self.frames = if rms < SILENCE_RMS { self.frames + 1 } else { 0 };
+        }
+        self.frames
     }
 }
 
+/// The existing audio that a job is joined with.
+struct Clips<'a> {
+    /// See [GenerationConfig::continuation].
+    continued: &'a [f32],
+    /// See [GenerationConfig::leads_into].
+    lead_into: &'a [f32],
+    /// Of all the audio, the samples of which are interleaved.
+    channels: usize,
+}
+
+impl Clips<'_> {
+    /// The full audio of a job, `decoded` from `tokens` tokens, the first `prompt_tokens`
+    /// of which are the end of the continued audio. That one is kept as is until the new
+    /// audio starts, and the new audio fades into the audio it leads into.
+    fn join(
+        &self,
+        decoded: Vec<f32>,
+        prompt_tokens: usize,
+        tokens: usize,
+        sampling_rate: usize,
+    ) -> VecDeque<f32> {
+        let channels = self.channels;
+        let prompt_samples = decoded.len() * prompt_tokens / tokens.max(1) / channels * channels;
+        let seam = (CONTINUATION_CROSSFADE_SECS * sampling_rate as f32) as usize * channels;
+        let overlap = seam.min(prompt_samples);
+        let continued = crossfade(self.continued, &decoded[prompt_samples - overlap..], overlap);
+        let lead_in = (LEAD_IN_CROSSFADE_SECS * sampling_rate as f32) as usize * channels;
+        crossfade(&continued, self.lead_into, lead_in).into()
+    }
+}
+
+/// The samples of `audio`, decoded from `tokens` tokens, that come after the first `skip` tokens.
+/// The audio has `channels` interleaved channels, which are never split.
+fn skip_tokens(
+    audio: &VecDeque<f32>,
+    skip: usize,
+    tokens: usize,
+    channels: usize,
+) -> VecDeque<f32> {
+    let start = audio.len() * skip / tokens.max(1) / channels * channels;
+    audio.range(start..).copied().collect()
+}
+
 #[derive(Clone)]
 pub struct AudioGenerationBackend {
     processor: Arc<dyn JobProcessor>,
     job_queue: Arc<RwLock<VecDeque<Job>>>,
+    /// Always locked after `job_queue`.
+    scheduler: Arc<Mutex<Scheduler>>,
     abort_token: CancellationToken,
+    workers: usize,
+    /// Set when shutting down, no more jobs are taken nor started from then on.
+    draining: Arc<AtomicBool>,
+    /// Seconds it takes a worker to generate a second of audio, averaged over the
+    /// last jobs. Unknown until a job finishes.
+    pace: Arc<Mutex<Option<f32>>>,
+    /// Audio of the last completed jobs, see [AudioGenerationBackend::with_result_cache].
+    cache: Option<Arc<ResultCache>>,
+    /// Set while the processor warms up, see [AudioGenerationBackend::warmup].
+    warming_up: Arc<AtomicBool>,
+    /// Where the tokens of the running jobs are saved, and every how many frames, see
+    /// [AudioGenerationBackend::with_checkpoints].
+    checkpoints: Option<(Arc<Checkpoints>, usize)>,
 }
 
+/// Error of the jobs submitted while the server is shutting down.
+pub const SHUTTING_DOWN: &str = "The server is shutting down";
+
 impl AudioGenerationBackend {
     pub fn new<T: JobProcessor + 'static>(processor: T) -> Self {
+        Self::with_workers(processor, 1)
+    }
+
+    /// Processes up to `workers` jobs at the same time, in scheduling order. The processor
+    /// is in charge of limiting how many of them are in each of its stages.
+    pub fn with_workers<T: JobProcessor + 'static>(processor: T, workers: usize) -> Self {
         Self {
             processor: Arc::new(processor),
             job_queue: Arc::new(RwLock::new(VecDeque::new())),
+            scheduler: Default::default(),
             abort_token: CancellationToken::new(),
+            workers: workers.max(1),
+            draining: Default::default(),
+            pace: Default::default(),
+            cache: None,
+            warming_up: Default::default(),
+            checkpoints: None,
+        }
+    }
+
+    /// Saves the tokens of the running jobs in `checkpoints` every `secs` seconds of
+    /// generated audio, and resumes the jobs from theirs.
+    pub fn with_checkpoints(mut self, checkpoints: Arc<Checkpoints>, secs: usize) -> Self {
+        self.checkpoints = Some((checkpoints, secs * INPUT_IDS_BATCH_PER_SECOND));
+        self
+    }
+
+    /// Keeps the audio of the last `entries` completed jobs, and answers the jobs asking
+    /// for the same audio with it without queueing them. Disabled with 0.
+    pub fn with_result_cache(mut self, entries: usize) -> Self {
+        self.cache = (entries > 0).then(|| Arc::new(ResultCache::new(entries)));
+        self
+    }
+
+    /// Whether the backend is still taking jobs.
+    pub fn is_running(&self) -> bool {
+        !self.abort_token.is_cancelled() && !self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Warms up the processor in the background, see [JobProcessor::warmup]. Jobs are
+    /// taken in the meantime, but the backend is not ready until it is done.
+    pub fn warmup(&self) {
+        self.warming_up.store(true, Ordering::SeqCst);
+        let (processor, warming_up) = (self.processor.clone(), self.warming_up.clone());
+        std::thread::spawn(move || {
+            let started = Instant::now();
+            match processor.warmup() {
+                Ok(()) => info!("Models warmed up in {:.1}s", started.elapsed().as_secs_f32()),
+                Err(err) => warn!("Could not warm up the models: {err}"),
+            }
+            warming_up.store(false, Ordering::SeqCst);
+        });
+    }
+
+    pub fn is_warming_up(&self) -> bool {
+        self.warming_up.load(Ordering::SeqCst)
+    }
+
+    /// Stops taking new jobs and starting the waiting ones, which are left for the job
+    /// store to recover on the next start. The running ones go on until they finish.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Amount of jobs being processed.
+    pub fn running_jobs(&self) -> usize {
+        self.job_queue.read().unwrap().iter().filter(|job| job.is_taken()).count()
+    }
+
+    /// Cancels the jobs being processed, which are reported by their worker once they stop.
+    pub fn cancel_running(&self) {
+        for job in self.job_queue.read().unwrap().iter().filter(|job| job.is_taken()) {
+            job.abort_token.cancel();
+        }
+    }
+
+    /// Amount of jobs that are either waiting in the queue or being processed.
+    pub fn queue_depth(&self) -> usize {
+        self.job_queue.read().unwrap().len()
+    }
+
+    /// Amount of jobs waiting for a worker.
+    pub fn waiting_jobs(&self) -> usize {
+        self.job_queue.read().unwrap().iter().filter(|job| !job.is_taken()).count()
+    }
+
+    /// Rough time until a job submitted now would start, from the audio still to be
+    /// generated for the jobs before it. The running jobs are assumed to be half done.
+    pub fn estimated_wait(&self) -> Option<Duration> {
+        let pace = (*self.pace.lock().unwrap())?;
+        let secs: f32 = self
+            .job_queue
+            .read()
+            .unwrap()
+            .iter()
+            .map(|job| match job.is_taken() {
+                true => job.req.secs as f32 / 2.0,
+                false => job.req.secs as f32,
+            })
+            .sum();
+        Some(Duration::from_secs_f32(secs * pace / self.workers as f32))
+    }
+
+    fn record_pace(&self, secs: usize, elapsed: Duration) {
+        let last = elapsed.as_secs_f32() / secs.max(1) as f32;
+        let mut pace = self.pace.lock().unwrap();
+        *pace = Some(match *pace {
+            Some(pace) => pace + (last - pace) * PACE_SMOOTHING,
+            None => last,
+        });
+    }
+
+    /// Sends the position of the waiting jobs that moved since it was last sent. Nothing
+    /// is sent while there are idle workers, as the jobs are about to start anyway.
+    fn report_positions(
+        &self,
+        queue: &mut VecDeque<Job>,
+        scheduler: &Scheduler,
+        outbound_tx: &Sender<BackendOutboundMsg>,
+    ) {
+        if queue.iter().filter(|job| job.is_taken()).count() < self.workers {
+            return;
+        }
+        for (position, i) in scheduler.order(queue).into_iter().enumerate() {
+            let job = &mut queue[i];
+            if job.reported_position != Some(position) {
+                job.reported_position = Some(position);
+                let _ = outbound_tx.send(BackendOutboundMsg::Queued((job.req.id.clone(), position)));
+            }
         }
     }
 
     fn job_processing_loop(self, outbound_tx: Sender<BackendOutboundMsg>) {
         loop {
-            let front = {
+            let next = {
                 // Immediately drop jq so that the lock is released.
-                let jq = self.job_queue.read().unwrap();
-                jq.front().cloned()
+                let mut jq = self.job_queue.write().unwrap();
+                let mut scheduler = self.scheduler.lock().unwrap();
+                let next = match self.draining.load(Ordering::SeqCst) {
+                    true => None,
+                    false => scheduler.order(&jq).first().map(|&i| jq[i].clone()),
+                };
+                if let Some(job) = &next {
+                    job.take();
+                    scheduler.served(&job.req.owner);
+                    // Sent while holding the lock, so that it goes before the new
+                    // positions of the jobs behind it.
+                    let _ = outbound_tx.send(BackendOutboundMsg::Start(job.req.clone()));
+                    self.report_positions(&mut jq, &scheduler, &outbound_tx);
+                }
+                next
             };
-            let Some(job) = front else {
+            let Some(job) = next else {
                 if self.abort_token.is_cancelled() {
                     return;
                 }
@@ -130,45 +895,132 @@ impl AudioGenerationBackend {
                 continue;
             };
 
-            let _ = outbound_tx.send(BackendOutboundMsg::Start(job.req.clone()));
-
             let output_tx_clone = outbound_tx.clone();
-            let abort_token = self.abort_token.clone();
             let job_id = job.req.id.clone();
             let cbk = Box::new(move |p| {
                 let msg = BackendOutboundMsg::Progress((job_id.clone(), p));
                 let _ = output_tx_clone.send(msg);
-                abort_token.is_cancelled() || job.abort_token.is_cancelled()
             });
 
-            let msg = match self.processor.process(&job.req.prompt, job.req.secs, cbk) {
-                Ok(filepath) => BackendOutboundMsg::Response((job.req.id, filepath)),
-                Err(err) => BackendOutboundMsg::Failure((job.req.id, err.to_string())),
+            let mut config = job.req.config.clone();
+            let checkpoint = self.checkpoints.clone().map(|(checkpoints, every)| {
+                match checkpoints.load(&job.req.id) {
+                    Ok(Some(tokens)) if !tokens.is_empty() => {
+                        info!("Resuming job {} from its checkpoint", job.req.id);
+                        config.resumed_tokens = Some(tokens);
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!("Could not load the checkpoint of {}: {err}", job.req.id),
+                }
+                (checkpoints, every, Mutex::new(vec![]))
+            });
+            let checkpoint = Arc::new(checkpoint);
+
+            let output_tx_clone = outbound_tx.clone();
+            let job_id = job.req.id.clone();
+            let job_checkpoint = checkpoint.clone();
+            let tokens_cbk = Box::new(move |tokens: Vec<i64>| {
+                if let Some((checkpoints, every, pending)) = job_checkpoint.as_ref() {
+                    let mut pending = pending.lock().unwrap();
+                    pending.push(tokens.clone());
+                    if pending.len() >= *every {
+                        if let Err(err) = checkpoints.append(&job_id, &pending) {
+                            warn!("Could not checkpoint job {job_id}: {err}");
+                        }
+                        pending.clear();
+                    }
+                }
+                let msg = BackendOutboundMsg::Tokens((job_id.clone(), tokens));
+                let _ = output_tx_clone.send(msg);
+            });
+
+            let output_tx_clone = outbound_tx.clone();
+            let job_id = job.req.id.clone();
+            let offset = AtomicUsize::new(0);
+            let audio_cbk = Box::new(move |chunk: VecDeque<f32>| {
+                let start = offset.fetch_add(chunk.len(), Ordering::SeqCst);
+                let msg = BackendOutboundMsg::Audio((job_id.clone(), start, chunk));
+                let _ = output_tx_clone.send(msg);
+            });
+
+            let started = Instant::now();
+            let result = self.processor.process(
+                &job.req.prompt,
+                job.req.secs,
+                &config,
+                job.abort_token.clone(),
+                cbk,
+                tokens_cbk,
+                audio_cbk,
+            );
+            if let Some((checkpoints, _, _)) = checkpoint.as_ref() {
+                checkpoints.remove(&job.req.id);
+            }
+            // Other workers might have finished jobs behind this one, so it is looked up
+            // instead of popping the front. Aborted jobs are already gone. Removed before
+            // reporting the outcome, so that the worker is seen as idle by then.
+            self.job_queue
+                .write()
+                .unwrap()
+                .retain(|other| !Arc::ptr_eq(&other.taken, &job.taken));
+            let id = job.req.id.clone();
+            let msg = match result {
+                _ if job.abort_token.is_cancelled() => BackendOutboundMsg::Cancelled(id),
+                Ok(audio) => {
+                    self.record_pace(job.req.secs, started.elapsed());
+                    if let Some(cache) = &self.cache {
+                        let seed = job.req.config.seed.unwrap_or_default();
+                        cache.insert(job.cache_key.clone(), audio.clone(), seed);
+                    }
+                    BackendOutboundMsg::Response((id, audio, false))
+                }
+                Err(err) => BackendOutboundMsg::Failure((id, ApiError::from(err))),
             };
             let _ = outbound_tx.send(msg);
-            self.job_queue.write().unwrap().pop_front();
         }
     }
 
-    fn msg_processing_loop(self, inbound_rx: Receiver<BackendInboundMsg>) {
+    fn msg_processing_loop(
+        self,
+        inbound_rx: Receiver<BackendInboundMsg>,
+        outbound_tx: Sender<BackendOutboundMsg>,
+    ) {
         while let Ok(msg) = inbound_rx.recv() {
             match msg {
+                BackendInboundMsg::Request(req) if self.draining.load(Ordering::SeqCst) => {
+                    let error = ApiError::new(ErrorCode::ShuttingDown, SHUTTING_DOWN);
+                    let _ = outbound_tx.send(BackendOutboundMsg::Failure((req.id, error)));
+                }
                 BackendInboundMsg::Request(req) => {
-                    self.job_queue.write().unwrap().push_back(Job::new(req));
+                    let key = ResultCache::key(&self.processor.name(), &req);
+                    if let Some((audio, seed)) = self.cache.as_ref().and_then(|c| c.get(&key)) {
+                        info!("Answering job {} with the audio of an identical one", req.id);
+                        let id = req.id.clone();
+                        let mut req = req;
+                        req.config.seed = Some(seed);
+                        let _ = outbound_tx.send(BackendOutboundMsg::Start(req));
+                        let _ = outbound_tx.send(BackendOutboundMsg::Response((id, audio, true)));
+                        continue;
+                    }
+                    let job = Job::new(req, key, &self.abort_token);
+                    let mut queue = self.job_queue.write().unwrap();
+                    queue.push_back(job);
+                    self.report_positions(&mut queue, &self.scheduler.lock().unwrap(), &outbound_tx);
                 }
                 BackendInboundMsg::Abort(id) => {
                     let mut queue = self.job_queue.write().unwrap();
-                    let mut to_remove = None;
-                    for (i, job) in queue.iter().enumerate() {
-                        if job.req.id == id {
-                            to_remove = Some(i);
-                            job.abort_token.cancel();
-                            break;
-                        }
-                    }
-                    if let Some(to_remove) = to_remove {
-                        queue.remove(to_remove);
+                    let Some(i) = queue.iter().position(|job| job.req.id == id) else {
+                        continue;
+                    };
+                    let Some(job) = queue.remove(i) else {
+                        continue;
+                    };
+                    job.abort_token.cancel();
+                    // Jobs being processed are reported by their worker once they stop.
+                    if job.take() {
+                        let _ = outbound_tx.send(BackendOutboundMsg::Cancelled(id));
                     }
+                    self.report_positions(&mut queue, &self.scheduler.lock().unwrap(), &outbound_tx);
                 }
             }
         }
@@ -179,12 +1031,15 @@ impl AudioGenerationBackend {
         let (inbound_tx, inbound_rx) = channel::<BackendInboundMsg>();
         let (outbound_tx, outbound_rx) = channel::<BackendOutboundMsg>();
 
-        // Job processing loop.
-        let self_clone = self.clone();
-        std::thread::spawn(move || self_clone.job_processing_loop(outbound_tx));
+        // Job processing loops.
+        for _ in 0..self.workers {
+            let self_clone = self.clone();
+            let outbound_tx = outbound_tx.clone();
+            std::thread::spawn(move || self_clone.job_processing_loop(outbound_tx));
+        }
 
         // Communications processing loop.
-        std::thread::spawn(move || self.msg_processing_loop(inbound_rx));
+        std::thread::spawn(move || self.msg_processing_loop(inbound_rx, outbound_tx));
 
         (inbound_tx, outbound_rx)
     }
@@ -209,6 +1064,9 @@ mod tests {
             id: id.clone(),
             prompt: "".to_string(),
             secs: 4,
+            config: Default::default(),
+            priority: Priority::Interactive,
+            owner: String::new(),
         }))?;
 
         assert_eq!(rx.recv()?.unwrap_start().id, id);
@@ -224,6 +1082,131 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn resamples_the_audio_to_the_output_rate() {
+        let audio = VecDeque::from([1.0, -1.0, 1.0, -1.0]);
+        assert_eq!(to_output_rate(audio.clone(), 32000, 2), audio);
+        assert_eq!(to_output_rate(audio, 16000, 2), VecDeque::from([1.0, -1.0].repeat(4)));
+    }
+
+    #[test]
+    fn warms_up_in_the_background() {
+        let processor = DummyJobProcessor::new(Duration::from_millis(50));
+        let backend = AudioGenerationBackend::new(processor);
+        backend.warmup();
+        assert!(backend.is_warming_up());
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!backend.is_warming_up());
+    }
+
+    #[test]
+    fn streams_the_first_audio_early_and_then_in_rolling_windows() {
+        let mut windows = StreamingWindows::default();
+        let first = FIRST_STREAMING_CHUNK_TOKENS;
+        assert_eq!(windows.next(first - 1), None);
+        assert_eq!(windows.next(first), Some((0..first, 0)));
+        assert_eq!(windows.next(first + STREAMING_CHUNK_TOKENS - 1), None);
+        let end = first + STREAMING_CHUNK_TOKENS;
+        // The first chunk is shorter than the context, so all of it is decoded again.
+        assert_eq!(windows.next(end), Some((0..end, first)));
+        let next = end + STREAMING_CHUNK_TOKENS;
+        let context = STREAMING_CONTEXT_TOKENS;
+        assert_eq!(windows.next(next), Some((end - context..next, context)));
+        assert_eq!(windows.streamed, next);
+    }
+
+    #[test]
+    fn seeds_the_segments_by_the_token_they_start_at() {
+        assert_eq!(segment_seed(7, 0), 7);
+        // A job resumed at a token does not sample again like the segment before it.
+        assert_ne!(segment_seed(7, 250), segment_seed(7, 0));
+        assert_ne!(segment_seed(7, 250), segment_seed(7, 500));
+        assert_eq!(segment_seed(7, 250), segment_seed(7, 250));
+    }
+
+    #[test]
+    fn counts_the_silence_the_audio_ends_in() {
+        let mut silence = TrailingSilence::default();
+        // A loud or quiet frame of 10 samples for each x or _.
+        let audio = |frames: &str| -> VecDeque<f32> {
+            frames.chars().flat_map(|v| [if v == 'x' { 0.5 } else { 0.001 }; 10]).collect()
+        };
+        assert_eq!(silence.push(&audio("___"), 3), 3);
+        assert_eq!(silence.push(&audio("__"), 2), 5);
+        assert_eq!(silence.push(&audio("x__"), 3), 2);
+        assert_eq!(silence.push(&audio("_x"), 2), 0);
+    }
+
+    #[test]
+    fn joins_the_new_audio_with_the_existing_clips() {
+        let clips = Clips {
+            continued: &[1.0; 100],
+            lead_into: &[3.0; 50],
+            channels: 1,
+        };
+        // 20 tokens, the first 5 of which are the end of the continued audio.
+        let decoded = [vec![1.0; 50], vec![2.0; 150]].concat();
+        let joined = clips.join(decoded.clone(), 5, 20, 100);
+        assert_eq!(joined.len(), 100 + 150);
+        assert_eq!(joined[0], 1.0);
+        assert_eq!(joined[150], 2.0);
+        assert!((joined[249] - 3.0).abs() < 0.1);
+
+        let alone = Clips {
+            continued: &[],
+            lead_into: &[],
+            channels: 1,
+        };
+        assert_eq!(alone.join(decoded.clone(), 0, 20, 100), VecDeque::from(decoded));
+
+        // The prompt of stereo audio ends between frames, never between channels.
+        let stereo = Clips {
+            continued: &[1.0; 4],
+            lead_into: &[],
+            channels: 2,
+        };
+        assert_eq!(stereo.join(vec![2.0; 10], 1, 3, 100).len(), 4 + 10 - 2);
+    }
+
+    #[test]
+    fn answers_identical_jobs_from_the_result_cache() -> anyhow::Result<()> {
+        let backend = AudioGenerationBackend::new(DummyJobProcessor::default()).with_result_cache(1);
+
+        let (tx, rx) = backend.run();
+
+        let req = |id: &str| AudioGenerationRequest {
+            id: id.to_string(),
+            prompt: "".to_string(),
+            secs: 2,
+            config: Default::default(),
+            priority: Priority::Interactive,
+            owner: id.to_string(),
+        };
+        let ids = [Uuid::new_v4().to_string(), Uuid::new_v4().to_string()];
+        tx.send(BackendInboundMsg::Request(req(&ids[0])))?;
+        let start = rx.recv()?.unwrap_start();
+        assert_eq!(start.id, ids[0]);
+        // Unseeded jobs are given a seed, the cached audio is reported with it.
+        let seed = start.config.seed;
+        assert!(seed.is_some());
+        assert_eq!(rx.recv()?.unwrap_progress().1, 0.5);
+        assert_eq!(rx.recv()?.unwrap_progress().1, 1.0);
+        let BackendOutboundMsg::Response((_, audio, false)) = rx.recv()? else {
+            panic!("expected the audio to be generated")
+        };
+
+        tx.send(BackendInboundMsg::Request(req(&ids[1])))?;
+        let start = rx.recv()?.unwrap_start();
+        assert_eq!((start.id, start.config.seed), (ids[1].clone(), seed));
+        let BackendOutboundMsg::Response((id, cached, true)) = rx.recv()? else {
+            panic!("expected the audio to be taken from the cache")
+        };
+        assert_eq!(id, ids[1]);
+        assert_eq!(cached, audio);
+
+        Ok(())
+    }
+
     #[test]
     fn handles_job_failure() -> anyhow::Result<()> {
         let backend = AudioGenerationBackend::new(DummyJobProcessor::default());
@@ -235,6 +1218,9 @@ mod tests {
             id: id.clone(),
             prompt: "fail at 2".to_string(),
             secs: 4,
+            config: Default::default(),
+            priority: Priority::Interactive,
+            owner: String::new(),
         }))?;
 
         assert_eq!(rx.recv()?.unwrap_start().id, id);
@@ -258,6 +1244,9 @@ mod tests {
             id: id.clone(),
             prompt: "".to_string(),
             secs: 4,
+            config: Default::default(),
+            priority: Priority::Interactive,
+            owner: String::new(),
         }))?;
 
         tokio::time::sleep(Duration::from_millis(50)).await;
@@ -265,13 +1254,16 @@ mod tests {
 
         assert_eq!(rx.recv()?.unwrap_start().id, id);
         assert_eq!(rx.recv()?.unwrap_progress().1, 0.25);
-        assert_eq!(rx.recv()?.unwrap_err().1, "Aborted");
+        assert_eq!(rx.recv()?.unwrap_cancelled(), id);
 
         let id = Uuid::new_v4().to_string();
         tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
             id: id.clone(),
             prompt: "".to_string(),
             secs: 1,
+            config: Default::default(),
+            priority: Priority::Interactive,
+            owner: String::new(),
         }))?;
 
         assert_eq!(rx.recv()?.unwrap_start().id, id);
@@ -279,4 +1271,193 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn cancels_queued_jobs() -> anyhow::Result<()> {
+        let processor = DummyJobProcessor::new(Duration::from_millis(50));
+        let backend = AudioGenerationBackend::new(processor);
+
+        let (tx, rx) = backend.run();
+
+        let ids = [Uuid::new_v4().to_string(), Uuid::new_v4().to_string()];
+        for id in ids.iter() {
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.clone(),
+                prompt: "".to_string(),
+                secs: 2,
+                config: Default::default(),
+                priority: Priority::Interactive,
+                owner: String::new(),
+            }))?;
+        }
+        // Gives time for the first job to start.
+        std::thread::sleep(Duration::from_millis(20));
+        tx.send(BackendInboundMsg::Abort(ids[1].clone()))?;
+
+        assert_eq!(rx.recv()?.unwrap_start().id, ids[0]);
+        assert_eq!(rx.recv()?.unwrap_queued(), (ids[1].clone(), 0));
+        assert_eq!(rx.recv()?.unwrap_cancelled(), ids[1]);
+        assert_eq!(rx.recv()?.unwrap_progress().1, 0.5);
+        assert_eq!(rx.recv()?.unwrap_progress().1, 1.0);
+        assert_eq!(rx.recv()?.unwrap_response().0, ids[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn drains_jobs_on_shutdown() -> anyhow::Result<()> {
+        let processor = DummyJobProcessor::new(Duration::from_millis(50));
+        let backend = AudioGenerationBackend::new(processor);
+
+        let (tx, rx) = backend.clone().run();
+
+        let submit = |id: &str| {
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.to_string(),
+                prompt: "".to_string(),
+                secs: 2,
+                config: Default::default(),
+                priority: Priority::Interactive,
+                owner: String::new(),
+            }))
+        };
+        let ids = [Uuid::new_v4().to_string(), Uuid::new_v4().to_string(), Uuid::new_v4().to_string()];
+        submit(&ids[0])?;
+        submit(&ids[1])?;
+        // Gives time for the first job to start.
+        std::thread::sleep(Duration::from_millis(20));
+        backend.drain();
+        assert!(!backend.is_running());
+        submit(&ids[2])?;
+
+        let mut rejected = vec![];
+        loop {
+            match rx.recv()? {
+                BackendOutboundMsg::Failure((id, err)) => rejected.push((id, err.message)),
+                BackendOutboundMsg::Response((id, _, _)) => {
+                    assert_eq!(id, ids[0]);
+                    break;
+                }
+                BackendOutboundMsg::Start(req) => assert_eq!(req.id, ids[0]),
+                _ => {}
+            }
+        }
+        assert_eq!(rejected, vec![(ids[2].clone(), SHUTTING_DOWN.to_string())]);
+        // The waiting job is never started.
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(backend.running_jobs(), 0);
+        assert_eq!(backend.queue_depth(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn schedules_interactive_jobs_first_and_fairly() -> anyhow::Result<()> {
+        let processor = DummyJobProcessor::new(Duration::from_millis(50));
+        let backend = AudioGenerationBackend::new(processor);
+
+        let (tx, rx) = backend.run();
+
+        let submit = |priority: Priority, owner: &str| {
+            let id = Uuid::new_v4().to_string();
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.clone(),
+                prompt: "".to_string(),
+                secs: 1,
+                config: Default::default(),
+                priority,
+                owner: owner.to_string(),
+            }))
+            .map(|_| id)
+        };
+        let running = submit(Priority::Batch, "exporter")?;
+        // Gives time for the first job to start, the rest of them wait behind it.
+        std::thread::sleep(Duration::from_millis(20));
+        let batch = submit(Priority::Batch, "exporter")?;
+        let alice_1 = submit(Priority::Interactive, "alice")?;
+        let alice_2 = submit(Priority::Interactive, "alice")?;
+        let bob = submit(Priority::Interactive, "bob")?;
+
+        let mut started = vec![];
+        let mut positions = vec![];
+        while started.len() < 5 {
+            match rx.recv()? {
+                BackendOutboundMsg::Start(req) => started.push(req.id),
+                BackendOutboundMsg::Queued(p) => positions.push(p),
+                BackendOutboundMsg::Failure((_, err)) => return Err(anyhow::anyhow!(err)),
+                _ => {}
+            }
+        }
+        assert_eq!(started, vec![running, alice_1, bob.clone(), alice_2, batch.clone()]);
+        assert!(positions.contains(&(batch, 3)));
+        assert!(positions.contains(&(bob, 0)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn processes_jobs_concurrently() -> anyhow::Result<()> {
+        let backend =
+            AudioGenerationBackend::with_workers(DummyJobProcessor::new(Duration::from_millis(50)), 2);
+
+        let (tx, rx) = backend.run();
+
+        let ids = [Uuid::new_v4().to_string(), Uuid::new_v4().to_string()];
+        for id in ids.iter() {
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.clone(),
+                prompt: "".to_string(),
+                secs: 4,
+                config: Default::default(),
+                priority: Priority::Interactive,
+                owner: String::new(),
+            }))?;
+        }
+
+        let mut started = vec![];
+        let mut finished = vec![];
+        while finished.len() < 2 {
+            match rx.recv()? {
+                BackendOutboundMsg::Start(req) => started.push(req.id),
+                BackendOutboundMsg::Response((id, _, _)) => finished.push(id),
+                BackendOutboundMsg::Failure((_, err)) => return Err(anyhow::anyhow!(err)),
+                _ => {}
+            }
+            // Both jobs start before any of them finishes.
+            if !finished.is_empty() {
+                assert_eq!(started.len(), 2);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn recreates_the_decoder_when_it_stalls() {
+        // A hung decoder keeps the token stream open without ever sending on it.
+        let (_hung, token_stream) = channel();
+        let stall_timeout = HumanDuration(Duration::from_millis(10));
+        let mut recreated = 0;
+
+        let err = next_tokens(&token_stream, stall_timeout, || recreated += 1).unwrap_err();
+
+        assert_eq!(ApiError::from(err).code, ErrorCode::TimedOut);
+        assert_eq!(recreated, 1);
+    }
+
+    #[test]
+    fn ends_the_segment_when_the_decoder_is_done() -> anyhow::Result<()> {
+        let (tx, token_stream) = channel();
+        tx.send(Ok(vec![1, 2, 3, 4]))?;
+        drop(tx);
+        let stall_timeout = HumanDuration(Duration::from_secs(60));
+        let mut recreated = 0;
+
+        let next = next_tokens(&token_stream, stall_timeout, || recreated += 1)?;
+        assert_eq!(next, Some(vec![1, 2, 3, 4]));
+        assert_eq!(next_tokens(&token_stream, stall_timeout, || recreated += 1)?, None);
+        assert_eq!(recreated, 0);
+
+        Ok(())
+    }
 }
@@ -1,12 +1,22 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use tokio::sync::{broadcast, oneshot, watch, Semaphore};
 use tracing::info;
 use uuid::Uuid;
 
 use crate::audio_manager::AudioManager;
-use crate::backend::audio_generation_backend::BackendOutboundMsg;
+use crate::backend::audio_generation_backend::{BackendOutboundMsg, INPUT_IDS_BATCH_PER_SECOND};
+use crate::backend::errors::ErrorCode;
 use crate::backend::music_gpt_chat::ChatEntry;
 use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::flac::to_flac;
+use crate::mp3::to_mp3;
+use crate::music_gen_config::{AudioFormat, FlacConfig, GenerationConfig, Mp3Config, MusicalHints};
+use crate::pcm::{to_pcm, BitDepth};
 use crate::storage::Storage;
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -22,12 +32,81 @@ pub struct AudioGenerationProgress {
     pub id: Uuid,
     pub chat_id: Uuid,
     pub progress: f32,
+    /// Jobs to be started before this one, only set while the job is waiting.
+    pub queue_position: Option<usize>,
+    /// Tokens generated so far, only set while the job runs.
+    pub tokens: Option<usize>,
+    /// Tokens the job generates in total, only set while the job runs.
+    pub total_tokens: Option<usize>,
+    /// Same as `progress`, from 0 to 100.
+    pub percent: f32,
+    /// Seconds until the job is done at the recent generation speed, only set once
+    /// there is enough of it to tell.
+    pub eta_secs: Option<f32>,
+}
+
+impl AudioGenerationProgress {
+    fn queued(id: Uuid, chat_id: Uuid, queue_position: usize) -> Self {
+        Self {
+            id,
+            chat_id,
+            progress: 0.0,
+            queue_position: Some(queue_position),
+            tokens: None,
+            total_tokens: None,
+            percent: 0.0,
+            eta_secs: None,
+        }
+    }
+}
+
+/// How far back the generation speed is measured for the ETA, so that it follows
+/// the machine getting busier or freer.
+const ETA_WINDOW: Duration = Duration::from_secs(10);
+
+/// Token counts of a running job over the last [ETA_WINDOW].
+struct Pace {
+    total_tokens: usize,
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl Pace {
+    fn new(secs: usize) -> Self {
+        Self {
+            total_tokens: secs.saturating_mul(INPUT_IDS_BATCH_PER_SECOND),
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Records that `progress` was reached at `now`, returning the tokens generated
+    /// so far and the seconds left.
+    fn update(&mut self, progress: f32, now: Instant) -> (usize, Option<f32>) {
+        let tokens = (progress * self.total_tokens as f32).round() as usize;
+        self.samples.push_back((now, tokens));
+        while let Some((at, _)) = self.samples.front() {
+            if now.duration_since(*at) <= ETA_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        let (since, from) = self.samples.front().copied().unwrap_or((now, tokens));
+        let elapsed = now.duration_since(since).as_secs_f32();
+        let eta = match (elapsed > 0.0, tokens > from) {
+            (true, true) => {
+                let per_sec = (tokens - from) as f32 / elapsed;
+                Some(self.total_tokens.saturating_sub(tokens) as f32 / per_sec)
+            }
+            _ => None,
+        };
+        (tokens, eta)
+    }
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub struct AudioGenerationError {
     pub id: Uuid,
     pub chat_id: Uuid,
+    pub code: ErrorCode,
     pub error: String,
 }
 
@@ -36,6 +115,42 @@ pub struct AudioGenerationResult {
     pub id: Uuid,
     pub chat_id: Uuid,
     pub relpath: String,
+    /// The audio is the one of an identical job that completed before, so it was
+    /// not generated again.
+    pub cached: bool,
+    /// Seed the audio was generated with, the same job with it generates it again.
+    pub seed: Option<u32>,
+    /// Tempo, key and time signature the audio was asked for.
+    pub hints: Option<MusicalHints>,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct AudioGenerationCancelled {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+}
+
+/// Raw codebook token ids generated in one decoding step. These are only
+/// forwarded to connections that explicitly tapped into the job.
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct AudioGenerationTokens {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub tokens: Vec<i64>,
+}
+
+/// A piece of the audio of a job sent while it is generated, as 16 bit PCM. Only
+/// forwarded to connections that negotiated audio streaming.
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct AudioGenerationChunk {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    /// Position of the first sample of the chunk in the whole audio.
+    pub offset: usize,
+    pub sample_rate: u32,
+    /// The samples of the stereo models are interleaved, left first.
+    pub channels: u16,
+    pub samples: Vec<i16>,
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -44,21 +159,195 @@ pub enum GenerationMessage {
     Progress(AudioGenerationProgress),
     Error(AudioGenerationError),
     Result(AudioGenerationResult),
+    Cancelled(AudioGenerationCancelled),
+    Tokens(AudioGenerationTokens),
+    Audio(AudioGenerationChunk),
+}
+
+impl GenerationMessage {
+    pub fn id(&self) -> Uuid {
+        match self {
+            GenerationMessage::Start(m) => m.id,
+            GenerationMessage::Progress(m) => m.id,
+            GenerationMessage::Error(m) => m.id,
+            GenerationMessage::Result(m) => m.id,
+            GenerationMessage::Cancelled(m) => m.id,
+            GenerationMessage::Tokens(m) => m.id,
+            GenerationMessage::Audio(m) => m.id,
+        }
+    }
+
+    pub fn chat_id(&self) -> Uuid {
+        match self {
+            GenerationMessage::Start(m) => m.chat_id,
+            GenerationMessage::Progress(m) => m.chat_id,
+            GenerationMessage::Error(m) => m.chat_id,
+            GenerationMessage::Result(m) => m.chat_id,
+            GenerationMessage::Cancelled(m) => m.chat_id,
+            GenerationMessage::Tokens(m) => m.chat_id,
+            GenerationMessage::Audio(m) => m.chat_id,
+        }
+    }
+}
+
+/// Number of [GenerationMessage]s kept for the connections that resume a session.
+pub const REPLAY_CAPACITY: usize = 4096;
+
+/// Where a job that has not finished yet is at, for bringing up to date the ones
+/// that start following it halfway.
+#[derive(Clone, Debug, Default)]
+pub struct JobSnapshot {
+    pub start: Option<AudioGenerationStart>,
+    pub progress: Option<AudioGenerationProgress>,
+}
+
+impl JobSnapshot {
+    /// The messages that get a new subscriber to the state of the job.
+    pub fn messages(&self) -> Vec<GenerationMessage> {
+        let start = self.start.clone().map(GenerationMessage::Start);
+        let progress = self.progress.clone().map(GenerationMessage::Progress);
+        start.into_iter().chain(progress).collect()
+    }
+
+    pub fn chat_id(&self) -> Option<Uuid> {
+        let start = self.start.as_ref().map(|v| v.chat_id);
+        start.or(self.progress.as_ref().map(|v| v.chat_id))
+    }
+}
+
+/// The last [GenerationMessage]s broadcast by the fanout, numbered in the order they
+/// were sent, so that a connection can pick up where a previous one left.
+#[derive(Clone)]
+pub struct EventLog {
+    events: Arc<Mutex<VecDeque<(u64, GenerationMessage)>>>,
+    latest: Arc<watch::Sender<u64>>,
+    /// The jobs that have not finished, which can be followed by any number of
+    /// connections no matter when they subscribe.
+    unfinished: Arc<Mutex<HashMap<Uuid, JobSnapshot>>>,
+    /// The audio generated so far by the unfinished jobs, for streaming it from the
+    /// start to the ones that come in late.
+    audio: Arc<Mutex<HashMap<Uuid, Vec<i16>>>>,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self {
+            events: Default::default(),
+            latest: Arc::new(watch::channel(0).0),
+            unfinished: Default::default(),
+            audio: Default::default(),
+        }
+    }
 }
 
+impl EventLog {
+    fn publish(&self, tx: &broadcast::Sender<GenerationMessage>, msg: GenerationMessage) {
+        self.track(&msg);
+        let mut events = self.events.lock().unwrap();
+        let seq = *self.latest.borrow() + 1;
+        events.push_back((seq, msg.clone()));
+        if events.len() > REPLAY_CAPACITY {
+            events.pop_front();
+        }
+        let _ = tx.send(msg);
+        self.latest.send_replace(seq);
+    }
+
+    fn track(&self, msg: &GenerationMessage) {
+        let mut unfinished = self.unfinished.lock().unwrap();
+        match msg {
+            GenerationMessage::Start(m) => {
+                unfinished.entry(m.id).or_default().start = Some(m.clone());
+            }
+            GenerationMessage::Progress(m) => {
+                unfinished.entry(m.id).or_default().progress = Some(m.clone());
+            }
+            GenerationMessage::Error(_)
+            | GenerationMessage::Result(_)
+            | GenerationMessage::Cancelled(_) => {
+                unfinished.remove(&msg.id());
+                self.audio.lock().unwrap().remove(&msg.id());
+            }
+            GenerationMessage::Audio(m) => {
+                let mut audio = self.audio.lock().unwrap();
+                let samples = audio.entry(m.id).or_default();
+                if m.offset == samples.len() {
+                    samples.extend_from_slice(&m.samples);
+                }
+            }
+            GenerationMessage::Tokens(_) => {}
+        }
+    }
+
+    /// The audio generated so far by the job, empty if it finished or is not known.
+    pub fn audio_so_far(&self, id: Uuid) -> Vec<i16> {
+        self.audio.lock().unwrap().get(&id).cloned().unwrap_or_default()
+    }
+
+    /// Where the job is at, none if it finished or is not known.
+    pub fn snapshot(&self, id: Uuid) -> Option<JobSnapshot> {
+        self.unfinished.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Where the unfinished jobs of the chat are at.
+    pub fn snapshots_of(&self, chat_id: Uuid) -> Vec<JobSnapshot> {
+        let unfinished = self.unfinished.lock().unwrap();
+        let snapshots = unfinished.values().filter(|v| v.chat_id() == Some(chat_id));
+        snapshots.cloned().collect()
+    }
+
+    /// Sequence number of the last message sent.
+    pub fn last_seq(&self) -> u64 {
+        *self.latest.borrow()
+    }
+
+    /// The messages still kept that were sent after `seq`.
+    pub fn since(&self, seq: u64) -> Vec<(u64, GenerationMessage)> {
+        let events = self.events.lock().unwrap();
+        let start = events.partition_point(|(v, _)| *v <= seq);
+        events.range(start..).cloned().collect()
+    }
+
+    /// Notified with the sequence number of every message sent.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.latest.subscribe()
+    }
+}
+
+/// Turns the backend messages into [GenerationMessage]s, saving the chat entries and
+/// the generated audio on the way. Up to `post_processing` audios are encoded and
+/// written at the same time, but their results are sent in the order the jobs completed,
+/// and the rest of the messages are forwarded in order. The audio
+/// has `channels` interleaved channels, and is also encoded with `mp3` or `flac` for the
+/// jobs that ask for it.
 pub fn audio_generation_fanout<S: Storage + 'static>(
     ai_rx: std::sync::mpsc::Receiver<BackendOutboundMsg>,
     storage: S,
+    post_processing: usize,
+    channels: u16,
+    mp3: Mp3Config,
+    flac: FlacConfig,
+    events: EventLog,
 ) -> tokio::sync::broadcast::Sender<GenerationMessage> {
     let (ai_broadcast_tx, _) = tokio::sync::broadcast::channel(1000); // Arbitrary number.
 
     let mut ai_rx = std_to_tokio_receiver(ai_rx);
     let ai_broadcast_tx_clone = ai_broadcast_tx.clone();
-    let audio_manager = AudioManager::default();
+    let post_processing = Arc::new(Semaphore::new(post_processing.max(1)));
+    let encodings = Arc::new((mp3, flac));
     tokio::spawn(async move {
+        // The pace of the running jobs, for telling how long they have left.
+        let mut paces = HashMap::<String, Pace>::new();
+        // The configs of the running jobs, the seed and hints of which are reported once
+        // they complete, and the format of which their audio is saved in.
+        let mut configs = HashMap::<String, GenerationConfig>::new();
+        // Done once the result of the last completed job is sent.
+        let mut last_result = None::<oneshot::Receiver<()>>;
         while let Some(msg) = ai_rx.recv().await {
             let outbound_msg = match msg {
                 BackendOutboundMsg::Start(msg) => {
+                    paces.insert(msg.id.clone(), Pace::new(msg.secs));
+                    configs.insert(msg.id.clone(), msg.config.clone());
                     let IdPair(chat_id, id) = msg.id.into();
                     let entry = ChatEntry::new_user(chat_id, id, msg.prompt.clone());
                     let _ = entry.save(&storage).await;
@@ -69,58 +358,179 @@ pub fn audio_generation_fanout<S: Storage + 'static>(
                         secs: msg.secs,
                     })
                 }
-                BackendOutboundMsg::Response((id, queue)) => {
-                    info!("Audio generated successfully");
-                    let IdPair(chat_id, id) = id.into();
-                    let relpath = format!("audios/{}.wav", id);
-                    let save_audio = || async {
-                        let bytes = audio_manager.to_wav(queue)?;
-                        storage.write(&relpath, bytes).await?;
-                        Ok::<(), anyhow::Error>(())
-                    };
-                    // If audio failed to be saved, do not count as a success.
-                    if let Err(err) = save_audio().await {
-                        let entry = ChatEntry::new_ai_err(chat_id, id, err.to_string());
-                        let _ = entry.save(&storage).await;
-                        GenerationMessage::Error(AudioGenerationError {
-                            id,
-                            chat_id,
-                            error: err.to_string(),
-                        })
-                    } else {
-                        let entry = ChatEntry::new_ai_success(chat_id, id, relpath.clone());
-                        let _ = entry.save(&storage).await;
-                        GenerationMessage::Result(AudioGenerationResult {
-                            id,
-                            chat_id,
-                            relpath,
-                        })
+                BackendOutboundMsg::Response((id, queue, cached)) => {
+                    match cached {
+                        true => info!("Audio taken from the result cache"),
+                        false => info!("Audio generated successfully"),
                     }
+                    paces.remove(&id);
+                    let GenerationConfig { seed, hints, format, .. } =
+                        configs.remove(&id).unwrap_or_default();
+                    let format = format.unwrap_or_default();
+                    let IdPair(chat_id, id) = id.into();
+                    let (storage, ai_broadcast_tx) = (storage.clone(), ai_broadcast_tx.clone());
+                    let (post_processing, events) = (post_processing.clone(), events.clone());
+                    let encodings = encodings.clone();
+                    let (sent_tx, sent_rx) = oneshot::channel();
+                    let previous = last_result.replace(sent_rx);
+                    tokio::spawn(async move {
+                        let msg = {
+                            let Ok(_permit) = post_processing.acquire().await else {
+                                return;
+                            };
+                            let audio =
+                                GeneratedAudio { queue, channels, cached, seed, hints, format };
+                            let (mp3, flac) = encodings.as_ref();
+                            save_audio(&storage, chat_id, id, audio, mp3, flac).await
+                        };
+                        if let Some(previous) = previous {
+                            let _ = previous.await;
+                        }
+                        events.publish(&ai_broadcast_tx, msg);
+                        let _ = sent_tx.send(());
+                    });
+                    continue;
                 }
                 BackendOutboundMsg::Failure((id, error)) => {
                     info!("Error generating audio {error}");
+                    paces.remove(&id);
+                    configs.remove(&id);
                     let IdPair(chat_id, id) = id.into();
-                    let entry = ChatEntry::new_ai_err(chat_id, id, error.clone());
+                    let entry = ChatEntry::new_ai_err(chat_id, id, error.message.clone());
                     let _ = entry.save(&storage).await;
-                    GenerationMessage::Error(AudioGenerationError { id, chat_id, error })
+                    GenerationMessage::Error(AudioGenerationError {
+                        id,
+                        chat_id,
+                        code: error.code,
+                        error: error.message,
+                    })
+                }
+                BackendOutboundMsg::Cancelled(id) => {
+                    info!("Audio generation cancelled");
+                    paces.remove(&id);
+                    configs.remove(&id);
+                    let IdPair(chat_id, id) = id.into();
+                    let entry = ChatEntry::new_ai_err(chat_id, id, "Cancelled".to_string());
+                    let _ = entry.save(&storage).await;
+                    GenerationMessage::Cancelled(AudioGenerationCancelled { id, chat_id })
+                }
+                BackendOutboundMsg::Queued((id, position)) => {
+                    let IdPair(chat_id, id) = id.into();
+                    let progress = AudioGenerationProgress::queued(id, chat_id, position);
+                    GenerationMessage::Progress(progress)
                 }
                 BackendOutboundMsg::Progress((id, progress)) => {
+                    let pace = paces.get_mut(&id);
+                    let total_tokens = pace.as_ref().map(|v| v.total_tokens);
+                    let (tokens, eta_secs) = match pace {
+                        Some(pace) => {
+                            let (tokens, eta) = pace.update(progress, Instant::now());
+                            (Some(tokens), eta)
+                        }
+                        None => (None, None),
+                    };
                     let IdPair(chat_id, id) = id.into();
                     GenerationMessage::Progress(AudioGenerationProgress {
                         id,
                         chat_id,
                         progress,
+                        queue_position: None,
+                        tokens,
+                        total_tokens,
+                        percent: progress * 100.0,
+                        eta_secs,
+                    })
+                }
+                BackendOutboundMsg::Tokens((id, tokens)) => {
+                    let IdPair(chat_id, id) = id.into();
+                    GenerationMessage::Tokens(AudioGenerationTokens {
+                        id,
+                        chat_id,
+                        tokens,
+                    })
+                }
+                BackendOutboundMsg::Audio((id, offset, samples)) => {
+                    let IdPair(chat_id, id) = id.into();
+                    let (samples, _) = to_pcm(samples, BitDepth::I16, false);
+                    GenerationMessage::Audio(AudioGenerationChunk {
+                        id,
+                        chat_id,
+                        offset,
+                        sample_rate: AudioManager::default().sampling_rate(),
+                        channels,
+                        samples: samples.into_iter().map(|v| v as i16).collect(),
                     })
                 }
             };
-            let _ = ai_broadcast_tx.send(outbound_msg);
+            events.publish(&ai_broadcast_tx, outbound_msg);
         }
     });
 
     ai_broadcast_tx_clone
 }
 
-fn std_to_tokio_receiver<T: Send + 'static>(
+/// The audio of a completed job, as the backend reported it.
+struct GeneratedAudio {
+    queue: VecDeque<f32>,
+    channels: u16,
+    cached: bool,
+    seed: Option<u32>,
+    hints: Option<MusicalHints>,
+    format: AudioFormat,
+}
+
+/// Saves the audio as a wav, which the melodies, the stems and the continuations read, and
+/// also in the format the job asked for, which the result points at.
+async fn save_audio<S: Storage>(
+    storage: &S,
+    chat_id: Uuid,
+    id: Uuid,
+    audio: GeneratedAudio,
+    mp3: &Mp3Config,
+    flac: &FlacConfig,
+) -> GenerationMessage {
+    let GeneratedAudio { queue, channels, cached, seed, hints, format } = audio;
+    let relpath = format!("audios/{}.{}", id, format.extension());
+    let save = || async {
+        let audio_manager = AudioManager::with_channels(channels);
+        let (samples, rate) = (queue.iter().copied(), audio_manager.sampling_rate());
+        let encoded = match format {
+            AudioFormat::Wav => None,
+            AudioFormat::Mp3 => Some(to_mp3(samples, channels, rate, mp3)?),
+            AudioFormat::Flac => Some(to_flac(samples, channels, rate, flac)?),
+        };
+        if let Some(bytes) = encoded {
+            storage.write(&relpath, bytes).await?;
+        }
+        let bytes = audio_manager.to_wav(queue)?;
+        storage.write(&format!("audios/{}.wav", id), bytes).await?;
+        Ok::<(), anyhow::Error>(())
+    };
+    // If audio failed to be saved, do not count as a success.
+    if let Err(err) = save().await {
+        let entry = ChatEntry::new_ai_err(chat_id, id, err.to_string());
+        let _ = entry.save(storage).await;
+        GenerationMessage::Error(AudioGenerationError {
+            id,
+            chat_id,
+            code: ErrorCode::Internal,
+            error: err.to_string(),
+        })
+    } else {
+        let entry = ChatEntry::new_ai_success(chat_id, id, relpath.clone());
+        let _ = entry.save(storage).await;
+        GenerationMessage::Result(AudioGenerationResult {
+            id,
+            chat_id,
+            relpath,
+            cached,
+            seed,
+            hints,
+        })
+    }
+}
+
+pub(crate) fn std_to_tokio_receiver<T: Send + 'static>(
     std_rx: std::sync::mpsc::Receiver<T>,
 ) -> tokio::sync::mpsc::UnboundedReceiver<T> {
     let (tokio_tx, tokio_rx) = tokio::sync::mpsc::unbounded_channel();
@@ -131,3 +541,23 @@ fn std_to_tokio_receiver<T: Send + 'static>(
     });
     tokio_rx
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_the_time_left_from_the_recent_pace() {
+        let mut pace = Pace::new(4);
+        assert_eq!(pace.total_tokens, 200);
+        let start = Instant::now();
+        assert_eq!(pace.update(0.0, start), (0, None));
+        // 50 tokens per second, 150 left.
+        assert_eq!(pace.update(0.25, start + Duration::from_secs(1)), (50, Some(3.0)));
+
+        // Only the last seconds count, here it went down to 10 tokens per second.
+        let later = start + ETA_WINDOW + Duration::from_secs(2);
+        pace.update(0.5, later);
+        assert_eq!(pace.update(0.75, later + Duration::from_secs(5)), (150, Some(5.0)));
+    }
+}
@@ -0,0 +1,183 @@
+use std::net::SocketAddr;
+
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::backend::health::PUBLIC_PATHS;
+use crate::backend::invites::{cookie_token, query_token, Invites, INVITE_COOKIE};
+use crate::music_gen_config::Secret;
+
+/// Path of the WebSocket endpoint, where clients can authenticate with their first
+/// message, as browsers cannot set headers on WebSocket connections.
+const WS_PATH: &str = "/ws";
+
+/// Who is making a request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Access {
+    /// Full access, like the local machine or clients with the API key.
+    Owner,
+    /// Access granted by the invite with this token.
+    Guest(String),
+    /// WebSocket clients that still need to send the API key.
+    Unauthenticated,
+}
+
+/// Decides the [Access] of the clients. Clients on the local machine are owners,
+/// remote ones need either the API key or an invite.
+#[derive(Clone)]
+pub struct Auth {
+    pub invites: Invites,
+    api_key: Option<Secret>,
+    /// Whether the clients on the loopback address are the local machine, which is not
+    /// the case behind a reverse proxy.
+    trust_loopback: bool,
+}
+
+impl Auth {
+    pub fn new(invites: Invites, api_key: Option<Secret>) -> Self {
+        Self {
+            invites,
+            api_key,
+            trust_loopback: true,
+        }
+    }
+
+    /// Treats the clients on the loopback address like the remote ones, for when a
+    /// reverse proxy or a Unix socket makes every request come from there.
+    pub fn with_untrusted_loopback(mut self) -> Self {
+        self.trust_loopback = false;
+        self
+    }
+
+    /// What remote clients need to use the server, `api-key` also lets them in
+    /// with an invite.
+    pub fn requirement(&self) -> &'static str {
+        match self.api_key {
+            Some(_) => "api-key",
+            None => "invite",
+        }
+    }
+
+    pub fn is_api_key(&self, key: &str) -> bool {
+        self.api_key
+            .as_ref()
+            .is_some_and(|v| constant_time_eq(v.expose().as_bytes(), key.as_bytes()))
+    }
+
+    pub fn access(&self, addr: SocketAddr, api_key: Option<&str>, invite: Option<&str>) -> Option<Access> {
+        let local = self.trust_loopback && addr.ip().is_loopback();
+        if local || api_key.is_some_and(|v| self.is_api_key(v)) {
+            return Some(Access::Owner);
+        }
+        match invite {
+            Some(token) if self.invites.is_valid(token) => Some(Access::Guest(token.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Middleware that resolves the [Access] of every request into its extensions,
+    /// rejecting the ones without access.
+    pub async fn middleware(self, ConnectInfo(addr): ConnectInfo<SocketAddr>, mut req: Request, next: Next) -> Response {
+        if PUBLIC_PATHS.contains(&req.uri().path()) {
+            return next.run(req).await;
+        }
+        let api_key = bearer_token(req.headers());
+        let from_query = query_token(req.uri().query());
+        let invite = from_query.clone().or_else(|| cookie_token(req.headers()));
+        let access = match self.access(addr, api_key.as_deref(), invite.as_deref()) {
+            Some(access) => access,
+            None if req.uri().path() == WS_PATH && self.api_key.is_some() => Access::Unauthenticated,
+            None => return (StatusCode::UNAUTHORIZED, "An API key or an invite is required").into_response(),
+        };
+        let set_cookie = match (&access, from_query) {
+            (Access::Guest(_), Some(token)) => Some(token),
+            _ => None,
+        };
+        req.extensions_mut().insert(access);
+        let mut res = next.run(req).await;
+        // Remembers the invite, so that the links inside the web app keep working.
+        if let Some(token) = set_cookie {
+            let cookie = format!("{INVITE_COOKIE}={token}; Path=/; HttpOnly; SameSite=Lax");
+            if let Ok(value) = HeaderValue::from_str(&cookie) {
+                res.headers_mut().append(header::SET_COOKIE, value);
+            }
+        }
+        res
+    }
+}
+
+/// Value of the cookie `name`.
+pub fn cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|v| v.trim().strip_prefix(name)?.strip_prefix('='))
+        .map(|v| v.to_string())
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(|v| v.trim().to_string())
+}
+
+/// Compares without short-circuiting, so that the time taken does not tell how much
+/// of a guessed key was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::invites::InviteRequest;
+    use crate::music_gen_config::SecretRef;
+
+    use super::*;
+
+    #[test]
+    fn resolves_the_access_of_the_clients() -> anyhow::Result<()> {
+        std::env::set_var("MUSICGPT_TEST_API_KEY", "s3cr3t");
+        let api_key = SecretRef::Env("MUSICGPT_TEST_API_KEY".to_string()).resolve()?;
+        let (local, remote) = ("127.0.0.1:1234".parse()?, "192.168.1.20:1234".parse()?);
+
+        let open = Auth::new(Invites::default(), None);
+        assert_eq!(open.access(local, None, None), Some(Access::Owner));
+        assert_eq!(open.access(remote, None, None), None);
+        assert_eq!(open.requirement(), "invite");
+
+        let auth = Auth::new(Invites::default(), Some(api_key));
+        let invite = auth.invites.mint(&InviteRequest {
+            ttl_minutes: 10,
+            max_generations: 1,
+        })?;
+        assert_eq!(auth.access(local, None, None), Some(Access::Owner));
+        assert_eq!(auth.access(remote, None, None), None);
+        assert_eq!(auth.access(remote, Some("wrong"), None), None);
+        assert_eq!(auth.access(remote, Some("s3cr3t"), None), Some(Access::Owner));
+        assert_eq!(
+            auth.access(remote, None, Some(&invite.token)),
+            Some(Access::Guest(invite.token.clone()))
+        );
+
+        assert_eq!(open.access(remote, None, Some(&invite.token)), None);
+        assert_eq!(auth.requirement(), "api-key");
+
+        let proxied = Auth::new(Invites::default(), auth.api_key.clone());
+        let proxied = proxied.with_untrusted_loopback();
+        assert_eq!(proxied.access(local, None, None), None);
+        assert_eq!(proxied.access(local, Some("s3cr3t"), None), Some(Access::Owner));
+        let proxied_open = Auth::new(Invites::default(), None);
+        assert_eq!(proxied_open.with_untrusted_loopback().access(local, None, None), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use std::sync::mpsc::Sender;
+
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::{AudioGenerationRequest, BackendInboundMsg, Priority};
+use crate::backend::errors::ErrorCode;
+use crate::backend::job_store::{JobState, JobStatus, JobStore};
+use crate::backend::limits::validate_prompt;
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::music_gen_config::GenerationConfig;
+
+/// Max amount of jobs in a single batch, counting the variations of each prompt.
+pub const MAX_BATCH_SIZE: usize = 32;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RestBatchRequest {
+    pub prompts: Vec<String>,
+    pub secs: usize,
+    /// Chat where all the generations are added, a new one is created if not set.
+    #[serde(default)]
+    pub chat_id: Option<Uuid>,
+    /// Overrides the server config for the jobs of this batch only.
+    #[serde(default)]
+    pub config: Option<GenerationConfig>,
+    /// Variations generated for each prompt, 1 if not set. Each one has its own seed, and
+    /// all of them are jobs of the batch, next to each other.
+    #[serde(default)]
+    pub num_outputs: Option<usize>,
+    /// Batch if not set.
+    #[serde(default)]
+    pub priority: Option<Priority>,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct GenerateBatchRequest {
+    pub prompts: Vec<String>,
+    pub secs: usize,
+    /// Chat where all the generations are added, a new one is created if not set.
+    #[serde(default)]
+    pub chat_id: Option<Uuid>,
+    /// Overrides the server config for the jobs of this batch only.
+    #[serde(default)]
+    pub config: Option<GenerationConfig>,
+    /// Variations generated for each prompt, 1 if not set. Each one has its own seed, and
+    /// all of them are jobs of the batch, next to each other.
+    #[serde(default)]
+    pub num_outputs: Option<usize>,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct BatchRequest {
+    pub batch_id: Uuid,
+}
+
+/// The jobs of a batch, together with their overall state.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct BatchStatus {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    /// Queued until a job starts, and running until all of them finish. Finished
+    /// batches are completed if all their jobs are, failed if any job failed and
+    /// cancelled otherwise.
+    pub state: JobState,
+    /// Share of the batch that is done, finished jobs count as fully done.
+    pub progress: f32,
+    /// In the same order as the prompts, each one followed by its variations.
+    pub jobs: Vec<JobStatus>,
+}
+
+impl BatchStatus {
+    /// The status of a batch made of `jobs`, none if there are none.
+    pub fn new(id: Uuid, jobs: Vec<JobStatus>) -> Option<Self> {
+        let chat_id = jobs.first()?.chat_id;
+        let state = if jobs.iter().all(|v| v.state.is_finished()) {
+            if jobs.iter().all(|v| v.state == JobState::Completed) {
+                JobState::Completed
+            } else if jobs.iter().any(|v| v.state == JobState::Failed) {
+                JobState::Failed
+            } else {
+                JobState::Cancelled
+            }
+        } else if jobs.iter().all(|v| v.state == JobState::Queued) {
+            JobState::Queued
+        } else {
+            JobState::Running
+        };
+        let done: f32 = jobs
+            .iter()
+            .map(|v| if v.state.is_finished() { 1.0 } else { v.progress })
+            .sum();
+        Some(Self {
+            id,
+            chat_id,
+            state,
+            progress: done / jobs.len() as f32,
+            jobs,
+        })
+    }
+}
+
+/// Several prompts generated with the same parameters, tracked as a unit.
+pub struct Batch {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    /// The id of the job of each prompt and variation, with its seed if it is one of
+    /// several variations.
+    pub jobs: Vec<(Uuid, String, Option<u32>)>,
+    pub secs: usize,
+    pub config: GenerationConfig,
+    pub priority: Priority,
+    pub owner: String,
+}
+
+impl Batch {
+    pub fn new(
+        chat_id: Uuid,
+        prompts: Vec<String>,
+        num_outputs: usize,
+        secs: usize,
+        config: GenerationConfig,
+        priority: Priority,
+        owner: String,
+    ) -> Self {
+        // Variations differ in their seed only, consecutive from the one of the config, so
+        // that they can be repeated together. They would hit the same cached result otherwise.
+        let seeds: Vec<_> = match num_outputs {
+            1 => vec![config.seed],
+            _ => {
+                let base = config.seed.unwrap_or_else(rand::random);
+                (0..num_outputs as u32).map(|v| Some(base.wrapping_add(v))).collect()
+            }
+        };
+        let jobs = prompts
+            .into_iter()
+            .flat_map(|prompt| seeds.iter().map(move |v| (Uuid::new_v4(), prompt.clone(), *v)))
+            .collect();
+        Self {
+            id: Uuid::new_v4(),
+            chat_id,
+            jobs,
+            secs,
+            config,
+            priority,
+            owner,
+        }
+    }
+
+    /// Admits the jobs in order until one is rejected, returning how many were and
+    /// why the rest were not. Fails if not even the first one is admitted.
+    pub fn admit(
+        &self,
+        mut admit: impl FnMut(Uuid) -> anyhow::Result<()>,
+    ) -> anyhow::Result<(usize, Option<String>)> {
+        for (i, (id, _, _)) in self.jobs.iter().enumerate() {
+            if let Err(err) = admit(*id) {
+                if i == 0 {
+                    return Err(err);
+                }
+                return Ok((i, Some(err.to_string())));
+            }
+        }
+        Ok((self.jobs.len(), None))
+    }
+
+    /// Records the batch and queues its first `admitted` jobs. The rest are failed
+    /// right away with `rejection`, so that the batch still finishes.
+    pub fn queue(
+        self,
+        store: &JobStore,
+        ai_tx: &Sender<BackendInboundMsg>,
+        admitted: usize,
+        rejection: Option<String>,
+    ) -> anyhow::Result<BatchStatus> {
+        let ids: Vec<_> = self.jobs.iter().map(|(id, _, _)| *id).collect();
+        for (id, prompt, _) in &self.jobs {
+            store.insert(*id, self.chat_id, prompt, self.secs)?;
+        }
+        store.insert_batch(self.id, &ids)?;
+        for (i, (id, prompt, seed)) in self.jobs.into_iter().enumerate() {
+            if i >= admitted {
+                store.transition(id, JobState::Failed, |s| s.error = rejection.clone())?;
+                continue;
+            }
+            ai_tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: IdPair(self.chat_id, id).to_string(),
+                prompt,
+                secs: self.secs,
+                config: GenerationConfig { seed, ..self.config.clone() },
+                priority: self.priority,
+                owner: self.owner.clone(),
+            }))?;
+        }
+        let jobs = store.batch(self.id)?;
+        BatchStatus::new(self.id, jobs).ok_or_else(|| anyhow!("Batch {} is empty", self.id))
+    }
+}
+
+/// Checks the parameters shared by all the jobs of a batch.
+pub fn validate_batch(
+    prompts: &[String],
+    num_outputs: Option<usize>,
+    secs: usize,
+    config: &Option<GenerationConfig>,
+) -> anyhow::Result<()> {
+    if prompts.is_empty() {
+        return Err(ErrorCode::InvalidRequest.err("A batch needs at least one prompt"));
+    }
+    if num_outputs == Some(0) {
+        return Err(ErrorCode::InvalidRequest.err("num_outputs must be greater than 0"));
+    }
+    if prompts.len() * num_outputs.unwrap_or(1) > MAX_BATCH_SIZE {
+        let message = format!("A batch can have at most {MAX_BATCH_SIZE} prompts and variations");
+        return Err(ErrorCode::InvalidRequest.err(message));
+    }
+    if secs == 0 {
+        return Err(ErrorCode::InvalidRequest.err("secs must be greater than 0"));
+    }
+    prompts.iter().try_for_each(|prompt| validate_prompt(prompt))?;
+    if let Some(config) = config {
+        config.validate()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    #[test]
+    fn fails_the_jobs_over_the_limits() -> anyhow::Result<()> {
+        let store = JobStore::in_memory()?;
+        let (tx, rx) = channel();
+        let prompts = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let batch = Batch::new(
+            Uuid::new_v4(),
+            prompts,
+            1,
+            2,
+            GenerationConfig::default(),
+            Priority::Batch,
+            "alice".to_string(),
+        );
+        let id = batch.id;
+        let mut left = 2;
+        let (admitted, rejection) = batch.admit(|_| match left {
+            0 => Err(anyhow!("Out of quota")),
+            _ => {
+                left -= 1;
+                Ok(())
+            }
+        })?;
+        assert_eq!(admitted, 2);
+
+        let status = batch.queue(&store, &tx, admitted, rejection)?;
+        assert_eq!(rx.try_iter().count(), 2);
+        assert_eq!(status.state, JobState::Queued);
+        assert_eq!(status.jobs[2].state, JobState::Failed);
+        assert_eq!(status.jobs[2].error.as_deref(), Some("Out of quota"));
+
+        for job in &status.jobs[..2] {
+            store.transition(job.id, JobState::Running, |_| {})?;
+        }
+        store.set_progress(status.jobs[0].id, 0.5)?;
+        let status = BatchStatus::new(id, store.batch(id)?).unwrap();
+        assert_eq!(status.state, JobState::Running);
+        assert_eq!(status.progress, 0.5);
+
+        for job in &status.jobs[..2] {
+            store.transition(job.id, JobState::Completed, |_| {})?;
+        }
+        let status = BatchStatus::new(id, store.batch(id)?).unwrap();
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!(status.progress, 1.0);
+
+        let rejected = Batch::new(
+            Uuid::new_v4(),
+            vec!["a".to_string()],
+            1,
+            2,
+            GenerationConfig::default(),
+            Priority::Batch,
+            "alice".to_string(),
+        );
+        assert!(rejected.admit(|_| Err(anyhow!("Out of quota"))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gives_each_variation_its_own_seed() -> anyhow::Result<()> {
+        let store = JobStore::in_memory()?;
+        let (tx, rx) = channel();
+        let prompts = vec!["a".to_string(), "b".to_string()];
+        let config = GenerationConfig { seed: Some(7), ..Default::default() };
+        let owner = "alice".to_string();
+        let batch = Batch::new(Uuid::new_v4(), prompts, 3, 2, config, Priority::Batch, owner);
+        let status = batch.queue(&store, &tx, 6, None)?;
+        assert_eq!(status.jobs.len(), 6);
+
+        let jobs: Vec<_> = rx
+            .try_iter()
+            .map(|msg| match msg {
+                BackendInboundMsg::Request(req) => (req.prompt, req.config.seed),
+                _ => unreachable!(),
+            })
+            .collect();
+        let expected = ["a", "a", "a", "b", "b", "b"].iter().zip([7, 8, 9, 7, 8, 9]);
+        let expected: Vec<_> = expected.map(|(p, s)| (p.to_string(), Some(s))).collect();
+        assert_eq!(jobs, expected);
+
+        assert!(validate_batch(&["a".to_string()], Some(0), 2, &None).is_err());
+        assert!(validate_batch(&["a".to_string()], Some(MAX_BATCH_SIZE + 1), 2, &None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::backend::errors::ErrorCode;
+use crate::backend::music_gpt_chat::Chat;
+use crate::storage::Storage;
+
+const DAY_MS: u128 = 24 * 60 * 60 * 1000;
+
+/// Selects the chats a bulk operation applies to. All the set conditions must match, and
+/// at least one must be set unless `all` is.
+#[derive(Clone, Debug, Default, Type, Serialize, Deserialize, PartialEq)]
+pub struct ChatFilter {
+    /// Selects every chat when no other condition is set, so that an empty filter does not
+    /// select them all by mistake.
+    #[serde(default)]
+    pub all: bool,
+    /// Explicit selection of chats.
+    #[serde(default)]
+    pub chat_ids: Option<Vec<Uuid>>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub untagged: bool,
+    #[serde(default)]
+    pub older_than_days: Option<u32>,
+    /// Case insensitive match against the chat name.
+    #[serde(default)]
+    pub name_contains: Option<String>,
+}
+
+impl ChatFilter {
+    fn has_condition(&self) -> bool {
+        self.chat_ids.is_some()
+            || self.tag.is_some()
+            || self.untagged
+            || self.older_than_days.is_some()
+            || self.name_contains.is_some()
+    }
+
+    fn matches(&self, chat: &Chat, now_ms: u128) -> bool {
+        if let Some(chat_ids) = &self.chat_ids {
+            if !chat_ids.contains(&chat.chat_id) {
+                return false;
+            }
+        }
+        if let Some(tag) = &self.tag {
+            if !chat.tags.contains(tag) {
+                return false;
+            }
+        }
+        if self.untagged && !chat.tags.is_empty() {
+            return false;
+        }
+        if let Some(days) = self.older_than_days {
+            if chat.created_at + days as u128 * DAY_MS > now_ms {
+                return false;
+            }
+        }
+        if let Some(name) = &self.name_contains {
+            if !chat.name.to_lowercase().contains(&name.to_lowercase()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub enum BulkAction {
+    Delete,
+    Tag(String),
+    Untag(String),
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct BulkRequest {
+    pub filter: ChatFilter,
+    pub action: BulkAction,
+    /// Only counts the chats that would be affected, without touching them.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct BulkProgress {
+    pub processed: usize,
+    pub total: usize,
+    pub dry_run: bool,
+}
+
+/// All the chats of `user_id` that match `filter`.
+pub async fn select_chats<S: Storage>(
+    storage: &S,
+    user_id: Option<Uuid>,
+    filter: &ChatFilter,
+) -> anyhow::Result<Vec<Chat>> {
+    if !filter.all && !filter.has_condition() {
+        return Err(ErrorCode::InvalidRequest.err("The filter needs a condition, or all set"));
+    }
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let chats = Chat::load_all_for(storage, user_id).await?;
+    Ok(chats.into_iter().filter(|v| filter.matches(v, now_ms)).collect())
+}
+
+/// Applies `req` to all the chats of `user_id` matching its filter, reporting through
+/// `on_progress` after each one of them. Dry runs only return the amount of matching
+/// chats, without reporting it.
+pub async fn run_bulk<S: Storage>(
+    storage: &S,
+    user_id: Option<Uuid>,
+    req: BulkRequest,
+    on_progress: impl Fn(BulkProgress),
+) -> anyhow::Result<BulkProgress> {
+    if let BulkAction::Tag(tag) | BulkAction::Untag(tag) = &req.action {
+        validate_tag(tag)?;
+    }
+    let chats = select_chats(storage, user_id, &req.filter).await?;
+    let mut progress = BulkProgress {
+        processed: 0,
+        total: chats.len(),
+        dry_run: req.dry_run,
+    };
+    if req.dry_run {
+        return Ok(progress);
+    }
+    for mut chat in chats {
+        match &req.action {
+            BulkAction::Delete => chat.delete(storage).await?,
+            BulkAction::Tag(tag) => {
+                if !chat.tags.contains(tag) {
+                    chat.tags.push(tag.clone());
+                    chat.save(storage).await?;
+                }
+            }
+            BulkAction::Untag(tag) => {
+                if chat.tags.contains(tag) {
+                    chat.tags.retain(|v| v != tag);
+                    chat.save(storage).await?;
+                }
+            }
+        }
+        progress.processed += 1;
+        on_progress(progress.clone());
+    }
+    Ok(progress)
+}
+
+fn validate_tag(tag: &str) -> anyhow::Result<()> {
+    if tag.trim().is_empty() || tag.trim() != tag {
+        return Err(ErrorCode::InvalidRequest.err(format!("Invalid tag {tag:?}")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    async fn chat(storage: &AppFs, name: &str, age_days: u128, tags: &[&str]) -> anyhow::Result<Chat> {
+        let mut chat = Chat::load(storage, Uuid::new_v4()).await?;
+        chat.name = name.to_string();
+        chat.created_at -= age_days * DAY_MS;
+        chat.tags = tags.iter().map(|v| v.to_string()).collect();
+        chat.save(storage).await?;
+        Ok(chat)
+    }
+
+    #[tokio::test]
+    async fn dry_runs_only_count() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        chat(&storage, "old", 40, &[]).await?;
+        chat(&storage, "old tagged", 40, &["keep"]).await?;
+        chat(&storage, "new", 0, &[]).await?;
+
+        let req = BulkRequest {
+            filter: ChatFilter {
+                untagged: true,
+                older_than_days: Some(30),
+                ..Default::default()
+            },
+            action: BulkAction::Delete,
+            dry_run: true,
+        };
+        let progress = run_bulk(&storage, None, req, |_| {}).await?;
+        assert_eq!(progress.total, 1);
+        assert_eq!(progress.processed, 0);
+        assert_eq!(Chat::load_all(&storage).await?.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deletes_old_untagged_chats() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let old = chat(&storage, "old", 40, &[]).await?;
+        chat(&storage, "old tagged", 40, &["keep"]).await?;
+        chat(&storage, "new", 0, &[]).await?;
+
+        let events = Mutex::new(vec![]);
+        let req = BulkRequest {
+            filter: ChatFilter {
+                untagged: true,
+                older_than_days: Some(30),
+                ..Default::default()
+            },
+            action: BulkAction::Delete,
+            dry_run: false,
+        };
+        run_bulk(&storage, None, req, |v| events.lock().unwrap().push(v)).await?;
+        assert_eq!(events.into_inner().unwrap().last().unwrap().processed, 1);
+
+        let names = Chat::load_all(&storage).await?.into_iter().map(|v| v.name).collect::<Vec<_>>();
+        assert_eq!(names.len(), 2);
+        assert!(!names.contains(&old.name));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tags_and_untags_matching_chats() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        chat(&storage, "Experiment X: drums", 0, &[]).await?;
+        chat(&storage, "experiment x: bass", 0, &["bass"]).await?;
+        chat(&storage, "Something else", 0, &[]).await?;
+
+        let tag = |action| BulkRequest {
+            filter: ChatFilter {
+                name_contains: Some("experiment x".to_string()),
+                ..Default::default()
+            },
+            action,
+            dry_run: false,
+        };
+        let tag_x = tag(BulkAction::Tag("x".to_string()));
+        let progress = run_bulk(&storage, None, tag_x, |_| {}).await?;
+        assert_eq!(progress.processed, 2);
+        let filter = ChatFilter {
+            tag: Some("x".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(select_chats(&storage, None, &filter).await?.len(), 2);
+
+        run_bulk(&storage, None, tag(BulkAction::Untag("x".to_string())), |_| {}).await?;
+        assert!(select_chats(&storage, None, &filter).await?.is_empty());
+
+        let blank = tag(BulkAction::Tag(" ".to_string()));
+        assert!(run_bulk(&storage, None, blank, |_| {}).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn needs_a_condition_to_select_every_chat() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        chat(&storage, "mine", 0, &[]).await?;
+        let mut req = BulkRequest {
+            filter: ChatFilter::default(),
+            action: BulkAction::Delete,
+            dry_run: false,
+        };
+        assert!(run_bulk(&storage, None, req.clone(), |_| {}).await.is_err());
+        assert_eq!(Chat::load_all(&storage).await?.len(), 1);
+        // Only the chats of the user.
+        req.filter.all = true;
+        assert_eq!(run_bulk(&storage, Some(Uuid::new_v4()), req.clone(), |_| {}).await?.total, 0);
+        assert_eq!(run_bulk(&storage, None, req, |_| {}).await?.processed, 1);
+        assert!(Chat::load_all(&storage).await?.is_empty());
+        Ok(())
+    }
+}
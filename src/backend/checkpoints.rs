@@ -0,0 +1,118 @@
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+
+/// Tokens generated so far by the running jobs, saved while they run so that the jobs
+/// interrupted by a restart resume from them instead of starting over. Each frame is
+/// appended as its amount of tokens followed by the tokens, which leaves a frame cut
+/// short by a crash easy to tell apart.
+pub struct Checkpoints {
+    dir: PathBuf,
+}
+
+impl Checkpoints {
+    pub fn new(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Adds `frames` to the checkpoint of the job `id`.
+    pub fn append(&self, id: &str, frames: &[Vec<i64>]) -> std::io::Result<()> {
+        let mut bytes = vec![];
+        for frame in frames {
+            bytes.push(frame.len() as u8);
+            bytes.extend(frame.iter().flat_map(|v| v.to_le_bytes()));
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(self.path(id))?;
+        file.write_all(&bytes)?;
+        file.sync_data()
+    }
+
+    /// The frames in the checkpoint of the job `id`, if it has one.
+    pub fn load(&self, id: &str) -> std::io::Result<Option<Vec<Vec<i64>>>> {
+        Ok(self.read(id)?.map(|(frames, _)| frames))
+    }
+
+    /// The frames in the checkpoint of the job `id`, and the bytes they take.
+    fn read(&self, id: &str) -> std::io::Result<Option<(Vec<Vec<i64>>, u64)>> {
+        let mut bytes = vec![];
+        match File::open(self.path(id)) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut frames = vec![];
+        let mut rest = bytes.as_slice();
+        while let [len, tail @ ..] = rest {
+            let len = *len as usize * 8;
+            if tail.len() < len {
+                break;
+            }
+            let frame = tail[..len].chunks(8).map(|v| i64::from_le_bytes(v.try_into().unwrap()));
+            frames.push(frame.collect());
+            rest = &tail[len..];
+        }
+        Ok(Some((frames, (bytes.len() - rest.len()) as u64)))
+    }
+
+    /// Whether the job `id` can resume from its checkpoint. Only once per checkpoint, a
+    /// job that takes the server down again before checkpointing more is not resumed.
+    /// The frame that the crash cut short, if any, is dropped so that the job appends
+    /// the next ones right after the last complete one.
+    pub fn resumable(&self, id: &str) -> bool {
+        let Ok(Some((frames, len))) = self.read(id) else {
+            return false;
+        };
+        let marker = self.path(id).with_extension("resumed");
+        let resumed = std::fs::read_to_string(&marker).ok();
+        if frames.is_empty() || resumed == Some(frames.len().to_string()) {
+            return false;
+        }
+        let truncated = OpenOptions::new().write(true).open(self.path(id));
+        truncated.and_then(|file| file.set_len(len)).is_ok()
+            && std::fs::write(marker, frames.len().to_string()).is_ok()
+    }
+
+    pub fn remove(&self, id: &str) {
+        let path = self.path(id);
+        let _ = std::fs::remove_file(path.with_extension("resumed"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    /// Job ids are made of two uuids in a json array, only their characters that can go
+    /// in a file name are kept.
+    fn path(&self, id: &str) -> PathBuf {
+        let name = id.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-');
+        self.dir.join(format!("{}.tokens", name.collect::<String>()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn resumes_once_from_the_saved_frames() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("musicgpt-checkpoints-{}", Uuid::new_v4()));
+        let checkpoints = Checkpoints::new(dir)?;
+        assert_eq!(checkpoints.load("job")?, None);
+        checkpoints.append("job", &[vec![1, 2, 3, 4], vec![5, 6, 7, 8]])?;
+        checkpoints.append("job", &[vec![-1; 8]])?;
+        // A frame cut short is left out.
+        let mut file = OpenOptions::new().append(true).open(checkpoints.path("job"))?;
+        file.write_all(&[4, 1, 0, 0])?;
+        let frames = vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8], vec![-1; 8]];
+        assert_eq!(checkpoints.load("job")?, Some(frames));
+
+        assert!(checkpoints.resumable("job"));
+        assert!(!checkpoints.resumable("job"));
+        checkpoints.append("job", &[vec![9; 4]])?;
+        assert_eq!(checkpoints.load("job")?.map(|v| v[3].clone()), Some(vec![9; 4]));
+        assert!(checkpoints.resumable("job"));
+        checkpoints.remove("job");
+        assert!(!checkpoints.resumable("job"));
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::audio_generation_backend::JobProcessor;
+use crate::music_gen_config::{DeviceScheduling, GenerationConfig};
+
+/// Spreads the jobs across copies of the same model, each one on its own device.
+pub struct MultiDeviceProcessor {
+    replicas: Vec<Box<dyn JobProcessor>>,
+    scheduling: DeviceScheduling,
+    /// Jobs running on each replica.
+    running: Mutex<Vec<usize>>,
+    /// Jobs started so far, for the round robin.
+    started: AtomicUsize,
+}
+
+impl MultiDeviceProcessor {
+    pub fn new(replicas: Vec<Box<dyn JobProcessor>>, scheduling: DeviceScheduling) -> Self {
+        assert!(!replicas.is_empty(), "There must be at least one replica");
+        Self {
+            running: Mutex::new(vec![0; replicas.len()]),
+            replicas,
+            scheduling,
+            started: AtomicUsize::new(0),
+        }
+    }
+
+    /// The replica that the next job runs on, which counts as running it until [Self::finish].
+    fn pick(&self) -> usize {
+        let mut running = self.running.lock().unwrap();
+        let i = match self.scheduling {
+            DeviceScheduling::RoundRobin => {
+                self.started.fetch_add(1, Ordering::SeqCst) % self.replicas.len()
+            }
+            // The first one of the least loaded, which fills the devices in order.
+            DeviceScheduling::LeastLoaded => (0..running.len())
+                .min_by_key(|i| running[*i])
+                .unwrap_or_default(),
+        };
+        running[i] += 1;
+        i
+    }
+
+    fn finish(&self, i: usize) {
+        self.running.lock().unwrap()[i] -= 1;
+    }
+}
+
+impl JobProcessor for MultiDeviceProcessor {
+    fn name(&self) -> String {
+        self.replicas[0].name()
+    }
+
+    fn device(&self) -> String {
+        self.replicas.iter().map(|v| v.device()).collect::<Vec<_>>().join(", ")
+    }
+
+    fn channels(&self) -> u16 {
+        self.replicas[0].channels()
+    }
+
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+        on_progress: Box<dyn Fn(f32) + Sync + Send + 'static>,
+        on_tokens: Box<dyn Fn(Vec<i64>) + Sync + Send + 'static>,
+        on_audio: Box<dyn Fn(VecDeque<f32>) + Sync + Send + 'static>,
+    ) -> anyhow::Result<VecDeque<f32>> {
+        let i = self.pick();
+        let _running = scopeguard::guard(i, |i| self.finish(i));
+        self.replicas[i].process(prompt, secs, config, cancel, on_progress, on_tokens, on_audio)
+    }
+
+    fn warmup(&self) -> anyhow::Result<()> {
+        self.replicas.iter().try_for_each(|v| v.warmup())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::_test_utils::DummyJobProcessor;
+
+    fn replicas() -> Vec<Box<dyn JobProcessor>> {
+        vec![Box::new(DummyJobProcessor::default()), Box::new(DummyJobProcessor::default())]
+    }
+
+    #[test]
+    fn spreads_the_jobs_across_the_devices() {
+        let processor = MultiDeviceProcessor::new(replicas(), DeviceScheduling::RoundRobin);
+        assert_eq!([processor.pick(), processor.pick(), processor.pick()], [0, 1, 0]);
+
+        let processor = MultiDeviceProcessor::new(replicas(), DeviceScheduling::LeastLoaded);
+        assert_eq!([processor.pick(), processor.pick()], [0, 1]);
+        processor.finish(1);
+        assert_eq!(processor.pick(), 1);
+        processor.finish(0);
+        assert_eq!(processor.pick(), 0);
+        assert_eq!(processor.device(), "Cpu, Cpu");
+    }
+}
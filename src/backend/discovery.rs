@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::backend::music_gpt_ws_handler::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// Service type the server is advertised with on the local network.
+pub const SERVICE_TYPE: &str = "_musicgpt._tcp.local.";
+
+/// What the clients on the local network are told about the server before connecting.
+#[derive(Clone, Debug)]
+pub struct Advertisement {
+    pub hostname: String,
+    pub port: u16,
+    pub scheme: &'static str,
+    /// See [crate::backend::auth::Auth::requirement].
+    pub auth: &'static str,
+}
+
+impl Advertisement {
+    /// The TXT record of the service.
+    fn properties(&self) -> HashMap<String, String> {
+        [
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("protocol", PROTOCOL_VERSION.to_string()),
+            ("min_protocol", MIN_PROTOCOL_VERSION.to_string()),
+            ("scheme", self.scheme.to_string()),
+            ("path", "/ws".to_string()),
+            ("auth", self.auth.to_string()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+    }
+}
+
+/// Advertises the server with mDNS until `shutdown` is cancelled, so that the clients
+/// on the local network can find it without typing its address.
+pub fn advertise(ad: Advertisement, shutdown: CancellationToken) -> anyhow::Result<()> {
+    let daemon = ServiceDaemon::new()?;
+    let host = format!("{}.local.", ad.hostname.trim_end_matches(".local"));
+    let instance = format!("MusicGPT on {}", ad.hostname);
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance,
+        &host,
+        "",
+        ad.port,
+        ad.properties(),
+    )?
+    .enable_addr_auto();
+    let fullname = service.get_fullname().to_string();
+    daemon.register(service)?;
+    info!("Advertising MusicGPT on the local network as {instance:?}");
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        if let Err(err) = daemon.unregister(&fullname) {
+            warn!("Could not stop advertising {fullname}: {err}");
+        }
+        let _ = daemon.shutdown();
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_the_clients_how_to_connect() {
+        let ad = Advertisement {
+            hostname: "studio".to_string(),
+            port: 8642,
+            scheme: "https",
+            auth: "api-key",
+        };
+        let properties = ad.properties();
+        assert_eq!(properties["protocol"], PROTOCOL_VERSION.to_string());
+        assert_eq!(properties["scheme"], "https");
+        assert_eq!(properties["auth"], "api-key");
+    }
+}
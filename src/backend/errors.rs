@@ -0,0 +1,154 @@
+use std::fmt::Display;
+use std::sync::mpsc::SendError;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+
+use crate::backend::audio_generation_backend::BackendInboundMsg;
+use crate::backend::rate_limit::Rejected;
+use crate::music_gen_config::ConfigError;
+
+/// Stable codes of the errors sent to the clients, so that they can tell them apart
+/// without parsing the messages. Codes are only ever added, never renamed.
+#[derive(Clone, Copy, Debug, Type, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request is malformed or has values out of bounds.
+    InvalidRequest,
+    PromptTooLong,
+    NotFound,
+    /// An API key, or a valid one, is needed.
+    Unauthorized,
+    /// Only the owner of the server can do it.
+    Forbidden,
+    /// The invite of the guest expired or used up its generations.
+    QuotaExceeded,
+    /// The client went over its rate limits, see [crate::backend::rate_limit::RateLimited].
+    RateLimited,
+    /// Too many jobs are waiting already, see [crate::backend::rate_limit::QueueFull].
+    QueueFull,
+    /// The request does not apply to the current state, like cancelling a finished job.
+    Conflict,
+    /// What was asked for existed but is no more, like the audio of a failed job.
+    Gone,
+    /// The model is not available to take jobs, because its backend stopped.
+    ModelNotLoaded,
+    ShuttingDown,
+    OutOfMemory,
+    /// The job went over the time limit or stopped making progress.
+    TimedOut,
+    Cancelled,
+    /// Anything else, the message says what.
+    Internal,
+}
+
+impl ErrorCode {
+    /// An error with this code, to be returned where an [anyhow::Error] is expected.
+    pub fn err(self, message: impl Display) -> anyhow::Error {
+        ApiError::new(self, message).into()
+    }
+
+    /// An error with this code, to be returned from the HTTP handlers.
+    pub fn response(self, message: impl Display) -> Response {
+        ApiError::new(self, message).into_response()
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::PromptTooLong => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Forbidden | ErrorCode::QuotaExceeded => StatusCode::FORBIDDEN,
+            ErrorCode::Conflict | ErrorCode::Cancelled => StatusCode::CONFLICT,
+            ErrorCode::Gone => StatusCode::GONE,
+            ErrorCode::ModelNotLoaded | ErrorCode::ShuttingDown | ErrorCode::QueueFull => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorCode::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::OutOfMemory | ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// An error as sent to the clients, over both the WebSocket and HTTP.
+#[derive(Clone, Debug, Error, Type, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[error("{message}")]
+pub struct ApiError {
+    pub code: ErrorCode,
+    /// For humans, it can change at any time.
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    /// Like the [From] conversion, with `code` for the errors that say nothing about
+    /// what went wrong.
+    pub fn or(err: anyhow::Error, code: ErrorCode) -> Self {
+        match err.downcast::<ApiError>() {
+            Ok(err) => err,
+            Err(err) if err.is::<ConfigError>() => Self::new(ErrorCode::InvalidRequest, err),
+            // The backend stops taking jobs when its thread exits.
+            Err(err) if err.is::<SendError<BackendInboundMsg>>() => {
+                Self::new(ErrorCode::ModelNotLoaded, "The model is not taking jobs")
+            }
+            Err(err) => match err.downcast::<Rejected>() {
+                Ok(rejected) => Self::new(rejected.code(), rejected),
+                Err(err) => Self::new(code, err),
+            },
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::or(err, ErrorCode::Internal)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.code.status(), Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::rate_limit::{QueueFull, RateLimited};
+
+    #[test]
+    fn maps_the_code_from_the_type_of_the_error() {
+        let err = ErrorCode::TimedOut.err("Stalled: no progress in 1m");
+        assert_eq!(ApiError::from(err).code, ErrorCode::TimedOut);
+        // Whatever the message says, it takes the type for a code.
+        assert_eq!(ApiError::from(anyhow::anyhow!("Job timed out")).code, ErrorCode::Internal);
+        let limited = RateLimited { reason: "Too many jobs".to_string(), retry_after_secs: 1 };
+        let err = anyhow::Error::new(Rejected::from(limited));
+        assert_eq!(ApiError::from(err).code, ErrorCode::RateLimited);
+        let full = QueueFull { position: 3, estimated_wait_secs: None, retry_after_secs: 30 };
+        let err = anyhow::Error::new(Rejected::from(full));
+        assert_eq!(ApiError::from(err).code, ErrorCode::QueueFull);
+    }
+
+    #[test]
+    fn keeps_the_code_of_wrapped_errors() {
+        let err = anyhow::Error::new(ApiError::new(ErrorCode::NotFound, "Chat 1 not found"));
+        assert_eq!(ApiError::or(err, ErrorCode::InvalidRequest).code, ErrorCode::NotFound);
+        let err = anyhow::anyhow!("secs must be greater than 0");
+        assert_eq!(ApiError::or(err, ErrorCode::InvalidRequest).code, ErrorCode::InvalidRequest);
+        assert_eq!(ApiError::from(anyhow::anyhow!("boom")).code, ErrorCode::Internal);
+        let err = anyhow::Error::new(SendError(BackendInboundMsg::Abort("1".to_string())));
+        assert_eq!(ApiError::from(err).code, ErrorCode::ModelNotLoaded);
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::Cursor;
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+
+use crate::backend::music_gpt_chat::{Chat, ChatEntry};
+use crate::storage::{Storage, AUDIOS_DIR};
+
+/// Chats with this tag make up the public library served in the feed.
+pub const PUBLISHED_TAG: &str = "published";
+/// Most podcast apps only look at the latest episodes.
+const MAX_ITEMS: usize = 100;
+
+struct FeedItem {
+    id: String,
+    title: String,
+    chat_name: String,
+    published_ms: u128,
+    length: usize,
+    duration_secs: u32,
+}
+
+/// Serves the generations of the published chats as an RSS feed compatible with
+/// podcast apps, with `base_url` prefixed to the audio URLs.
+pub async fn feed<S: Storage>(storage: S, base_url: String) -> Response {
+    match render_feed(&storage, &base_url).await {
+        Ok(xml) => ([(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")], xml).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+async fn render_feed<S: Storage>(storage: &S, base_url: &str) -> anyhow::Result<String> {
+    let mut items = vec![];
+    // Chats come newest first.
+    for chat in Chat::load_all(storage).await? {
+        if !chat.tags.iter().any(|t| t == PUBLISHED_TAG) {
+            continue;
+        }
+        let entries = Chat::load_entries(storage, chat.chat_id).await?;
+        for entry in entries.iter().rev() {
+            let ChatEntry::Ai(ai) = entry else { continue };
+            if !ai.error.is_empty() {
+                continue;
+            }
+            let Some(bytes) = storage.read(&format!("{AUDIOS_DIR}/{}.wav", ai.id)).await? else {
+                continue;
+            };
+            let duration_secs = hound::WavReader::new(Cursor::new(&bytes))
+                .map(|r| r.duration() / r.spec().sample_rate.max(1))
+                .unwrap_or_default();
+            // The user entry with the same id holds the prompt.
+            let prompt = entries.iter().find_map(|e| match e {
+                ChatEntry::User(u) if u.id == ai.id => Some(u.text.clone()),
+                _ => None,
+            });
+            items.push(FeedItem {
+                id: ai.id.to_string(),
+                title: prompt.unwrap_or_else(|| chat.name.clone()),
+                chat_name: chat.name.clone(),
+                published_ms: chat.created_at,
+                length: bytes.len(),
+                duration_secs,
+            });
+        }
+        if items.len() >= MAX_ITEMS {
+            break;
+        }
+    }
+    items.truncate(MAX_ITEMS);
+
+    let base_url = base_url.trim_end_matches('/');
+    let mut xml = String::new();
+    xml.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push_str(r#"<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">"#);
+    xml.push_str("<channel>");
+    xml.push_str("<title>MusicGPT</title>");
+    xml.push_str(&format!("<link>{}</link>", escape(base_url)));
+    xml.push_str("<description>Music generated with MusicGPT</description>");
+    xml.push_str("<itunes:author>MusicGPT</itunes:author>");
+    xml.push_str("<itunes:explicit>false</itunes:explicit>");
+    for item in items {
+        let url = format!("{base_url}/api/audio/{}.wav", item.id);
+        xml.push_str("<item>");
+        xml.push_str(&format!("<title>{}</title>", escape(&item.title)));
+        xml.push_str(&format!("<description>{}</description>", escape(&item.chat_name)));
+        xml.push_str(&format!(r#"<guid isPermaLink="false">{}</guid>"#, item.id));
+        xml.push_str(&format!("<pubDate>{}</pubDate>", rfc2822(item.published_ms)));
+        xml.push_str(&format!(
+            r#"<enclosure url="{}" length="{}" type="audio/wav"/>"#,
+            escape(&url),
+            item.length
+        ));
+        xml.push_str(&format!("<itunes:duration>{}</itunes:duration>", item.duration_secs));
+        xml.push_str("</item>");
+    }
+    xml.push_str("</channel></rss>");
+    Ok(xml)
+}
+
+fn rfc2822(ms: u128) -> String {
+    DateTime::<Utc>::from_timestamp_millis(ms as i64)
+        .unwrap_or_default()
+        .to_rfc2822()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::audio_manager::AudioManager;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    async fn chat(storage: &AppFs, name: &str, tags: &[&str], prompt: &str) -> anyhow::Result<Uuid> {
+        let chat = Chat {
+            chat_id: Uuid::new_v4(),
+            name: name.to_string(),
+            created_at: 1_700_000_000_000,
+            tags: tags.iter().map(|v| v.to_string()).collect(),
+            user_id: None,
+        };
+        chat.save(storage).await?;
+        let id = Uuid::new_v4();
+        ChatEntry::new_user(chat.chat_id, id, prompt.to_string()).save(storage).await?;
+        let relpath = format!("{AUDIOS_DIR}/{id}.wav");
+        let wav = AudioManager::default().to_wav([0.0; 32000].into_iter().collect())?;
+        storage.write(&relpath, wav).await?;
+        ChatEntry::new_ai_success(chat.chat_id, id, relpath).save(storage).await?;
+        Ok(id)
+    }
+
+    #[tokio::test]
+    async fn only_includes_published_chats() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let published = chat(&storage, "Jazz", &[PUBLISHED_TAG], "Smooth jazz & rain").await?;
+        let private = chat(&storage, "Drafts", &[], "Something secret").await?;
+
+        let xml = render_feed(&storage, "http://localhost:8642/").await?;
+        assert!(xml.contains("<title>Smooth jazz &amp; rain</title>"));
+        assert!(xml.contains(&format!(
+            r#"<enclosure url="http://localhost:8642/api/audio/{published}.wav""#
+        )));
+        assert!(xml.contains("<itunes:duration>1</itunes:duration>"));
+        assert!(xml.contains("Tue, 14 Nov 2023"));
+        assert!(!xml.contains(&private.to_string()));
+        assert!(!xml.contains("Something secret"));
+        Ok(())
+    }
+}
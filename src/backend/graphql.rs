@@ -0,0 +1,372 @@
+use std::collections::BTreeSet;
+
+use async_graphql::http::{GraphiQLSource, ALL_WEBSOCKET_PROTOCOLS};
+use async_graphql::{
+    Context, Data, EmptyMutation, Object, Result, Schema, SimpleObject, Subscription, Union, ID,
+};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
+use axum::extract::WebSocketUpgrade;
+use axum::response::{Html, IntoResponse, Response};
+use futures_util::Stream;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::backend::audio_generation_fanout::{EventLog, GenerationMessage};
+use crate::backend::job_store::{JobState, JobStatus, JobStore};
+use crate::backend::music_gpt_chat::{Chat, ChatEntry};
+use crate::backend::rest_api::finished_status;
+use crate::backend::users::User;
+use crate::storage::{AppFs, Storage};
+
+/// Queries are POSTed here, and opening it in a browser shows GraphiQL.
+pub const GRAPHQL_PATH: &str = "/api/graphql";
+/// Subscriptions open a WebSocket here.
+pub const GRAPHQL_WS_PATH: &str = "/api/graphql/ws";
+
+pub type MusicGptSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+/// The chats, their messages and tracks, and the jobs of the caller, read-only.
+/// Every request carries the [User] making it, if any, and only sees their data.
+pub fn schema(
+    storage: AppFs,
+    jobs: JobStore,
+    ai_broadcast_tx: &broadcast::Sender<GenerationMessage>,
+    events: EventLog,
+) -> MusicGptSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(storage)
+        .data(jobs)
+        .data(ai_broadcast_tx.clone())
+        .data(events)
+        .finish()
+}
+
+pub async fn graphql(
+    schema: MusicGptSchema,
+    user: Option<User>,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    schema.execute(req.into_inner().data(user)).await.into()
+}
+
+pub async fn graphql_ws(
+    schema: MusicGptSchema,
+    user: Option<User>,
+    protocol: GraphQLProtocol,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            let mut data = Data::default();
+            data.insert(user);
+            GraphQLWebSocket::new(stream, schema, protocol).with_data(data).serve()
+        })
+}
+
+/// Page for trying out queries in the browser.
+pub async fn graphiql() -> Response {
+    let page = GraphiQLSource::build()
+        .endpoint(GRAPHQL_PATH)
+        .subscription_endpoint(GRAPHQL_WS_PATH)
+        .finish();
+    Html(page).into_response()
+}
+
+fn user_id(ctx: &Context<'_>) -> Option<Uuid> {
+    ctx.data_opt::<Option<User>>().cloned().flatten().map(|v| v.id)
+}
+
+fn parse_id(id: &ID) -> Result<Uuid> {
+    Ok(Uuid::parse_str(id)?)
+}
+
+fn audio_url(id: Uuid) -> String {
+    format!("/api/audio/{id}.wav")
+}
+
+async fn chats(ctx: &Context<'_>, tag: Option<String>) -> Result<Vec<Chat>> {
+    let storage = ctx.data::<AppFs>()?;
+    let mut chats = Chat::load_all_for(storage, user_id(ctx)).await?;
+    if let Some(tag) = tag {
+        chats.retain(|v| v.tags.contains(&tag));
+    }
+    Ok(chats)
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// The chats of the caller, newest first, only the ones with `tag` if set.
+    async fn chats(&self, ctx: &Context<'_>, tag: Option<String>) -> Result<Vec<ChatNode>> {
+        Ok(chats(ctx, tag).await?.into_iter().map(ChatNode).collect())
+    }
+
+    async fn chat(&self, ctx: &Context<'_>, id: ID) -> Result<Option<ChatNode>> {
+        let storage = ctx.data::<AppFs>()?;
+        let chat_id = parse_id(&id)?;
+        if !storage.exists(&format!("chats/{chat_id}")).await? {
+            return Ok(None);
+        }
+        Ok(Chat::load_for(storage, chat_id, user_id(ctx)).await.ok().map(ChatNode))
+    }
+
+    /// The generated tracks of the caller, newest chats first, only the ones in chats
+    /// with `tag` if set.
+    async fn tracks(&self, ctx: &Context<'_>, tag: Option<String>) -> Result<Vec<Track>> {
+        let storage = ctx.data::<AppFs>()?;
+        let mut tracks = vec![];
+        for chat in chats(ctx, tag).await? {
+            let entries = Chat::load_entries(storage, chat.chat_id).await?;
+            tracks.extend(tracks_of(&entries));
+        }
+        Ok(tracks)
+    }
+
+    /// All the tags in the chats of the caller.
+    async fn tags(&self, ctx: &Context<'_>) -> Result<Vec<String>> {
+        let tags: BTreeSet<_> = chats(ctx, None).await?.into_iter().flat_map(|v| v.tags).collect();
+        Ok(tags.into_iter().collect())
+    }
+
+    async fn job(&self, ctx: &Context<'_>, id: ID) -> Result<Option<Job>> {
+        Ok(job_status(ctx, parse_id(&id)?).await?.map(Job::from))
+    }
+}
+
+/// The status of the job `id`, as long as it is in a chat of the caller.
+async fn job_status(ctx: &Context<'_>, id: Uuid) -> Result<Option<JobStatus>> {
+    let Some(status) = ctx.data::<JobStore>()?.get(id)? else {
+        return Ok(None);
+    };
+    let storage = ctx.data::<AppFs>()?;
+    if Chat::user_of(storage, status.chat_id).await? != user_id(ctx) {
+        return Ok(None);
+    }
+    Ok(Some(status))
+}
+
+pub struct ChatNode(Chat);
+
+#[Object(name = "Chat")]
+impl ChatNode {
+    async fn id(&self) -> ID {
+        self.0.chat_id.into()
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Milliseconds since the Unix epoch.
+    async fn created_at(&self) -> i64 {
+        self.0.created_at as i64
+    }
+
+    async fn tags(&self) -> &[String] {
+        &self.0.tags
+    }
+
+    /// The prompts and their generations, oldest first.
+    async fn messages(&self, ctx: &Context<'_>) -> Result<Vec<Message>> {
+        let storage = ctx.data::<AppFs>()?;
+        let entries = Chat::load_entries(storage, self.0.chat_id).await?;
+        let messages = entries.into_iter().map(|entry| match entry {
+            ChatEntry::User(v) => Message::Prompt(Prompt {
+                id: v.id.into(),
+                text: v.text,
+            }),
+            ChatEntry::Ai(v) => Message::Generation(Generation {
+                id: v.id.into(),
+                audio_url: v.error.is_empty().then(|| audio_url(v.id)),
+                error: (!v.error.is_empty()).then_some(v.error),
+            }),
+        });
+        Ok(messages.collect())
+    }
+
+    /// The generations that succeeded, oldest first.
+    async fn tracks(&self, ctx: &Context<'_>) -> Result<Vec<Track>> {
+        let storage = ctx.data::<AppFs>()?;
+        let entries = Chat::load_entries(storage, self.0.chat_id).await?;
+        Ok(tracks_of(&entries))
+    }
+}
+
+#[derive(Union)]
+pub enum Message {
+    Prompt(Prompt),
+    Generation(Generation),
+}
+
+#[derive(SimpleObject)]
+pub struct Prompt {
+    /// Same as the one of its generation.
+    id: ID,
+    text: String,
+}
+
+#[derive(SimpleObject)]
+pub struct Generation {
+    id: ID,
+    audio_url: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct Track {
+    id: ID,
+    chat_id: ID,
+    prompt: Option<String>,
+    audio_url: String,
+}
+
+fn tracks_of(entries: &[ChatEntry]) -> Vec<Track> {
+    let ai = entries.iter().filter_map(|v| match v {
+        ChatEntry::Ai(ai) if ai.error.is_empty() => Some(ai),
+        _ => None,
+    });
+    let tracks = ai.map(|ai| {
+        // The user entry with the same id holds the prompt.
+        let prompt = entries.iter().find_map(|e| match e {
+            ChatEntry::User(u) if u.id == ai.id => Some(u.text.clone()),
+            _ => None,
+        });
+        Track {
+            id: ai.id.into(),
+            chat_id: ai.chat_id.into(),
+            prompt,
+            audio_url: audio_url(ai.id),
+        }
+    });
+    tracks.collect()
+}
+
+#[derive(SimpleObject)]
+pub struct Job {
+    id: ID,
+    chat_id: ID,
+    state: JobState,
+    progress: f32,
+    /// Jobs to be started before this one, only set while the job is waiting.
+    queue_position: Option<usize>,
+    /// Seconds until the job is done at the recent generation speed, if known.
+    eta_secs: Option<f32>,
+    audio_url: Option<String>,
+    error: Option<String>,
+}
+
+impl From<JobStatus> for Job {
+    fn from(status: JobStatus) -> Self {
+        Self {
+            id: status.id.into(),
+            chat_id: status.chat_id.into(),
+            state: status.state,
+            progress: status.progress,
+            queue_position: None,
+            eta_secs: None,
+            audio_url: status.audio_url,
+            error: status.error,
+        }
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Where the job is at, right away and then on every change until it finishes.
+    async fn job_progress(&self, ctx: &Context<'_>, id: ID) -> Result<impl Stream<Item = Job>> {
+        let id = parse_id(&id)?;
+        // Subscribed before reading the status, so that no update falls in between.
+        let mut rx = ctx.data::<broadcast::Sender<GenerationMessage>>()?.subscribe();
+        let Some(status) = job_status(ctx, id).await? else {
+            return Err(format!("Job {id} not found").into());
+        };
+        let progress = ctx.data::<EventLog>()?.snapshot(id).and_then(|v| v.progress);
+        Ok(async_stream::stream! {
+            let finished = status.state.is_finished();
+            let mut job = Job::from(status);
+            if let Some(progress) = progress {
+                job.progress = progress.progress;
+                job.queue_position = progress.queue_position;
+                job.eta_secs = progress.eta_secs;
+            }
+            yield job;
+            if finished {
+                return;
+            }
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                };
+                if msg.id() != id {
+                    continue;
+                }
+                match msg {
+                    GenerationMessage::Start(m) => {
+                        yield Job::from(JobStatus::new(id, m.chat_id, JobState::Running));
+                    }
+                    GenerationMessage::Progress(m) => {
+                        let state = match m.queue_position {
+                            Some(_) => JobState::Queued,
+                            None => JobState::Running,
+                        };
+                        let mut job = Job::from(JobStatus::new(id, m.chat_id, state));
+                        job.progress = m.progress;
+                        job.queue_position = m.queue_position;
+                        job.eta_secs = m.eta_secs;
+                        yield job;
+                    }
+                    msg => {
+                        if let Some(status) = finished_status(msg) {
+                            yield Job::from(status);
+                            return;
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queries_the_chats_of_the_caller() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let (tx, _) = broadcast::channel(1);
+        let schema = schema(storage.clone(), JobStore::in_memory()?, &tx, EventLog::default());
+        let (chat_id, id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut chat = Chat::load(&storage, chat_id).await?;
+        chat.name = "Jazz".to_string();
+        chat.tags = vec!["published".to_string()];
+        chat.save(&storage).await?;
+        ChatEntry::new_user(chat_id, id, "Smooth jazz".to_string()).save(&storage).await?;
+        ChatEntry::new_ai_success(chat_id, id, format!("audios/{id}.wav")).save(&storage).await?;
+        let mut other = Chat::load(&storage, Uuid::new_v4()).await?;
+        other.user_id = Some(Uuid::new_v4());
+        other.save(&storage).await?;
+
+        let query = r#"{
+            chats { name tags messages { ... on Prompt { text } ... on Generation { audioUrl } } }
+            tracks(tag: "published") { prompt audioUrl }
+            tags
+        }"#;
+        let res = schema.execute(query).await;
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let data = res.data.into_json()?;
+        assert_eq!(data["chats"].as_array().unwrap().len(), 1);
+        assert_eq!(data["chats"][0]["messages"][0]["text"], "Smooth jazz");
+        assert_eq!(data["chats"][0]["messages"][1]["audioUrl"], audio_url(id));
+        assert_eq!(data["tracks"][0]["prompt"], "Smooth jazz");
+        assert_eq!(data["tags"], serde_json::json!(["published"]));
+        Ok(())
+    }
+}
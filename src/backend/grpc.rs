@@ -0,0 +1,262 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use futures_util::Stream;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::{BackendInboundMsg, Priority};
+use crate::backend::audio_generation_fanout::{
+    AudioGenerationChunk, AudioGenerationProgress, GenerationMessage,
+};
+use crate::backend::auth::{Access, Auth};
+use crate::backend::errors::{ApiError, ErrorCode};
+use crate::backend::job_store::{JobState, JobStatus};
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::rest_api::{finished_status, RestApi, RestGenerateRequest};
+use crate::backend::users::{User, Users, USER_HEADER};
+use crate::music_gen_config::GenerationConfig;
+use crate::storage::AppFs;
+
+pub mod proto {
+    tonic::include_proto!("musicgpt");
+}
+
+use proto::generate_event::Event;
+use proto::music_gpt_server::{MusicGpt, MusicGptServer};
+use proto::{AudioChunk, GenerateEvent, GenerateRequest, Job, JobRequest, Progress};
+
+/// The generation API over gRPC, see `proto/musicgpt.proto`. Jobs are submitted and
+/// tracked the same way as the ones of the HTTP API.
+#[derive(Clone)]
+pub struct Grpc {
+    rest: RestApi<AppFs>,
+    ai_broadcast_tx: broadcast::Sender<GenerationMessage>,
+    auth: Auth,
+    users: Users,
+}
+
+impl Grpc {
+    pub fn new(
+        rest: RestApi<AppFs>,
+        ai_broadcast_tx: &broadcast::Sender<GenerationMessage>,
+        auth: Auth,
+        users: Users,
+    ) -> Self {
+        Self {
+            rest,
+            ai_broadcast_tx: ai_broadcast_tx.clone(),
+            auth,
+            users,
+        }
+    }
+
+    pub fn into_service(self) -> MusicGptServer<Self> {
+        MusicGptServer::new(self)
+    }
+
+    /// Same rules as for the HTTP API, the API key goes in the `authorization`
+    /// metadata as a bearer token. There are no invites over gRPC.
+    fn access<T>(&self, req: &Request<T>) -> Result<(Access, SocketAddr), Status> {
+        let addr = req
+            .remote_addr()
+            .ok_or_else(|| Status::internal("Unknown client address"))?;
+        let api_key = req
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|v| v.trim());
+        match self.auth.access(addr, api_key, None) {
+            Some(access) => Ok((access, addr)),
+            None => Err(Status::unauthenticated("An API key is required")),
+        }
+    }
+
+    /// The user whose token is in the [USER_HEADER] metadata, if any. Like over HTTP,
+    /// unknown tokens are treated as no user.
+    fn user<T>(&self, req: &Request<T>) -> Option<User> {
+        let token = req.metadata().get(USER_HEADER)?.to_str().ok()?;
+        self.users.by_token(token.trim())
+    }
+
+    /// The status of the job `id`, as long as it is in a chat of `user`.
+    async fn status(&self, id: &str, user: Option<User>) -> Result<JobStatus, Status> {
+        let id = parse_uuid(id)?;
+        match self.rest.status(id, user).await {
+            Ok(Some(status)) => Ok(status),
+            Ok(None) => Err(Status::not_found(format!("Job {id} not found"))),
+            Err(err) => Err(Status::internal(err.to_string())),
+        }
+    }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<GenerateEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl MusicGpt for Grpc {
+    type GenerateStreamStream = EventStream;
+
+    async fn generate_stream(
+        &self,
+        req: Request<GenerateRequest>,
+    ) -> Result<Response<EventStream>, Status> {
+        let (access, addr) = self.access(&req)?;
+        let user = self.user(&req);
+        let req = req.into_inner();
+        let chat_id = match req.chat_id.as_str() {
+            "" => None,
+            chat_id => Some(parse_uuid(chat_id)?),
+        };
+        let priority = match req.interactive {
+            true => Priority::Interactive,
+            false => Priority::Batch,
+        };
+        let req = RestGenerateRequest {
+            prompt: req.prompt,
+            secs: req.secs as usize,
+            chat_id,
+            config: Some(GenerationConfig {
+                top_k: req.top_k.map(|v| v as usize),
+                guidance_scale: req.guidance_scale,
+                temperature: req.temperature,
+                top_p: req.top_p,
+                seed: req.seed,
+                negative_prompt: req.negative_prompt,
+                model: req.model,
+                ..Default::default()
+            }),
+            priority: Some(priority),
+        };
+        // Subscribed before submitting, so that no event of the job is missed.
+        let mut rx = self.ai_broadcast_tx.subscribe();
+        let status = self
+            .rest
+            .submit(req, access, user, addr.ip())
+            .await
+            .map_err(submit_status)?;
+        let id = status.id;
+        let stream = async_stream::stream! {
+            yield Ok(event(Event::Accepted(job(status))));
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    // The audio would have gaps, better to fail than to hand it out.
+                    Err(RecvError::Lagged(n)) => {
+                        yield Err(Status::data_loss(format!("Missed {n} events of the job")));
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                if msg.id() != id {
+                    continue;
+                }
+                match msg {
+                    GenerationMessage::Progress(m) => {
+                        yield Ok(event(Event::Progress(progress(m))));
+                    }
+                    GenerationMessage::Audio(m) => {
+                        yield Ok(event(Event::Audio(audio_chunk(m))));
+                    }
+                    msg => {
+                        if let Some(status) = finished_status(msg) {
+                            yield Ok(event(Event::Finished(job(status))));
+                            return;
+                        }
+                    }
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_job(&self, req: Request<JobRequest>) -> Result<Response<Job>, Status> {
+        self.access(&req)?;
+        let status = self.status(&req.get_ref().id, self.user(&req)).await?;
+        Ok(Response::new(job(status)))
+    }
+
+    async fn cancel_job(&self, req: Request<JobRequest>) -> Result<Response<Job>, Status> {
+        self.access(&req)?;
+        let status = self.status(&req.get_ref().id, self.user(&req)).await?;
+        if status.state.is_finished() {
+            let msg = format!("Job {} already finished", status.id);
+            return Err(Status::failed_precondition(msg));
+        }
+        let id_pair = IdPair(status.chat_id, status.id).to_string();
+        self.rest
+            .ai_tx
+            .send(BackendInboundMsg::Abort(id_pair))
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(job(status)))
+    }
+}
+
+/// The gRPC status of a job that could not be submitted, by the [ErrorCode] of the error.
+fn submit_status(err: anyhow::Error) -> Status {
+    let err = ApiError::or(err, ErrorCode::InvalidRequest);
+    let message = err.message;
+    match err.code {
+        ErrorCode::InvalidRequest | ErrorCode::PromptTooLong => Status::invalid_argument(message),
+        ErrorCode::NotFound | ErrorCode::Gone => Status::not_found(message),
+        ErrorCode::Unauthorized => Status::unauthenticated(message),
+        ErrorCode::Forbidden => Status::permission_denied(message),
+        ErrorCode::QuotaExceeded | ErrorCode::RateLimited | ErrorCode::OutOfMemory => {
+            Status::resource_exhausted(message)
+        }
+        ErrorCode::Conflict => Status::failed_precondition(message),
+        ErrorCode::ModelNotLoaded | ErrorCode::ShuttingDown | ErrorCode::QueueFull => {
+            Status::unavailable(message)
+        }
+        ErrorCode::TimedOut => Status::deadline_exceeded(message),
+        ErrorCode::Cancelled => Status::cancelled(message),
+        ErrorCode::Internal => Status::internal(message),
+    }
+}
+
+fn parse_uuid(id: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(id).map_err(|err| Status::invalid_argument(format!("Invalid id {id}: {err}")))
+}
+
+fn event(event: Event) -> GenerateEvent {
+    GenerateEvent { event: Some(event) }
+}
+
+fn job(status: JobStatus) -> Job {
+    let state = match status.state {
+        JobState::Queued => proto::JobState::Queued,
+        JobState::Running => proto::JobState::Running,
+        JobState::Completed => proto::JobState::Completed,
+        JobState::Failed => proto::JobState::Failed,
+        JobState::Cancelled => proto::JobState::Cancelled,
+    };
+    Job {
+        id: status.id.to_string(),
+        chat_id: status.chat_id.to_string(),
+        state: state.into(),
+        progress: status.progress,
+        audio_url: status.audio_url,
+        error: status.error,
+    }
+}
+
+fn progress(m: AudioGenerationProgress) -> Progress {
+    Progress {
+        progress: m.progress,
+        queue_position: m.queue_position.map(|v| v as u32),
+        tokens: m.tokens.map(|v| v as u32),
+        total_tokens: m.total_tokens.map(|v| v as u32),
+        eta_secs: m.eta_secs,
+    }
+}
+
+fn audio_chunk(m: AudioGenerationChunk) -> AudioChunk {
+    AudioChunk {
+        offset: m.offset as u64,
+        sample_rate: m.sample_rate,
+        channels: m.channels as u32,
+        pcm: m.samples.iter().flat_map(|v| v.to_le_bytes()).collect(),
+    }
+}
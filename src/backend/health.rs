@@ -0,0 +1,65 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::audio_generation_backend::AudioGenerationBackend;
+use crate::backend::music_gpt_ws_handler::Info;
+
+/// Paths that orchestrators and reverse proxies can use without authenticating.
+pub const PUBLIC_PATHS: [&str; 3] = ["/healthz", "/readyz", "/version"];
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    /// The models are running their warmup generation, the server is ready once it ends.
+    pub warming_up: bool,
+    pub model: String,
+    pub device: String,
+    /// Jobs waiting or running.
+    pub queue_depth: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Version {
+    pub version: String,
+    /// Commit the server was built from, if it was built from a git checkout.
+    pub git_sha: Option<String>,
+    pub model: String,
+    pub device: String,
+}
+
+/// The process is up, regardless of whether it can take jobs.
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// The models are loaded in their execution provider and the backend is taking jobs.
+/// The server only starts listening once the models are loaded, so this only fails
+/// while they warm up or if the backend stops.
+pub async fn readyz(backend: AudioGenerationBackend, info: Info) -> Response {
+    let warming_up = backend.is_warming_up();
+    let readiness = Readiness {
+        ready: backend.is_running() && !warming_up,
+        warming_up,
+        model: info.model,
+        device: info.device,
+        queue_depth: backend.queue_depth(),
+    };
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness)).into_response()
+}
+
+pub async fn version(info: Info) -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: option_env!("MUSICGPT_GIT_SHA").map(|v| v.to_string()),
+        model: info.model,
+        device: info.device,
+    })
+}
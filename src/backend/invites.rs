@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::auth::{cookie, Access};
+use crate::backend::errors::ErrorCode;
+
+/// Cookie holding the invite of a guest, set when they open their invite link.
+pub const INVITE_COOKIE: &str = "musicgpt_invite";
+/// Query parameter carrying the invite, as in `/?invite=<token>`.
+const INVITE_PARAM: &str = "invite";
+const TOKEN_LEN: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Invite {
+    pub token: String,
+    /// Milliseconds since the Unix epoch after which the invite stops working.
+    pub expires_at: u128,
+    pub max_generations: usize,
+    pub generations: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct InviteRequest {
+    #[serde(default = "default_ttl_minutes")]
+    pub ttl_minutes: u64,
+    #[serde(default = "default_max_generations")]
+    pub max_generations: usize,
+}
+
+fn default_ttl_minutes() -> u64 {
+    120
+}
+
+fn default_max_generations() -> usize {
+    10
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct InviteLink {
+    /// Link to share with the guest.
+    pub url: String,
+    #[serde(flatten)]
+    pub invite: Invite,
+}
+
+/// Invites minted by the owner for letting others use the server for a while. They
+/// are only kept in memory, restarting the server revokes all of them.
+#[derive(Clone, Default)]
+pub struct Invites {
+    invites: Arc<RwLock<HashMap<String, Invite>>>,
+}
+
+impl Invites {
+    pub fn mint(&self, req: &InviteRequest) -> anyhow::Result<Invite> {
+        if req.ttl_minutes == 0 || req.max_generations == 0 {
+            return Err(anyhow!("ttl_minutes and max_generations must be greater than 0"));
+        }
+        let token: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LEN)
+            .map(char::from)
+            .collect();
+        let invite = Invite {
+            token: token.clone(),
+            expires_at: now() + req.ttl_minutes as u128 * 60_000,
+            max_generations: req.max_generations,
+            generations: 0,
+        };
+        let mut invites = self.invites.write().unwrap();
+        invites.retain(|_, v| v.expires_at > now());
+        invites.insert(token, invite.clone());
+        Ok(invite)
+    }
+
+    pub fn list(&self) -> Vec<Invite> {
+        let now = now();
+        let mut invites: Vec<_> = self
+            .invites
+            .read()
+            .unwrap()
+            .values()
+            .filter(|v| v.expires_at > now)
+            .cloned()
+            .collect();
+        invites.sort_by_key(|v| v.expires_at);
+        invites
+    }
+
+    pub fn revoke(&self, token: &str) -> bool {
+        self.invites.write().unwrap().remove(token).is_some()
+    }
+
+    pub fn is_valid(&self, token: &str) -> bool {
+        let invites = self.invites.read().unwrap();
+        invites.get(token).is_some_and(|v| v.expires_at > now())
+    }
+
+    /// Counts a generation against the invite of a guest, failing once it has
+    /// expired or used up all of its generations. Owners are not limited.
+    pub fn charge(&self, access: &Access) -> anyhow::Result<()> {
+        let Access::Guest(token) = access else {
+            return Ok(());
+        };
+        let mut invites = self.invites.write().unwrap();
+        let invite = invites
+            .get_mut(token)
+            .filter(|v| v.expires_at > now())
+            .ok_or_else(|| ErrorCode::QuotaExceeded.err("The invite has expired"))?;
+        if invite.generations >= invite.max_generations {
+            return Err(ErrorCode::QuotaExceeded.err(format!(
+                "The invite has used up its {} generations",
+                invite.max_generations
+            )));
+        }
+        invite.generations += 1;
+        Ok(())
+    }
+}
+
+/// Owner only endpoint for minting an invite link.
+pub async fn mint_invite(invites: Invites, access: Access, base_url: String, req: InviteRequest) -> Response {
+    if access != Access::Owner {
+        return (StatusCode::FORBIDDEN, "Only the owner can invite guests").into_response();
+    }
+    match invites.mint(&req) {
+        Ok(invite) => {
+            let url = format!("{base_url}/?{INVITE_PARAM}={}", invite.token);
+            Json(InviteLink { url, invite }).into_response()
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+/// Owner only endpoint listing the invites that have not expired.
+pub async fn list_invites(invites: Invites, access: Access) -> Response {
+    if access != Access::Owner {
+        return (StatusCode::FORBIDDEN, "Only the owner can list invites").into_response();
+    }
+    Json(invites.list()).into_response()
+}
+
+/// Owner only endpoint revoking an invite.
+pub async fn revoke_invite(invites: Invites, access: Access, token: String) -> Response {
+    if access != Access::Owner {
+        return (StatusCode::FORBIDDEN, "Only the owner can revoke invites").into_response();
+    }
+    if invites.revoke(&token) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Invite not found").into_response()
+    }
+}
+
+/// Invite passed in the query, as in invite links.
+pub fn query_token(query: Option<&str>) -> Option<String> {
+    query?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(INVITE_PARAM)?.strip_prefix('='))
+        .map(|v| v.to_string())
+}
+
+/// Invite remembered by the browser of a guest.
+pub fn cookie_token(headers: &HeaderMap) -> Option<String> {
+    cookie(headers, INVITE_COOKIE)
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderValue};
+
+    use super::*;
+
+    #[test]
+    fn limits_the_guests() -> anyhow::Result<()> {
+        let invites = Invites::default();
+        let invite = invites.mint(&InviteRequest {
+            ttl_minutes: 10,
+            max_generations: 2,
+        })?;
+        assert!(invites.is_valid(&invite.token));
+        assert!(!invites.is_valid("wrong"));
+
+        let guest = Access::Guest(invite.token.clone());
+        invites.charge(&guest)?;
+        invites.charge(&guest)?;
+        assert!(invites.charge(&guest).is_err());
+        invites.charge(&Access::Owner)?;
+        assert_eq!(invites.list()[0].generations, 2);
+
+        assert!(invites.revoke(&invite.token));
+        assert!(!invites.is_valid(&invite.token));
+        assert!(invites.charge(&guest).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_the_token_from_the_query_or_the_cookie() {
+        assert_eq!(query_token(Some("a=1&invite=abc")), Some("abc".to_string()));
+        assert_eq!(query_token(Some("invited=abc")), None);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("x=1; musicgpt_invite=abc"));
+        assert_eq!(cookie_token(&headers), Some("abc".to_string()));
+    }
+}
@@ -0,0 +1,739 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use async_graphql::Enum;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::{AudioGenerationRequest, BackendInboundMsg, Priority};
+use crate::backend::checkpoints::Checkpoints;
+use crate::backend::music_gpt_chat::ChatEntry;
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::storage::Storage;
+
+/// Error of the jobs that were running when the server went down.
+pub const INTERRUPTED: &str = "Interrupted by a server restart";
+
+#[derive(Clone, Copy, Debug, Type, Serialize, Deserialize, JsonSchema, Enum, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "queued" => JobState::Queued,
+            "running" => JobState::Running,
+            "completed" => JobState::Completed,
+            "failed" => JobState::Failed,
+            "cancelled" => JobState::Cancelled,
+            _ => return Err(anyhow!("Unknown job state {s}")),
+        })
+    }
+
+    /// States from which a job can move into this one.
+    fn allowed_from(&self) -> &'static [JobState] {
+        match self {
+            // Interrupted jobs that resume from their checkpoint.
+            JobState::Queued => &[JobState::Running],
+            JobState::Running => &[JobState::Queued],
+            JobState::Completed => &[JobState::Running],
+            JobState::Failed => &[JobState::Queued, JobState::Running],
+            JobState::Cancelled => &[JobState::Queued, JobState::Running],
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Completed | JobState::Failed | JobState::Cancelled)
+    }
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct JobStatus {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub state: JobState,
+    pub progress: f32,
+    /// Where the generated audio can be downloaded from, once completed.
+    pub audio_url: Option<String>,
+    pub error: Option<String>,
+}
+
+impl JobStatus {
+    pub fn new(id: Uuid, chat_id: Uuid, state: JobState) -> Self {
+        Self {
+            id,
+            chat_id,
+            state,
+            progress: 0.0,
+            audio_url: None,
+            error: None,
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let invalid = |i: usize, err: anyhow::Error| {
+            rusqlite::Error::FromSqlConversionFailure(i, rusqlite::types::Type::Text, err.into())
+        };
+        let uuid = |i: usize| {
+            let v: String = row.get(i)?;
+            Uuid::parse_str(&v).map_err(|e| invalid(i, e.into()))
+        };
+        let state: String = row.get(2)?;
+        Ok(Self {
+            id: uuid(0)?,
+            chat_id: uuid(1)?,
+            state: JobState::parse(&state).map_err(|e| invalid(2, e))?,
+            progress: row.get(3)?,
+            audio_url: row.get(4)?,
+            error: row.get(5)?,
+        })
+    }
+}
+
+/// The jobs submitted by an owner, see [AudioGenerationRequest::owner].
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct Usage {
+    pub owner: String,
+    pub jobs: usize,
+    pub completed: usize,
+    pub failed: usize,
+    /// Seconds of audio generated by the completed jobs.
+    pub generated_secs: usize,
+}
+
+/// Conditions the jobs of a page of history must all match.
+#[derive(Clone, Debug, Default)]
+pub struct JobFilter {
+    pub state: Option<JobState>,
+    pub chat_id: Option<Uuid>,
+    /// Milliseconds since the Unix epoch, inclusive.
+    pub created_from: Option<i64>,
+    /// Milliseconds since the Unix epoch, inclusive.
+    pub created_to: Option<i64>,
+}
+
+/// Position of a job in the history, pages start right after it. Stable even if
+/// jobs are added in the meantime, as those go at the other end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PageCursor {
+    created_at: i64,
+    rowid: i64,
+}
+
+impl Display for PageCursor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.created_at, self.rowid)
+    }
+}
+
+impl FromStr for PageCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid page {s}");
+        let (created_at, rowid) = s.split_once('.').ok_or_else(invalid)?;
+        Ok(Self {
+            created_at: created_at.parse().map_err(|_| invalid())?,
+            rowid: rowid.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+const COLUMNS: &str = "id, chat_id, state, progress, audio_url, error";
+
+/// Columns added after the table was first created, with their definition.
+const ADDED_COLUMNS: [(&str, &str); 3] = [
+    ("config", "TEXT NOT NULL DEFAULT '{}'"),
+    ("priority", "TEXT NOT NULL DEFAULT 'interactive'"),
+    ("owner", "TEXT NOT NULL DEFAULT ''"),
+];
+
+/// Source of truth for the state of the jobs. State transitions are checked and
+/// applied in a single transaction, so they can never be observed half done or
+/// out of order, e.g. a completed job going back to running.
+#[derive(Clone)]
+pub struct JobStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl JobStore {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path)?;
+        // Progress is written often, WAL keeps those writes cheap.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        Self::init(conn)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> anyhow::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> anyhow::Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                id TEXT PRIMARY KEY,
+                chat_id TEXT NOT NULL,
+                state TEXT NOT NULL,
+                progress REAL NOT NULL DEFAULT 0,
+                audio_url TEXT,
+                error TEXT,
+                prompt TEXT NOT NULL DEFAULT '',
+                secs INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS jobs_state ON jobs (state, created_at);
+            CREATE INDEX IF NOT EXISTS jobs_created ON jobs (created_at);
+            CREATE TABLE IF NOT EXISTS batch_jobs (
+                job_id TEXT PRIMARY KEY,
+                batch_id TEXT NOT NULL,
+                position INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS batch_jobs_batch ON batch_jobs (batch_id, position);",
+        )?;
+        let existing = conn
+            .prepare("SELECT name FROM pragma_table_info('jobs')")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (column, definition) in ADDED_COLUMNS {
+            if !existing.iter().any(|v| v == column) {
+                conn.execute(&format!("ALTER TABLE jobs ADD COLUMN {column} {definition}"), [])?;
+            }
+        }
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Adds a new job in the queued state. Fails if the job already exists.
+    pub fn insert(&self, id: Uuid, chat_id: Uuid, prompt: &str, secs: usize) -> anyhow::Result<JobStatus> {
+        let now = now();
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO jobs (id, chat_id, state, prompt, secs, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+            params![id.to_string(), chat_id.to_string(), JobState::Queued.as_str(), prompt, secs, now],
+        )?;
+        self.get(id)?.ok_or_else(|| anyhow!("Job {id} was not inserted"))
+    }
+
+    /// Adds the job in the queued state unless it is already known, for jobs that
+    /// were submitted somewhere else and are only seen once they start.
+    pub fn ensure(&self, id: Uuid, chat_id: Uuid, prompt: &str, secs: usize) -> anyhow::Result<()> {
+        let now = now();
+        self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO jobs (id, chat_id, state, prompt, secs, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+            params![id.to_string(), chat_id.to_string(), JobState::Queued.as_str(), prompt, secs, now],
+        )?;
+        Ok(())
+    }
+
+    /// Saves everything needed for running the job again after a restart, adding it in
+    /// the queued state if it is not known yet.
+    pub fn save_request(&self, req: &AudioGenerationRequest) -> anyhow::Result<()> {
+        let IdPair(chat_id, id) = req.id.clone().into();
+        let now = now();
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO jobs (id, chat_id, state, prompt, secs, config, priority, owner, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9)
+             ON CONFLICT (id) DO UPDATE SET
+                prompt = excluded.prompt,
+                secs = excluded.secs,
+                config = excluded.config,
+                priority = excluded.priority,
+                owner = excluded.owner",
+            params![
+                id.to_string(),
+                chat_id.to_string(),
+                JobState::Queued.as_str(),
+                req.prompt,
+                req.secs,
+                serde_json::to_string(&req.config)?,
+                priority_str(req.priority),
+                req.owner,
+                now
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The requests of the queued jobs, oldest first.
+    pub fn queued_requests(&self) -> anyhow::Result<Vec<AudioGenerationRequest>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, chat_id, prompt, secs, config, priority, owner FROM jobs
+             WHERE state = ?1 ORDER BY created_at, rowid",
+        )?;
+        let rows = stmt.query_map(params![JobState::Queued.as_str()], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, usize>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+            ))
+        })?;
+        let mut requests = vec![];
+        for row in rows {
+            let (id, chat_id, prompt, secs, config, priority, owner) = row?;
+            let id = IdPair(Uuid::parse_str(&chat_id)?, Uuid::parse_str(&id)?);
+            requests.push(AudioGenerationRequest {
+                id: id.to_string(),
+                prompt,
+                secs,
+                config: serde_json::from_str(&config)?,
+                priority: parse_priority(&priority)?,
+                owner,
+            });
+        }
+        Ok(requests)
+    }
+
+    /// Moves the job into `to`, failing if that is not allowed from its current state.
+    /// `update` sets the rest of the fields in the same transaction.
+    pub fn transition(
+        &self,
+        id: Uuid,
+        to: JobState,
+        update: impl FnOnce(&mut JobStatus),
+    ) -> anyhow::Result<JobStatus> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let Some(mut status) = Self::get_in(&tx, id)? else {
+            return Err(anyhow!("Job {id} not found"));
+        };
+        if !to.allowed_from().contains(&status.state) {
+            return Err(anyhow!(
+                "Job {id} cannot go from {} to {}",
+                status.state.as_str(),
+                to.as_str()
+            ));
+        }
+        status.state = to;
+        update(&mut status);
+        tx.execute(
+            "UPDATE jobs SET state = ?2, progress = ?3, audio_url = ?4, error = ?5, updated_at = ?6
+             WHERE id = ?1",
+            params![id.to_string(), to.as_str(), status.progress, status.audio_url, status.error, now()],
+        )?;
+        tx.commit()?;
+        Ok(status)
+    }
+
+    /// Updates the progress of a running job, ignored for jobs in any other state.
+    pub fn set_progress(&self, id: Uuid, progress: f32) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE jobs SET progress = ?2, updated_at = ?3 WHERE id = ?1 AND state = ?4",
+            params![id.to_string(), progress, now(), JobState::Running.as_str()],
+        )?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: Uuid) -> anyhow::Result<Option<JobStatus>> {
+        Self::get_in(&self.conn.lock().unwrap(), id)
+    }
+
+    fn get_in(conn: &Connection, id: Uuid) -> anyhow::Result<Option<JobStatus>> {
+        Ok(conn
+            .query_row(
+                &format!("SELECT {COLUMNS} FROM jobs WHERE id = ?1"),
+                params![id.to_string()],
+                JobStatus::from_row,
+            )
+            .optional()?)
+    }
+
+    /// Groups the jobs `ids` in the batch `batch_id`, keeping their order.
+    pub fn insert_batch(&self, batch_id: Uuid, ids: &[Uuid]) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for (position, id) in ids.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO batch_jobs (job_id, batch_id, position) VALUES (?1, ?2, ?3)",
+                params![id.to_string(), batch_id.to_string(), position],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The jobs of the batch `batch_id`, in the order they were submitted.
+    pub fn batch(&self, batch_id: Uuid) -> anyhow::Result<Vec<JobStatus>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COLUMNS} FROM jobs JOIN batch_jobs ON job_id = id
+             WHERE batch_id = ?1 ORDER BY position"
+        ))?;
+        let rows = stmt.query_map(params![batch_id.to_string()], JobStatus::from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Jobs in `state`, or all of them, oldest first.
+    pub fn list(&self, state: Option<JobState>) -> anyhow::Result<Vec<JobStatus>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COLUMNS} FROM jobs WHERE ?1 IS NULL OR state = ?1 ORDER BY created_at, rowid"
+        ))?;
+        let rows = stmt.query_map(params![state.map(|v| v.as_str())], JobStatus::from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Up to `limit` jobs matching `filter`, newest first, starting right after `after`.
+    pub fn page(
+        &self,
+        filter: &JobFilter,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(PageCursor, JobStatus)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COLUMNS}, created_at, rowid FROM jobs
+             WHERE (?1 IS NULL OR state = ?1)
+                AND (?2 IS NULL OR chat_id = ?2)
+                AND (?3 IS NULL OR created_at >= ?3)
+                AND (?4 IS NULL OR created_at <= ?4)
+                AND (?5 IS NULL OR (created_at, rowid) < (?5, ?6))
+             ORDER BY created_at DESC, rowid DESC LIMIT ?7"
+        ))?;
+        let rows = stmt.query_map(
+            params![
+                filter.state.map(|v| v.as_str()),
+                filter.chat_id.map(|v| v.to_string()),
+                filter.created_from,
+                filter.created_to,
+                after.map(|v| v.created_at),
+                after.map(|v| v.rowid),
+                limit,
+            ],
+            |row| {
+                let cursor = PageCursor {
+                    created_at: row.get(6)?,
+                    rowid: row.get(7)?,
+                };
+                Ok((cursor, JobStatus::from_row(row)?))
+            },
+        )?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Deletes the finished jobs in `state`, or in any finished state, returning how
+    /// many were deleted. Unfinished jobs are never deleted.
+    pub fn delete_finished(&self, state: Option<JobState>) -> anyhow::Result<usize> {
+        let states = match state {
+            Some(state) if !state.is_finished() => {
+                return Err(anyhow!("Only finished jobs can be deleted"));
+            }
+            Some(state) => vec![state],
+            None => vec![JobState::Completed, JobState::Failed, JobState::Cancelled],
+        };
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut deleted = 0;
+        for state in states {
+            tx.execute(
+                "DELETE FROM batch_jobs WHERE job_id IN (SELECT id FROM jobs WHERE state = ?1)",
+                params![state.as_str()],
+            )?;
+            deleted += tx.execute("DELETE FROM jobs WHERE state = ?1", params![state.as_str()])?;
+        }
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// The jobs of each owner, the busiest first.
+    pub fn usage(&self) -> anyhow::Result<Vec<Usage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT owner, COUNT(*),
+                SUM(state = ?1),
+                SUM(state = ?2),
+                SUM(CASE WHEN state = ?1 THEN secs ELSE 0 END)
+             FROM jobs GROUP BY owner ORDER BY COUNT(*) DESC, owner",
+        )?;
+        let rows = stmt.query_map(
+            params![JobState::Completed.as_str(), JobState::Failed.as_str()],
+            |row| {
+                Ok(Usage {
+                    owner: row.get(0)?,
+                    jobs: row.get(1)?,
+                    completed: row.get(2)?,
+                    failed: row.get(3)?,
+                    generated_secs: row.get(4)?,
+                })
+            },
+        )?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+/// Every request sent to the returned channel is saved in `jobs` before being
+/// forwarded to `ai_tx`, so that queued work survives a restart.
+pub fn persist_jobs(jobs: JobStore, ai_tx: Sender<BackendInboundMsg>) -> Sender<BackendInboundMsg> {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        for msg in rx {
+            if let BackendInboundMsg::Request(req) = &msg {
+                if let Err(err) = jobs.save_request(req) {
+                    warn!("Could not persist job {}: {err}", req.id);
+                }
+            }
+            if ai_tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+    tx
+}
+
+/// Picks up the jobs left behind by a previous run. Queued jobs are submitted again,
+/// but running ones are failed, as they might be the reason the server went down.
+/// Unless they made progress since they last resumed from one of the `checkpoints`,
+/// in which case they are submitted again to resume from it.
+pub async fn recover_jobs<S: Storage>(
+    jobs: &JobStore,
+    ai_tx: &Sender<BackendInboundMsg>,
+    storage: &S,
+    checkpoints: Option<&Checkpoints>,
+) -> anyhow::Result<()> {
+    for status in jobs.list(Some(JobState::Running))? {
+        let id = IdPair(status.chat_id, status.id).to_string();
+        if let Some(checkpoints) = checkpoints {
+            if checkpoints.resumable(&id) {
+                info!("Job {} was interrupted, resuming it from its checkpoint", status.id);
+                jobs.transition(status.id, JobState::Queued, |_| {})?;
+                continue;
+            }
+            checkpoints.remove(&id);
+        }
+        warn!("Job {} was interrupted, marking it as failed", status.id);
+        jobs.transition(status.id, JobState::Failed, |s| s.error = Some(INTERRUPTED.to_string()))?;
+        ChatEntry::new_ai_err(status.chat_id, status.id, INTERRUPTED.to_string())
+            .save(storage)
+            .await?;
+    }
+    let requests = jobs.queued_requests()?;
+    if !requests.is_empty() {
+        info!("Resubmitting {} queued jobs", requests.len());
+    }
+    for req in requests {
+        ai_tx.send(BackendInboundMsg::Request(req))?;
+    }
+    Ok(())
+}
+
+fn priority_str(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Interactive => "interactive",
+        Priority::Batch => "batch",
+    }
+}
+
+fn parse_priority(s: &str) -> anyhow::Result<Priority> {
+    Ok(match s {
+        "interactive" => Priority::Interactive,
+        "batch" => Priority::Batch,
+        _ => return Err(anyhow!("Unknown job priority {s}")),
+    })
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::music_gpt_chat::Chat;
+    use crate::music_gen_config::GenerationConfig;
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[test]
+    fn applies_valid_transitions_only() -> anyhow::Result<()> {
+        let store = JobStore::in_memory()?;
+        let (id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let status = store.insert(id, chat_id, "a song", 2)?;
+        assert_eq!(status.state, JobState::Queued);
+        assert!(store.insert(id, chat_id, "a song", 2).is_err());
+
+        // Progress of jobs that are not running is ignored.
+        store.set_progress(id, 0.5)?;
+        assert_eq!(store.get(id)?.unwrap().progress, 0.0);
+
+        assert!(store.transition(id, JobState::Completed, |_| {}).is_err());
+        store.transition(id, JobState::Running, |_| {})?;
+        store.set_progress(id, 0.5)?;
+        assert_eq!(store.get(id)?.unwrap().progress, 0.5);
+        let status = store.transition(id, JobState::Completed, |s| s.progress = 1.0)?;
+        assert_eq!(status.progress, 1.0);
+        assert_eq!(store.get(id)?, Some(status));
+
+        assert!(store.transition(id, JobState::Running, |_| {}).is_err());
+        assert!(store.transition(id, JobState::Failed, |_| {}).is_err());
+        assert!(store.transition(Uuid::new_v4(), JobState::Running, |_| {}).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn lists_jobs_by_state() -> anyhow::Result<()> {
+        let store = JobStore::in_memory()?;
+        let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        for id in ids {
+            store.insert(id, Uuid::new_v4(), "", 1)?;
+        }
+        store.ensure(ids[0], Uuid::new_v4(), "ignored", 1)?;
+        store.transition(ids[1], JobState::Failed, |s| s.error = Some("boom".to_string()))?;
+
+        let queued = store.list(Some(JobState::Queued))?;
+        assert_eq!(queued.iter().map(|s| s.id).collect::<Vec<_>>(), vec![ids[0], ids[2]]);
+        let failed = store.list(Some(JobState::Failed))?;
+        assert_eq!(failed[0].error.as_deref(), Some("boom"));
+        assert_eq!(store.list(None)?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn pages_through_the_history() -> anyhow::Result<()> {
+        let store = JobStore::in_memory()?;
+        let chat_id = Uuid::new_v4();
+        let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        for id in ids {
+            store.insert(id, chat_id, "", 1)?;
+        }
+        store.insert(Uuid::new_v4(), Uuid::new_v4(), "", 1)?;
+        let filter = JobFilter {
+            chat_id: Some(chat_id),
+            ..Default::default()
+        };
+
+        let page = store.page(&filter, None, 2)?;
+        assert_eq!(page.iter().map(|(_, s)| s.id).collect::<Vec<_>>(), vec![ids[2], ids[1]]);
+        let cursor: PageCursor = page[1].0.to_string().parse()?;
+        let page = store.page(&filter, Some(cursor), 2)?;
+        assert_eq!(page.iter().map(|(_, s)| s.id).collect::<Vec<_>>(), vec![ids[0]]);
+
+        let running = JobFilter {
+            state: Some(JobState::Running),
+            ..filter
+        };
+        assert!(store.page(&running, None, 2)?.is_empty());
+        assert!("nope".parse::<PageCursor>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn purges_finished_jobs_and_reports_usage() -> anyhow::Result<()> {
+        let store = JobStore::in_memory()?;
+        let request = |owner: &str| AudioGenerationRequest {
+            id: IdPair(Uuid::new_v4(), Uuid::new_v4()).to_string(),
+            prompt: "a song".to_string(),
+            secs: 3,
+            config: GenerationConfig::default(),
+            priority: Priority::Batch,
+            owner: owner.to_string(),
+        };
+        let mut ids = vec![];
+        for owner in ["alice", "alice", "bob"] {
+            let req = request(owner);
+            store.save_request(&req)?;
+            let IdPair(_, id) = req.id.into();
+            ids.push(id);
+        }
+        store.transition(ids[0], JobState::Running, |_| {})?;
+        store.transition(ids[0], JobState::Completed, |_| {})?;
+        store.transition(ids[2], JobState::Failed, |_| {})?;
+
+        let usage = store.usage()?;
+        assert_eq!(usage[0].owner, "alice");
+        assert_eq!((usage[0].jobs, usage[0].completed, usage[0].generated_secs), (2, 1, 3));
+        assert_eq!((usage[1].jobs, usage[1].failed, usage[1].generated_secs), (1, 1, 0));
+
+        assert!(store.delete_finished(Some(JobState::Queued)).is_err());
+        assert_eq!(store.delete_finished(Some(JobState::Failed))?, 1);
+        assert_eq!(store.delete_finished(None)?, 1);
+        assert_eq!(store.list(None)?.iter().map(|s| s.id).collect::<Vec<_>>(), vec![ids[1]]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recovers_jobs_after_a_restart() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let path = storage.path_buf("jobs.sqlite");
+        let request = |priority| AudioGenerationRequest {
+            id: IdPair(Uuid::new_v4(), Uuid::new_v4()).to_string(),
+            prompt: "a song".to_string(),
+            secs: 3,
+            config: GenerationConfig { top_k: Some(5), ..Default::default() },
+            priority,
+            owner: "alice".to_string(),
+        };
+        let (queued, running) = (request(Priority::Batch), request(Priority::Interactive));
+        {
+            let store = JobStore::open(&path)?;
+            let (tx, rx) = channel();
+            let tx = persist_jobs(store.clone(), tx);
+            tx.send(BackendInboundMsg::Request(queued.clone()))?;
+            tx.send(BackendInboundMsg::Request(running.clone()))?;
+            rx.recv()?;
+            rx.recv()?;
+            let IdPair(_, id) = running.id.clone().into();
+            store.transition(id, JobState::Running, |_| {})?;
+        }
+
+        let store = JobStore::open(&path)?;
+        let (tx, rx) = channel();
+        recover_jobs(&store, &tx, &storage, None).await?;
+
+        let BackendInboundMsg::Request(req) = rx.try_recv()? else {
+            panic!("expected a request")
+        };
+        assert_eq!(req.id, queued.id);
+        assert_eq!(req.config, queued.config);
+        assert_eq!(req.priority, Priority::Batch);
+        assert_eq!(req.owner, "alice");
+        assert!(rx.try_recv().is_err());
+
+        let IdPair(chat_id, id) = running.id.into();
+        let status = store.get(id)?.unwrap();
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!(status.error.as_deref(), Some(INTERRUPTED));
+        let entries = Chat::load_entries(&storage, chat_id).await?;
+        assert!(matches!(&entries[..], [ChatEntry::Ai(ai)] if ai.error == INTERRUPTED));
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt::{Display, Formatter};
+
+use axum::extract::DefaultBodyLimit;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::backend::errors::ErrorCode;
+
+/// Biggest HTTP request body taken, bigger ones are answered with a 413.
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Biggest WebSocket message taken, bigger ones are answered with [InvalidMessage].
+pub const MAX_WS_MESSAGE_BYTES: usize = 1024 * 1024;
+/// Longest prompt taken, the text encoder truncates way before this anyway.
+pub const MAX_PROMPT_CHARS: usize = 2000;
+
+/// Why a WebSocket message was not handled.
+#[derive(Clone, Copy, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub enum InvalidReason {
+    /// Over [MAX_WS_MESSAGE_BYTES].
+    TooLarge,
+    /// Not a message of the protocol.
+    Malformed,
+    /// A message of the protocol with values out of bounds.
+    Invalid,
+}
+
+/// Sent back for the WebSocket messages that were not handled, the connection stays
+/// open so that the client can carry on.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct InvalidMessage {
+    pub reason: InvalidReason,
+    pub message: String,
+}
+
+impl InvalidMessage {
+    pub fn new(reason: InvalidReason, message: impl Display) -> Self {
+        Self {
+            reason,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for InvalidMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+pub fn validate_prompt(prompt: &str) -> anyhow::Result<()> {
+    let chars = prompt.chars().count();
+    if chars > MAX_PROMPT_CHARS {
+        let message =
+            format!("The prompt has {chars} characters, the maximum is {MAX_PROMPT_CHARS}");
+        return Err(ErrorCode::PromptTooLong.err(message));
+    }
+    Ok(())
+}
+
+/// Layer rejecting the HTTP requests with a body over [MAX_BODY_BYTES].
+pub fn body_limit() -> DefaultBodyLimit {
+    DefaultBodyLimit::max(MAX_BODY_BYTES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_the_prompt_length() {
+        assert!(validate_prompt("lo-fi beats").is_ok());
+        assert!(validate_prompt(&"é".repeat(MAX_PROMPT_CHARS)).is_ok());
+        let err = validate_prompt(&"a".repeat(MAX_PROMPT_CHARS + 1)).unwrap_err();
+        assert!(err.to_string().contains(&format!("maximum is {MAX_PROMPT_CHARS}")));
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::Cursor;
+
+use axum::body::Bytes;
+use axum::extract::DefaultBodyLimit;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::audio_manager::AudioManager;
+use crate::backend::errors::ErrorCode;
+use crate::music_gen_config::GenerationConfig;
+use crate::storage::{Storage, AUDIOS_DIR};
+
+/// Where the uploaded melodies are kept, relative to the data dir.
+pub const MELODIES_DIR: &str = "melodies";
+/// Longest melody kept, the melody models only look at the first 30 seconds anyway.
+pub const MAX_MELODY_SECS: usize = 30;
+/// Biggest melody upload taken, enough for the longest melody as 16 bit stereo at 48kHz.
+pub const MAX_MELODY_BYTES: usize = 8 * 1024 * 1024;
+
+/// An uploaded melody, the jobs follow it when its id is set in the `melody` field of
+/// their generation config.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct Melody {
+    pub id: Uuid,
+    /// Length of the melody kept, longer uploads are cut.
+    pub secs: f32,
+}
+
+/// Decodes a wav file into mono samples at `sampling_rate`.
+pub fn decode_wav(bytes: &[u8], sampling_rate: u32) -> anyhow::Result<Vec<f32>> {
+    let reader = hound::WavReader::new(Cursor::new(bytes))?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.into_samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|v| v.map(|v| v as f32 / scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    let channels = spec.channels.max(1) as usize;
+    let mono = samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect::<Vec<_>>();
+    Ok(resample(&mono, spec.sample_rate, sampling_rate))
+}
+
+/// Linear interpolation, which is enough for telling the pitch of the melody and for
+/// the sound effects of AudioGen.
+pub fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let len = (samples.len() as u64 * to as u64 / from as u64) as usize;
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * from as f64 / to as f64;
+            let j = position as usize;
+            let fraction = (position - j as f64) as f32;
+            let a = samples[j];
+            let b = samples.get(j + 1).copied().unwrap_or(a);
+            a + (b - a) * fraction
+        })
+        .collect()
+}
+
+/// Where the clip `id` is, relative to the data dir: the audio generated by the job `id`
+/// or the uploaded melody `id`. Both kinds of clips can be used as melody or continued.
+pub fn clip_paths(id: Uuid) -> [String; 2] {
+    [format!("{AUDIOS_DIR}/{id}.wav"), format!("{MELODIES_DIR}/{id}.wav")]
+}
+
+/// Fails with [ErrorCode::NotFound] if a clip referenced by `config` does not exist, so
+/// that the job is rejected before being queued.
+pub async fn check_clips<S: Storage>(
+    storage: &S,
+    config: Option<&GenerationConfig>,
+) -> anyhow::Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+    for id in [config.melody, config.continuation, config.leads_into].into_iter().flatten() {
+        let [generated, uploaded] = clip_paths(id);
+        if !storage.exists(&generated).await? && !storage.exists(&uploaded).await? {
+            return Err(ErrorCode::NotFound.err(format!("Audio {id} not found")));
+        }
+    }
+    Ok(())
+}
+
+/// Stores the wav file in the body of the request as a new melody.
+pub async fn upload_melody<S: Storage>(storage: S, bytes: Bytes) -> Response {
+    let audio_manager = AudioManager::default();
+    let sampling_rate = audio_manager.sampling_rate();
+    let mut samples = match decode_wav(&bytes, sampling_rate) {
+        Ok(samples) if samples.is_empty() => {
+            return ErrorCode::InvalidRequest.response("The melody has no audio")
+        }
+        Ok(samples) => samples,
+        Err(err) => return ErrorCode::InvalidRequest.response(format!("Invalid wav file: {err}")),
+    };
+    samples.truncate(MAX_MELODY_SECS * sampling_rate as usize);
+    let melody = Melody {
+        id: Uuid::new_v4(),
+        secs: samples.len() as f32 / sampling_rate as f32,
+    };
+    let wav = match audio_manager.to_wav(samples.into()) {
+        Ok(wav) => wav,
+        Err(err) => return ErrorCode::Internal.response(err),
+    };
+    let path = format!("{MELODIES_DIR}/{}.wav", melody.id);
+    if let Err(err) = storage.write(&path, wav).await {
+        return ErrorCode::Internal.response(err);
+    }
+    Json(melody).into_response()
+}
+
+/// Layer taking melody uploads over the default body limit.
+pub fn melody_body_limit() -> DefaultBodyLimit {
+    DefaultBodyLimit::max(MAX_MELODY_BYTES)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    fn stereo_wav(sample_rate: u32, frames: usize) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut bytes = Cursor::new(vec![]);
+        let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+        for _ in 0..frames {
+            writer.write_sample(i16::MAX / 2).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn decodes_melodies_into_mono_at_the_model_rate() -> anyhow::Result<()> {
+        let samples = decode_wav(&stereo_wav(48000, 48000), 32000)?;
+        assert_eq!(samples.len(), 32000);
+        assert!(samples.iter().all(|v| (v - 0.25).abs() < 1e-3));
+        assert_eq!(decode_wav(&stereo_wav(16000, 16000), 32000)?.len(), 32000);
+        assert!(decode_wav(b"not a wav", 32000).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stores_uploaded_melodies() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let config = |id: Uuid| GenerationConfig {
+            continuation: Some(id),
+            ..Default::default()
+        };
+        assert!(check_clips(&storage, None).await.is_ok());
+        assert!(check_clips(&storage, Some(&config(Uuid::new_v4()))).await.is_err());
+
+        let wav = stereo_wav(32000, 32000 * (MAX_MELODY_SECS + 5));
+        let response = upload_melody(storage.clone(), wav.into()).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        let melody: Melody = serde_json::from_slice(&body)?;
+        assert_eq!(melody.secs, MAX_MELODY_SECS as f32);
+        assert!(check_clips(&storage, Some(&config(melody.id))).await.is_ok());
+
+        let generated = Uuid::new_v4();
+        storage.write(&format!("{AUDIOS_DIR}/{generated}.wav"), stereo_wav(32000, 1)).await?;
+        assert!(check_clips(&storage, Some(&config(generated))).await.is_ok());
+        Ok(())
+    }
+}
@@ -1,5 +1,9 @@
-pub use audio_generation_backend::MusicGenJobProcessor;
+pub use audio_generation_backend::{DecoderFactory, JobProcessor, MusicGenJobProcessor};
+pub use devices::MultiDeviceProcessor;
+pub use models::{ModelLoader, ModelSwitchingProcessor};
+pub use pipeline::Pipeline;
 pub use server::*;
+pub use shadow::ShadowOptions;
 
 pub use music_gpt_ws_handler::MusicGPTWebSocketHandler; 
 
@@ -7,10 +11,45 @@ mod audio_generation_backend;
 mod server;
 #[cfg(test)]
 mod _test_utils;
+mod admin;
 mod music_gpt_chat;
 mod audio_generation_fanout;
+mod auth;
+mod batches;
+mod bulk;
+mod checkpoints;
+mod devices;
+mod discovery;
+mod errors;
+mod feed;
+mod graphql;
+mod grpc;
+mod health;
+mod invites;
+mod job_store;
+mod limits;
+mod melodies;
+mod models;
 mod ws_handler;
 mod music_gpt_ws_handler;
+mod observer_ws_handler;
+mod openapi;
+mod pipeline;
+mod playlist;
+mod rate_limit;
+mod rest_api;
+mod result_cache;
+mod sessions;
+mod shadow;
+mod shutdown;
+mod stems;
+mod suggestions;
+mod tls;
+#[cfg(unix)]
+mod unix_socket;
+mod users;
+mod web_assets;
+mod webhooks;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::backend::audio_generation_backend::JobProcessor;
+use crate::music_gen_config::GenerationConfig;
+
+/// Loads the model named like one of the values of `--model`, like "small" or "melody".
+pub type ModelLoader = Box<dyn Fn(&str) -> anyhow::Result<Box<dyn JobProcessor>> + Send + Sync>;
+
+/// A model that is loaded once, by the first job that names it.
+type LoadedModel = Arc<OnceLock<Result<Arc<dyn JobProcessor>, String>>>;
+
+/// Runs each job on the model named in its config, or on the default one if it names
+/// none. The other models, as long as they are `allowed`, are loaded the first time a
+/// job names them, and past `capacity` of them the least recently used one is unloaded,
+/// once its jobs finish.
+pub struct ModelSwitchingProcessor {
+    /// Name of the default model, which is always loaded.
+    default_model: String,
+    default: Box<dyn JobProcessor>,
+    loader: ModelLoader,
+    allowed: Vec<String>,
+    capacity: usize,
+    /// Least recently used first, including the ones being loaded.
+    loaded: Mutex<VecDeque<(String, LoadedModel)>>,
+}
+
+impl ModelSwitchingProcessor {
+    pub fn new(
+        default_model: String,
+        default: Box<dyn JobProcessor>,
+        loader: ModelLoader,
+        allowed: Vec<String>,
+        capacity: usize,
+    ) -> Self {
+        Self {
+            default_model,
+            default,
+            loader,
+            allowed,
+            capacity,
+            loaded: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The model `name`, loading it if it is not. Jobs waiting for a model that is being
+    /// loaded wait for it to be loaded too, instead of loading it again, while the jobs
+    /// on the other models go on.
+    fn model(&self, name: &str) -> ort::Result<Arc<dyn JobProcessor>> {
+        if !self.allowed.iter().any(|v| v == name) {
+            return Err(ort::Error::new(format!("Jobs cannot switch to model {name}")));
+        }
+        let model = {
+            let mut loaded = self.loaded.lock().unwrap();
+            match loaded.iter().position(|(v, _)| v == name) {
+                Some(i) => {
+                    let entry = loaded.remove(i).unwrap();
+                    let model = entry.1.clone();
+                    loaded.push_back(entry);
+                    model
+                }
+                None => {
+                    while loaded.len() >= self.capacity.max(1) {
+                        if let Some((evicted, _)) = loaded.pop_front() {
+                            info!("Unloading model {evicted}");
+                        }
+                    }
+                    let model = LoadedModel::default();
+                    loaded.push_back((name.to_string(), model.clone()));
+                    model
+                }
+            }
+        };
+        match model.get_or_init(|| self.load(name)) {
+            Ok(processor) => Ok(processor.clone()),
+            Err(err) => {
+                // Tried again by the next job that names it.
+                self.loaded.lock().unwrap().retain(|(_, v)| !Arc::ptr_eq(v, &model));
+                Err(ort::Error::new(err.clone()))
+            }
+        }
+    }
+
+    fn load(&self, name: &str) -> Result<Arc<dyn JobProcessor>, String> {
+        info!("Loading model {name}");
+        let processor: Arc<dyn JobProcessor> = (self.loader)(name)
+            .map_err(|err| format!("Could not load model {name}: {err}"))?
+            .into();
+        // The audio of every job goes through the same pipeline.
+        if processor.channels() != self.default.channels() {
+            return Err(format!(
+                "Model {name} generates audio with {} channels instead of {}",
+                processor.channels(),
+                self.default.channels()
+            ));
+        }
+        Ok(processor)
+    }
+}
+
+impl JobProcessor for ModelSwitchingProcessor {
+    fn name(&self) -> String {
+        self.default.name()
+    }
+
+    fn device(&self) -> String {
+        self.default.device()
+    }
+
+    fn channels(&self) -> u16 {
+        self.default.channels()
+    }
+
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        config: &GenerationConfig,
+        cancel: CancellationToken,
+        on_progress: Box<dyn Fn(f32) + Sync + Send + 'static>,
+        on_tokens: Box<dyn Fn(Vec<i64>) + Sync + Send + 'static>,
+        on_audio: Box<dyn Fn(VecDeque<f32>) + Sync + Send + 'static>,
+    ) -> anyhow::Result<VecDeque<f32>> {
+        match config.model.as_deref() {
+            Some(name) if name != self.default_model => self
+                .model(name)?
+                .process(prompt, secs, config, cancel, on_progress, on_tokens, on_audio),
+            _ => self
+                .default
+                .process(prompt, secs, config, cancel, on_progress, on_tokens, on_audio),
+        }
+    }
+
+    /// Only the default model, the others are loaded on demand.
+    fn warmup(&self) -> anyhow::Result<()> {
+        self.default.warmup()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::backend::_test_utils::DummyJobProcessor;
+
+    #[test]
+    fn loads_the_models_on_demand() -> anyhow::Result<()> {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let loads_clone = loads.clone();
+        let loader: ModelLoader = Box::new(move |name| match name {
+            "medium" | "melody" => {
+                loads_clone.fetch_add(1, Ordering::SeqCst);
+                Ok(Box::new(DummyJobProcessor::new(Duration::ZERO)))
+            }
+            _ => Err(anyhow::anyhow!("Unknown model")),
+        });
+        let default = Box::new(DummyJobProcessor::new(Duration::ZERO));
+        let allowed = vec!["medium".to_string(), "melody".to_string(), "huge".to_string()];
+        let processor =
+            ModelSwitchingProcessor::new("small".to_string(), default, loader, allowed, 1);
+        let process = |model: Option<&str>| {
+            let config = GenerationConfig {
+                model: model.map(|v| v.to_string()),
+                ..Default::default()
+            };
+            processor.process(
+                "A song",
+                1,
+                &config,
+                CancellationToken::new(),
+                Box::new(|_| {}),
+                Box::new(|_| {}),
+                Box::new(|_| {}),
+            )
+        };
+
+        process(None)?;
+        process(Some("small"))?;
+        assert_eq!(loads.load(Ordering::SeqCst), 0);
+        process(Some("medium"))?;
+        process(Some("medium"))?;
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        // Only one model stays loaded besides the default one.
+        process(Some("melody"))?;
+        process(Some("medium"))?;
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+        assert!(process(Some("huge")).is_err());
+        assert!(process(Some("large")).is_err());
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn loads_a_model_without_blocking_the_others() -> anyhow::Result<()> {
+        // Loading the medium model hangs until it is released, after the melody is used.
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let loader: ModelLoader = Box::new(move |name| {
+            if name == "medium" {
+                started_tx.send(())?;
+                let _ = release_rx.lock().unwrap().recv();
+            }
+            Ok(Box::new(DummyJobProcessor::new(Duration::ZERO)))
+        });
+        let default = Box::new(DummyJobProcessor::new(Duration::ZERO));
+        let allowed = vec!["medium".to_string(), "melody".to_string()];
+        let processor =
+            ModelSwitchingProcessor::new("small".to_string(), default, loader, allowed, 2);
+        processor.model("melody")?;
+        let processor = &processor;
+        std::thread::scope(|s| {
+            let medium = s.spawn(|| processor.model("medium").map(|_| ()));
+            started_rx.recv()?;
+            let (done_tx, done_rx) = std::sync::mpsc::channel();
+            s.spawn(move || done_tx.send(processor.model("melody").map(|_| ())));
+            // Only waited for long in case the melody is blocked behind the medium model.
+            let melody = done_rx.recv_timeout(Duration::from_secs(10));
+            release_tx.send(())?;
+            assert!(melody.is_ok(), "The melody model waited for the medium one");
+            melody??;
+            medium.join().unwrap()?;
+            Ok(())
+        })
+    }
+}
@@ -1,3 +1,4 @@
+use crate::backend::errors::ErrorCode;
 use crate::storage::Storage;
 
 use serde::{Deserialize, Serialize};
@@ -69,6 +70,12 @@ pub struct Chat {
     pub chat_id: Uuid,
     pub name: String,
     pub created_at: u128,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The user the chat belongs to, chats without one are shared by the clients
+    /// that are not registered.
+    #[serde(default)]
+    pub user_id: Option<Uuid>,
 }
 
 const METADATA_FILE: &str = ".metadata.json";
@@ -90,6 +97,8 @@ impl Chat {
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_millis(),
+            tags: vec![],
+            user_id: None,
         };
         let this_serial = serde_json::to_string(&this)?;
         storage.write(&metadata_file, this_serial).await?;
@@ -117,6 +126,30 @@ impl Chat {
         Ok(result)
     }
 
+    /// The user the chat belongs to, without creating it if it does not exist.
+    pub async fn user_of<S: Storage>(storage: &S, chat_id: Uuid) -> anyhow::Result<Option<Uuid>> {
+        let metadata_file = format!("chats/{chat_id}/{METADATA_FILE}");
+        let Some(this_serial) = storage.read(&metadata_file).await? else {
+            return Ok(None);
+        };
+        Ok(serde_json::from_slice::<Self>(&this_serial).ok().and_then(|v| v.user_id))
+    }
+
+    /// Like [Chat::load], failing if the chat belongs to someone other than `user_id`.
+    pub async fn load_for<S: Storage>(storage: &S, chat_id: Uuid, user_id: Option<Uuid>) -> anyhow::Result<Self> {
+        if Self::user_of(storage, chat_id).await? != user_id {
+            return Err(ErrorCode::NotFound.err(format!("Chat {chat_id} not found")));
+        }
+        Self::load(storage, chat_id).await
+    }
+
+    /// Like [Chat::load_all], only with the chats of `user_id`.
+    pub async fn load_all_for<S: Storage>(storage: &S, user_id: Option<Uuid>) -> anyhow::Result<Vec<Self>> {
+        let mut chats = Self::load_all(storage).await?;
+        chats.retain(|v| v.user_id == user_id);
+        Ok(chats)
+    }
+
     pub async fn save<S: Storage>(&self, storage: &S) -> anyhow::Result<()> {
         let this_serial = serde_json::to_string(self)?;
         storage
@@ -218,6 +251,24 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn only_lists_the_chats_of_the_user() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let (alice, bob) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+        let mut chat = Chat::load(&storage, Uuid::new_v4()).await?;
+        chat.user_id = alice;
+        chat.save(&storage).await?;
+        let shared = Chat::load(&storage, Uuid::new_v4()).await?;
+
+        assert_eq!(Chat::load_all_for(&storage, alice).await?, vec![chat.clone()]);
+        assert_eq!(Chat::load_all_for(&storage, bob).await?, vec![]);
+        assert_eq!(Chat::load_all_for(&storage, None).await?, vec![shared]);
+        assert!(Chat::load_for(&storage, chat.chat_id, alice).await.is_ok());
+        assert!(Chat::load_for(&storage, chat.chat_id, bob).await.is_err());
+        assert!(Chat::load_for(&storage, chat.chat_id, None).await.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn list_messages_in_non_existing_chat_returns_empty_vec() -> anyhow::Result<()> {
         let storage = AppFs::new_tmp();
@@ -1,18 +1,41 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::mpsc::Sender;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
+use axum::extract::ws::{close_code, CloseFrame};
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use specta::Type;
-use tracing::{error, info};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use crate::backend::audio_generation_backend::{AudioGenerationRequest, BackendInboundMsg};
-use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::audio_generation_backend::{AudioGenerationRequest, BackendInboundMsg, Priority};
+use crate::backend::audio_generation_fanout::{
+    AudioGenerationChunk, EventLog, GenerationMessage, JobSnapshot,
+};
+use crate::backend::batches::{validate_batch, Batch, BatchRequest, BatchStatus, GenerateBatchRequest};
+use crate::backend::bulk::{run_bulk, BulkProgress, BulkRequest};
+use crate::backend::auth::{Access, Auth};
+use crate::backend::errors::{ApiError, ErrorCode};
+use crate::backend::job_store::JobStore;
+use crate::backend::limits::{validate_prompt, InvalidMessage, InvalidReason};
+use crate::backend::melodies::check_clips;
+use crate::backend::rate_limit::{QueueFull, RateLimited, RateLimiter, Rejected};
 use crate::backend::music_gpt_chat::{Chat, ChatEntry};
-use crate::backend::ws_handler::WsHandler;
+use crate::backend::sessions::{ResumeRequest, ResumeResponse, Session, Sessions};
+use crate::backend::users::User;
+use crate::backend::ws_handler::{Keepalive, WsHandler};
+use crate::config_profiles::{parse_profile_command, ConfigProfile};
+use crate::music_gen_config::GenerationConfig;
 use crate::storage::Storage;
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -26,6 +49,18 @@ pub struct GenerateAudioRequest {
     pub chat_id: Uuid,
     pub prompt: String,
     pub secs: usize,
+    /// Overrides the server config for this job only.
+    #[serde(default)]
+    pub config: Option<GenerationConfig>,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct CommitPreviewRequest {
+    /// The id of the previously generated preview.
+    pub preview_id: Uuid,
+    /// The id for the full quality generation.
+    pub id: Uuid,
+    pub chat_id: Uuid,
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -34,6 +69,34 @@ pub struct AbortGenerationRequest {
     pub chat_id: Uuid,
 }
 
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct TokenTapRequest {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct SubscribeJobRequest {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct AuthenticateRequest {
+    pub api_key: String,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct SetProfileRequest {
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct Profiles {
+    pub active: Option<String>,
+    pub profiles: Vec<ConfigProfile>,
+}
+
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub struct Info {
     pub model: String,
@@ -46,27 +109,151 @@ pub struct SetChatMetadataRequest {
     pub name: Option<String>,
 }
 
+/// Version of the WebSocket protocol spoken by this server.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest version of the WebSocket protocol this server still speaks. Clients
+/// between this one and [PROTOCOL_VERSION] are served with the version they know,
+/// and newer clients are downgraded to [PROTOCOL_VERSION].
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Sent before closing the connection when the client is too old for this server.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct VersionMismatch {
+    pub client_version: u32,
+    pub min_version: u32,
+    pub max_version: u32,
+}
+
+impl Display for VersionMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Protocol version {} is not supported, this server speaks versions {} to {}",
+            self.client_version, self.min_version, self.max_version
+        )
+    }
+}
+
+/// Features of the WebSocket protocol. Clients declare the ones they support in
+/// [InboundMsg::Hello], and the server answers with the ones enabled for the
+/// connection. Clients that never say hello get [Capabilities::legacy].
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct Capabilities {
+    pub protocol_version: u32,
+    /// Outbound messages are sent as binary frames instead of text frames.
+    #[serde(default)]
+    pub binary_frames: bool,
+    /// Outbound messages over a size are sent zlib compressed, see
+    /// [crate::backend::ws_handler::COMPRESSED_FRAME_MAGIC].
+    #[serde(default)]
+    pub compression: bool,
+    /// Audio is streamed while it is generated instead of only announced once saved,
+    /// in the binary frames described in [audio_frame].
+    #[serde(default)]
+    pub streaming_audio: bool,
+}
+
+impl Capabilities {
+    /// Everything this server can do.
+    pub fn server() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            binary_frames: true,
+            compression: true,
+            streaming_audio: true,
+        }
+    }
+
+    /// What frontends that predate the handshake understand.
+    pub fn legacy() -> Self {
+        Self {
+            protocol_version: 1,
+            binary_frames: false,
+            compression: false,
+            streaming_audio: false,
+        }
+    }
+
+    /// The features supported by both sides, at the newest protocol version both speak.
+    pub fn negotiate(&self, client: &Capabilities) -> Result<Self, VersionMismatch> {
+        if client.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(VersionMismatch {
+                client_version: client.protocol_version,
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: self.protocol_version,
+            });
+        }
+        Ok(Self {
+            protocol_version: self.protocol_version.min(client.protocol_version),
+            binary_frames: self.binary_frames && client.binary_frames,
+            compression: self.compression && client.compression,
+            streaming_audio: self.streaming_audio && client.streaming_audio,
+        })
+    }
+}
+
 // === Inbound ===
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub enum InboundMsg {
+    Hello(Capabilities),
+    /// Required before anything else from remote clients when the server has an API key.
+    Authenticate(AuthenticateRequest),
+    /// Re-attaches to the jobs of a previous connection of the same client.
+    Resume(ResumeRequest),
     GenerateAudioNewChat(GenerateAudioRequest),
     GenerateAudio(GenerateAudioRequest),
+    GeneratePreview(GenerateAudioRequest),
+    CommitPreview(CommitPreviewRequest),
     AbortGeneration(AbortGenerationRequest),
     GetChat(ChatRequest),
     SetChatMetadata(SetChatMetadataRequest),
     DelChat(ChatRequest),
+    TapTokens(TokenTapRequest),
+    UntapTokens(TokenTapRequest),
+    /// Follows a job started elsewhere, like in another tab. Where the job is at is
+    /// sent right away, and then its messages like for the jobs of this connection.
+    SubscribeJob(SubscribeJobRequest),
+    GetProfiles,
+    SaveProfile(ConfigProfile),
+    SetProfile(SetProfileRequest),
+    Bulk(BulkRequest),
+    /// Queues a job for each prompt, all of them in the same chat.
+    GenerateBatch(GenerateBatchRequest),
+    GetBatch(BatchRequest),
 }
 
 // === Outbound ===
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub enum OutboundMsg {
+    Welcome(Capabilities),
+    Resumed(ResumeResponse),
     Generation(GenerationMessage),
     Info(Info),
     Chat((Chat, Vec<ChatEntry>)),
     Chats(Vec<Chat>),
-    Error(String),
+    Profiles(Profiles),
+    BulkProgress(BulkProgress),
+    Batch(BatchStatus),
+    /// The job was not submitted, it can be retried later.
+    RateLimited(RateLimited),
+    /// The job was not submitted as the server is too busy, it can be retried later.
+    QueueFull(QueueFull),
+    /// The connection is closed right after this message.
+    VersionMismatch(VersionMismatch),
+    /// The message was not handled, as it was too big, malformed or out of bounds.
+    Invalid(InvalidMessage),
+    Error(ApiError),
+}
+
+impl From<Rejected> for OutboundMsg {
+    fn from(value: Rejected) -> Self {
+        match value {
+            Rejected::RateLimited(limited) => OutboundMsg::RateLimited(limited),
+            Rejected::QueueFull(full) => OutboundMsg::QueueFull(full),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -75,6 +262,246 @@ pub struct MusicGptWsHandler<S: Storage> {
     pub ai_broadcast_tx: tokio::sync::broadcast::Sender<GenerationMessage>,
     pub ai_tx: Sender<BackendInboundMsg>,
     pub info: Info,
+    /// Jobs for which this connection receives the raw generated tokens.
+    pub token_taps: Arc<RwLock<HashSet<Uuid>>>,
+    /// Profile applied to all the jobs, shared by all the connections.
+    pub active_profile: Arc<RwLock<Option<ConfigProfile>>>,
+    /// Read-only profiles declared in the config file.
+    pub config_profiles: Arc<Vec<ConfigProfile>>,
+    /// Full quality requests of this connection waiting for their preview to be approved,
+    /// with when they were made.
+    pub previews: Arc<RwLock<HashMap<Uuid, (Instant, AudioGenerationRequest)>>>,
+    /// Messages for this connection emitted while a request is being handled,
+    /// like the progress of bulk operations.
+    pub events_tx: tokio::sync::broadcast::Sender<OutboundMsg>,
+    /// What was agreed with the client of this connection in the handshake.
+    pub capabilities: Arc<RwLock<Capabilities>>,
+    /// Compression is offered in the handshake, can be disabled in the config.
+    pub ws_compression: bool,
+    pub keepalive: Option<Keepalive>,
+    /// Set when the handshake fails, the connection is closed after the response.
+    pub rejected: Arc<RwLock<Option<VersionMismatch>>>,
+    /// Owner of the jobs submitted through this connection.
+    pub client_id: Uuid,
+    pub auth: Auth,
+    /// Who is on the other side of this connection.
+    pub access: Arc<RwLock<Access>>,
+    pub rate_limiter: RateLimiter,
+    /// Where batches are recorded, so that their progress can be looked up as a unit.
+    pub jobs: JobStore,
+    pub client_ip: IpAddr,
+    /// The registered user on the other side of this connection, if any.
+    pub user: Option<User>,
+    /// Chats whose generation messages are forwarded to this connection.
+    pub visible_chats: Arc<RwLock<HashSet<Uuid>>>,
+    pub shutdown: CancellationToken,
+    /// The generation messages are read from here rather than from the broadcast, so
+    /// that the ones missed by a previous connection of the session can be replayed.
+    pub events: EventLog,
+    pub sessions: Sessions,
+    pub session_id: Arc<RwLock<Option<Uuid>>>,
+    /// Sequence number of the last generation message handled by this connection.
+    pub cursor: Arc<AtomicU64>,
+    /// Wakes up the subscription when the cursor is moved back by a resume.
+    pub resumed: Arc<Notify>,
+}
+
+/// Max length of the drafts generated by preview requests.
+pub const PREVIEW_SECS: usize = 5;
+
+/// Time a preview can be committed for after it was requested.
+pub const PREVIEW_TTL: Duration = Duration::from_secs(10 * 60);
+
+pub const EVENTS_CAPACITY: usize = 64;
+
+/// First bytes of the binary frames with streamed audio, which tell them apart from
+/// the JSON messages sent as binary frames.
+pub const AUDIO_FRAME_MAGIC: &[u8; 4] = b"MGAU";
+
+/// Encodes a chunk of streamed audio as a binary frame. All numbers are little endian:
+///
+/// | bytes  | content                            |
+/// |--------|------------------------------------|
+/// | 0..4   | [AUDIO_FRAME_MAGIC]                |
+/// | 4..20  | job id                             |
+/// | 20..36 | chat id                            |
+/// | 36..44 | offset of the first sample, as u64 |
+/// | 44..48 | sample rate, as u32                |
+/// | 48..50 | channels, as u16                   |
+/// | 50..   | interleaved samples, as i16        |
+pub fn audio_frame(chunk: &AudioGenerationChunk) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(50 + 2 * chunk.samples.len());
+    frame.extend_from_slice(AUDIO_FRAME_MAGIC);
+    frame.extend_from_slice(chunk.id.as_bytes());
+    frame.extend_from_slice(chunk.chat_id.as_bytes());
+    frame.extend_from_slice(&(chunk.offset as u64).to_le_bytes());
+    frame.extend_from_slice(&chunk.sample_rate.to_le_bytes());
+    frame.extend_from_slice(&chunk.channels.to_le_bytes());
+    for sample in &chunk.samples {
+        frame.extend_from_slice(&sample.to_le_bytes());
+    }
+    frame
+}
+
+impl<S: Storage> MusicGptWsHandler<S> {
+    /// Clones the handler resetting all the state that is scoped to a single connection.
+    pub fn for_connection(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            ai_broadcast_tx: self.ai_broadcast_tx.clone(),
+            ai_tx: self.ai_tx.clone(),
+            info: self.info.clone(),
+            token_taps: Default::default(),
+            active_profile: self.active_profile.clone(),
+            config_profiles: self.config_profiles.clone(),
+            previews: Default::default(),
+            events_tx: tokio::sync::broadcast::channel(EVENTS_CAPACITY).0,
+            capabilities: Arc::new(RwLock::new(Capabilities::legacy())),
+            ws_compression: self.ws_compression,
+            keepalive: self.keepalive,
+            rejected: Default::default(),
+            client_id: Uuid::new_v4(),
+            auth: self.auth.clone(),
+            access: Arc::new(RwLock::new(Access::Owner)),
+            rate_limiter: self.rate_limiter.clone(),
+            jobs: self.jobs.clone(),
+            client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            user: None,
+            visible_chats: Default::default(),
+            shutdown: self.shutdown.clone(),
+            events: self.events.clone(),
+            sessions: self.sessions.clone(),
+            session_id: Default::default(),
+            cursor: Arc::new(AtomicU64::new(self.events.last_seq())),
+            resumed: Default::default(),
+        }
+    }
+
+    fn server_capabilities(&self) -> Capabilities {
+        Capabilities {
+            compression: self.ws_compression,
+            ..Capabilities::server()
+        }
+    }
+
+    fn access(&self) -> Access {
+        self.access.read().unwrap().clone()
+    }
+
+    fn user_id(&self) -> Option<Uuid> {
+        self.user.as_ref().map(|v| v.id)
+    }
+
+    /// Owner of the jobs for the scheduler, users are served fairly across their connections.
+    fn owner(&self) -> String {
+        self.user_id().unwrap_or(self.client_id).to_string()
+    }
+
+    /// Binds this connection to a session, taking over the state of its previous
+    /// connection, if any, and replaying the generation messages it did not get.
+    fn resume(&self, session_id: Uuid, secret: Option<Uuid>) -> anyhow::Result<ResumeResponse> {
+        let session = Session::new(
+            self.user_id(),
+            self.token_taps.clone(),
+            self.visible_chats.clone(),
+            self.cursor.clone(),
+        );
+        let fresh = session.secret;
+        let previous = self.sessions.attach(session_id, secret, session)?;
+        *self.session_id.write().unwrap() = Some(session_id);
+        let Some(previous) = previous else {
+            return Ok(ResumeResponse { session_id, resumed: false, secret: fresh });
+        };
+        info!("Resuming session {session_id}");
+        let token_taps = previous.token_taps.read().unwrap().clone();
+        self.token_taps.write().unwrap().extend(token_taps);
+        let visible_chats = previous.visible_chats.read().unwrap().clone();
+        self.visible_chats.write().unwrap().extend(visible_chats);
+        self.cursor
+            .fetch_min(previous.cursor.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.resumed.notify_one();
+        Ok(ResumeResponse { session_id, resumed: true, secret: previous.secret })
+    }
+
+    /// The chats of the user of this connection, which from then on are visible to it.
+    async fn chats(&self) -> anyhow::Result<Vec<Chat>> {
+        let chats = Chat::load_all_for(&self.storage, self.user_id()).await?;
+        self.visible_chats
+            .write()
+            .unwrap()
+            .extend(chats.iter().map(|v| v.chat_id));
+        Ok(chats)
+    }
+
+    /// Loads a chat of the user of this connection, failing if it belongs to someone else.
+    async fn chat(&self, chat_id: Uuid) -> anyhow::Result<Chat> {
+        let chat = Chat::load_for(&self.storage, chat_id, self.user_id()).await?;
+        self.visible_chats.write().unwrap().insert(chat_id);
+        Ok(chat)
+    }
+
+    /// Runs `submit` for the job `id`, already admitted by the rate limiter, giving its
+    /// slot back if it fails.
+    async fn submit_admitted<T>(
+        &self,
+        id: Uuid,
+        submit: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let submitted = submit.await;
+        if submitted.is_err() {
+            self.rate_limiter.release(self.client_ip, id);
+        }
+        submitted
+    }
+
+    /// The config for a job, with the per-request overrides applied on top
+    /// of the active profile.
+    fn job_config(&self, overrides: &Option<GenerationConfig>) -> GenerationConfig {
+        let profile = self.active_profile.read().unwrap();
+        let base = profile.as_ref().map(|p| p.config.clone()).unwrap_or_default();
+        base.merged(&overrides.clone().unwrap_or_default())
+    }
+
+    /// Brings this connection up to date with jobs that were already running.
+    fn send_snapshots(&self, snapshots: Vec<JobSnapshot>) {
+        for msg in snapshots.iter().flat_map(JobSnapshot::messages) {
+            let _ = self.events_tx.send(OutboundMsg::Generation(msg));
+        }
+    }
+
+    fn check_owner(&self, action: &str) -> anyhow::Result<()> {
+        match self.access() {
+            Access::Owner => Ok(()),
+            _ => Err(ErrorCode::Forbidden.err(format!("Only the owner can {action}"))),
+        }
+    }
+
+    /// The full request of the preview `id` of this connection, unless it expired.
+    fn preview(&self, id: Uuid) -> anyhow::Result<AudioGenerationRequest> {
+        let mut previews = self.previews.write().unwrap();
+        previews.retain(|_, (created, _)| created.elapsed() < PREVIEW_TTL);
+        match previews.get(&id) {
+            Some((_, full)) => Ok(full.clone()),
+            None => Err(ErrorCode::NotFound.err(format!("No pending preview with id {id}"))),
+        }
+    }
+
+    async fn profiles(&self) -> anyhow::Result<Profiles> {
+        let active = self.active_profile.read().unwrap().as_ref().map(|p| p.name.clone());
+        let profiles = ConfigProfile::load_all(&self.storage, &self.config_profiles).await?;
+        Ok(Profiles { active, profiles })
+    }
+
+    /// The profile applies to the jobs of everyone, so only the owner can change it.
+    async fn set_profile(&self, name: Option<String>) -> anyhow::Result<Profiles> {
+        self.check_owner("switch profiles")?;
+        let profile = match name {
+            Some(name) => Some(ConfigProfile::load(&self.storage, &self.config_profiles, &name).await?),
+            None => None,
+        };
+        *self.active_profile.write().unwrap() = profile;
+        self.profiles().await
+    }
 }
 
 #[async_trait]
@@ -83,7 +510,10 @@ impl<S: Storage + 'static> WsHandler for MusicGptWsHandler<S> {
     type Outbound = OutboundMsg;
 
     async fn handle_init(&self) -> Vec<OutboundMsg> {
-        let chats = Chat::load_all(&self.storage).await.unwrap_or_default();
+        if self.access() == Access::Unauthenticated {
+            return vec![OutboundMsg::Info(self.info.clone())];
+        }
+        let chats = self.chats().await.unwrap_or_default();
         vec![
             OutboundMsg::Info(self.info.clone()),
             OutboundMsg::Chats(chats),
@@ -91,85 +521,430 @@ impl<S: Storage + 'static> WsHandler for MusicGptWsHandler<S> {
     }
 
     async fn handle_inbound_msg(&self, msg: InboundMsg) -> Option<OutboundMsg> {
+        if let Err(err) = msg.validate() {
+            let invalid = InvalidMessage::new(InvalidReason::Invalid, err);
+            return Some(OutboundMsg::Invalid(invalid));
+        }
         async move {
+            let handshake = matches!(msg, InboundMsg::Hello(_) | InboundMsg::Authenticate(_));
+            if !handshake && self.access() == Access::Unauthenticated {
+                return Err(ErrorCode::Unauthorized.err("Authenticate with the API key first"));
+            }
+            // A `/profile <name>` prompt switches the profile instead of generating audio.
+            if let InboundMsg::GenerateAudioNewChat(req) | InboundMsg::GenerateAudio(req) = &msg {
+                if let Some(name) = parse_profile_command(&req.prompt) {
+                    info!("Switching to profile {name}");
+                    let profiles = self.set_profile(Some(name.to_string())).await?;
+                    return Ok(Some(OutboundMsg::Profiles(profiles)));
+                }
+            }
             let res = match msg {
+                InboundMsg::Authenticate(req) => {
+                    if !self.auth.is_api_key(&req.api_key) {
+                        return Err(ErrorCode::Unauthorized.err("Invalid API key"));
+                    }
+                    *self.access.write().unwrap() = Access::Owner;
+                    // The chats were held back on connection.
+                    Some(OutboundMsg::Chats(self.chats().await?))
+                }
+                InboundMsg::Hello(client) => match self.server_capabilities().negotiate(&client) {
+                    Ok(capabilities) => {
+                        info!("Negotiated capabilities {capabilities:?}");
+                        *self.capabilities.write().unwrap() = capabilities.clone();
+                        Some(OutboundMsg::Welcome(capabilities))
+                    }
+                    Err(mismatch) => {
+                        warn!("Rejecting client: {mismatch}");
+                        *self.rejected.write().unwrap() = Some(mismatch.clone());
+                        Some(OutboundMsg::VersionMismatch(mismatch))
+                    }
+                },
+                InboundMsg::Resume(req) => {
+                    Some(OutboundMsg::Resumed(self.resume(req.session_id, req.secret)?))
+                }
                 InboundMsg::GenerateAudioNewChat(req) => {
                     info!("Generating audio for new chat");
-                    let chat = Chat {
-                        chat_id: req.chat_id,
-                        name: req.prompt.clone(),
-                        created_at: SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_millis(),
-                    };
-                    chat.save(&self.storage).await?;
-                    self.ai_tx
-                        .send(BackendInboundMsg::Request(AudioGenerationRequest {
-                            id: IdPair(req.chat_id, req.id).to_string(),
-                            prompt: req.prompt.clone(),
-                            secs: req.secs,
-                        }))?;
-                    let chats = Chat::load_all(&self.storage).await?;
+                    validate_overrides(&req)?;
+                    check_clips(&self.storage, req.config.as_ref()).await?;
+                    if let Err(rejected) = self.rate_limiter.admit(self.client_ip, req.id) {
+                        return Ok(Some(rejected.into()));
+                    }
+                    let chats = self.submit_admitted(req.id, async {
+                        self.auth.invites.charge(&self.access())?;
+                        let chat = Chat {
+                            chat_id: req.chat_id,
+                            name: req.prompt.clone(),
+                            created_at: SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap()
+                                .as_millis(),
+                            tags: vec![],
+                            user_id: self.user_id(),
+                        };
+                        chat.save(&self.storage).await?;
+                        // Listed before submitting, so that the connection sees the job start.
+                        let chats = self.chats().await?;
+                        self.ai_tx
+                            .send(BackendInboundMsg::Request(AudioGenerationRequest {
+                                id: IdPair(req.chat_id, req.id).to_string(),
+                                prompt: req.prompt.clone(),
+                                secs: req.secs,
+                                config: self.job_config(&req.config),
+                                priority: Priority::Interactive,
+                                owner: self.owner(),
+                            }))?;
+                        Ok(chats)
+                    })
+                    .await?;
                     Some(OutboundMsg::Chats(chats))
                 }
                 InboundMsg::GenerateAudio(req) => {
                     info!("Generating audio for existing chat");
-                    self.ai_tx
-                        .send(BackendInboundMsg::Request(AudioGenerationRequest {
+                    validate_overrides(&req)?;
+                    check_clips(&self.storage, req.config.as_ref()).await?;
+                    self.chat(req.chat_id).await?;
+                    if let Err(rejected) = self.rate_limiter.admit(self.client_ip, req.id) {
+                        return Ok(Some(rejected.into()));
+                    }
+                    self.submit_admitted(req.id, async {
+                        self.auth.invites.charge(&self.access())?;
+                        self.ai_tx
+                            .send(BackendInboundMsg::Request(AudioGenerationRequest {
+                                id: IdPair(req.chat_id, req.id).to_string(),
+                                prompt: req.prompt.clone(),
+                                secs: req.secs,
+                                config: self.job_config(&req.config),
+                                priority: Priority::Interactive,
+                                owner: self.owner(),
+                            }))?;
+                        Ok(())
+                    })
+                    .await?;
+                    None
+                }
+                InboundMsg::GenerateBatch(req) => {
+                    info!("Generating a batch of {} prompts", req.prompts.len());
+                    validate_batch(&req.prompts, req.num_outputs, req.secs, &req.config)?;
+                    if let Some(chat_id) = req.chat_id {
+                        self.chat(chat_id).await?;
+                    }
+                    let name = req.prompts[0].clone();
+                    let batch = Batch::new(
+                        req.chat_id.unwrap_or_else(Uuid::new_v4),
+                        req.prompts,
+                        req.num_outputs.unwrap_or(1),
+                        req.secs,
+                        self.job_config(&req.config),
+                        Priority::Batch,
+                        self.owner(),
+                    );
+                    let admission = batch.admit(|id| {
+                        self.rate_limiter.admit(self.client_ip, id)?;
+                        let charged = self.auth.invites.charge(&self.access());
+                        if charged.is_err() {
+                            self.rate_limiter.release(self.client_ip, id);
+                        }
+                        charged
+                    });
+                    let (admitted, rejection) = match admission {
+                        Ok(v) => v,
+                        Err(err) => match err.downcast::<Rejected>() {
+                            Ok(rejected) => return Ok(Some(rejected.into())),
+                            Err(err) => return Err(err),
+                        },
+                    };
+                    let ids: Vec<_> = batch.jobs[..admitted].iter().map(|(id, _, _)| *id).collect();
+                    let queued = async {
+                        if req.chat_id.is_none() {
+                            let chat = Chat {
+                                chat_id: batch.chat_id,
+                                name,
+                                created_at: SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap()
+                                    .as_millis(),
+                                tags: vec![],
+                                user_id: self.user_id(),
+                            };
+                            chat.save(&self.storage).await?;
+                            // Listed before submitting, so that the connection sees the jobs start.
+                            let _ = self.events_tx.send(OutboundMsg::Chats(self.chats().await?));
+                        }
+                        batch.queue(&self.jobs, &self.ai_tx, admitted, rejection)
+                    }
+                    .await;
+                    if queued.is_err() {
+                        for id in ids {
+                            self.rate_limiter.release(self.client_ip, id);
+                        }
+                    }
+                    Some(OutboundMsg::Batch(queued?))
+                }
+                InboundMsg::GetBatch(req) => {
+                    let not_found =
+                        || ErrorCode::NotFound.err(format!("Batch {} not found", req.batch_id));
+                    let jobs = self.jobs.batch(req.batch_id)?;
+                    let status = BatchStatus::new(req.batch_id, jobs).ok_or_else(not_found)?;
+                    self.chat(status.chat_id).await.map_err(|_| not_found())?;
+                    Some(OutboundMsg::Batch(status))
+                }
+                InboundMsg::GeneratePreview(req) => {
+                    info!("Generating preview");
+                    validate_overrides(&req)?;
+                    check_clips(&self.storage, req.config.as_ref()).await?;
+                    self.chat(req.chat_id).await?;
+                    if let Err(rejected) = self.rate_limiter.admit(self.client_ip, req.id) {
+                        return Ok(Some(rejected.into()));
+                    }
+                    self.submit_admitted(req.id, async {
+                        self.auth.invites.charge(&self.access())?;
+                        let full = AudioGenerationRequest {
                             id: IdPair(req.chat_id, req.id).to_string(),
                             prompt: req.prompt.clone(),
                             secs: req.secs,
+                            config: self.job_config(&req.config),
+                            priority: Priority::Interactive,
+                            owner: self.owner(),
+                        };
+                        let preview = AudioGenerationRequest {
+                            secs: req.secs.min(PREVIEW_SECS),
+                            ..full.clone()
+                        };
+                        let mut previews = self.previews.write().unwrap();
+                        previews.retain(|_, (created, _)| created.elapsed() < PREVIEW_TTL);
+                        previews.insert(req.id, (Instant::now(), full));
+                        drop(previews);
+                        self.ai_tx.send(BackendInboundMsg::Request(preview))?;
+                        Ok(())
+                    })
+                    .await?;
+                    None
+                }
+                InboundMsg::CommitPreview(req) => {
+                    info!("Committing preview");
+                    self.chat(req.chat_id).await?;
+                    let full = self.preview(req.preview_id)?;
+                    if let Err(rejected) = self.rate_limiter.admit(self.client_ip, req.id) {
+                        return Ok(Some(rejected.into()));
+                    }
+                    self.submit_admitted(req.id, async {
+                        self.auth.invites.charge(&self.access())?;
+                        self.previews.write().unwrap().remove(&req.preview_id);
+                        self.ai_tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                            id: IdPair(req.chat_id, req.id).to_string(),
+                            ..full
                         }))?;
+                        Ok(())
+                    })
+                    .await?;
                     None
                 }
                 InboundMsg::AbortGeneration(req) => {
                     info!("Aborting audio generation");
+                    self.chat(req.chat_id).await?;
                     let id = IdPair(req.chat_id, req.id).to_string();
                     self.ai_tx.send(BackendInboundMsg::Abort(id))?;
                     None
                 }
                 InboundMsg::GetChat(req) => {
-                    let chat = Chat::load(&self.storage, req.chat_id).await?;
+                    let chat = self.chat(req.chat_id).await?;
                     let history = Chat::load_entries(&self.storage, req.chat_id).await?;
+                    // Other connections may be generating in this chat.
+                    self.send_snapshots(self.events.snapshots_of(req.chat_id));
                     Some(OutboundMsg::Chat((chat, history)))
                 }
                 InboundMsg::SetChatMetadata(req) => {
                     info!("Modifying the chat's metadata");
-                    let mut chat = Chat::load(&self.storage, req.chat_id).await?;
+                    let mut chat = self.chat(req.chat_id).await?;
                     chat.update_metadata(&self.storage, req.name).await?;
                     None
                 }
                 InboundMsg::DelChat(req) => {
                     info!("Deleting chat");
-                    let chat = Chat::load(&self.storage, req.chat_id).await?;
+                    let chat = self.chat(req.chat_id).await?;
                     chat.delete(&self.storage).await?;
-                    let chats = Chat::load_all(&self.storage).await?;
+                    let chats = self.chats().await?;
                     Some(OutboundMsg::Chats(chats))
                 }
+                InboundMsg::TapTokens(req) => {
+                    self.token_taps.write().unwrap().insert(req.id);
+                    None
+                }
+                InboundMsg::UntapTokens(req) => {
+                    self.token_taps.write().unwrap().remove(&req.id);
+                    None
+                }
+                InboundMsg::SubscribeJob(req) => {
+                    self.chat(req.chat_id).await?;
+                    // The job has to be in the chat that was checked.
+                    let snapshot = self.events.snapshot(req.id);
+                    let snapshot = snapshot.filter(|v| v.chat_id() == Some(req.chat_id));
+                    self.send_snapshots(snapshot.into_iter().collect());
+                    None
+                }
+                InboundMsg::GetProfiles => Some(OutboundMsg::Profiles(self.profiles().await?)),
+                InboundMsg::SaveProfile(profile) => {
+                    self.check_owner("save profiles")?;
+                    info!("Saving profile {}", profile.name);
+                    profile.save(&self.storage, &self.config_profiles).await?;
+                    Some(OutboundMsg::Profiles(self.profiles().await?))
+                }
+                InboundMsg::SetProfile(req) => {
+                    info!("Switching to profile {:?}", req.name);
+                    Some(OutboundMsg::Profiles(self.set_profile(req.name).await?))
+                }
+                InboundMsg::Bulk(req) => {
+                    self.check_owner("run bulk actions")?;
+                    info!("Running bulk {:?} (dry run: {})", req.action, req.dry_run);
+                    let dry_run = req.dry_run;
+                    let progress = run_bulk(&self.storage, self.user_id(), req, |progress| {
+                        let _ = self.events_tx.send(OutboundMsg::BulkProgress(progress));
+                    })
+                    .await?;
+                    if dry_run {
+                        Some(OutboundMsg::BulkProgress(progress))
+                    } else {
+                        Some(OutboundMsg::Chats(self.chats().await?))
+                    }
+                }
             };
             Ok::<Option<OutboundMsg>, anyhow::Error>(res)
         }
         .await
         .unwrap_or_else(|err| {
-            let error = err.to_string();
-            error!(error, "Error handling inbound message");
+            let error = ApiError::from(err);
+            error!(error = error.message, "Error handling inbound message");
             Some(OutboundMsg::Error(error))
         })
     }
 
     fn handle_subscription(&self) -> impl StreamExt<Item = OutboundMsg> + Send + 'static {
-        let mut rx = self.ai_broadcast_tx.subscribe();
+        let events = self.events.clone();
+        let mut latest = events.subscribe();
+        let (cursor, resumed) = (self.cursor.clone(), self.resumed.clone());
+        let mut events_rx = self.events_tx.subscribe();
+        let token_taps = self.token_taps.clone();
+        let access = self.access.clone();
+        let visible_chats = self.visible_chats.clone();
+        let capabilities = self.capabilities.clone();
         async_stream::stream! {
-            while let Ok(msg) = rx.recv().await {
-                yield OutboundMsg::Generation(msg)
+            loop {
+                let mut prev = cursor.load(Ordering::SeqCst);
+                for (seq, msg) in events.since(prev) {
+                    // Moved back by a resume, the messages are read again from there.
+                    if cursor.compare_exchange(prev, seq, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                        break;
+                    }
+                    prev = seq;
+                    if *access.read().unwrap() == Access::Unauthenticated {
+                        continue;
+                    }
+                    if !visible_chats.read().unwrap().contains(&msg.chat_id()) {
+                        continue;
+                    }
+                    if let GenerationMessage::Tokens(tokens) = &msg {
+                        let tapped = token_taps.read().unwrap().contains(&tokens.id);
+                        if !tapped {
+                            continue;
+                        }
+                    }
+                    if let GenerationMessage::Audio(_) = &msg {
+                        if !capabilities.read().unwrap().streaming_audio {
+                            continue;
+                        }
+                    }
+                    yield OutboundMsg::Generation(msg)
+                }
+                tokio::select! {
+                    changed = latest.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                    }
+                    _ = resumed.notified() => {}
+                    msg = events_rx.recv() => {
+                        match msg {
+                            Ok(msg) => yield msg,
+                            Err(RecvError::Lagged(_)) => {}
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                }
             }
         }
     }
 
-    async fn handle_error(&self, err: impl Display + Send) -> Option<OutboundMsg> {
-        Some(OutboundMsg::Error(err.to_string()))
+    /// Messages that do not deserialize usually come from a frontend built for another
+    /// protocol version, so the error says which one this connection speaks.
+    async fn handle_error(&self, mut err: InvalidMessage) -> Option<OutboundMsg> {
+        if err.reason == InvalidReason::Malformed {
+            let version = self.capabilities.read().unwrap().protocol_version;
+            err.message = format!("Unsupported message for protocol version {version}: {err}");
+        }
+        Some(OutboundMsg::Invalid(err))
+    }
+
+    fn binary_frames(&self) -> impl Fn() -> bool + Send + Sync + 'static {
+        let capabilities = self.capabilities.clone();
+        move || capabilities.read().unwrap().binary_frames
+    }
+
+    fn compression(&self) -> impl Fn() -> bool + Send + Sync + 'static {
+        let capabilities = self.capabilities.clone();
+        move || capabilities.read().unwrap().compression
+    }
+
+    fn raw_frame(msg: &OutboundMsg) -> Option<Vec<u8>> {
+        match msg {
+            OutboundMsg::Generation(GenerationMessage::Audio(chunk)) => Some(audio_frame(chunk)),
+            _ => None,
+        }
+    }
+
+    fn shutdown(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    async fn handle_close(&self) {
+        self.previews.write().unwrap().clear();
+        if let Some(session_id) = *self.session_id.read().unwrap() {
+            self.sessions.detach(session_id, &self.cursor);
+        }
+    }
+
+    fn keepalive(&self) -> Option<Keepalive> {
+        self.keepalive
+    }
+
+    fn close_frame(&self) -> Option<CloseFrame<'static>> {
+        let mismatch = self.rejected.read().unwrap().clone()?;
+        Some(CloseFrame {
+            code: close_code::PROTOCOL,
+            reason: mismatch.to_string().into(),
+        })
+    }
+}
+
+impl InboundMsg {
+    /// Checks the bounds that the types cannot express, before handling the message.
+    fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            InboundMsg::GenerateAudioNewChat(req)
+            | InboundMsg::GenerateAudio(req)
+            | InboundMsg::GeneratePreview(req) => validate_prompt(&req.prompt),
+            InboundMsg::GenerateBatch(req) => {
+                req.prompts.iter().try_for_each(|prompt| validate_prompt(prompt))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn validate_overrides(req: &GenerateAudioRequest) -> anyhow::Result<()> {
+    if let Some(config) = &req.config {
+        config.validate()?;
     }
+    Ok(())
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
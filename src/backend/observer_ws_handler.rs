@@ -0,0 +1,174 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::extract::WebSocketUpgrade;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use tokio::sync::broadcast::error::RecvError;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use sysinfo::System;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::{AudioGenerationBackend, STALLED_JOBS};
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::auth::Access;
+use crate::backend::limits::InvalidMessage;
+use crate::backend::music_gpt_ws_handler::Info;
+use crate::backend::ws_handler::{Keepalive, WsHandler};
+
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct ObservedJob {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct ObservedJobError {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct Telemetry {
+    /// Global CPU usage in percent.
+    pub cpu_usage: f32,
+    /// Used RAM in bytes.
+    pub used_memory: u64,
+    /// Total RAM in bytes.
+    pub total_memory: u64,
+    /// Jobs failed since startup because their inference stalled.
+    pub stalled_jobs: usize,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub enum ObserverMsg {
+    Info(Info),
+    /// Amount of jobs waiting or running, sent each time it changes.
+    QueueDepth(usize),
+    JobStarted(ObservedJob),
+    JobCompleted(ObservedJob),
+    JobFailed(ObservedJobError),
+    JobCancelled(ObservedJob),
+    Telemetry(Telemetry),
+    Error(String),
+}
+
+/// Read-only connection exposing aggregate server events for dashboards and
+/// monitoring widgets. It never carries prompts or audio, and it does not
+/// accept any inbound message.
+#[derive(Clone)]
+pub struct ObserverWsHandler {
+    pub backend: AudioGenerationBackend,
+    pub ai_broadcast_tx: tokio::sync::broadcast::Sender<GenerationMessage>,
+    pub info: Info,
+    pub shutdown: CancellationToken,
+    pub keepalive: Option<Keepalive>,
+}
+
+/// Owner only endpoint upgrading to an observer connection, as job ids and failures
+/// of every user go through it.
+pub async fn observe(handler: ObserverWsHandler, access: Access, ws: WebSocketUpgrade) -> Response {
+    if access != Access::Owner {
+        return (StatusCode::FORBIDDEN, "Only the owner can observe the server").into_response();
+    }
+    ws.on_upgrade(move |ws| handler.handle(ws))
+}
+
+#[async_trait]
+impl WsHandler for ObserverWsHandler {
+    type Inbound = serde_json::Value;
+    type Outbound = ObserverMsg;
+
+    async fn handle_init(&self) -> Vec<ObserverMsg> {
+        vec![
+            ObserverMsg::Info(self.info.clone()),
+            ObserverMsg::QueueDepth(self.backend.queue_depth()),
+        ]
+    }
+
+    async fn handle_inbound_msg(&self, _: serde_json::Value) -> Option<ObserverMsg> {
+        Some(ObserverMsg::Error(
+            "Observer connections are read-only".to_string(),
+        ))
+    }
+
+    fn handle_subscription(&self) -> impl StreamExt<Item = ObserverMsg> + Send + 'static {
+        let mut rx = self.ai_broadcast_tx.subscribe();
+        let backend = self.backend.clone();
+        async_stream::stream! {
+            let mut queue_interval = tokio::time::interval(QUEUE_POLL_INTERVAL);
+            let mut telemetry_interval = tokio::time::interval(TELEMETRY_INTERVAL);
+            let mut last_depth = backend.queue_depth();
+            let mut system = System::new();
+            loop {
+                tokio::select! {
+                    msg = rx.recv() => {
+                        let msg = match msg {
+                            Ok(msg) => msg,
+                            Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => break,
+                        };
+                        match msg {
+                            GenerationMessage::Start(msg) => {
+                                yield ObserverMsg::JobStarted(ObservedJob { id: msg.id, chat_id: msg.chat_id })
+                            }
+                            GenerationMessage::Result(msg) => {
+                                yield ObserverMsg::JobCompleted(ObservedJob { id: msg.id, chat_id: msg.chat_id })
+                            }
+                            GenerationMessage::Error(msg) => {
+                                yield ObserverMsg::JobFailed(ObservedJobError {
+                                    id: msg.id,
+                                    chat_id: msg.chat_id,
+                                    error: msg.error,
+                                })
+                            }
+                            GenerationMessage::Cancelled(msg) => {
+                                yield ObserverMsg::JobCancelled(ObservedJob { id: msg.id, chat_id: msg.chat_id })
+                            }
+                            GenerationMessage::Progress(_)
+                            | GenerationMessage::Tokens(_)
+                            | GenerationMessage::Audio(_) => {}
+                        }
+                    }
+                    _ = queue_interval.tick() => {
+                        let depth = backend.queue_depth();
+                        if depth != last_depth {
+                            last_depth = depth;
+                            yield ObserverMsg::QueueDepth(depth)
+                        }
+                    }
+                    _ = telemetry_interval.tick() => {
+                        system.refresh_cpu();
+                        system.refresh_memory();
+                        yield ObserverMsg::Telemetry(Telemetry {
+                            cpu_usage: system.global_cpu_info().cpu_usage(),
+                            used_memory: system.used_memory(),
+                            total_memory: system.total_memory(),
+                            stalled_jobs: STALLED_JOBS.load(Ordering::Relaxed),
+                        })
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_error(&self, err: InvalidMessage) -> Option<ObserverMsg> {
+        Some(ObserverMsg::Error(err.to_string()))
+    }
+
+    fn shutdown(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    fn keepalive(&self) -> Option<Keepalive> {
+        self.keepalive
+    }
+}
@@ -0,0 +1,571 @@
+use axum::response::Html;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{ObjectValidation, Schema};
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+use crate::backend::admin::{AdminJobsQuery, EvictReport, GcReport, PurgeReport, ADMIN_HEADER};
+use crate::backend::batches::{BatchStatus, RestBatchRequest};
+use crate::backend::errors::ApiError;
+use crate::backend::health::{Readiness, Version};
+use crate::backend::invites::{Invite, InviteLink, InviteRequest};
+use crate::backend::job_store::{JobStatus, Usage};
+use crate::backend::melodies::{Melody, MAX_MELODY_BYTES, MAX_MELODY_SECS};
+use crate::backend::playlist::PlaylistQuery;
+use crate::backend::rate_limit::{QueueFull, RateLimited};
+use crate::backend::rest_api::{JobPage, JobsQuery, RestGenerateRequest};
+use crate::backend::shadow::ShadowReport;
+use crate::backend::suggestions::{Suggestion, SuggestionsQuery};
+use crate::backend::users::{RegisterRequest, User};
+use crate::backend::webhooks::{Webhook, WebhookEvent, WebhookRequest};
+
+/// OpenAPI 3 document describing the HTTP API, so that clients can be generated
+/// for it. The WebSocket protocol is not covered, see `web/src/backend/bindings.ts`.
+pub fn openapi() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let job_status = json_response(&mut gen, "The job status", |gen| gen.subschema_for::<JobStatus>());
+    let generate_request = gen.subschema_for::<RestGenerateRequest>();
+    let batch_status = json_response(&mut gen, "The batch status", |gen| {
+        gen.subschema_for::<BatchStatus>()
+    });
+    let batch_request = gen.subschema_for::<RestBatchRequest>();
+    let melody = json_response(&mut gen, "The stored melody", |gen| gen.subschema_for::<Melody>());
+    // The body of the errors of the job endpoints, with a code that clients can branch on.
+    let api_error = gen.subschema_for::<ApiError>();
+    let rate_limited = json_response(&mut gen, "Too many jobs, see the Retry-After header", |gen| {
+        gen.subschema_for::<RateLimited>()
+    });
+    let queue_full = json_response(&mut gen, "Too many jobs waiting, see Retry-After", |gen| {
+        gen.subschema_for::<QueueFull>()
+    });
+    let suggestions = json_response(&mut gen, "Suggestions, best first", |gen| {
+        gen.subschema_for::<Vec<Suggestion>>()
+    });
+    let shadow_report = json_response(&mut gen, "The shadow report", |gen| {
+        gen.subschema_for::<ShadowReport>()
+    });
+    let invite_request = gen.subschema_for::<InviteRequest>();
+    let invite_link = json_response(&mut gen, "The invite and the link to share", |gen| {
+        gen.subschema_for::<InviteLink>()
+    });
+    let invites = json_response(&mut gen, "The invites that have not expired", |gen| {
+        gen.subschema_for::<Vec<Invite>>()
+    });
+    let webhook_request = gen.subschema_for::<WebhookRequest>();
+    let webhook = json_response(&mut gen, "The webhook", |gen| gen.subschema_for::<Webhook>());
+    let webhooks = json_response(&mut gen, "The webhooks", |gen| gen.subschema_for::<Vec<Webhook>>());
+    // Not used by any endpoint, it is the body of the requests sent to the webhooks.
+    gen.subschema_for::<WebhookEvent>();
+    let register_request = gen.subschema_for::<RegisterRequest>();
+    let user = json_response(&mut gen, "The user, its token is also set as a cookie", |gen| {
+        gen.subschema_for::<User>()
+    });
+    let readiness = json_response(&mut gen, "The server is taking jobs", |gen| {
+        gen.subschema_for::<Readiness>()
+    });
+    let not_ready = json_response(&mut gen, "The server is not taking jobs", |gen| {
+        gen.subschema_for::<Readiness>()
+    });
+    let version = json_response(&mut gen, "The versions", |gen| gen.subschema_for::<Version>());
+    let admin_jobs = json_response(&mut gen, "The jobs, oldest first", |gen| {
+        gen.subschema_for::<Vec<JobStatus>>()
+    });
+    let purge_report = json_response(&mut gen, "What was purged", |gen| {
+        gen.subschema_for::<PurgeReport>()
+    });
+    let usage = json_response(&mut gen, "The usage of each owner, the busiest first", |gen| {
+        gen.subschema_for::<Vec<Usage>>()
+    });
+    let evict_report = json_response(&mut gen, "The evicted models", |gen| {
+        gen.subschema_for::<EvictReport>()
+    });
+    let gc_report = json_response(&mut gen, "What was removed", |gen| gen.subschema_for::<GcReport>());
+    let admin_jobs_params = query_params::<AdminJobsQuery>(&mut gen);
+    // The admin token is needed on top of the API key, if the server has one.
+    let admin_security = json!([{ "adminToken": [] }, { "adminToken": [], "apiKey": [] }]);
+    let admin_responses = |ok: Value| {
+        json!({
+            "200": ok,
+            "403": text_response("The admin token is not valid"),
+            "404": text_response("The admin API is disabled"),
+        })
+    };
+    let job_page = json_response(&mut gen, "A page of the job history", |gen| {
+        gen.subschema_for::<JobPage>()
+    });
+    let jobs_params = query_params::<JobsQuery>(&mut gen);
+    let playlist_params = query_params::<PlaylistQuery>(&mut gen);
+    let suggestions_params = query_params::<SuggestionsQuery>(&mut gen);
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "MusicGPT",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/api/generate": {
+                "post": {
+                    "summary": "Submits a generation job",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": generate_request } },
+                    },
+                    "responses": {
+                        "202": job_status,
+                        "400": error_response("The request is not valid", &api_error),
+                        "404": error_response("The chat or the melody does not exist", &api_error),
+                        "429": rate_limited,
+                        "503": queue_full,
+                    },
+                },
+            },
+            "/api/melodies": {
+                "post": {
+                    "summary": "Uploads a melody for the jobs to follow",
+                    "description": format!(
+                        "A wav file of up to {MAX_MELODY_BYTES} bytes, only its first \
+                        {MAX_MELODY_SECS} seconds are kept. Set the returned id as the `melody` of \
+                        the generation config, only the melody models take it, or as its \
+                        `continuation` for extending the clip."
+                    ),
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "audio/wav": { "schema": { "type": "string", "format": "binary" } },
+                        },
+                    },
+                    "responses": {
+                        "200": melody,
+                        "400": error_response("The body is not a wav file", &api_error),
+                        "413": text_response("The body is too large"),
+                    },
+                },
+            },
+            "/api/generate/batch": {
+                "post": {
+                    "summary": "Submits a generation job for each prompt, tracked as a single batch",
+                    "description": "All the jobs go to the same chat. Jobs that go over the rate limits \
+                        are failed, the batch is only rejected if not even the first one fits.",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": batch_request } },
+                    },
+                    "responses": {
+                        "202": batch_status,
+                        "400": error_response("The request is not valid", &api_error),
+                        "429": rate_limited,
+                        "503": queue_full,
+                    },
+                },
+            },
+            "/api/batches/{id}": {
+                "get": {
+                    "summary": "Polls the status of a batch and its jobs",
+                    "parameters": [path_param("id", json!({ "type": "string", "format": "uuid" }))],
+                    "responses": {
+                        "200": batch_status,
+                        "404": error_response("The batch does not exist", &api_error),
+                    },
+                },
+            },
+            "/api/jobs": {
+                "get": {
+                    "summary": "Lists the jobs of the caller, newest first",
+                    "description": "Pass the `next_page` of a page as `page` to get the next one.",
+                    "parameters": jobs_params,
+                    "responses": {
+                        "200": job_page,
+                        "400": error_response("The filters or the page are not valid", &api_error),
+                    },
+                },
+            },
+            "/api/jobs/{id}": {
+                "get": {
+                    "summary": "Polls the status of a job",
+                    "parameters": [path_param("id", json!({ "type": "string", "format": "uuid" }))],
+                    "responses": {
+                        "200": job_status,
+                        "404": error_response("The job does not exist", &api_error),
+                    },
+                },
+                "delete": {
+                    "summary": "Cancels a queued or running job",
+                    "parameters": [path_param("id", json!({ "type": "string", "format": "uuid" }))],
+                    "responses": {
+                        "202": job_status,
+                        "404": error_response("The job does not exist", &api_error),
+                        "409": error_response("The job already finished", &api_error),
+                    },
+                },
+            },
+            "/api/jobs/{id}/events": {
+                "get": {
+                    "summary": "Streams the progress of a job as Server-Sent Events",
+                    "description": "A `status` event with the current job status, then `progress` events, \
+                        and finally a `completed`, `failed` or `cancelled` event with the final job status.",
+                    "parameters": [path_param("id", json!({ "type": "string", "format": "uuid" }))],
+                    "responses": {
+                        "200": {
+                            "description": "The event stream",
+                            "content": { "text/event-stream": { "schema": { "type": "string" } } },
+                        },
+                        "404": error_response("The job does not exist", &api_error),
+                    },
+                },
+            },
+            "/api/jobs/{id}/stream": {
+                "get": {
+                    "summary": "Streams the audio of a job while it is generated",
+                    "description": "A WAV of unknown length that `<audio>` tags and players can \
+                        play live. Completed jobs are redirected to their audio file.",
+                    "parameters": [path_param("id", json!({ "type": "string", "format": "uuid" }))],
+                    "responses": {
+                        "200": wav_response(),
+                        "307": { "description": "The job completed, its audio is at the Location header" },
+                        "404": error_response("The job does not exist", &api_error),
+                        "410": error_response("The job failed or was cancelled", &api_error),
+                    },
+                },
+            },
+            "/api/jobs/{id}/stems/{stem}": {
+                "get": {
+                    "summary": "Downloads a stem of the audio of a completed job",
+                    "description": "The stems are `bass`, `mids` and `highs`, separated the \
+                        first time any of them is asked and cached from then on.",
+                    "parameters": [
+                        path_param("id", json!({ "type": "string", "format": "uuid" })),
+                        path_param("stem", json!({ "type": "string", "example": "bass" })),
+                    ],
+                    "responses": {
+                        "200": wav_response(),
+                        "404": error_response("The job or the stem does not exist", &api_error),
+                    },
+                },
+            },
+            "/api/audio/{file}": {
+                "get": {
+                    "summary": "Downloads the audio of a completed job",
+                    "parameters": [
+                        path_param("file", json!({ "type": "string", "example": "<id>.mp3" })),
+                        {
+                            "name": "Range",
+                            "in": "header",
+                            "required": false,
+                            "description": "A single range of bytes, like `bytes=0-1023`",
+                            "schema": { "type": "string" },
+                        },
+                    ],
+                    "responses": {
+                        "200": audio_response(),
+                        "206": audio_response(),
+                        "404": error_response("The audio does not exist", &api_error),
+                        "416": { "description": "The range is past the end of the audio" },
+                    },
+                },
+            },
+            "/api/playlist": {
+                "get": {
+                    "summary": "Renders several generations as one continuous audio",
+                    "parameters": playlist_params,
+                    "responses": {
+                        "200": wav_response(),
+                        "400": text_response("The playlist could not be rendered"),
+                        "404": text_response("A generation does not exist or is of another user"),
+                    },
+                },
+            },
+            "/api/suggestions": {
+                "get": {
+                    "summary": "Autocompletes a partially typed prompt",
+                    "parameters": suggestions_params,
+                    "responses": { "200": suggestions },
+                },
+            },
+            "/feed.xml": {
+                "get": {
+                    "summary": "RSS feed, compatible with podcast apps, of the chats tagged as published",
+                    "responses": {
+                        "200": {
+                            "description": "The feed",
+                            "content": { "application/rss+xml": { "schema": { "type": "string" } } },
+                        },
+                    },
+                },
+            },
+            "/api/invites": {
+                "post": {
+                    "summary": "Mints an invite link granting temporary guest access, owner only",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": invite_request } },
+                    },
+                    "responses": {
+                        "200": invite_link,
+                        "400": text_response("The request is not valid"),
+                        "403": text_response("The client is not the owner"),
+                    },
+                },
+                "get": {
+                    "summary": "Lists the invites, owner only",
+                    "responses": {
+                        "200": invites,
+                        "403": text_response("The client is not the owner"),
+                    },
+                },
+            },
+            "/api/invites/{token}": {
+                "delete": {
+                    "summary": "Revokes an invite, owner only",
+                    "parameters": [path_param("token", json!({ "type": "string" }))],
+                    "responses": {
+                        "204": { "description": "The invite was revoked" },
+                        "403": text_response("The client is not the owner"),
+                        "404": text_response("The invite does not exist"),
+                    },
+                },
+            },
+            "/api/webhooks": {
+                "post": {
+                    "summary": "Registers a URL notified of the job lifecycle events, owner only",
+                    "description": "The URL receives a POST with a `WebhookEvent` when a job is queued, \
+                        started, completed, failed or cancelled. If a webhook secret is configured, the \
+                        `x-musicgpt-signature` header has the HMAC-SHA256 of the body as `sha256=<hex>`.",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": webhook_request } },
+                    },
+                    "responses": {
+                        "200": webhook,
+                        "400": text_response("The URL is not valid"),
+                        "403": text_response("The client is not the owner"),
+                    },
+                },
+                "get": {
+                    "summary": "Lists the webhooks, owner only",
+                    "responses": {
+                        "200": webhooks,
+                        "403": text_response("The client is not the owner"),
+                    },
+                },
+            },
+            "/api/webhooks/{id}": {
+                "delete": {
+                    "summary": "Removes a webhook registered through the API, owner only",
+                    "parameters": [path_param("id", json!({ "type": "string", "format": "uuid" }))],
+                    "responses": {
+                        "204": { "description": "The webhook was removed" },
+                        "403": text_response("The client is not the owner"),
+                        "404": text_response("The webhook does not exist or is in the config file"),
+                    },
+                },
+            },
+            "/api/users": {
+                "post": {
+                    "summary": "Registers a user, who only sees their own chats, jobs and audio",
+                    "description": "Requests are made as the user by sending its token in the \
+                        `x-musicgpt-user` header or in the cookie set when registering.",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": register_request } },
+                    },
+                    "responses": {
+                        "200": user,
+                        "400": text_response("The name is not valid"),
+                    },
+                },
+            },
+            "/api/admin/jobs": {
+                "get": {
+                    "summary": "Lists the jobs, admin only",
+                    "security": admin_security,
+                    "parameters": admin_jobs_params,
+                    "responses": admin_responses(admin_jobs),
+                },
+                "delete": {
+                    "summary": "Cancels the queued jobs and deletes the finished ones, admin only",
+                    "description": "Only the jobs in `state` if set. Running jobs are never purged.",
+                    "security": admin_security,
+                    "parameters": admin_jobs_params,
+                    "responses": admin_responses(purge_report),
+                },
+            },
+            "/api/admin/usage": {
+                "get": {
+                    "summary": "Jobs and generated seconds of each owner, admin only",
+                    "security": admin_security,
+                    "responses": admin_responses(usage),
+                },
+            },
+            "/api/admin/models/evict": {
+                "post": {
+                    "summary": "Removes the downloaded models that are not in use, admin only",
+                    "security": admin_security,
+                    "responses": admin_responses(evict_report),
+                },
+            },
+            "/api/admin/gc": {
+                "post": {
+                    "summary": "Removes stale stems and leftovers of interrupted downloads, admin only",
+                    "security": admin_security,
+                    "responses": admin_responses(gc_report),
+                },
+            },
+            "/healthz": {
+                "get": {
+                    "summary": "Liveness probe, answered as long as the process is up",
+                    "security": [],
+                    "responses": { "200": text_response("The process is up") },
+                },
+            },
+            "/readyz": {
+                "get": {
+                    "summary": "Readiness probe, whether the models are loaded and jobs are being taken",
+                    "security": [],
+                    "responses": { "200": readiness, "503": not_ready },
+                },
+            },
+            "/version": {
+                "get": {
+                    "summary": "Version of the server and of the loaded model",
+                    "security": [],
+                    "responses": { "200": version },
+                },
+            },
+            "/api/shadow/report": {
+                "get": {
+                    "summary": "Compares the primary and the shadow backends, owner only",
+                    "responses": {
+                        "200": shadow_report,
+                        "403": text_response("The client is not the owner"),
+                    },
+                },
+            },
+        },
+        // Only remote clients of servers configured with an API key need it.
+        "security": [{}, { "apiKey": [] }],
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {
+                "apiKey": { "type": "http", "scheme": "bearer" },
+                "adminToken": { "type": "apiKey", "in": "header", "name": ADMIN_HEADER },
+            },
+        },
+    })
+}
+
+/// Swagger UI pointed at [openapi].
+pub async fn swagger_ui() -> Html<&'static str> {
+    Html(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>MusicGPT API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>"#,
+    )
+}
+
+fn json_response(
+    gen: &mut SchemaGenerator,
+    description: &str,
+    schema: impl FnOnce(&mut SchemaGenerator) -> Schema,
+) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema(gen) } },
+    })
+}
+
+fn text_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "text/plain": { "schema": { "type": "string" } } },
+    })
+}
+
+fn error_response(description: &str, schema: &Schema) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+fn wav_response() -> Value {
+    json!({
+        "description": "The audio",
+        "content": { "audio/wav": { "schema": { "type": "string", "format": "binary" } } },
+    })
+}
+
+/// The audio in the format of the extension of the file, `.wav`, `.mp3` or `.flac`.
+fn audio_response() -> Value {
+    let binary = json!({ "schema": { "type": "string", "format": "binary" } });
+    json!({
+        "description": "The audio",
+        "content": { "audio/wav": binary, "audio/mpeg": binary, "audio/flac": binary },
+    })
+}
+
+fn path_param(name: &str, schema: Value) -> Value {
+    json!({ "name": name, "in": "path", "required": true, "schema": schema })
+}
+
+/// One query parameter per field of `T`.
+fn query_params<T: JsonSchema>(gen: &mut SchemaGenerator) -> Vec<Value> {
+    let schema = T::json_schema(gen).into_object();
+    let Some(object) = schema.object else {
+        return vec![];
+    };
+    let ObjectValidation { properties, required, .. } = *object;
+    properties
+        .into_iter()
+        .map(|(name, schema)| {
+            json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&name),
+                "schema": schema,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_http_api() {
+        let doc = openapi();
+        assert_eq!(doc["openapi"], "3.0.3");
+        let generate = &doc["paths"]["/api/generate"]["post"];
+        assert_eq!(
+            generate["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/RestGenerateRequest"
+        );
+        assert!(doc["components"]["schemas"]["JobStatus"].is_object());
+        assert!(doc["components"]["schemas"]["GenerationConfig"].is_object());
+        assert!(doc["components"]["schemas"]["WebhookEvent"].is_object());
+        assert_eq!(
+            generate["responses"]["400"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/ApiError"
+        );
+
+        let upload = &doc["paths"]["/api/melodies"]["post"];
+        assert!(upload["requestBody"]["content"]["audio/wav"].is_object());
+        assert!(doc["components"]["schemas"]["Melody"].is_object());
+
+        let params = doc["paths"]["/api/playlist"]["get"]["parameters"].as_array().unwrap();
+        let ids = params.iter().find(|p| p["name"] == "ids").unwrap();
+        assert_eq!(ids["required"], true);
+        let codec = params.iter().find(|p| p["name"] == "codec").unwrap();
+        assert_eq!(codec["required"], false);
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::music_gen_config::PipelineConfig;
+
+/// Time between the checks of whether the jobs waiting for a slot were cancelled.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// Bounds how many jobs can be inside one stage of the generation at the same time.
+/// Jobs over the limit block until a slot is released, which makes the jobs waiting
+/// for a stage the queue in front of it.
+pub struct StageLimiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A slot in a stage, released when dropped.
+pub struct StagePermit<'a> {
+    limiter: &'a StageLimiter,
+}
+
+impl StageLimiter {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            available: Mutex::new(limit.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// A slot in the stage, or none if `cancel` is cancelled before getting it, so that
+    /// cancelled jobs do not wait for a stage they will not go through.
+    pub fn acquire(&self, cancel: &CancellationToken) -> Option<StagePermit<'_>> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 && !cancel.is_cancelled() {
+            available = self.released.wait_timeout(available, CANCEL_POLL).unwrap().0;
+        }
+        if cancel.is_cancelled() {
+            return None;
+        }
+        *available -= 1;
+        Some(StagePermit { limiter: self })
+    }
+}
+
+impl Drop for StagePermit<'_> {
+    fn drop(&mut self) {
+        *self.limiter.available.lock().unwrap() += 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+/// Limits for the model stages of a job processor. Post-processing happens
+/// outside the processor, see [crate::backend::audio_generation_fanout].
+pub struct Pipeline {
+    pub text_encoder: StageLimiter,
+    pub decoder: StageLimiter,
+    pub audio_encoder: StageLimiter,
+}
+
+impl Pipeline {
+    pub fn new(config: &PipelineConfig) -> Self {
+        Self {
+            text_encoder: StageLimiter::new(config.text_encoder),
+            decoder: StageLimiter::new(config.decoder),
+            audio_encoder: StageLimiter::new(config.audio_encoder),
+        }
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new(&PipelineConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+
+    use super::*;
+
+    #[test]
+    fn bounds_the_jobs_in_a_stage() {
+        let limiter = Arc::new(StageLimiter::new(2));
+        let inside = Arc::new(AtomicUsize::new(0));
+        let max_inside = Arc::new(AtomicUsize::new(0));
+        // Only the jobs in the stage get to it, so they are in it two at a time.
+        let both_inside = Arc::new(Barrier::new(2));
+
+        let handles = (0..6)
+            .map(|_| {
+                let (limiter, inside, max_inside) = (limiter.clone(), inside.clone(), max_inside.clone());
+                let both_inside = both_inside.clone();
+                std::thread::spawn(move || {
+                    let _permit = limiter.acquire(&CancellationToken::new()).unwrap();
+                    let now = inside.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_inside.fetch_max(now, Ordering::SeqCst);
+                    both_inside.wait();
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(max_inside.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn stops_waiting_when_cancelled() {
+        let limiter = Arc::new(StageLimiter::new(1));
+        let cancel = CancellationToken::new();
+        let _permit = limiter.acquire(&cancel).unwrap();
+        let waiting = {
+            let (limiter, cancel) = (limiter.clone(), cancel.clone());
+            std::thread::spawn(move || limiter.acquire(&cancel).is_none())
+        };
+        // Whether it is already waiting or not, it does not get the slot.
+        cancel.cancel();
+        assert!(waiting.join().unwrap());
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+
+use anyhow::anyhow;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::audio_manager::AudioManager;
+use crate::backend::job_store::JobStore;
+use crate::backend::music_gpt_chat::Chat;
+use crate::pcm::BitDepth;
+use crate::storage::Storage;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaylistCodec {
+    #[default]
+    WavF32,
+    WavS16,
+    WavS24,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct PlaylistQuery {
+    /// Comma separated list of generation ids, in playback order.
+    pub ids: String,
+    #[serde(default)]
+    pub crossfade_ms: usize,
+    #[serde(default)]
+    pub codec: PlaylistCodec,
+    /// Removes the dead air at the start and end of each generation, so that the
+    /// playback has no gaps between them. On unless set to `false`.
+    #[serde(default = "default_true")]
+    pub trim_silence: bool,
+    /// Scales each generation so that its loudness matches the first one. On unless
+    /// set to `false`.
+    #[serde(default = "default_true")]
+    pub match_levels: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Samples below this absolute value (-60 dBFS) are considered silence.
+const SILENCE_THRESHOLD: f32 = 0.001;
+
+/// Serves all the generations in the playlist as one continuous audio file, so that
+/// it can be consumed by any client capable of playing a single audio URL. Only the
+/// generations in the chats of `user_id` can be in it.
+pub async fn playlist<S: Storage>(
+    storage: S,
+    jobs: JobStore,
+    user_id: Option<Uuid>,
+    query: PlaylistQuery,
+) -> Response {
+    if let Err(err) = check_owned(&storage, &jobs, user_id, &query.ids).await {
+        return (StatusCode::NOT_FOUND, err.to_string()).into_response();
+    }
+    match render_playlist(&storage, &query).await {
+        Ok(bytes) => ([(header::CONTENT_TYPE, "audio/wav")], bytes).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+/// Fails on the first of `ids` that is not a generation of `user_id`, with the same error
+/// as a generation that does not exist.
+async fn check_owned<S: Storage>(
+    storage: &S,
+    jobs: &JobStore,
+    user_id: Option<Uuid>,
+    ids: &str,
+) -> anyhow::Result<()> {
+    for id in ids.split(',').filter(|v| !v.is_empty()) {
+        let id = Uuid::parse_str(id)?;
+        let chat_id = jobs.get(id)?.map(|v| v.chat_id);
+        let owned = match chat_id {
+            Some(chat_id) => Chat::user_of(storage, chat_id).await? == user_id,
+            None => false,
+        };
+        if !owned {
+            return Err(anyhow!("Generation {id} not found"));
+        }
+    }
+    Ok(())
+}
+
+async fn render_playlist<S: Storage>(storage: &S, query: &PlaylistQuery) -> anyhow::Result<Vec<u8>> {
+    let audio_manager = AudioManager::default();
+    let crossfade = query.crossfade_ms * audio_manager.sampling_rate() as usize / 1000;
+
+    let mut result = VecDeque::new();
+    let mut reference_rms = None;
+    for id in query.ids.split(',').filter(|v| !v.is_empty()) {
+        let id = Uuid::parse_str(id)?;
+        let Some(bytes) = storage.read(&format!("audios/{id}.wav")).await? else {
+            return Err(anyhow!("Generation {id} not found"));
+        };
+        let mut samples = hound::WavReader::new(Cursor::new(bytes))?
+            .into_samples::<f32>()
+            .collect::<Result<VecDeque<_>, _>>()?;
+        if query.trim_silence {
+            trim_silence(&mut samples, SILENCE_THRESHOLD);
+        }
+        if query.match_levels {
+            let target = *reference_rms.get_or_insert_with(|| rms(&samples));
+            match_level(&mut samples, target);
+        }
+        append_crossfaded(&mut result, samples, crossfade);
+    }
+
+    Ok(match query.codec {
+        PlaylistCodec::WavF32 => audio_manager.to_wav(result)?,
+        PlaylistCodec::WavS16 => audio_manager.to_wav_pcm(result, BitDepth::I16, true)?.0,
+        PlaylistCodec::WavS24 => audio_manager.to_wav_pcm(result, BitDepth::I24, true)?.0,
+    })
+}
+
+/// Appends `next` to `acc`, linearly crossfading the last `len` samples of `acc`
+/// with the first `len` samples of `next`. With `len` 0 the tracks are just joined
+/// back to back without any gap.
+fn append_crossfaded(acc: &mut VecDeque<f32>, mut next: VecDeque<f32>, len: usize) {
+    let len = len.min(acc.len()).min(next.len());
+    let start = acc.len() - len;
+    for i in 0..len {
+        let fade_in = (i + 1) as f32 / (len + 1) as f32;
+        let head = next.pop_front().unwrap_or_default();
+        acc[start + i] = acc[start + i] * (1.0 - fade_in) + head * fade_in;
+    }
+    acc.extend(next);
+}
+
+/// Removes the leading and trailing samples whose absolute value is below `threshold`.
+fn trim_silence(samples: &mut VecDeque<f32>, threshold: f32) {
+    while samples.front().is_some_and(|v| v.abs() < threshold) {
+        samples.pop_front();
+    }
+    while samples.back().is_some_and(|v| v.abs() < threshold) {
+        samples.pop_back();
+    }
+}
+
+fn rms(samples: &VecDeque<f32>) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|v| v * v).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+/// Scales `samples` so that their RMS matches `target_rms`, without letting the
+/// peak go over full scale.
+fn match_level(samples: &mut VecDeque<f32>, target_rms: f32) {
+    let current = rms(samples);
+    if current < SILENCE_THRESHOLD {
+        return;
+    }
+    let peak = samples.iter().fold(0.0f32, |acc, v| acc.max(v.abs()));
+    let gain = (target_rms / current).min(1.0 / peak);
+    for v in samples.iter_mut() {
+        *v *= gain;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[test]
+    fn trims_and_levels_by_default() -> anyhow::Result<()> {
+        let query: PlaylistQuery = serde_json::from_value(serde_json::json!({ "ids": "" }))?;
+        assert!(query.trim_silence);
+        assert!(query.match_levels);
+        Ok(())
+    }
+
+    #[test]
+    fn appends_without_gaps() {
+        let mut acc = VecDeque::from([1.0, 1.0]);
+        append_crossfaded(&mut acc, VecDeque::from([2.0, 2.0]), 0);
+        assert_eq!(acc, VecDeque::from([1.0, 1.0, 2.0, 2.0]));
+    }
+
+    #[test]
+    fn crossfades_the_seam() {
+        let mut acc = VecDeque::from([1.0, 1.0, 1.0]);
+        append_crossfaded(&mut acc, VecDeque::from([0.0, 0.0, 0.0]), 3);
+        assert_eq!(acc, VecDeque::from([0.75, 0.5, 0.25]));
+    }
+
+    #[test]
+    fn trims_silence_at_both_ends() {
+        let mut samples = VecDeque::from([0.0, 0.0001, 0.5, 0.0, -0.5, 0.0]);
+        trim_silence(&mut samples, SILENCE_THRESHOLD);
+        assert_eq!(samples, VecDeque::from([0.5, 0.0, -0.5]));
+
+        let mut samples = VecDeque::from([0.0; 4]);
+        trim_silence(&mut samples, SILENCE_THRESHOLD);
+        assert!(samples.is_empty());
+    }
+
+    #[test]
+    fn matches_levels_without_clipping() {
+        let mut samples = VecDeque::from([0.1, -0.1]);
+        match_level(&mut samples, 0.2);
+        assert_eq!(samples, VecDeque::from([0.2, -0.2]));
+
+        let mut samples = VecDeque::from([0.5, 0.0, 0.0, 0.0]);
+        match_level(&mut samples, 0.8);
+        assert_eq!(samples, VecDeque::from([1.0, 0.0, 0.0, 0.0]));
+    }
+
+    #[tokio::test]
+    async fn renders_a_playlist() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let audio_manager = AudioManager::default();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let wav = audio_manager.to_wav(VecDeque::from([0.5; 10]))?;
+        storage.write(&format!("audios/{a}.wav"), &wav).await?;
+        storage.write(&format!("audios/{b}.wav"), &wav).await?;
+
+        let query = PlaylistQuery {
+            ids: format!("{a},{b}"),
+            crossfade_ms: 0,
+            codec: PlaylistCodec::WavF32,
+            trim_silence: false,
+            match_levels: false,
+        };
+        let bytes = render_playlist(&storage, &query).await?;
+        let reader = hound::WavReader::new(Cursor::new(bytes))?;
+        assert_eq!(reader.len(), 20);
+
+        let query = PlaylistQuery {
+            ids: Uuid::new_v4().to_string(),
+            ..query
+        };
+        assert!(render_playlist(&storage, &query).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_takes_the_generations_of_the_user() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let jobs = JobStore::in_memory()?;
+        let (alice, bob) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+        let (chat_id, id) = (Uuid::new_v4(), Uuid::new_v4());
+        let chat = Chat {
+            chat_id,
+            name: "Chat".to_string(),
+            created_at: 0,
+            tags: vec![],
+            user_id: alice,
+        };
+        chat.save(&storage).await?;
+        jobs.insert(id, chat_id, "A song", 10)?;
+
+        assert!(check_owned(&storage, &jobs, alice, &id.to_string()).await.is_ok());
+        assert!(check_owned(&storage, &jobs, bob, &id.to_string()).await.is_err());
+        assert!(check_owned(&storage, &jobs, None, &id.to_string()).await.is_err());
+        let unknown = format!("{id},{}", Uuid::new_v4());
+        assert!(check_owned(&storage, &jobs, alice, &unknown).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trims_and_levels_the_seams() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let audio_manager = AudioManager::default();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let wav = audio_manager.to_wav(VecDeque::from([0.0, 0.5, 0.5, 0.0]))?;
+        storage.write(&format!("audios/{a}.wav"), &wav).await?;
+        let wav = audio_manager.to_wav(VecDeque::from([0.25, 0.25, 0.0]))?;
+        storage.write(&format!("audios/{b}.wav"), &wav).await?;
+
+        let query = PlaylistQuery {
+            ids: format!("{a},{b}"),
+            crossfade_ms: 0,
+            codec: PlaylistCodec::WavF32,
+            trim_silence: true,
+            match_levels: true,
+        };
+        let bytes = render_playlist(&storage, &query).await?;
+        let samples = hound::WavReader::new(Cursor::new(bytes))?
+            .into_samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(samples, vec![0.5; 4]);
+        Ok(())
+    }
+}
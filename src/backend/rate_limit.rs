@@ -0,0 +1,327 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::AudioGenerationBackend;
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::errors::ErrorCode;
+use crate::backend::job_store::JobStore;
+use crate::music_gen_config::RateLimitConfig;
+
+const WINDOW: Duration = Duration::from_secs(60);
+/// There's no telling when a running job will finish, so clients over the concurrent
+/// jobs limit are asked to come back after this long.
+const CONCURRENT_RETRY_SECS: u64 = 5;
+/// Clients are asked to come back after this long when the queue is full and there
+/// is no telling how fast it goes down.
+const QUEUE_FULL_RETRY_SECS: u64 = 30;
+
+/// A job was rejected for going over the rate limits.
+#[derive(Clone, Debug, PartialEq, Error, Type, Serialize, Deserialize, JsonSchema)]
+#[error("{reason}, retry after {retry_after_secs}s")]
+pub struct RateLimited {
+    pub reason: String,
+    /// Seconds to wait before submitting again.
+    pub retry_after_secs: u64,
+}
+
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        let retry_after = self.retry_after_secs.to_string();
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after)],
+            Json(self),
+        )
+            .into_response()
+    }
+}
+
+/// A job was rejected because the server has too many jobs waiting already.
+#[derive(Clone, Debug, PartialEq, Error, Type, Serialize, Deserialize, JsonSchema)]
+#[error("The queue is full with {position} jobs waiting, retry after {retry_after_secs}s")]
+pub struct QueueFull {
+    /// Jobs waiting to start, the job would have been behind all of them.
+    pub position: usize,
+    /// Rough seconds until the jobs waiting now have started, if it can be told.
+    pub estimated_wait_secs: Option<u64>,
+    /// Seconds to wait before submitting again.
+    pub retry_after_secs: u64,
+}
+
+impl IntoResponse for QueueFull {
+    fn into_response(self) -> Response {
+        let retry_after = self.retry_after_secs.to_string();
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after)],
+            Json(self),
+        )
+            .into_response()
+    }
+}
+
+/// Why a job was not admitted.
+#[derive(Clone, Debug, PartialEq, Error)]
+pub enum Rejected {
+    #[error(transparent)]
+    RateLimited(#[from] RateLimited),
+    #[error(transparent)]
+    QueueFull(#[from] QueueFull),
+}
+
+impl Rejected {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Rejected::RateLimited(_) => ErrorCode::RateLimited,
+            Rejected::QueueFull(_) => ErrorCode::QueueFull,
+        }
+    }
+}
+
+impl IntoResponse for Rejected {
+    fn into_response(self) -> Response {
+        match self {
+            Rejected::RateLimited(limited) => limited.into_response(),
+            Rejected::QueueFull(full) => full.into_response(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ClientJobs {
+    submitted: VecDeque<Instant>,
+    unfinished: HashSet<Uuid>,
+}
+
+/// Enforces [RateLimitConfig] for every client, and the queue limit for all of them.
+/// Jobs count as unfinished from the moment they are admitted until the backend
+/// reports them as done, or until the job store has them finished if the reports
+/// were missed.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    clients: Arc<Mutex<HashMap<IpAddr, ClientJobs>>>,
+    /// Backend whose waiting jobs are limited, and the limit.
+    queue: Option<(AudioGenerationBackend, usize)>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        config: RateLimitConfig,
+        ai_broadcast_tx: &tokio::sync::broadcast::Sender<GenerationMessage>,
+        jobs: JobStore,
+    ) -> Self {
+        let limiter = Self {
+            config,
+            clients: Default::default(),
+            queue: None,
+        };
+        let clients = limiter.clients.clone();
+        let mut rx = ai_broadcast_tx.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let id = match rx.recv().await {
+                    Ok(GenerationMessage::Result(m)) => Some(m.id),
+                    Ok(GenerationMessage::Error(m)) => Some(m.id),
+                    Ok(GenerationMessage::Cancelled(m)) => Some(m.id),
+                    Ok(_) => continue,
+                    // The jobs that finished among the skipped messages are only in the store.
+                    Err(RecvError::Lagged(_)) => None,
+                    Err(RecvError::Closed) => break,
+                };
+                let mut clients = clients.lock().unwrap();
+                for client in clients.values_mut() {
+                    match id {
+                        Some(id) => {
+                            client.unfinished.remove(&id);
+                        }
+                        None => client.unfinished.retain(|id| !is_finished(&jobs, *id)),
+                    }
+                }
+                clients.retain(|_, jobs| !jobs.unfinished.is_empty() || !jobs.submitted.is_empty());
+            }
+        });
+        limiter
+    }
+
+    /// Rejects the jobs submitted while `max_waiting` jobs are waiting in `backend`.
+    pub fn with_queue_limit(mut self, backend: AudioGenerationBackend, max_waiting: usize) -> Self {
+        self.queue = Some((backend, max_waiting));
+        self
+    }
+
+    /// Admits the job `id` of `client`, or tells it how long to wait.
+    pub fn admit(&self, client: IpAddr, id: Uuid) -> Result<(), Rejected> {
+        if let Some((backend, max)) = &self.queue {
+            let position = backend.waiting_jobs();
+            if position >= *max {
+                let estimated_wait_secs = backend.estimated_wait().map(|v| v.as_secs());
+                return Err(Rejected::QueueFull(QueueFull {
+                    position,
+                    estimated_wait_secs,
+                    retry_after_secs: estimated_wait_secs.unwrap_or(QUEUE_FULL_RETRY_SECS).max(1),
+                }));
+            }
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let jobs = clients.entry(client).or_default();
+        while jobs.submitted.front().is_some_and(|t| now - *t >= WINDOW) {
+            jobs.submitted.pop_front();
+        }
+        if let Some(max) = self.config.concurrent_jobs {
+            if jobs.unfinished.len() >= max {
+                return Err(Rejected::RateLimited(RateLimited {
+                    reason: format!("At most {max} jobs can be queued or running at the same time"),
+                    retry_after_secs: CONCURRENT_RETRY_SECS,
+                }));
+            }
+        }
+        if let Some(max) = self.config.jobs_per_minute {
+            if jobs.submitted.len() >= max {
+                let oldest = jobs.submitted[jobs.submitted.len() - max];
+                let wait = WINDOW.saturating_sub(now - oldest);
+                return Err(Rejected::RateLimited(RateLimited {
+                    reason: format!("At most {max} jobs can be submitted per minute"),
+                    retry_after_secs: wait.as_secs_f64().ceil() as u64,
+                }));
+            }
+        }
+        jobs.submitted.push_back(now);
+        jobs.unfinished.insert(id);
+        Ok(())
+    }
+
+    /// Gives back the slot of the job `id` of `client`, admitted but never submitted.
+    pub fn release(&self, client: IpAddr, id: Uuid) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(jobs) = clients.get_mut(&client) {
+            if jobs.unfinished.remove(&id) {
+                jobs.submitted.pop_back();
+            }
+        }
+    }
+}
+
+fn is_finished(jobs: &JobStore, id: Uuid) -> bool {
+    match jobs.get(id) {
+        Ok(status) => status.is_some_and(|v| v.state.is_finished()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::audio_generation_fanout::{AudioGenerationCancelled, AudioGenerationResult};
+    use crate::backend::job_store::JobState;
+
+    use super::*;
+
+    // The time is paused, the sleeps only let the limiter read the broadcast messages.
+    #[tokio::test(start_paused = true)]
+    async fn limits_jobs_per_client() -> anyhow::Result<()> {
+        let (tx, _) = tokio::sync::broadcast::channel(16);
+        let limiter = RateLimiter::new(
+            RateLimitConfig {
+                jobs_per_minute: Some(3),
+                concurrent_jobs: Some(2),
+            },
+            &tx,
+            JobStore::in_memory()?,
+        );
+        let (client, other) = ("192.168.1.20".parse()?, "192.168.1.21".parse()?);
+        let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+
+        limiter.admit(client, ids[0])?;
+        limiter.admit(client, ids[1])?;
+        let Rejected::RateLimited(limited) = limiter.admit(client, ids[2]).unwrap_err() else {
+            panic!("expected the client to be rate limited")
+        };
+        assert_eq!(limited.retry_after_secs, CONCURRENT_RETRY_SECS);
+        limiter.admit(other, ids[2])?;
+
+        tx.send(GenerationMessage::Result(AudioGenerationResult {
+            id: ids[0],
+            chat_id: Uuid::new_v4(),
+            relpath: "".to_string(),
+            cached: false,
+            seed: None,
+            hints: None,
+        }))?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        limiter.admit(client, ids[2])?;
+
+        tx.send(GenerationMessage::Result(AudioGenerationResult {
+            id: ids[1],
+            chat_id: Uuid::new_v4(),
+            relpath: "".to_string(),
+            cached: false,
+            seed: None,
+            hints: None,
+        }))?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let Rejected::RateLimited(limited) = limiter.admit(client, ids[3]).unwrap_err() else {
+            panic!("expected the client to be rate limited")
+        };
+        assert!(limited.reason.contains("per minute"));
+        assert!((59..=60).contains(&limited.retry_after_secs));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_back_released_slots() -> anyhow::Result<()> {
+        let (tx, _) = tokio::sync::broadcast::channel(16);
+        let config = RateLimitConfig {
+            jobs_per_minute: Some(1),
+            concurrent_jobs: Some(1),
+        };
+        let limiter = RateLimiter::new(config, &tx, JobStore::in_memory()?);
+        let client = "192.168.1.20".parse()?;
+        let (id, other) = (Uuid::new_v4(), Uuid::new_v4());
+
+        limiter.admit(client, id)?;
+        assert!(limiter.admit(client, other).is_err());
+        limiter.release(client, id);
+        limiter.admit(client, other)?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn catches_up_with_the_store_when_lagging() -> anyhow::Result<()> {
+        let (tx, _) = tokio::sync::broadcast::channel(1);
+        let jobs = JobStore::in_memory()?;
+        let config = RateLimitConfig {
+            jobs_per_minute: None,
+            concurrent_jobs: Some(1),
+        };
+        let limiter = RateLimiter::new(config, &tx, jobs.clone());
+        let client = "192.168.1.20".parse()?;
+        let id = Uuid::new_v4();
+
+        limiter.admit(client, id)?;
+        jobs.insert(id, Uuid::new_v4(), "a song", 1)?;
+        jobs.transition(id, JobState::Cancelled, |_| {})?;
+        // The cancellation is pushed out of the channel before the limiter reads it.
+        for id in [id, Uuid::new_v4(), Uuid::new_v4()] {
+            tx.send(GenerationMessage::Cancelled(AudioGenerationCancelled {
+                id,
+                chat_id: Uuid::new_v4(),
+            }))?;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        limiter.admit(client, Uuid::new_v4())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,823 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::audio_manager::AudioManager;
+use crate::backend::audio_generation_backend::{AudioGenerationRequest, BackendInboundMsg, Priority};
+use crate::backend::audio_generation_fanout::{EventLog, GenerationMessage};
+use crate::backend::auth::Access;
+use crate::backend::batches::{validate_batch, Batch, BatchStatus, RestBatchRequest};
+use crate::backend::errors::{ApiError, ErrorCode};
+use crate::backend::invites::Invites;
+use crate::backend::job_store::{JobFilter, JobState, JobStatus, JobStore, PageCursor};
+use crate::backend::limits::validate_prompt;
+use crate::backend::melodies::check_clips;
+use crate::backend::rate_limit::{RateLimiter, Rejected};
+use crate::backend::stems::{stems, BandSeparator};
+use crate::backend::music_gpt_chat::Chat;
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::users::User;
+use crate::config_profiles::ConfigProfile;
+use crate::music_gen_config::{AudioFormat, GenerationConfig};
+use crate::storage::{Storage, AUDIOS_DIR};
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RestGenerateRequest {
+    pub prompt: String,
+    pub secs: usize,
+    /// Chat where the generation is added, a new one is created if not set.
+    #[serde(default)]
+    pub chat_id: Option<Uuid>,
+    /// Overrides the server config for this job only.
+    #[serde(default)]
+    pub config: Option<GenerationConfig>,
+    /// Batch if not set, scripts are rarely waiting on a job the way chat users are.
+    #[serde(default)]
+    pub priority: Option<Priority>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct JobsQuery {
+    #[serde(default)]
+    pub status: Option<JobState>,
+    /// Only the jobs of this chat.
+    #[serde(default)]
+    pub chat: Option<Uuid>,
+    /// Only the jobs created at or after this time, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub from: Option<i64>,
+    /// Only the jobs created at or before this time, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub to: Option<i64>,
+    /// The `next_page` of the previous page, the newest jobs if not set.
+    #[serde(default)]
+    pub page: Option<String>,
+    /// Jobs per page, 20 if not set.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct JobPage {
+    /// Newest first.
+    pub jobs: Vec<JobStatus>,
+    /// Where the next page starts, not set once there are no more jobs. The next
+    /// page can still come out empty.
+    pub next_page: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Owner of the jobs submitted through the HTTP API by clients that are not registered.
+const REST_OWNER: &str = "rest";
+
+/// Plain HTTP JSON API for scripting generations without the WebSocket protocol.
+/// Jobs go through the same backend as the ones submitted through the web app.
+#[derive(Clone)]
+pub struct RestApi<S: Storage> {
+    pub storage: S,
+    pub ai_tx: Sender<BackendInboundMsg>,
+    /// Profile applied to all the jobs, shared with the WebSocket connections.
+    pub active_profile: Arc<RwLock<Option<ConfigProfile>>>,
+    jobs: JobStore,
+    ai_broadcast_tx: tokio::sync::broadcast::Sender<GenerationMessage>,
+    /// Where the running jobs are at, for the streams opened halfway through a job.
+    events: EventLog,
+    invites: Invites,
+    rate_limiter: RateLimiter,
+    /// Channels of the audio of the jobs, interleaved in the streams.
+    channels: u16,
+}
+
+impl<S: Storage + 'static> RestApi<S> {
+    pub fn new(
+        storage: S,
+        ai_tx: Sender<BackendInboundMsg>,
+        active_profile: Arc<RwLock<Option<ConfigProfile>>>,
+        jobs: JobStore,
+        ai_broadcast_tx: &tokio::sync::broadcast::Sender<GenerationMessage>,
+        events: EventLog,
+        invites: Invites,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        // The database is written from its own thread, so that the runtime never waits on it.
+        let (track_tx, track_rx) = channel();
+        let jobs_clone = jobs.clone();
+        std::thread::spawn(move || track_jobs(&jobs_clone, track_rx));
+        let mut rx = ai_broadcast_tx.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(GenerationMessage::Tokens(_) | GenerationMessage::Audio(_)) => continue,
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("The job tracker missed {skipped} generation messages");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if track_tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            storage,
+            ai_tx,
+            active_profile,
+            jobs,
+            ai_broadcast_tx: ai_broadcast_tx.clone(),
+            events,
+            invites,
+            rate_limiter,
+            channels: 1,
+        }
+    }
+
+    /// Streams the audio of the jobs with `channels` interleaved channels.
+    pub fn with_channels(mut self, channels: u16) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    pub async fn generate(
+        &self,
+        req: RestGenerateRequest,
+        access: Access,
+        user: Option<User>,
+        client: IpAddr,
+    ) -> Response {
+        match self.submit(req, access, user, client).await {
+            Ok(status) => (StatusCode::ACCEPTED, Json(status)).into_response(),
+            Err(err) => match err.downcast::<Rejected>() {
+                Ok(rejected) => rejected.into_response(),
+                Err(err) => ApiError::or(err, ErrorCode::InvalidRequest).into_response(),
+            },
+        }
+    }
+
+    pub(crate) async fn submit(
+        &self,
+        req: RestGenerateRequest,
+        access: Access,
+        user: Option<User>,
+        client: IpAddr,
+    ) -> anyhow::Result<JobStatus> {
+        if req.secs == 0 {
+            return Err(ErrorCode::InvalidRequest.err("secs must be greater than 0"));
+        }
+        validate_prompt(&req.prompt)?;
+        let overrides = req.config.unwrap_or_default();
+        overrides.validate()?;
+        check_clips(&self.storage, Some(&overrides)).await?;
+        let user_id = user.map(|v| v.id);
+        if let Some(chat_id) = req.chat_id {
+            Chat::load_for(&self.storage, chat_id, user_id).await?;
+        }
+        let id = Uuid::new_v4();
+        self.rate_limiter.admit(client, id)?;
+        let submitted = self.submit_admitted(id, req, overrides, access, user_id).await;
+        if submitted.is_err() {
+            self.rate_limiter.release(client, id);
+        }
+        submitted
+    }
+
+    /// Charges `access` and submits the job `id`, whose slot in the rate limiter is
+    /// already taken.
+    async fn submit_admitted(
+        &self,
+        id: Uuid,
+        req: RestGenerateRequest,
+        overrides: GenerationConfig,
+        access: Access,
+        user_id: Option<Uuid>,
+    ) -> anyhow::Result<JobStatus> {
+        self.invites.charge(&access)?;
+        let config = {
+            let profile = self.active_profile.read().unwrap();
+            let base = profile.as_ref().map(|p| p.config.clone()).unwrap_or_default();
+            base.merged(&overrides)
+        };
+
+        let chat_id = match req.chat_id {
+            Some(chat_id) => chat_id,
+            None => {
+                let chat = Chat {
+                    chat_id: Uuid::new_v4(),
+                    name: req.prompt.clone(),
+                    created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis(),
+                    tags: vec![],
+                    user_id,
+                };
+                chat.save(&self.storage).await?;
+                chat.chat_id
+            }
+        };
+        let status = self.jobs.insert(id, chat_id, &req.prompt, req.secs)?;
+        self.ai_tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+            id: IdPair(chat_id, id).to_string(),
+            prompt: req.prompt,
+            secs: req.secs,
+            config,
+            priority: req.priority.unwrap_or(Priority::Batch),
+            owner: user_id.map_or(REST_OWNER.to_string(), |v| v.to_string()),
+        }))?;
+        Ok(status)
+    }
+
+    pub async fn generate_batch(
+        &self,
+        req: RestBatchRequest,
+        access: Access,
+        user: Option<User>,
+        client: IpAddr,
+    ) -> Response {
+        match self.submit_batch(req, access, user, client).await {
+            Ok(status) => (StatusCode::ACCEPTED, Json(status)).into_response(),
+            Err(err) => match err.downcast::<Rejected>() {
+                Ok(rejected) => rejected.into_response(),
+                Err(err) => ApiError::or(err, ErrorCode::InvalidRequest).into_response(),
+            },
+        }
+    }
+
+    /// Queues a job for each prompt, all of them in the same chat. Jobs over the rate
+    /// limits are failed, the batch is only rejected if not even the first job fits.
+    async fn submit_batch(
+        &self,
+        req: RestBatchRequest,
+        access: Access,
+        user: Option<User>,
+        client: IpAddr,
+    ) -> anyhow::Result<BatchStatus> {
+        validate_batch(&req.prompts, req.num_outputs, req.secs, &req.config)?;
+        let user_id = user.map(|v| v.id);
+        if let Some(chat_id) = req.chat_id {
+            Chat::load_for(&self.storage, chat_id, user_id).await?;
+        }
+        let config = {
+            let profile = self.active_profile.read().unwrap();
+            let base = profile.as_ref().map(|p| p.config.clone()).unwrap_or_default();
+            base.merged(&req.config.unwrap_or_default())
+        };
+        let name = req.prompts[0].clone();
+        let batch = Batch::new(
+            req.chat_id.unwrap_or_else(Uuid::new_v4),
+            req.prompts,
+            req.num_outputs.unwrap_or(1),
+            req.secs,
+            config,
+            req.priority.unwrap_or(Priority::Batch),
+            user_id.map_or(REST_OWNER.to_string(), |v| v.to_string()),
+        );
+        let (admitted, rejection) = batch.admit(|id| {
+            self.rate_limiter.admit(client, id)?;
+            let charged = self.invites.charge(&access);
+            if charged.is_err() {
+                self.rate_limiter.release(client, id);
+            }
+            charged
+        })?;
+        let ids: Vec<_> = batch.jobs[..admitted].iter().map(|(id, _, _)| *id).collect();
+        let queued = async {
+            if req.chat_id.is_none() {
+                let chat = Chat {
+                    chat_id: batch.chat_id,
+                    name,
+                    created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis(),
+                    tags: vec![],
+                    user_id,
+                };
+                chat.save(&self.storage).await?;
+            }
+            batch.queue(&self.jobs, &self.ai_tx, admitted, rejection)
+        }
+        .await;
+        if queued.is_err() {
+            for id in ids {
+                self.rate_limiter.release(client, id);
+            }
+        }
+        queued
+    }
+
+    /// The status of the batch `id`, as long as it is in a chat of `user`.
+    pub async fn batch(&self, id: Uuid, user: Option<User>) -> Response {
+        let status = match self.jobs.batch(id) {
+            Ok(jobs) => BatchStatus::new(id, jobs),
+            Err(err) => return ErrorCode::Internal.response(err),
+        };
+        let Some(status) = status else {
+            return ErrorCode::NotFound.response(format!("Batch {id} not found"));
+        };
+        let user_id = user.map(|v| v.id);
+        match Chat::user_of(&self.storage, status.chat_id).await {
+            Ok(owner) if owner == user_id => Json(status).into_response(),
+            Ok(_) => ErrorCode::NotFound.response(format!("Batch {id} not found")),
+            Err(err) => ErrorCode::Internal.response(err),
+        }
+    }
+
+    /// The status of the job `id`, as long as it is in a chat of `user`.
+    pub(crate) async fn status(
+        &self,
+        id: Uuid,
+        user: Option<User>,
+    ) -> anyhow::Result<Option<JobStatus>> {
+        let Some(status) = self.jobs.get(id)? else {
+            return Ok(None);
+        };
+        let user_id = user.map(|v| v.id);
+        if Chat::user_of(&self.storage, status.chat_id).await? != user_id {
+            return Ok(None);
+        }
+        Ok(Some(status))
+    }
+
+    /// A page of the job history of `user`.
+    pub async fn jobs(&self, query: JobsQuery, user: Option<User>) -> Response {
+        match self.history(query, user).await {
+            Ok(page) => Json(page).into_response(),
+            Err(err) => ApiError::or(err, ErrorCode::InvalidRequest).into_response(),
+        }
+    }
+
+    async fn history(&self, query: JobsQuery, user: Option<User>) -> anyhow::Result<JobPage> {
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let mut after = query.page.as_deref().map(str::parse::<PageCursor>).transpose()?;
+        let user_id = user.map(|v| v.id);
+        if let Some(chat_id) = query.chat {
+            Chat::load_for(&self.storage, chat_id, user_id).await?;
+        }
+        let filter = JobFilter {
+            state: query.status,
+            chat_id: query.chat,
+            created_from: query.from,
+            created_to: query.to,
+        };
+        // The store does not know who the chats belong to, so the jobs of others are
+        // skipped here, reading more until the page is full.
+        let mut owners = HashMap::new();
+        let mut jobs = vec![];
+        loop {
+            let rows = self.jobs.page(&filter, after, limit)?;
+            let exhausted = rows.len() < limit;
+            for (cursor, status) in rows {
+                after = Some(cursor);
+                let owner = match owners.get(&status.chat_id) {
+                    Some(owner) => *owner,
+                    None => {
+                        let owner = Chat::user_of(&self.storage, status.chat_id).await?;
+                        owners.insert(status.chat_id, owner);
+                        owner
+                    }
+                };
+                if owner != user_id {
+                    continue;
+                }
+                jobs.push(status);
+                if jobs.len() == limit {
+                    let next_page = Some(cursor.to_string());
+                    return Ok(JobPage { jobs, next_page });
+                }
+            }
+            if exhausted {
+                return Ok(JobPage { jobs, next_page: None });
+            }
+        }
+    }
+
+    pub async fn job(&self, id: Uuid, user: Option<User>) -> Response {
+        match self.status(id, user).await {
+            Ok(Some(status)) => Json(status).into_response(),
+            Ok(None) => ErrorCode::NotFound.response(format!("Job {id} not found")),
+            Err(err) => ErrorCode::Internal.response(err),
+        }
+    }
+
+    /// Streams the progress of a job as Server-Sent Events. The current status is sent
+    /// first as a `status` event, together with the last `progress` event if the job
+    /// is running, followed by `progress` events, and the stream ends
+    /// with either a `completed`, `failed` or `cancelled` event.
+    pub async fn events(&self, id: Uuid, user: Option<User>) -> Response {
+        // Subscribed before reading the status, so that no update falls in between.
+        let mut rx = self.ai_broadcast_tx.subscribe();
+        let status = match self.status(id, user).await {
+            Ok(Some(status)) => status,
+            Ok(None) => return ErrorCode::NotFound.response(format!("Job {id} not found")),
+            Err(err) => return ErrorCode::Internal.response(err),
+        };
+        let progress = self.events.snapshot(id).and_then(|v| v.progress);
+        let stream = async_stream::stream! {
+            let finished = status.state.is_finished();
+            yield sse_event("status", &status);
+            if finished {
+                return;
+            }
+            if let Some(progress) = progress {
+                yield sse_event("progress", &progress);
+            }
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                };
+                if msg.id() != id {
+                    continue;
+                }
+                if let GenerationMessage::Progress(m) = &msg {
+                    yield sse_event("progress", m);
+                } else if let Some(status) = finished_status(msg) {
+                    let name = match status.state {
+                        JobState::Completed => "completed",
+                        JobState::Failed => "failed",
+                        _ => "cancelled",
+                    };
+                    yield sse_event(name, &status);
+                    return;
+                }
+            }
+        };
+        Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+    }
+
+    /// Streams the audio of a job while it is generated, as a WAV of unknown length
+    /// that players can start playing right away. The audio generated before the
+    /// request is sent first. Completed jobs are redirected to their audio file.
+    pub async fn stream(&self, id: Uuid, user: Option<User>) -> Response {
+        // Subscribed before reading the audio so far, so that no chunk falls in between.
+        let mut rx = self.ai_broadcast_tx.subscribe();
+        let status = match self.status(id, user).await {
+            Ok(Some(status)) => status,
+            Ok(None) => return ErrorCode::NotFound.response(format!("Job {id} not found")),
+            Err(err) => return ErrorCode::Internal.response(err),
+        };
+        let audio_url = format!("/api/audio/{id}.wav");
+        match status.state {
+            JobState::Completed => {
+                let audio_url = status.audio_url.as_deref().unwrap_or(&audio_url);
+                return Redirect::temporary(audio_url).into_response();
+            }
+            JobState::Failed | JobState::Cancelled => {
+                return ErrorCode::Gone.response(format!("Job {id} has no audio"));
+            }
+            // Running jobs that are gone from the log just finished, their result
+            // might not be in the store yet.
+            JobState::Running if self.events.snapshot(id).is_none() => {
+                return Redirect::temporary(&audio_url).into_response();
+            }
+            JobState::Queued | JobState::Running => {}
+        }
+        let so_far = self.events.audio_so_far(id);
+        let sample_rate = AudioManager::default().sampling_rate();
+        let channels = self.channels;
+        let stream = async_stream::stream! {
+            yield Ok::<_, Infallible>(streaming_wav_header(sample_rate, channels));
+            let mut next = so_far.len();
+            yield Ok(pcm_bytes(&so_far));
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    // A gap would be heard, the player can reconnect instead.
+                    Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => return,
+                };
+                if msg.id() != id {
+                    continue;
+                }
+                match msg {
+                    GenerationMessage::Audio(m) => {
+                        if m.offset > next {
+                            return;
+                        }
+                        // What was already sent with the audio so far is skipped.
+                        let skip = (next - m.offset).min(m.samples.len());
+                        next += m.samples.len() - skip;
+                        yield Ok(pcm_bytes(&m.samples[skip..]));
+                    }
+                    GenerationMessage::Result(_)
+                    | GenerationMessage::Error(_)
+                    | GenerationMessage::Cancelled(_) => return,
+                    _ => {}
+                }
+            }
+        };
+        (
+            [(header::CONTENT_TYPE, "audio/wav"), (header::CACHE_CONTROL, "no-cache")],
+            Body::from_stream(stream),
+        )
+            .into_response()
+    }
+
+    /// Cancels a queued or running job. The job is marked as cancelled once the
+    /// backend confirms it stopped.
+    pub async fn cancel(&self, id: Uuid, user: Option<User>) -> Response {
+        let status = match self.status(id, user).await {
+            Ok(Some(status)) => status,
+            Ok(None) => return ErrorCode::NotFound.response(format!("Job {id} not found")),
+            Err(err) => return ErrorCode::Internal.response(err),
+        };
+        if status.state.is_finished() {
+            return ErrorCode::Conflict.response(format!("Job {id} already finished"));
+        }
+        let id_pair = IdPair(status.chat_id, id).to_string();
+        match self.ai_tx.send(BackendInboundMsg::Abort(id_pair)) {
+            Ok(()) => (StatusCode::ACCEPTED, Json(status)).into_response(),
+            Err(err) => ApiError::from(anyhow::Error::new(err)).into_response(),
+        }
+    }
+
+    /// Serves the stem `name` of the audio of the job `id` as a wav. The stems are
+    /// separated the first time any of them is asked, and cached from then on.
+    pub async fn stem(&self, id: Uuid, name: String, user: Option<User>) -> Response {
+        match self.status(id, user).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return ErrorCode::NotFound.response(format!("Job {id} not found")),
+            Err(err) => return ErrorCode::Internal.response(err),
+        }
+        let stems = match stems(&self.storage, &BandSeparator::default(), id).await {
+            Ok(stems) => stems,
+            Err(err) => return ApiError::or(err, ErrorCode::Internal).into_response(),
+        };
+        let Some(path) = stems.get(&name) else {
+            return ErrorCode::NotFound.response(format!("Stem {name} not found"));
+        };
+        match self.storage.read(path).await {
+            Ok(Some(bytes)) => ([(header::CONTENT_TYPE, "audio/wav")], bytes).into_response(),
+            Ok(None) => ErrorCode::NotFound.response(format!("Stem {name} not found")),
+            Err(err) => ErrorCode::Internal.response(err),
+        }
+    }
+
+    /// Serves `<id>.wav`, `<id>.mp3` and `<id>.flac` files from the generated audios of
+    /// `user`, or the part of them asked in the `Range` header, so that players can seek
+    /// in them.
+    pub async fn audio(&self, file: String, user: Option<User>, headers: HeaderMap) -> Response {
+        let parsed = file.rsplit_once('.').and_then(|(id, extension)| {
+            Some((Uuid::parse_str(id).ok()?, AudioFormat::from_extension(extension)?))
+        });
+        let Some((id, format)) = parsed else {
+            return ErrorCode::NotFound.response(format!("{file} not found"));
+        };
+        match self.status(id, user).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return ErrorCode::NotFound.response(format!("{file} not found")),
+            Err(err) => return ErrorCode::Internal.response(err),
+        }
+        match self.storage.read(&format!("{AUDIOS_DIR}/{id}.{}", format.extension())).await {
+            Ok(Some(bytes)) => ranged(&headers, format.content_type(), bytes),
+            Ok(None) => ErrorCode::NotFound.response(format!("{file} not found")),
+            Err(err) => ErrorCode::Internal.response(err),
+        }
+    }
+}
+
+enum ByteRange {
+    Full,
+    /// Inclusive bounds.
+    Partial(usize, usize),
+    Unsatisfiable,
+}
+
+/// The single range of bytes asked in a `Range` header. Malformed headers and multiple
+/// ranges are ignored, which results in the full content being sent.
+fn byte_range(range: Option<&str>, total: usize) -> ByteRange {
+    let Some(spec) = range.and_then(|v| v.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<usize>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (total.saturating_sub(suffix), total.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, "") => match start.parse::<usize>() {
+            Ok(start) => (start, total.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<usize>(), end.parse::<usize>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(total.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+    if start >= total {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+fn ranged(headers: &HeaderMap, content_type: &'static str, bytes: Vec<u8>) -> Response {
+    let total = bytes.len();
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    match byte_range(range, total) {
+        ByteRange::Full => (
+            [(header::CONTENT_TYPE, content_type), (header::ACCEPT_RANGES, "bytes")],
+            bytes,
+        )
+            .into_response(),
+        ByteRange::Partial(start, end) => (
+            StatusCode::PARTIAL_CONTENT,
+            [
+                (header::CONTENT_TYPE, content_type.to_string()),
+                (header::ACCEPT_RANGES, "bytes".to_string()),
+                (header::CONTENT_RANGE, format!("bytes {start}-{end}/{total}")),
+            ],
+            bytes[start..=end].to_vec(),
+        )
+            .into_response(),
+        ByteRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{total}"))],
+        )
+            .into_response(),
+    }
+}
+
+/// Header of a 16 bit WAV with `channels` interleaved channels whose length is not known
+/// yet, which most players read as a stream that goes on until the connection closes.
+fn streaming_wav_header(sample_rate: u32, channels: u16) -> Vec<u8> {
+    let block_align = channels * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM.
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+fn pcm_bytes(samples: &[i16]) -> Vec<u8> {
+    samples.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn sse_event(name: &str, data: &impl Serialize) -> Result<Event, Infallible> {
+    Ok(Event::default()
+        .event(name)
+        .data(serde_json::to_string(data).unwrap_or_default()))
+}
+
+/// Url of the audio of the job `id`, saved at `relpath`, in the format that it asked for.
+pub(crate) fn audio_url(id: Uuid, relpath: &str) -> String {
+    let extension = relpath.rsplit_once('.').map_or("wav", |(_, extension)| extension);
+    format!("/api/audio/{id}.{extension}")
+}
+
+/// The status a job ends up with after `msg`, none if `msg` does not finish it.
+pub(crate) fn finished_status(msg: GenerationMessage) -> Option<JobStatus> {
+    match msg {
+        GenerationMessage::Result(m) => {
+            let mut status = JobStatus::new(m.id, m.chat_id, JobState::Completed);
+            status.progress = 1.0;
+            status.audio_url = Some(audio_url(m.id, &m.relpath));
+            Some(status)
+        }
+        GenerationMessage::Error(m) => {
+            let mut status = JobStatus::new(m.id, m.chat_id, JobState::Failed);
+            status.error = Some(m.error);
+            Some(status)
+        }
+        GenerationMessage::Cancelled(m) => {
+            Some(JobStatus::new(m.id, m.chat_id, JobState::Cancelled))
+        }
+        _ => None,
+    }
+}
+
+/// How often the progress of the running jobs is saved. Jobs report it on every step,
+/// but only the latest one of each job in this interval is written.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps `jobs` up to date with the generation messages of `rx`, until it is closed.
+fn track_jobs(jobs: &JobStore, rx: Receiver<GenerationMessage>) {
+    let mut progress = HashMap::new();
+    let mut saved_at = Instant::now();
+    let save = |progress: &mut HashMap<Uuid, f32>| {
+        for (id, progress) in progress.drain() {
+            if let Err(err) = jobs.set_progress(id, progress) {
+                warn!("Could not update the progress of job {id}: {err}");
+            }
+        }
+    };
+    loop {
+        match rx.recv_timeout(PROGRESS_INTERVAL) {
+            Ok(GenerationMessage::Progress(m)) => {
+                progress.insert(m.id, m.progress);
+            }
+            Ok(msg) => {
+                // Finishing a job sets its progress, which must not be overwritten.
+                progress.remove(&msg.id());
+                if let Err(err) = track(jobs, msg) {
+                    warn!("Could not update the job state: {err}");
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if saved_at.elapsed() >= PROGRESS_INTERVAL {
+            save(&mut progress);
+            saved_at = Instant::now();
+        }
+    }
+    save(&mut progress);
+}
+
+fn track(jobs: &JobStore, msg: GenerationMessage) -> anyhow::Result<()> {
+    match msg {
+        GenerationMessage::Start(m) => {
+            // Jobs submitted through the WebSocket are first seen here.
+            jobs.ensure(m.id, m.chat_id, &m.prompt, m.secs)?;
+            jobs.transition(m.id, JobState::Running, |_| {})?;
+        }
+        GenerationMessage::Progress(m) => jobs.set_progress(m.id, m.progress)?,
+        GenerationMessage::Result(m) => {
+            jobs.transition(m.id, JobState::Completed, |status| {
+                status.progress = 1.0;
+                status.audio_url = Some(audio_url(m.id, &m.relpath));
+            })?;
+        }
+        GenerationMessage::Error(m) => {
+            // Jobs submitted through the WebSocket that failed before starting are not known yet.
+            jobs.ensure(m.id, m.chat_id, "", 0)?;
+            jobs.transition(m.id, JobState::Failed, |status| status.error = Some(m.error))?;
+        }
+        GenerationMessage::Cancelled(m) => {
+            jobs.ensure(m.id, m.chat_id, "", 0)?;
+            jobs.transition(m.id, JobState::Cancelled, |_| {})?;
+        }
+        GenerationMessage::Tokens(_) | GenerationMessage::Audio(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::audio_generation_fanout::{AudioGenerationProgress, AudioGenerationStart};
+
+    use super::*;
+
+    fn progress(id: Uuid, chat_id: Uuid, progress: f32) -> GenerationMessage {
+        GenerationMessage::Progress(AudioGenerationProgress {
+            id,
+            chat_id,
+            progress,
+            queue_position: None,
+            tokens: None,
+            total_tokens: None,
+            percent: progress * 100.0,
+            eta_secs: None,
+        })
+    }
+
+    #[test]
+    fn saves_only_the_latest_progress() -> anyhow::Result<()> {
+        let jobs = JobStore::in_memory()?;
+        let (id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let (tx, rx) = channel();
+        tx.send(GenerationMessage::Start(AudioGenerationStart {
+            id,
+            chat_id,
+            prompt: "prompt".to_string(),
+            secs: 10,
+        }))?;
+        for i in 1..=4 {
+            tx.send(progress(id, chat_id, i as f32 / 4.0))?;
+        }
+        drop(tx);
+        track_jobs(&jobs, rx);
+
+        let status = jobs.get(id)?.unwrap();
+        assert_eq!(status.state, JobState::Running);
+        assert_eq!(status.progress, 1.0);
+        Ok(())
+    }
+}
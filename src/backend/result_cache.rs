@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::backend::audio_generation_backend::AudioGenerationRequest;
+
+/// Audio of the last jobs that completed, so that a job asking for exactly the same
+/// thing is answered right away instead of being generated again. The seed the audio
+/// was generated with is kept along, as jobs without one are answered too.
+pub struct ResultCache {
+    capacity: usize,
+    /// Least recently used first.
+    entries: Mutex<VecDeque<(String, VecDeque<f32>, u32)>>,
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// What the audio of a job depends on: the model, the prompt, the length and
+    /// the generation settings. The id, priority and owner of the job do not count.
+    pub fn key(model: &str, req: &AudioGenerationRequest) -> String {
+        let config = serde_json::to_string(&req.config).unwrap_or_default();
+        format!("{model}\n{}\n{config}\n{}", req.secs, req.prompt)
+    }
+
+    /// The audio under `key` and its seed.
+    pub fn get(&self, key: &str) -> Option<(VecDeque<f32>, u32)> {
+        let mut entries = self.entries.lock().unwrap();
+        let i = entries.iter().position(|(k, _, _)| k == key)?;
+        let entry = entries.remove(i)?;
+        let result = (entry.1.clone(), entry.2);
+        entries.push_back(entry);
+        Some(result)
+    }
+
+    /// Keeps `audio`, generated with `seed`, under `key`, forgetting the least recently
+    /// used entry if full.
+    pub fn insert(&self, key: String, audio: VecDeque<f32>, seed: u32) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(k, _, _)| *k != key);
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((key, audio, seed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::audio_generation_backend::Priority;
+
+    use super::*;
+
+    #[test]
+    fn forgets_the_least_recently_used_audio() {
+        let req = |prompt: &str, id: &str| AudioGenerationRequest {
+            id: id.to_string(),
+            prompt: prompt.to_string(),
+            secs: 1,
+            config: Default::default(),
+            priority: Priority::Interactive,
+            owner: id.to_string(),
+        };
+        let key = |prompt: &str| ResultCache::key("Dummy", &req(prompt, "1"));
+        assert_eq!(key("a"), ResultCache::key("Dummy", &req("a", "2")));
+        assert_ne!(key("a"), ResultCache::key("Other", &req("a", "1")));
+
+        let cache = ResultCache::new(2);
+        cache.insert(key("a"), VecDeque::from([1.0]), 1);
+        cache.insert(key("b"), VecDeque::from([2.0]), 2);
+        assert_eq!(cache.get(&key("a")), Some((VecDeque::from([1.0]), 1)));
+        cache.insert(key("c"), VecDeque::from([3.0]), 3);
+        assert_eq!(cache.get(&key("b")), None);
+        assert_eq!(cache.get(&key("a")), Some((VecDeque::from([1.0]), 1)));
+        assert_eq!(cache.get(&key("c")), Some((VecDeque::from([3.0]), 3)));
+    }
+}
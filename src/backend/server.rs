@@ -1,20 +1,91 @@
-use axum::extract::WebSocketUpgrade;
-use axum::response::Html;
-use axum::routing::get;
-use axum::Router;
-use tower_http::services::ServeDir;
-use tracing::info;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest};
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Path, Query, Request, WebSocketUpgrade};
+use axum::http::{header, HeaderMap, HeaderValue, Method, Uri};
+use axum::middleware::{self, Next};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::{AllowMethods, AllowOrigin, Any, CorsLayer};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::backend::admin::{Admin, AdminJobsQuery};
 use crate::backend::audio_generation_backend::{AudioGenerationBackend, JobProcessor};
-use crate::backend::audio_generation_fanout::audio_generation_fanout;
-use crate::backend::music_gpt_ws_handler::{Info, MusicGptWsHandler};
-use crate::backend::ws_handler::WsHandler;
+use crate::backend::audio_generation_fanout::{audio_generation_fanout, EventLog};
+use crate::backend::auth::{Access, Auth};
+use crate::backend::batches::RestBatchRequest;
+use crate::backend::discovery::{advertise, Advertisement};
+use crate::backend::feed::feed;
+use crate::backend::graphql::{self, graphiql, graphql_ws, GRAPHQL_PATH, GRAPHQL_WS_PATH};
+use crate::backend::grpc::Grpc;
+use crate::backend::health::{healthz, readyz, version};
+use crate::backend::invites::{list_invites, mint_invite, revoke_invite, InviteRequest, Invites};
+use crate::backend::checkpoints::Checkpoints;
+use crate::backend::job_store::{persist_jobs, recover_jobs, JobStore};
+use crate::backend::limits::body_limit;
+use crate::backend::melodies::{melody_body_limit, upload_melody};
+use crate::backend::music_gpt_ws_handler::{
+    Capabilities, Info, MusicGptWsHandler, EVENTS_CAPACITY,
+};
+use crate::backend::observer_ws_handler::{observe, ObserverWsHandler};
+use crate::backend::openapi::{openapi, swagger_ui};
+use crate::backend::playlist::{playlist, PlaylistQuery};
+use crate::backend::rate_limit::RateLimiter;
+use crate::backend::rest_api::{JobsQuery, RestApi, RestGenerateRequest};
+use crate::backend::sessions::Sessions;
+use crate::backend::shadow::{run_shadow, shadow_report, ShadowOptions};
+use crate::backend::shutdown::{drain, signal, InFlight};
+use crate::backend::stems::{gc_stems, BandSeparator, StemSeparator};
+use crate::backend::suggestions::{suggestions, SuggestionsQuery};
+use crate::backend::tls::rustls_config;
+#[cfg(unix)]
+use crate::backend::unix_socket::serve_unix;
+use crate::backend::users::{register_user, RegisterRequest, User, Users};
+use crate::backend::web_assets::WebAssets;
+use crate::backend::webhooks::{list_webhooks, register_webhook, remove_webhook, WebhookRequest, Webhooks};
+use crate::backend::ws_handler::{Keepalive, WsHandler};
+use crate::config_profiles::ConfigProfile;
+use crate::music_gen_config::{PipelineConfig, Secret, ServerConfig};
 use crate::storage::AppFs;
 
+/// SQLite database with the state of the jobs, relative to the data dir.
+const JOBS_DB: &str = "jobs.sqlite";
+/// Where the tokens of the running jobs are saved, see [Checkpoints].
+const CHECKPOINTS_DIR: &str = "checkpoints";
+/// How long the connections are given to close once the jobs are drained.
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Registered users, relative to the data dir.
+const USERS_FILE: &str = "users.json";
+/// Webhooks registered through the API, relative to the data dir.
+const WEBHOOKS_FILE: &str = "webhooks.json";
+
 pub struct RunOptions {
-    pub port: usize,
-    pub auto_open: bool,
-    pub expose: bool,
+    pub server: ServerConfig,
+    pub profile: Option<ConfigProfile>,
+    /// Profiles declared in the config file, on top of the ones stored in the data dir.
+    pub config_profiles: Vec<ConfigProfile>,
+    pub shadow: Option<ShadowOptions>,
+    pub pipeline: PipelineConfig,
+    /// Key that remote clients must send, see [Auth].
+    pub api_key: Option<Secret>,
+    /// Key the webhooks are signed with, see [Webhooks].
+    pub webhook_secret: Option<Secret>,
+    /// Token for the admin endpoints, which are disabled if not set, see [Admin].
+    pub admin_token: Option<Secret>,
+    /// Files of the models in use, relative to the data dir. Evicting the cached
+    /// models keeps these.
+    pub model_files: Vec<String>,
+    /// Also serves the API on a Unix socket at this path, for reverse proxies and
+    /// other local processes.
+    pub unix_socket: Option<PathBuf>,
+    /// Only serves the API on `unix_socket`, without opening the TCP port.
+    pub unix_socket_only: bool,
 }
 
 pub async fn run<T: JobProcessor + 'static>(
@@ -24,32 +95,434 @@ pub async fn run<T: JobProcessor + 'static>(
 ) -> anyhow::Result<()> {
     let model = processor.name();
     let device = processor.device();
+    let channels = processor.channels();
 
-    let (ai_tx, ai_rx) = AudioGenerationBackend::new(processor).run();
-    let ai_broadcast_tx = audio_generation_fanout(ai_rx, storage.clone());
+    let mut backend = AudioGenerationBackend::with_workers(processor, opts.pipeline.max_in_flight())
+        .with_result_cache(opts.pipeline.result_cache);
+    let checkpoints = match opts.pipeline.checkpoint_secs {
+        Some(secs) => {
+            let checkpoints = Arc::new(Checkpoints::new(storage.path_buf(CHECKPOINTS_DIR))?);
+            backend = backend.with_checkpoints(checkpoints.clone(), secs);
+            Some(checkpoints)
+        }
+        None => None,
+    };
+    let observed_backend = backend.clone();
+    let health_backend = backend.clone();
+    let drained_backend = backend.clone();
+    let queued_backend = backend.clone();
+    if opts.pipeline.warmup {
+        backend.warmup();
+    }
+    let (ai_tx, ai_rx) = backend.run();
+    let events = EventLog::default();
+    let ai_broadcast_tx = audio_generation_fanout(
+        ai_rx,
+        storage.clone(),
+        opts.pipeline.post_processing,
+        channels,
+        opts.pipeline.mp3.clone(),
+        opts.pipeline.flac.clone(),
+        events.clone(),
+    );
+    let in_flight = InFlight::track(&ai_broadcast_tx);
+    let shutdown = CancellationToken::new();
+    let ai_tx = match opts.shadow {
+        Some(shadow) => run_shadow(shadow, ai_tx, ai_broadcast_tx.subscribe(), storage.clone()),
+        None => ai_tx,
+    };
+    let job_store = JobStore::open(storage.path_buf(JOBS_DB))?;
+    let ai_tx = persist_jobs(job_store.clone(), ai_tx);
+    recover_jobs(&job_store, &ai_tx, &storage, checkpoints.as_deref()).await?;
 
-    let root_dir = storage.root.clone();
+    let (playlist_storage, playlist_jobs) = (storage.clone(), job_store.clone());
+    let shadow_storage = storage.clone();
+    let suggestions_storage = storage.clone();
+    let gc_storage = storage.clone();
+    let feed_storage = storage.clone();
+    let melodies_storage = storage.clone();
+    let tls_storage = storage.clone();
+    let scheme = opts.server.scheme();
+    let web_assets = WebAssets::new(opts.server.web_dir.as_deref());
+    tokio::spawn(async move {
+        match gc_stems(&gc_storage, Some(&BandSeparator::default().version())).await {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {removed} stale stem cache entries"),
+            Err(err) => warn!("Could not clean up the stems cache: {err}"),
+        }
+    });
+    let active_profile = Arc::new(RwLock::new(opts.profile.clone()));
+    let invites = Invites::default();
+    let rate_limiter = RateLimiter::new(
+        opts.server.rate_limit.clone(),
+        &ai_broadcast_tx,
+        job_store.clone(),
+    );
+    let rate_limiter = match opts.pipeline.max_waiting_jobs {
+        Some(max) => rate_limiter.with_queue_limit(queued_backend, max),
+        None => rate_limiter,
+    };
+    let webhooks_broadcast_tx = ai_broadcast_tx.clone();
+    let (invites_mint, invites_list) = (invites.clone(), invites.clone());
+    let invites_revoke = invites.clone();
+    let users = Users::open(storage.path_buf(USERS_FILE))?;
+    let (users_register, users_layer) = (users.clone(), users.clone());
+    let webhooks = Webhooks::open(
+        storage.path_buf(WEBHOOKS_FILE),
+        &opts.server.webhooks,
+        opts.webhook_secret.clone(),
+    )?;
+    let (webhooks_register, webhooks_list) = (webhooks.clone(), webhooks.clone());
+    let webhooks_remove = webhooks.clone();
+    let admin = Admin::new(
+        opts.admin_token.clone(),
+        storage.clone(),
+        job_store.clone(),
+        ai_tx.clone(),
+        opts.model_files.clone(),
+    );
+    let (admin_jobs, admin_purge, admin_usage) = (admin.clone(), admin.clone(), admin.clone());
+    let (admin_models, admin_gc) = (admin.clone(), admin);
+    let auth = Auth::new(invites.clone(), opts.api_key.clone());
+    // Every request of a reverse proxy or of the Unix socket comes from the loopback address.
+    let proxied = opts.server.behind_proxy || opts.unix_socket.is_some();
+    let auth = match proxied {
+        true => auth.with_untrusted_loopback(),
+        false => auth,
+    };
+    let rest_api = RestApi::new(
+        storage.clone(),
+        ai_tx.clone(),
+        active_profile.clone(),
+        job_store.clone(),
+        &ai_broadcast_tx,
+        events.clone(),
+        invites.clone(),
+        rate_limiter.clone(),
+    )
+    .with_channels(channels);
+    let (rest_jobs, rest_cancel) = (rest_api.clone(), rest_api.clone());
+    let (rest_events, rest_audio) = (rest_api.clone(), rest_api.clone());
+    let (rest_batch, rest_batches) = (rest_api.clone(), rest_api.clone());
+    let (rest_history, rest_stream) = (rest_api.clone(), rest_api.clone());
+    let rest_stems = rest_api.clone();
+    let grpc = Grpc::new(rest_api.clone(), &ai_broadcast_tx, auth.clone(), users);
+    let graphql_schema =
+        graphql::schema(storage.clone(), job_store.clone(), &ai_broadcast_tx, events.clone());
+    let graphql_ws_schema = graphql_schema.clone();
+    let info = Info { model, device };
+    let (ready_info, version_info) = (info.clone(), info.clone());
+    let keepalive = Some(Keepalive {
+        ping_interval: opts.server.ws_ping_interval.0,
+        idle_timeout: opts.server.ws_idle_timeout.0,
+    });
+    let sessions = Sessions::default();
+    sessions.spawn_reaper();
+    let observer_ws_handler = ObserverWsHandler {
+        backend: observed_backend,
+        ai_broadcast_tx: ai_broadcast_tx.clone(),
+        info: info.clone(),
+        shutdown: shutdown.clone(),
+        keepalive,
+    };
     let ws_handler = MusicGptWsHandler {
         ai_tx,
         storage,
-        info: Info { model, device },
+        info,
         ai_broadcast_tx,
+        token_taps: Default::default(),
+        active_profile,
+        config_profiles: Arc::new(opts.config_profiles),
+        previews: Default::default(),
+        events_tx: tokio::sync::broadcast::channel(EVENTS_CAPACITY).0,
+        capabilities: Arc::new(RwLock::new(Capabilities::legacy())),
+        ws_compression: opts.server.ws_compression,
+        keepalive,
+        rejected: Default::default(),
+        client_id: Uuid::new_v4(),
+        auth: auth.clone(),
+        access: Arc::new(RwLock::new(Access::Owner)),
+        rate_limiter,
+        jobs: job_store,
+        client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        user: None,
+        visible_chats: Default::default(),
+        shutdown: shutdown.clone(),
+        events: events.clone(),
+        sessions,
+        session_id: Default::default(),
+        cursor: Default::default(),
+        resumed: Default::default(),
     };
 
     let app = Router::new()
-        .fallback(get(web_app))
-        .nest_service("/files", ServeDir::new(root_dir))
+        .fallback(get(move |uri: Uri| async move { web_assets.serve(uri).await }))
+        .route(
+            "/api/playlist",
+            get(
+                |Extension(user): Extension<Option<User>>, Query(query): Query<PlaylistQuery>| async move {
+                    let user_id = user.map(|v| v.id);
+                    playlist(playlist_storage, playlist_jobs, user_id, query).await
+                },
+            ),
+        )
+        .route(
+            "/api/suggestions",
+            get(
+                |Extension(user): Extension<Option<User>>, Query(query): Query<SuggestionsQuery>| async move {
+                    suggestions(suggestions_storage, user.map(|v| v.id), query).await
+                },
+            ),
+        )
+        .route(
+            "/api/shadow/report",
+            get(|Extension(access): Extension<Access>| async move {
+                shadow_report(shadow_storage, access).await
+            }),
+        )
+        .route(
+            "/feed.xml",
+            get(move |headers: HeaderMap| async move {
+                let host = headers
+                    .get(header::HOST)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("localhost");
+                feed(feed_storage, format!("{scheme}://{host}")).await
+            }),
+        )
+        .route(
+            "/api/generate",
+            post(
+                |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                 Extension(access): Extension<Access>,
+                 Extension(user): Extension<Option<User>>,
+                 Json(req): Json<RestGenerateRequest>| async move {
+                    rest_api.generate(req, access, user, addr.ip()).await
+                },
+            ),
+        )
+        .route(
+            "/api/melodies",
+            post(|body: Bytes| async move { upload_melody(melodies_storage, body).await })
+                .layer(melody_body_limit()),
+        )
+        .route(
+            "/api/generate/batch",
+            post(
+                |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                 Extension(access): Extension<Access>,
+                 Extension(user): Extension<Option<User>>,
+                 Json(req): Json<RestBatchRequest>| async move {
+                    rest_batch.generate_batch(req, access, user, addr.ip()).await
+                },
+            ),
+        )
+        .route(
+            "/api/batches/:id",
+            get(
+                |Extension(user): Extension<Option<User>>, Path(id): Path<Uuid>| async move {
+                    rest_batches.batch(id, user).await
+                },
+            ),
+        )
+        .route(
+            "/api/jobs",
+            get(
+                |Extension(user): Extension<Option<User>>, Query(query): Query<JobsQuery>| async move {
+                    rest_history.jobs(query, user).await
+                },
+            ),
+        )
+        .route(
+            "/api/jobs/:id",
+            get(
+                |Extension(user): Extension<Option<User>>, Path(id): Path<Uuid>| async move {
+                    rest_jobs.job(id, user).await
+                },
+            )
+            .delete(
+                |Extension(user): Extension<Option<User>>, Path(id): Path<Uuid>| async move {
+                    rest_cancel.cancel(id, user).await
+                },
+            ),
+        )
+        .route(
+            GRAPHQL_PATH,
+            get(graphiql).post(
+                |Extension(user): Extension<Option<User>>, req: GraphQLRequest| async move {
+                    graphql::graphql(graphql_schema, user, req).await
+                },
+            ),
+        )
+        .route(
+            GRAPHQL_WS_PATH,
+            get(
+                |Extension(user): Extension<Option<User>>,
+                 protocol: GraphQLProtocol,
+                 upgrade: WebSocketUpgrade| async move {
+                    graphql_ws(graphql_ws_schema, user, protocol, upgrade).await
+                },
+            ),
+        )
+        .route(
+            "/api/jobs/:id/stream",
+            get(
+                |Extension(user): Extension<Option<User>>, Path(id): Path<Uuid>| async move {
+                    rest_stream.stream(id, user).await
+                },
+            ),
+        )
+        .route(
+            "/api/jobs/:id/stems/:stem",
+            get(
+                |Extension(user): Extension<Option<User>>,
+                 Path((id, stem)): Path<(Uuid, String)>| async move {
+                    rest_stems.stem(id, stem, user).await
+                },
+            ),
+        )
+        .route(
+            "/api/jobs/:id/events",
+            get(
+                |Extension(user): Extension<Option<User>>, Path(id): Path<Uuid>| async move {
+                    rest_events.events(id, user).await
+                },
+            ),
+        )
+        .route(
+            "/api/audio/:file",
+            get(
+                |Extension(user): Extension<Option<User>>,
+                 Path(file): Path<String>,
+                 headers: HeaderMap| async move { rest_audio.audio(file, user, headers).await },
+            ),
+        )
+        .route(
+            "/api/invites",
+            post(
+                move |Extension(access): Extension<Access>,
+                      headers: HeaderMap,
+                      Json(req): Json<InviteRequest>| async move {
+                    let host = headers
+                        .get(header::HOST)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or("localhost");
+                    let base_url = format!("{scheme}://{host}");
+                    mint_invite(invites_mint, access, base_url, req).await
+                },
+            )
+            .get(|Extension(access): Extension<Access>| async move {
+                list_invites(invites_list, access).await
+            }),
+        )
+        .route(
+            "/api/invites/:token",
+            delete(
+                |Extension(access): Extension<Access>, Path(token): Path<String>| async move {
+                    revoke_invite(invites_revoke, access, token).await
+                },
+            ),
+        )
+        .route(
+            "/api/webhooks",
+            post(
+                |Extension(access): Extension<Access>, Json(req): Json<WebhookRequest>| async move {
+                    register_webhook(webhooks_register, access, req).await
+                },
+            )
+            .get(|Extension(access): Extension<Access>| async move {
+                list_webhooks(webhooks_list, access).await
+            }),
+        )
+        .route(
+            "/api/webhooks/:id",
+            delete(
+                |Extension(access): Extension<Access>, Path(id): Path<Uuid>| async move {
+                    remove_webhook(webhooks_remove, access, id).await
+                },
+            ),
+        )
+        .route(
+            "/api/admin/jobs",
+            get(|headers: HeaderMap, Query(query): Query<AdminJobsQuery>| async move {
+                admin_jobs.jobs(headers, query).await
+            })
+            .delete(|headers: HeaderMap, Query(query): Query<AdminJobsQuery>| async move {
+                admin_purge.purge(headers, query).await
+            }),
+        )
+        .route(
+            "/api/admin/usage",
+            get(|headers: HeaderMap| async move { admin_usage.usage(headers).await }),
+        )
+        .route(
+            "/api/admin/models/evict",
+            post(|headers: HeaderMap| async move { admin_models.evict_models(headers).await }),
+        )
+        .route(
+            "/api/admin/gc",
+            post(|headers: HeaderMap| async move { admin_gc.gc(headers).await }),
+        )
+        .route(
+            "/api/users",
+            post(|Json(req): Json<RegisterRequest>| async move {
+                register_user(users_register, req).await
+            }),
+        )
+        .route("/healthz", get(healthz))
+        .route(
+            "/readyz",
+            get(|| async move { readyz(health_backend, ready_info).await }),
+        )
+        .route("/version", get(|| async move { version(version_info).await }))
+        .route("/api/openapi.json", get(|| async { Json(openapi()) }))
+        .route("/api/docs", get(swagger_ui))
         .route(
             "/ws",
-            get(|ws: WebSocketUpgrade| async move {
-                let ws_handler = ws_handler.clone();
+            get(
+                |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                 Extension(access): Extension<Access>,
+                 Extension(user): Extension<Option<User>>,
+                 ws: WebSocketUpgrade| async move {
+                let mut ws_handler = ws_handler.for_connection();
+                *ws_handler.access.write().unwrap() = access;
+                ws_handler.client_ip = addr.ip();
+                ws_handler.user = user;
                 ws.on_upgrade(move |ws| ws_handler.handle(ws))
             }),
+        )
+        .route(
+            "/ws/observer",
+            get(
+                |Extension(access): Extension<Access>, ws: WebSocketUpgrade| async move {
+                    observe(observer_ws_handler, access, ws).await
+                },
+            ),
         );
 
-    let port = opts.port;
-    let host = if opts.expose { "0.0.0.0" } else { "127.0.0.1" };
-    let advertised = if opts.expose {
+    let server = opts.server;
+    let app = app.layer(body_limit());
+    let app = app.layer(middleware::from_fn(move |req: Request, next: Next| {
+        users_layer.clone().middleware(req, next)
+    }));
+    let auth_requirement = auth.requirement();
+    let app = app.layer(middleware::from_fn(
+        move |info: ConnectInfo<SocketAddr>, req: Request, next: Next| {
+            auth.clone().middleware(info, req, next)
+        },
+    ));
+    let app = match cors_layer(&server.cors_origins, &server.cors_methods)? {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+
+    let port = server.port;
+    let host = match &server.bind_address {
+        Some(address) => address.as_str(),
+        None if server.expose => "0.0.0.0",
+        None => "127.0.0.1",
+    };
+    let advertised = if server.expose || server.bind_address.is_some() {
         hostname::get()
             .unwrap_or_default()
             .to_str()
@@ -58,21 +531,134 @@ pub async fn run<T: JobProcessor + 'static>(
     } else {
         "localhost".to_string()
     };
-    let listener = tokio::net::TcpListener::bind(format!("{host}:{port}")).await?;
-    let addr = format!("http://{advertised}:{port}");
-    info!("MusicGPT running at {addr}");
-    if opts.auto_open {
+    if (server.expose || proxied) && opts.api_key.is_none() {
+        info!("Remote clients need an invite link to use the server, set an API key to \
+               give them full access");
+    }
+    if opts.unix_socket_only && opts.unix_socket.is_none() {
+        anyhow::bail!("A Unix socket path is needed to serve only on a Unix socket");
+    }
+    let tcp = match opts.unix_socket_only {
+        true => None,
+        false => {
+            let tls = rustls_config(&server, &tls_storage, &advertised).await?;
+            let listener = tokio::net::TcpListener::bind(format!("{host}:{port}")).await?;
+            Some((listener, tls))
+        }
+    };
+    let unix_server = match &opts.unix_socket {
+        #[cfg(unix)]
+        Some(path) => Some(serve_unix(path, app.clone(), shutdown.clone())?),
+        #[cfg(not(unix))]
+        Some(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+        None => None,
+    };
+    if let Some(grpc_port) = server.grpc_port {
+        let grpc_addr = tokio::net::lookup_host(format!("{host}:{grpc_port}"))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Could not resolve {host}:{grpc_port}"))?;
+        let grpc_server = tonic::transport::Server::builder()
+            .add_service(grpc.into_service())
+            .serve_with_shutdown(grpc_addr, shutdown.clone().cancelled_owned());
+        info!("gRPC API running at {grpc_addr}");
+        tokio::spawn(async move {
+            if let Err(err) = grpc_server.await {
+                warn!("The gRPC API stopped: {err}");
+            }
+        });
+    }
+    let addr = format!("{scheme}://{advertised}:{port}");
+    if tcp.is_some() {
+        info!("MusicGPT running at {addr}");
+    }
+    webhooks.run(&webhooks_broadcast_tx, addr.clone());
+    if server.expose && server.mdns && tcp.is_some() {
+        let ad = Advertisement {
+            hostname: advertised.clone(),
+            port: port as u16,
+            scheme,
+            auth: auth_requirement,
+        };
+        // Discovery is a convenience, the server works the same without it.
+        if let Err(err) = advertise(ad, shutdown.clone()) {
+            warn!("Could not advertise the server on the local network: {err}");
+        }
+    }
+    if server.auto_open && tcp.is_some() {
         let _ = open::that(addr);
     }
 
-    Ok(axum::serve(listener, app).await?)
+    // The connections are closed once the running jobs are done, so that their
+    // clients still get the results.
+    let grace_period = server.shutdown_grace_period.0;
+    let shutdown_clone = shutdown.clone();
+    tokio::spawn(async move {
+        signal().await;
+        drain(&drained_backend, &in_flight, grace_period).await;
+        shutdown_clone.cancel();
+    });
+
+    let unix_server = tokio::spawn(async move {
+        if let Some(unix_server) = unix_server {
+            unix_server.await
+        }
+    });
+    match tcp {
+        Some((listener, Some(tls))) => {
+            let handle = axum_server::Handle::new();
+            let handle_clone = handle.clone();
+            tokio::spawn(async move {
+                shutdown.cancelled().await;
+                handle_clone.graceful_shutdown(Some(CLOSE_TIMEOUT));
+            });
+            axum_server::from_tcp_rustls(listener.into_std()?, tls)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?
+        }
+        Some((listener, None)) => {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await?
+        }
+        None => {}
+    }
+    unix_server.await?;
+    info!("MusicGPT stopped");
+    Ok(())
 }
 
-async fn web_app() -> Html<&'static str> {
-    Html(include_str!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/web/dist/index.html"
-    )))
+/// Allows cross-origin requests from `origins`, where `*` means any origin, with
+/// `methods`, or any method if empty.
+fn cors_layer(origins: &[String], methods: &[String]) -> anyhow::Result<Option<CorsLayer>> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+    let allow_origin = if origins.iter().any(|v| v == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|v| HeaderValue::from_str(v))
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    let allow_methods = if methods.is_empty() {
+        AllowMethods::any()
+    } else {
+        let methods = methods
+            .iter()
+            .map(|v| Method::from_bytes(v.to_uppercase().as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowMethods::list(methods)
+    };
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(allow_methods)
+            .allow_headers(Any),
+    ))
 }
 
 #[cfg(test)]
@@ -85,14 +671,38 @@ mod tests {
     use serde::de::DeserializeOwned;
     use serde::Serialize;
     use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
     use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
     use uuid::Uuid;
 
     use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::backend::admin::{PurgeReport, ADMIN_HEADER};
+    use crate::backend::audio_generation_fanout::GenerationMessage;
+    use crate::backend::batches::BatchStatus;
+    use crate::backend::bulk::{BulkAction, BulkRequest, ChatFilter};
+    use crate::backend::errors::{ApiError, ErrorCode};
+    use crate::backend::grpc;
+    use crate::backend::grpc::proto::generate_event::Event;
+    use crate::backend::grpc::proto::music_gpt_client::MusicGptClient;
+    use crate::backend::grpc::proto::{GenerateRequest, JobRequest};
+    use crate::backend::job_store::{JobState, JobStatus, Usage};
+    use crate::backend::limits::{InvalidReason, MAX_BODY_BYTES, MAX_PROMPT_CHARS, MAX_WS_MESSAGE_BYTES};
+    use crate::backend::melodies::Melody;
     use crate::backend::music_gpt_chat::{AiChatEntry, ChatEntry, UserChatEntry};
+    use crate::backend::observer_ws_handler::ObserverMsg;
+    use crate::backend::rate_limit::QueueFull;
+    use crate::backend::rest_api::JobPage;
+    use crate::backend::sessions::{ResumeRequest, ResumeResponse};
+    use crate::backend::users::USER_HEADER;
+    use crate::backend::webhooks::{WebhookEvent, WebhookEventKind, EVENT_HEADER};
     use crate::backend::music_gpt_ws_handler::{
-        AbortGenerationRequest, ChatRequest, GenerateAudioRequest, InboundMsg, OutboundMsg,
+        AbortGenerationRequest, ChatRequest, CommitPreviewRequest, GenerateAudioRequest,
+        InboundMsg, OutboundMsg, SetProfileRequest, SubscribeJobRequest, TokenTapRequest,
+        VersionMismatch, AUDIO_FRAME_MAGIC, MIN_PROTOCOL_VERSION, PREVIEW_SECS, PROTOCOL_VERSION,
     };
+    use crate::audio_manager::AudioManager;
+    use crate::config_units::HumanDuration;
+    use crate::music_gen_config::{GenerationConfig, RateLimitConfig, SecretRef};
 
     use super::*;
 
@@ -107,6 +717,7 @@ mod tests {
             chat_id,
             prompt: "Create a cool song".to_string(),
             secs: 4,
+            config: None,
         })
         .to_ws(&mut ws)
         .await?;
@@ -119,9 +730,12 @@ mod tests {
         assert_eq!(p.id, id);
         assert_eq!(p.chat_id, chat_id);
         assert_eq!(p.progress, 0.25);
+        assert_eq!(p.percent, 25.0);
+        assert_eq!((p.tokens, p.total_tokens), (Some(50), Some(200)));
 
         let p = OutboundMsg::from_ws(&mut ws).await?.progress();
         assert_eq!(p.progress, 0.5);
+        assert_eq!(p.tokens, Some(100));
 
         let p = OutboundMsg::from_ws(&mut ws).await?.progress();
         assert_eq!(p.progress, 0.75);
@@ -133,13 +747,234 @@ mod tests {
         assert_eq!(p.id, id);
         assert_eq!(p.chat_id, chat_id);
         assert_eq!(p.relpath, format!("audios/{id}.wav"));
+        // Unseeded jobs are reported with the seed they were given.
+        assert!(p.seed.is_some());
 
-        let res = reqwest::get(format!("http://{host}/files/audios/{id}.wav")).await?;
+        let res = reqwest::get(format!("http://{host}/api/audio/{id}.wav")).await?;
         assert_eq!(res.status(), 200);
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn negotiates_capabilities() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        InboundMsg::Hello(Capabilities {
+            protocol_version: PROTOCOL_VERSION + 1,
+            binary_frames: true,
+            compression: true,
+            streaming_audio: false,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        let msg = ws.next().await.unwrap()?;
+        assert!(msg.is_binary());
+        let OutboundMsg::Welcome(capabilities) = serde_json::from_slice(&msg.into_data())? else {
+            panic!("expected a welcome message")
+        };
+        assert_eq!(
+            capabilities,
+            Capabilities {
+                protocol_version: PROTOCOL_VERSION,
+                binary_frames: true,
+                compression: true,
+                streaming_audio: false,
+            }
+        );
+
+        InboundMsg::GetProfiles.to_ws(&mut ws).await?;
+        let msg = ws.next().await.unwrap()?;
+        assert!(msg.is_binary());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_protocol_versions() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        ws.send(tokio_tungstenite::tungstenite::Message::Text(
+            r#"{"NotAMessage":{}}"#.to_string(),
+        ))
+        .await?;
+        let OutboundMsg::Invalid(err) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("expected an error")
+        };
+        assert_eq!(err.reason, InvalidReason::Malformed);
+        assert!(err.message.contains(&format!("protocol version {PROTOCOL_VERSION}")));
+
+        InboundMsg::Hello(Capabilities {
+            protocol_version: MIN_PROTOCOL_VERSION - 1,
+            ..Capabilities::legacy()
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let OutboundMsg::VersionMismatch(mismatch) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("expected a version mismatch")
+        };
+        assert_eq!(
+            mismatch,
+            VersionMismatch {
+                client_version: MIN_PROTOCOL_VERSION - 1,
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            }
+        );
+        let msg = ws.next().await.unwrap()?;
+        let tokio_tungstenite::tungstenite::Message::Close(Some(frame)) = msg else {
+            panic!("expected a close frame")
+        };
+        assert_eq!(u16::from(frame.code), 1002);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streams_audio_as_binary_frames() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        InboundMsg::Hello(Capabilities {
+            streaming_audio: true,
+            ..Capabilities::legacy()
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let OutboundMsg::Welcome(capabilities) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("expected a welcome message")
+        };
+        assert!(capabilities.streaming_audio);
+
+        let (id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "with audio".to_string(),
+            secs: 2,
+            config: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        let mut chunks = vec![];
+        loop {
+            let msg = ws.next().await.unwrap()?;
+            if msg.is_binary() {
+                let frame = msg.into_data();
+                assert_eq!(&frame[0..4], AUDIO_FRAME_MAGIC);
+                assert_eq!(&frame[4..20], id.as_bytes());
+                assert_eq!(&frame[20..36], chat_id.as_bytes());
+                let offset = u64::from_le_bytes(frame[36..44].try_into()?);
+                assert_eq!(u32::from_le_bytes(frame[44..48].try_into()?), 32000);
+                assert_eq!(u16::from_le_bytes(frame[48..50].try_into()?), 1);
+                let samples: Vec<i16> = frame[50..]
+                    .chunks(2)
+                    .map(|v| i16::from_le_bytes([v[0], v[1]]))
+                    .collect();
+                chunks.push((offset, samples));
+                continue;
+            }
+            if let OutboundMsg::Generation(GenerationMessage::Result(_)) =
+                serde_json::from_slice(&msg.into_data())?
+            {
+                break;
+            }
+        }
+        assert_eq!(chunks, vec![(0, vec![0]), (1, vec![32767])]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumes_a_session_after_reconnecting() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::new(Duration::from_millis(50))).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let session_id = Uuid::new_v4();
+        let secret = None;
+        InboundMsg::Resume(ResumeRequest { session_id, secret }).to_ws(&mut ws).await?;
+        let OutboundMsg::Resumed(res) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("expected the session")
+        };
+        assert!(!res.resumed);
+
+        let (id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: 4,
+            config: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        OutboundMsg::from_ws(&mut ws).await?.start();
+        ws.close(None).await?;
+        // The job finishes while the client is away.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let (mut ws, _) = connect_async(&format!("ws://{host}/ws")).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        let secret = Some(res.secret);
+        InboundMsg::Resume(ResumeRequest { session_id, secret }).to_ws(&mut ws).await?;
+        let (mut resumed, mut result) = (None, None);
+        while resumed.is_none() || result.is_none() {
+            match OutboundMsg::from_ws(&mut ws).await? {
+                OutboundMsg::Resumed(res) => resumed = Some(res),
+                OutboundMsg::Generation(GenerationMessage::Progress(p)) => assert_eq!(p.id, id),
+                OutboundMsg::Generation(GenerationMessage::Result(r)) => result = Some(r),
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+        let secret = res.secret;
+        assert_eq!(resumed, Some(ResumeResponse { session_id, resumed: true, secret }));
+        assert_eq!(result.unwrap().id, id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn late_subscribers_catch_up_with_a_running_job() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::new(Duration::from_millis(100))).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let (id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: 6,
+            config: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        OutboundMsg::from_ws(&mut ws).await?.start();
+        OutboundMsg::from_ws(&mut ws).await?.progress();
+
+        // Another tab opens while the job runs.
+        let (mut other, _) = connect_async(&format!("ws://{host}/ws")).await?;
+        OutboundMsg::from_ws(&mut other).await?.info();
+        OutboundMsg::from_ws(&mut other).await?.chats();
+        InboundMsg::SubscribeJob(SubscribeJobRequest { id, chat_id }).to_ws(&mut other).await?;
+        let (mut start, mut result) = (None, None);
+        while result.is_none() {
+            match OutboundMsg::from_ws(&mut other).await? {
+                OutboundMsg::Generation(GenerationMessage::Start(m)) => start = Some(m),
+                OutboundMsg::Generation(GenerationMessage::Progress(p)) => assert_eq!(p.id, id),
+                OutboundMsg::Generation(GenerationMessage::Result(r)) => result = Some(r),
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+        assert_eq!(start.unwrap().prompt, "Create a cool song");
+        assert_eq!(result.unwrap().id, id);
+        Ok(())
+    }
+
     // TODO: for some reason this test fails in CI with a timeout.
     #[cfg(not(target_os = "macos"))]
     #[tokio::test]
@@ -153,6 +988,7 @@ mod tests {
             chat_id,
             prompt: "Create a cool song".to_string(),
             secs: 4,
+            config: None,
         })
         .to_ws(&mut ws)
         .await?;
@@ -172,10 +1008,9 @@ mod tests {
         assert_eq!(p.chat_id, chat_id);
         assert_eq!(p.progress, 0.25);
 
-        let p = OutboundMsg::from_ws(&mut ws).await?.error();
+        let p = OutboundMsg::from_ws(&mut ws).await?.cancelled();
         assert_eq!(p.id, id);
         assert_eq!(p.chat_id, chat_id);
-        assert_eq!(p.error, "Aborted");
 
         Ok(())
     }
@@ -191,6 +1026,7 @@ mod tests {
             chat_id,
             prompt: "fail at 2".to_string(),
             secs: 4,
+            config: None,
         })
         .to_ws(&mut ws)
         .await?;
@@ -211,6 +1047,7 @@ mod tests {
         assert_eq!(p.id, id);
         assert_eq!(p.chat_id, chat_id);
         assert_eq!(p.error, "Failed at 2");
+        assert_eq!(p.code, ErrorCode::Internal);
 
         Ok(())
     }
@@ -229,6 +1066,7 @@ mod tests {
             chat_id,
             prompt: "foo".to_string(),
             secs: 1,
+            config: None,
         })
         .to_ws(&mut ws)
         .await?;
@@ -269,49 +1107,1318 @@ mod tests {
         Ok(())
     }
 
-    #[async_trait]
-    trait TungsteniteMsg: Sized {
-        async fn to_ws(
-            self,
-            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-        ) -> anyhow::Result<()>;
+    #[tokio::test]
+    async fn streams_tokens_to_tapped_connections() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
 
-        async fn from_ws(
-            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-        ) -> anyhow::Result<Self>;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::TapTokens(TokenTapRequest { id, chat_id })
+            .to_ws(&mut ws)
+            .await?;
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "with tokens".to_string(),
+            secs: 2,
+            config: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.start();
+
+        let t = OutboundMsg::from_ws(&mut ws).await?.tokens();
+        assert_eq!(t.id, id);
+        assert_eq!(t.chat_id, chat_id);
+        assert_eq!(t.tokens, [0, 0, 0, 0]);
+        OutboundMsg::from_ws(&mut ws).await?.progress();
+
+        let t = OutboundMsg::from_ws(&mut ws).await?.tokens();
+        assert_eq!(t.tokens, [1, 1, 1, 1]);
+        OutboundMsg::from_ws(&mut ws).await?.progress();
+
+        OutboundMsg::from_ws(&mut ws).await?.result();
+
+        Ok(())
     }
 
-    #[async_trait]
-    impl<T: Serialize + DeserializeOwned + Send> TungsteniteMsg for T {
-        async fn to_ws(
-            self,
-            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-        ) -> anyhow::Result<()> {
-            let msg = serde_json::to_string(&self).expect("Could not serialize msg");
-            Ok(ws
-                .send(tokio_tungstenite::tungstenite::Message::Text(msg))
-                .await?)
-        }
+    #[tokio::test]
+    async fn switches_profiles_with_chat_commands() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
 
-        async fn from_ws(
-            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-        ) -> anyhow::Result<Self> {
-            let msg = ws.next().await.unwrap().unwrap();
-            Ok(serde_json::de::from_str(msg.to_text()?)?)
-        }
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let profile = ConfigProfile {
+            name: "fast".to_string(),
+            config: GenerationConfig { top_k: Some(5), ..Default::default() },
+        };
+        InboundMsg::SaveProfile(profile.clone())
+            .to_ws(&mut ws)
+            .await?;
+        let profiles = OutboundMsg::from_ws(&mut ws).await?.profiles();
+        assert_eq!(profiles.active, None);
+        assert_eq!(profiles.profiles, vec![profile]);
+
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
+            prompt: "/profile fast".to_string(),
+            secs: 1,
+            config: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let profiles = OutboundMsg::from_ws(&mut ws).await?.profiles();
+        assert_eq!(profiles.active, Some("fast".to_string()));
+
+        InboundMsg::SetProfile(SetProfileRequest { name: None })
+            .to_ws(&mut ws)
+            .await?;
+        let profiles = OutboundMsg::from_ws(&mut ws).await?.profiles();
+        assert_eq!(profiles.active, None);
+
+        Ok(())
     }
 
-    static PORT: AtomicU16 = AtomicU16::new(8643);
+    #[tokio::test]
+    async fn only_the_owner_switches_profiles() -> anyhow::Result<()> {
+        std::env::set_var("MUSICGPT_TEST_PROFILES_API_KEY", "s3cr3t");
+        let api_key = SecretRef::Env("MUSICGPT_TEST_PROFILES_API_KEY".to_string()).resolve()?;
+        let run_options = RunOptions {
+            // Otherwise the local test client is the owner.
+            server: ServerConfig { behind_proxy: true, ..Default::default() },
+            api_key: Some(api_key),
+            ..test_run_options()
+        };
+        let (_, host) = spawn_with_options(DummyJobProcessor::default(), run_options).await?;
+        let res = reqwest::Client::new()
+            .post(format!("http://{host}/api/invites"))
+            .bearer_auth("s3cr3t")
+            .header("Content-Type", "application/json")
+            .body(r#"{ "max_generations": 3 }"#)
+            .send()
+            .await?;
+        let link: serde_json::Value = serde_json::from_slice(&res.bytes().await?)?;
+        let token = link["token"].as_str().unwrap();
+        let (mut ws, _) = connect_async(&format!("ws://{host}/ws?invite={token}")).await?;
 
-    async fn spawn<P: JobProcessor + 'static>(
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        InboundMsg::SetProfile(SetProfileRequest { name: None })
+            .to_ws(&mut ws)
+            .await?;
+        let OutboundMsg::Error(err) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("expected an error")
+        };
+        assert_eq!(err.code, ErrorCode::Forbidden);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn runs_bulk_operations() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        InboundMsg::GenerateAudioNewChat(GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
+            prompt: "Create a cool song".to_string(),
+            secs: 1,
+            config: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        loop {
+            if let OutboundMsg::Generation(GenerationMessage::Result(_)) =
+                OutboundMsg::from_ws(&mut ws).await?
+            {
+                break;
+            }
+        }
+
+        let bulk = |dry_run| InboundMsg::Bulk(BulkRequest {
+            filter: ChatFilter { all: true, ..Default::default() },
+            action: BulkAction::Delete,
+            dry_run,
+        });
+        bulk(true).to_ws(&mut ws).await?;
+        let progress = OutboundMsg::from_ws(&mut ws).await?.bulk_progress();
+        assert_eq!(progress.total, 1);
+        assert_eq!(progress.processed, 0);
+
+        bulk(false).to_ws(&mut ws).await?;
+        let (mut progress, mut chats) = (None, None);
+        for _ in 0..2 {
+            match OutboundMsg::from_ws(&mut ws).await? {
+                OutboundMsg::BulkProgress(p) => progress = Some(p),
+                OutboundMsg::Chats(c) => chats = Some(c),
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+        assert_eq!(progress.unwrap().processed, 1);
+        assert_eq!(chats.unwrap(), vec![]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn commits_previews_with_full_settings() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let preview_id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GeneratePreview(GenerateAudioRequest {
+            id: preview_id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: PREVIEW_SECS + 2,
+            config: Some(GenerationConfig { top_k: Some(10), ..Default::default() }),
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        let start = OutboundMsg::from_ws(&mut ws).await?.start();
+        assert_eq!(start.id, preview_id);
+        assert_eq!(start.secs, PREVIEW_SECS);
+        for _ in 0..PREVIEW_SECS {
+            OutboundMsg::from_ws(&mut ws).await?.progress();
+        }
+        OutboundMsg::from_ws(&mut ws).await?.result();
+
+        let id = Uuid::new_v4();
+        InboundMsg::CommitPreview(CommitPreviewRequest {
+            preview_id,
+            id,
+            chat_id,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        let start = OutboundMsg::from_ws(&mut ws).await?.start();
+        assert_eq!(start.id, id);
+        assert_eq!(start.secs, PREVIEW_SECS + 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_invalid_messages_without_closing_the_connection() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::default()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let huge = "a".repeat(MAX_WS_MESSAGE_BYTES + 1);
+        ws.send(tokio_tungstenite::tungstenite::Message::Text(huge)).await?;
+        let OutboundMsg::Invalid(err) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("expected an error")
+        };
+        assert_eq!(err.reason, InvalidReason::TooLarge);
+
+        InboundMsg::GenerateAudioNewChat(GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
+            prompt: "a".repeat(MAX_PROMPT_CHARS + 1),
+            secs: 1,
+            config: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let OutboundMsg::Invalid(err) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("expected an error")
+        };
+        assert_eq!(err.reason, InvalidReason::Invalid);
+
+        // Still usable.
+        InboundMsg::GetProfiles.to_ws(&mut ws).await?;
+        assert!(matches!(OutboundMsg::from_ws(&mut ws).await?, OutboundMsg::Profiles(_)));
+
+        let res = reqwest::Client::new()
+            .post(format!("http://{host}/api/generate"))
+            .header("Content-Type", "application/json")
+            .body(vec![b' '; MAX_BODY_BYTES + 1])
+            .send()
+            .await?;
+        assert_eq!(res.status(), 413);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() -> anyhow::Result<()> {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let server = ServerConfig {
+            ws_ping_interval: HumanDuration(Duration::from_millis(100)),
+            ws_idle_timeout: HumanDuration(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let (mut ws, _) = spawn_with(DummyJobProcessor::default(), server).await?;
+        // Nothing is read, so the pings are not answered.
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let mut pings = 0;
+        let frame = loop {
+            match ws.next().await.unwrap()? {
+                Message::Ping(_) => pings += 1,
+                Message::Close(frame) => break frame.unwrap(),
+                _ => continue,
+            }
+        };
+        assert!(pings > 0);
+        assert_eq!(u16::from(frame.code), 1001);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cannot_commit_unknown_previews() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let preview_id = Uuid::new_v4();
+        InboundMsg::CommitPreview(CommitPreviewRequest {
+            preview_id,
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        let OutboundMsg::Error(err) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("expected an error")
+        };
+        assert_eq!(err.code, ErrorCode::NotFound);
+        assert_eq!(err.message, format!("No pending preview with id {preview_id}"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cannot_commit_previews_of_other_connections() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::default()).await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let preview_id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GeneratePreview(GenerateAudioRequest {
+            id: preview_id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: PREVIEW_SECS,
+            config: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        OutboundMsg::from_ws(&mut ws).await?.start();
+        drop(ws);
+
+        let (mut other, _) = connect_async(&format!("ws://{host}/ws")).await?;
+        OutboundMsg::from_ws(&mut other).await?.info();
+        OutboundMsg::from_ws(&mut other).await?.chats();
+        InboundMsg::CommitPreview(CommitPreviewRequest {
+            preview_id,
+            id: Uuid::new_v4(),
+            chat_id,
+        })
+        .to_ws(&mut other)
+        .await?;
+
+        // Skips the messages of the preview, if this connection sees its chat.
+        let err = loop {
+            if let OutboundMsg::Error(err) = OutboundMsg::from_ws(&mut other).await? {
+                break err;
+            }
+        };
+        assert_eq!(err.code, ErrorCode::NotFound);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn observers_receive_aggregate_events() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::new(Duration::from_millis(400))).await?;
+        let (mut observer, _) = connect_async(&format!("ws://{host}/ws/observer")).await?;
+
+        let ObserverMsg::Info(_) = ObserverMsg::from_ws(&mut observer).await? else {
+            panic!("expected info")
+        };
+        let ObserverMsg::QueueDepth(0) = ObserverMsg::from_ws(&mut observer).await? else {
+            panic!("expected an empty queue")
+        };
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: 2,
+            config: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        let mut events = vec![];
+        loop {
+            match ObserverMsg::from_ws(&mut observer).await? {
+                ObserverMsg::JobStarted(job) => events.push(format!("started {}", job.id)),
+                ObserverMsg::QueueDepth(depth) => events.push(format!("depth {depth}")),
+                ObserverMsg::JobCompleted(job) => {
+                    events.push(format!("completed {}", job.id));
+                    break;
+                }
+                ObserverMsg::Telemetry(_) => {}
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+        assert!(events.contains(&format!("started {id}")));
+        assert!(events.contains(&"depth 1".to_string()));
+        assert_eq!(events.last(), Some(&format!("completed {id}")));
+
+        serde_json::json!({ "GenerateAudio": {} }).to_ws(&mut observer).await?;
+        loop {
+            match ObserverMsg::from_ws(&mut observer).await? {
+                ObserverMsg::Error(err) => {
+                    assert_eq!(err, "Observer connections are read-only");
+                    break;
+                }
+                ObserverMsg::QueueDepth(_) | ObserverMsg::Telemetry(_) => {}
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limits_clients() -> anyhow::Result<()> {
+        let rate_limit = RateLimitConfig {
+            jobs_per_minute: Some(1),
+            concurrent_jobs: None,
+        };
+        let server = ServerConfig {
+            rate_limit,
+            ..Default::default()
+        };
+        let (_, host) = spawn_with(DummyJobProcessor::default(), server).await?;
+        let client = reqwest::Client::new();
+
+        let req = RestGenerateRequest {
+            prompt: "Create a cool song".to_string(),
+            secs: 1,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let mut statuses = vec![];
+        for _ in 0..2 {
+            let res = client
+                .post(format!("http://{host}/api/generate"))
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_string(&req)?)
+                .send()
+                .await?;
+            statuses.push(res.status());
+            if res.status() == 429 {
+                assert!(res.headers().contains_key("retry-after"));
+                let body: serde_json::Value = serde_json::from_slice(&res.bytes().await?)?;
+                assert!(body["retry_after_secs"].as_u64().unwrap() > 0);
+            }
+        }
+        assert_eq!(statuses, vec![202, 429]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_jobs_when_the_queue_is_full() -> anyhow::Result<()> {
+        let run_options = RunOptions {
+            pipeline: PipelineConfig {
+                max_concurrent_jobs: Some(1),
+                max_waiting_jobs: Some(1),
+                ..Default::default()
+            },
+            ..test_run_options()
+        };
+        let processor = DummyJobProcessor::new(Duration::from_millis(100));
+        let (_, host) = spawn_with_options(processor, run_options).await?;
+        let client = reqwest::Client::new();
+
+        let req = RestGenerateRequest {
+            prompt: "Create a cool song".to_string(),
+            secs: 10,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let mut statuses = vec![];
+        for _ in 0..3 {
+            let res = client
+                .post(format!("http://{host}/api/generate"))
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_string(&req)?)
+                .send()
+                .await?;
+            statuses.push(res.status());
+            if res.status() == 503 {
+                assert!(res.headers().contains_key("retry-after"));
+                let full: QueueFull = serde_json::from_slice(&res.bytes().await?)?;
+                assert_eq!(full.position, 1);
+            }
+            // Gives the backend time to start the first job.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(statuses, vec![202, 202, 503]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delivers_webhooks() -> anyhow::Result<()> {
+        let (events_tx, mut events_rx) = tokio::sync::mpsc::unbounded_channel();
+        let receiver = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, Json(event): Json<WebhookEvent>| async move {
+                let kind = headers[EVENT_HEADER].to_str().unwrap().to_string();
+                let _ = events_tx.send((kind, event));
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let hook_url = format!("http://{}/hook", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("http://{host}/api/webhooks"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::json!({ "url": hook_url }).to_string())
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+
+        let req = RestGenerateRequest {
+            prompt: "Create a cool song".to_string(),
+            secs: 1,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let res = client
+            .post(format!("http://{host}/api/generate"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+
+        let mut kinds = vec![];
+        loop {
+            let (kind, event) = events_rx.recv().await.unwrap();
+            assert_eq!(event.id, status.id);
+            kinds.push(kind);
+            if event.event == WebhookEventKind::Completed {
+                let download_url = event.download_url.unwrap();
+                assert!(download_url.ends_with(&format!("/api/audio/{}.wav", status.id)));
+                break;
+            }
+        }
+        // Nothing else was running, so the job never waited in the queue.
+        assert_eq!(kinds, vec!["started", "completed"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mints_and_revokes_invites() -> anyhow::Result<()> {
+        let (_, host) = spawn_with(
+            DummyJobProcessor::default(),
+            ServerConfig {
+                invite_only: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let client = reqwest::Client::new();
+
+        let res = client
+            .post(format!("http://{host}/api/invites"))
+            .header("Content-Type", "application/json")
+            .body(r#"{ "max_generations": 3 }"#)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        let link: serde_json::Value = serde_json::from_slice(&res.bytes().await?)?;
+        let token = link["token"].as_str().unwrap();
+        assert_eq!(link["url"], format!("http://{host}/?invite={token}"));
+        assert_eq!(link["max_generations"], 3);
+
+        let res = reqwest::get(format!("http://{host}/api/invites")).await?;
+        let invites: Vec<serde_json::Value> = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(invites.len(), 1);
+
+        let url = format!("http://{host}/api/invites/{token}");
+        assert_eq!(client.delete(&url).send().await?.status(), 204);
+        assert_eq!(client.delete(&url).send().await?.status(), 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn allows_configured_cors_origins() -> anyhow::Result<()> {
+        let server = ServerConfig {
+            cors_origins: vec!["https://example.com".to_string()],
+            cors_methods: vec!["GET".to_string(), "post".to_string()],
+            ..ServerConfig::default()
+        };
+        let (_, host) = spawn_with(DummyJobProcessor::default(), server).await?;
+
+        let client = reqwest::Client::new();
+        let res = client
+            .get(format!("http://{host}/api/shadow/report"))
+            .header("Origin", "https://example.com")
+            .send()
+            .await?;
+        assert_eq!(
+            res.headers()["access-control-allow-origin"],
+            "https://example.com"
+        );
+
+        let res = client
+            .get(format!("http://{host}/api/shadow/report"))
+            .header("Origin", "https://other.com")
+            .send()
+            .await?;
+        assert!(res.headers().get("access-control-allow-origin").is_none());
+
+        let res = client
+            .request(Method::OPTIONS, format!("http://{host}/api/generate"))
+            .header("Origin", "https://example.com")
+            .header("Access-Control-Request-Method", "POST")
+            .send()
+            .await?;
+        assert_eq!(res.headers()["access-control-allow-methods"], "GET,POST");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_health_and_version() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+
+        let res = reqwest::get(format!("http://{host}/healthz")).await?;
+        assert_eq!(res.status(), 200);
+
+        let res = reqwest::get(format!("http://{host}/readyz")).await?;
+        assert_eq!(res.status(), 200);
+        let readiness: serde_json::Value = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(readiness["ready"], true);
+        assert_eq!(readiness["warming_up"], false);
+        assert_eq!(readiness["queue_depth"], 0);
+
+        let res = reqwest::get(format!("http://{host}/version")).await?;
+        let version: serde_json::Value = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streams_the_audio_while_it_is_generated() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::new(Duration::from_millis(100))).await?;
+        let client = reqwest::Client::new();
+        let req = RestGenerateRequest {
+            prompt: "with audio".to_string(),
+            secs: 4,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let res = client
+            .post(format!("http://{host}/api/generate"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+
+        let res = reqwest::get(format!("http://{host}/api/jobs/{}/stream", status.id)).await?;
+        assert_eq!(res.headers()["content-type"], "audio/wav");
+        let bytes = res.bytes().await?;
+        assert_eq!(&bytes[..4], b"RIFF");
+        // The header and one 16 bit sample per second.
+        assert_eq!(bytes.len(), 44 + 4 * 2);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let res = reqwest::get(format!("http://{host}/api/jobs/{}/stream", status.id)).await?;
+        assert_eq!(res.url().path(), format!("/api/audio/{}.wav", status.id));
+        assert_eq!(res.status(), 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn separates_the_stems_on_demand() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let client = reqwest::Client::new();
+        let req = RestGenerateRequest {
+            prompt: "with stems".to_string(),
+            secs: 2,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let res = client
+            .post(format!("http://{host}/api/generate"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let stems = format!("http://{host}/api/jobs/{}/stems", status.id);
+        for _ in 0..2 {
+            let res = reqwest::get(format!("{stems}/bass")).await?;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.headers()["content-type"], "audio/wav");
+            assert_eq!(&res.bytes().await?[..4], b"RIFF");
+        }
+        let res = reqwest::get(format!("{stems}/drums")).await?;
+        assert_eq!(res.status(), 404);
+        let missing = Uuid::new_v4();
+        let res = reqwest::get(format!("http://{host}/api/jobs/{missing}/stems/bass")).await?;
+        assert_eq!(res.status(), 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queries_the_chats_with_graphql() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let client = reqwest::Client::new();
+        let req = RestGenerateRequest {
+            prompt: "Create a cool song".to_string(),
+            secs: 1,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let res = client
+            .post(format!("http://{host}/api/generate"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let query = format!(
+            r#"{{ job(id: "{}") {{ state audioUrl }} chats {{ name tracks {{ prompt }} }} }}"#,
+            status.id
+        );
+        let res = client
+            .post(format!("http://{host}/api/graphql"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::json!({ "query": query }).to_string())
+            .send()
+            .await?;
+        let body: serde_json::Value = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(body["data"]["job"]["state"], "COMPLETED");
+        assert_eq!(body["data"]["chats"][0]["name"], "Create a cool song");
+        assert_eq!(body["data"]["chats"][0]["tracks"][0]["prompt"], "Create a cool song");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generates_through_grpc() -> anyhow::Result<()> {
+        let grpc_port = PORT.fetch_add(1, Ordering::SeqCst);
+        let server = ServerConfig {
+            grpc_port: Some(grpc_port as usize),
+            ..Default::default()
+        };
+        let (_, host) = spawn_with(DummyJobProcessor::default(), server).await?;
+        // The gRPC server starts listening on its own, it might not be there yet.
+        let mut client = None;
+        for _ in 0..20 {
+            match MusicGptClient::connect(format!("http://localhost:{grpc_port}")).await {
+                Ok(v) => {
+                    client = Some(v);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+        let mut client = client.expect("the gRPC server did not start");
+
+        let req = GenerateRequest {
+            prompt: "with audio".to_string(),
+            secs: 3,
+            ..Default::default()
+        };
+        let mut stream = client.generate_stream(req).await?.into_inner();
+        let Some(Event::Accepted(accepted)) = stream.message().await?.and_then(|v| v.event) else {
+            panic!("expected the job to be accepted")
+        };
+        let (mut pcm, mut finished) = (vec![], None);
+        while let Some(msg) = stream.message().await? {
+            match msg.event {
+                Some(Event::Progress(_)) => {}
+                Some(Event::Audio(chunk)) => pcm.extend(chunk.pcm),
+                Some(Event::Finished(job)) => finished = Some(job),
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        let finished = finished.unwrap();
+        assert_eq!(finished.id, accepted.id);
+        assert_eq!(finished.state(), grpc::proto::JobState::Completed);
+        // One 16 bit sample per second.
+        assert_eq!(pcm.len(), 6);
+
+        // The job store is updated from the same events, give it a moment.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let job = client.get_job(JobRequest { id: accepted.id.clone() }).await?.into_inner();
+        assert_eq!(job.audio_url, Some(format!("/api/audio/{}.wav", accepted.id)));
+        // Registered users do not see the jobs of others.
+        let res = reqwest::Client::new()
+            .post(format!("http://{host}/api/users"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::json!({ "name": "alice" }).to_string())
+            .send()
+            .await?;
+        let alice = serde_json::from_slice::<User>(&res.bytes().await?)?;
+        let mut req = tonic::Request::new(JobRequest { id: accepted.id.clone() });
+        req.metadata_mut().insert(USER_HEADER, alice.token.parse()?);
+        assert_eq!(client.get_job(req).await.unwrap_err().code(), tonic::Code::NotFound);
+        let mut req = tonic::Request::new(JobRequest { id: accepted.id.clone() });
+        req.metadata_mut().insert(USER_HEADER, alice.token.parse()?);
+        assert_eq!(client.cancel_job(req).await.unwrap_err().code(), tonic::Code::NotFound);
+        let err = client.cancel_job(JobRequest { id: accepted.id }).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        let err = client.get_job(JobRequest { id: "nope".to_string() }).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generates_through_the_rest_api() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let client = reqwest::Client::new();
+
+        let req = RestGenerateRequest {
+            prompt: "Create a cool song".to_string(),
+            secs: 2,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let res = client
+            .post(format!("http://{host}/api/generate"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 202);
+        let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+
+        let status = loop {
+            let res = reqwest::get(format!("http://{host}/api/jobs/{}", status.id)).await?;
+            let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+            if status.state == JobState::Completed {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let audio_url = format!("http://{host}{}", status.audio_url.unwrap());
+        let res = reqwest::get(&audio_url).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "audio/wav");
+        assert_eq!(res.headers()["accept-ranges"], "bytes");
+        let wav = res.bytes().await?;
+
+        let res = client.get(&audio_url).header("Range", "bytes=10-19").send().await?;
+        assert_eq!(res.status(), 206);
+        assert_eq!(res.headers()["content-range"], format!("bytes 10-19/{}", wav.len()));
+        assert_eq!(res.bytes().await?, wav[10..20]);
+        let res = client.get(&audio_url).header("Range", "bytes=-4").send().await?;
+        assert_eq!(res.bytes().await?, wav[wav.len() - 4..]);
+        let res = client
+            .get(&audio_url)
+            .header("Range", format!("bytes={}-", wav.len()))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 416);
+
+        let res = reqwest::get(format!("http://{host}/api/jobs/{}", Uuid::new_v4())).await?;
+        assert_eq!(res.status(), 404);
+        let res = reqwest::get(format!("http://{host}/api/audio/{}.wav", Uuid::new_v4())).await?;
+        assert_eq!(res.status(), 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conditions_jobs_on_uploaded_melodies() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let client = reqwest::Client::new();
+
+        // Over the limit of the other endpoints.
+        let wav = AudioManager::default().to_wav(vec![0.1; 32000 * 20].into())?;
+        assert!(wav.len() > MAX_BODY_BYTES);
+        let res = client.post(format!("http://{host}/api/melodies")).body(wav).send().await?;
+        assert_eq!(res.status(), 200);
+        let melody: Melody = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(melody.secs, 20.0);
+
+        let generate = |melody: Uuid| {
+            let req = RestGenerateRequest {
+                prompt: "Create a cool song".to_string(),
+                secs: 2,
+                chat_id: None,
+                config: Some(GenerationConfig { melody: Some(melody), ..Default::default() }),
+                priority: None,
+            };
+            client
+                .post(format!("http://{host}/api/generate"))
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_string(&req).unwrap())
+                .send()
+        };
+        assert_eq!(generate(melody.id).await?.status(), 202);
+        let res = generate(Uuid::new_v4()).await?;
+        assert_eq!(res.status(), 404);
+        let err: ApiError = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(err.code, ErrorCode::NotFound);
+
+        let res = client.post(format!("http://{host}/api/melodies")).body("nope").send().await?;
+        assert_eq!(res.status(), 400);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_on_a_unix_socket() -> anyhow::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("musicgpt-{}.sock", Uuid::new_v4()));
+        let run_options = RunOptions {
+            unix_socket: Some(path.clone()),
+            ..test_run_options()
+        };
+        spawn_with_options(DummyJobProcessor::default(), run_options).await?;
+
+        for (uri, body) in [("/healthz", "ok"), ("/api/jobs", r#""jobs":[]"#)] {
+            let mut stream = tokio::net::UnixStream::connect(&path).await?;
+            let req = format!("GET {uri} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            stream.write_all(req.as_bytes()).await?;
+            let mut res = String::new();
+            stream.read_to_string(&mut res).await?;
+            assert!(res.starts_with("HTTP/1.1 200"), "{res}");
+            assert!(res.contains(body), "{res}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn manages_the_jobs_through_the_admin_api() -> anyhow::Result<()> {
+        std::env::set_var("MUSICGPT_TEST_ADMIN_TOKEN", "4dm1n");
+        let admin_token = SecretRef::Env("MUSICGPT_TEST_ADMIN_TOKEN".to_string()).resolve()?;
+        let run_options = RunOptions {
+            admin_token: Some(admin_token),
+            ..test_run_options()
+        };
+        let (_, host) = spawn_with_options(DummyJobProcessor::default(), run_options).await?;
+        let client = reqwest::Client::new();
+
+        let req = RestGenerateRequest {
+            prompt: "Create a cool song".to_string(),
+            secs: 2,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let res = client
+            .post(format!("http://{host}/api/generate"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+        loop {
+            let res = reqwest::get(format!("http://{host}/api/jobs/{}", status.id)).await?;
+            let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+            if status.state == JobState::Completed {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let res = reqwest::get(format!("http://{host}/api/admin/usage")).await?;
+        assert_eq!(res.status(), 403);
+        let admin_get = |path: &str| {
+            client
+                .get(format!("http://{host}{path}"))
+                .header(ADMIN_HEADER, "4dm1n")
+        };
+        let res = admin_get("/api/admin/usage").send().await?;
+        let usage: Vec<Usage> = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(usage.len(), 1);
+        assert_eq!((usage[0].jobs, usage[0].completed, usage[0].generated_secs), (1, 1, 2));
+
+        let res = admin_get("/api/admin/jobs?state=completed").send().await?;
+        let jobs: Vec<JobStatus> = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(jobs.iter().map(|v| v.id).collect::<Vec<_>>(), vec![status.id]);
+
+        let res = client
+            .delete(format!("http://{host}/api/admin/jobs"))
+            .header(ADMIN_HEADER, "4dm1n")
+            .send()
+            .await?;
+        let report: PurgeReport = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(report, PurgeReport { cancelled: 0, deleted: 1 });
+        let res = reqwest::get(format!("http://{host}/api/jobs/{}", status.id)).await?;
+        assert_eq!(res.status(), 404);
+
+        let res = client
+            .post(format!("http://{host}/api/admin/gc"))
+            .header(ADMIN_HEADER, "4dm1n")
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generates_batches_through_the_rest_api() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let client = reqwest::Client::new();
+
+        let req = RestBatchRequest {
+            prompts: vec!["A cool song".to_string(), "A sad song".to_string()],
+            secs: 2,
+            chat_id: None,
+            config: None,
+            num_outputs: None,
+            priority: None,
+        };
+        let res = client
+            .post(format!("http://{host}/api/generate/batch"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 202);
+        let status: BatchStatus = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(status.jobs.len(), 2);
+
+        let status = loop {
+            let res = reqwest::get(format!("http://{host}/api/batches/{}", status.id)).await?;
+            let status: BatchStatus = serde_json::from_slice(&res.bytes().await?)?;
+            if status.state == JobState::Completed {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(status.progress, 1.0);
+        assert!(status.jobs.iter().all(|v| v.chat_id == status.chat_id && v.audio_url.is_some()));
+
+        let empty = RestBatchRequest { prompts: vec![], ..req };
+        let res = client
+            .post(format!("http://{host}/api/generate/batch"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&empty)?)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 400);
+        let res = reqwest::get(format!("http://{host}/api/batches/{}", Uuid::new_v4())).await?;
+        assert_eq!(res.status(), 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pages_through_the_job_history() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let client = reqwest::Client::new();
+
+        let mut submitted = vec![];
+        for prompt in ["First song", "Second song", "Third song"] {
+            let req = RestGenerateRequest {
+                prompt: prompt.to_string(),
+                secs: 1,
+                chat_id: None,
+                config: None,
+                priority: None,
+            };
+            let res = client
+                .post(format!("http://{host}/api/generate"))
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_string(&req)?)
+                .send()
+                .await?;
+            submitted.push(serde_json::from_slice::<JobStatus>(&res.bytes().await?)?);
+        }
+
+        let res = reqwest::get(format!("http://{host}/api/jobs?limit=2")).await?;
+        let page: JobPage = serde_json::from_slice(&res.bytes().await?)?;
+        let ids: Vec<_> = page.jobs.iter().map(|v| v.id).collect();
+        assert_eq!(ids, vec![submitted[2].id, submitted[1].id]);
+        let next_page = page.next_page.unwrap();
+        let res = reqwest::get(format!("http://{host}/api/jobs?limit=2&page={next_page}")).await?;
+        let page: JobPage = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(page.jobs.iter().map(|v| v.id).collect::<Vec<_>>(), vec![submitted[0].id]);
+        assert_eq!(page.next_page, None);
+
+        let chat_id = submitted[1].chat_id;
+        let res = reqwest::get(format!("http://{host}/api/jobs?chat={chat_id}")).await?;
+        let page: JobPage = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(page.jobs.iter().map(|v| v.id).collect::<Vec<_>>(), vec![submitted[1].id]);
+
+        let res = reqwest::get(format!("http://{host}/api/jobs?page=nope")).await?;
+        assert_eq!(res.status(), 400);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn isolates_the_chats_of_each_user() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let client = reqwest::Client::new();
+        let mut users = vec![];
+        for name in ["alice", "bob"] {
+            let res = client
+                .post(format!("http://{host}/api/users"))
+                .header("Content-Type", "application/json")
+                .body(serde_json::json!({ "name": name }).to_string())
+                .send()
+                .await?;
+            assert!(res.headers()["set-cookie"].to_str()?.starts_with("musicgpt_user="));
+            users.push(serde_json::from_slice::<User>(&res.bytes().await?)?);
+        }
+        let (alice, bob) = (&users[0], &users[1]);
+
+        let req = RestGenerateRequest {
+            prompt: "Create a cool song".to_string(),
+            secs: 1,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let res = client
+            .post(format!("http://{host}/api/generate"))
+            .header(USER_HEADER, &alice.token)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+        let get_as = |url: &str, user: &User| client.get(url).header(USER_HEADER, &user.token).send();
+        let job_url = format!("http://{host}/api/jobs/{}", status.id);
+        loop {
+            let res = get_as(&job_url, alice).await?;
+            let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+            if status.state == JobState::Completed {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let audio_url = format!("http://{host}/api/audio/{}.wav", status.id);
+        assert_eq!(get_as(&audio_url, alice).await?.status(), 200);
+        assert_eq!(get_as(&audio_url, bob).await?.status(), 404);
+        assert_eq!(get_as(&job_url, bob).await?.status(), 404);
+        assert_eq!(client.get(&job_url).send().await?.status(), 404);
+
+        for (user, expected) in [(alice, 1), (bob, 0)] {
+            let mut req = format!("ws://{host}/ws").into_client_request()?;
+            req.headers_mut().insert(USER_HEADER, user.token.parse()?);
+            let (mut ws, _) = connect_async(req).await?;
+            OutboundMsg::from_ws(&mut ws).await?.info();
+            assert_eq!(OutboundMsg::from_ws(&mut ws).await?.chats().len(), expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streams_job_events() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::new(Duration::from_millis(10))).await?;
+        let client = reqwest::Client::new();
+
+        let req = RestGenerateRequest {
+            prompt: "Create a cool song".to_string(),
+            secs: 2,
+            chat_id: None,
+            config: None,
+            priority: None,
+        };
+        let res = client
+            .post(format!("http://{host}/api/generate"))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+
+        let res = reqwest::get(format!("http://{host}/api/jobs/{}/events", status.id)).await?;
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+        // The stream ends after the completion event.
+        let body = tokio::time::timeout(Duration::from_secs(5), res.text()).await??;
+        let events = body
+            .lines()
+            .filter_map(|l| l.strip_prefix("event: "))
+            .collect::<Vec<_>>();
+        assert_eq!(events.first(), Some(&"status"));
+        assert!(events.contains(&"progress"));
+        assert_eq!(events.last(), Some(&"completed"));
+
+        // Once finished, the stream only has the final status.
+        loop {
+            let res = reqwest::get(format!("http://{host}/api/jobs/{}", status.id)).await?;
+            let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+            if status.state == JobState::Completed {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let res = reqwest::get(format!("http://{host}/api/jobs/{}/events", status.id)).await?;
+        let body = res.text().await?;
+        assert_eq!(body.matches("event: ").count(), 1);
+        assert!(body.contains(r#""state":"completed""#));
+
+        let res = reqwest::get(format!("http://{host}/api/jobs/{}/events", Uuid::new_v4())).await?;
+        assert_eq!(res.status(), 404);
+        let err: ApiError = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(err.code, ErrorCode::NotFound);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancels_jobs_through_the_rest_api() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::new(Duration::from_millis(100))).await?;
+        let client = reqwest::Client::new();
+
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let req = RestGenerateRequest {
+                prompt: "Create a cool song".to_string(),
+                secs: 10,
+                chat_id: None,
+                config: None,
+                priority: None,
+            };
+            let res = client
+                .post(format!("http://{host}/api/generate"))
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_string(&req)?)
+                .send()
+                .await?;
+            let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+            ids.push(status.id);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // One job is running and the other one is still queued.
+        for id in ids.iter() {
+            let res = client.delete(format!("http://{host}/api/jobs/{id}")).send().await?;
+            assert_eq!(res.status(), 202);
+        }
+        for id in ids.iter() {
+            loop {
+                let res = reqwest::get(format!("http://{host}/api/jobs/{id}")).await?;
+                let status: JobStatus = serde_json::from_slice(&res.bytes().await?)?;
+                if status.state == JobState::Cancelled {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let res = client.delete(format!("http://{host}/api/jobs/{id}")).send().await?;
+            assert_eq!(res.status(), 409);
+        }
+
+        let res = client.delete(format!("http://{host}/api/jobs/{}", Uuid::new_v4())).send().await?;
+        assert_eq!(res.status(), 404);
+        Ok(())
+    }
+
+    #[async_trait]
+    trait TungsteniteMsg: Sized {
+        async fn to_ws(
+            self,
+            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        ) -> anyhow::Result<()>;
+
+        async fn from_ws(
+            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        ) -> anyhow::Result<Self>;
+    }
+
+    #[async_trait]
+    impl<T: Serialize + DeserializeOwned + Send> TungsteniteMsg for T {
+        async fn to_ws(
+            self,
+            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        ) -> anyhow::Result<()> {
+            let msg = serde_json::to_string(&self).expect("Could not serialize msg");
+            Ok(ws
+                .send(tokio_tungstenite::tungstenite::Message::Text(msg))
+                .await?)
+        }
+
+        async fn from_ws(
+            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        ) -> anyhow::Result<Self> {
+            let msg = ws.next().await.unwrap().unwrap();
+            Ok(serde_json::de::from_slice(&msg.into_data())?)
+        }
+    }
+
+    static PORT: AtomicU16 = AtomicU16::new(8643);
+
+    async fn spawn<P: JobProcessor + 'static>(
+        processor: P,
+    ) -> anyhow::Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, String)> {
+        spawn_with(processor, ServerConfig::default()).await
+    }
+
+    async fn spawn_with<P: JobProcessor + 'static>(
+        processor: P,
+        server: ServerConfig,
+    ) -> anyhow::Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, String)> {
+        let run_options = RunOptions {
+            server,
+            ..test_run_options()
+        };
+        spawn_with_options(processor, run_options).await
+    }
+
+    /// The options of the tests, which change only the ones they are about.
+    fn test_run_options() -> RunOptions {
+        RunOptions {
+            server: ServerConfig::default(),
+            profile: None,
+            config_profiles: vec![],
+            shadow: None,
+            pipeline: Default::default(),
+            api_key: None,
+            webhook_secret: None,
+            admin_token: None,
+            model_files: vec![],
+            unix_socket: None,
+            unix_socket_only: false,
+        }
+    }
+
+    async fn spawn_with_options<P: JobProcessor + 'static>(
         processor: P,
+        run_options: RunOptions,
     ) -> anyhow::Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, String)> {
         let app_fs = AppFs::new_tmp();
         let port = PORT.fetch_add(1, Ordering::SeqCst) as usize;
         let run_options = RunOptions {
-            port,
-            auto_open: false,
-            expose: false,
+            server: ServerConfig {
+                port,
+                auto_open: false,
+                ..run_options.server
+            },
+            ..run_options
         };
         tokio::spawn(run(app_fs, processor, run_options));
         let (ws_stream, _) = connect_async(&format!("ws://localhost:{port}/ws")).await?;
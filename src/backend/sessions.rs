@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+/// How long the session of a closed connection can still be resumed.
+pub const SESSION_TTL: Duration = Duration::from_secs(300);
+
+/// Sent by the client right after connecting, with an id it keeps across reconnections.
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct ResumeRequest {
+    pub session_id: Uuid,
+    /// The secret given by the server when the session was created, without which it
+    /// cannot be resumed.
+    #[serde(default)]
+    pub secret: Option<Uuid>,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ResumeResponse {
+    pub session_id: Uuid,
+    /// Whether a previous connection of the session was found. If so, the generation
+    /// messages it missed are sent again.
+    pub resumed: bool,
+    /// To be sent back for resuming the session after reconnecting.
+    pub secret: Uuid,
+}
+
+/// The state of a connection that outlives it, so that a client that reconnects
+/// re-attaches to its jobs.
+#[derive(Clone)]
+pub struct Session {
+    pub user_id: Option<Uuid>,
+    /// Issued by the server, so that knowing the id of the session is not enough for
+    /// taking it over.
+    pub secret: Uuid,
+    pub token_taps: Arc<RwLock<HashSet<Uuid>>>,
+    pub visible_chats: Arc<RwLock<HashSet<Uuid>>>,
+    /// Sequence number of the last generation message handled by the connection.
+    pub cursor: Arc<AtomicU64>,
+    detached_at: Option<Instant>,
+}
+
+impl Session {
+    pub fn new(
+        user_id: Option<Uuid>,
+        token_taps: Arc<RwLock<HashSet<Uuid>>>,
+        visible_chats: Arc<RwLock<HashSet<Uuid>>>,
+        cursor: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            user_id,
+            secret: Uuid::new_v4(),
+            token_taps,
+            visible_chats,
+            cursor,
+            detached_at: None,
+        }
+    }
+
+    fn expired(&self) -> bool {
+        self.detached_at.is_some_and(|v| v.elapsed() >= SESSION_TTL)
+    }
+}
+
+/// The sessions of the WebSocket connections, shared by all of them.
+#[derive(Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<Uuid, Session>>>,
+}
+
+impl Sessions {
+    /// Binds a connection to the session `id`, returning the state of the previous
+    /// connection of the session if there was one. The previous connection might not
+    /// be closed yet, as dropped connections can take a while to be noticed. Resuming
+    /// needs the `secret` of the session, which the new connection keeps.
+    pub fn attach(
+        &self,
+        id: Uuid,
+        secret: Option<Uuid>,
+        mut session: Session,
+    ) -> anyhow::Result<Option<Session>> {
+        self.reap();
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(previous) = sessions.get(&id) {
+            if previous.user_id != session.user_id || secret != Some(previous.secret) {
+                return Err(anyhow!("Session {id} belongs to someone else"));
+            }
+            session.secret = previous.secret;
+        }
+        Ok(sessions.insert(id, session))
+    }
+
+    /// Forgets the sessions that can no longer be resumed, returning how many.
+    pub fn reap(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, v| !v.expired());
+        before - sessions.len()
+    }
+
+    /// Reaps the sessions every [SESSION_TTL], so that the ones of clients that never
+    /// come back do not pile up.
+    pub fn spawn_reaper(&self) {
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SESSION_TTL);
+            loop {
+                interval.tick().await;
+                sessions.reap();
+            }
+        });
+    }
+
+    /// Starts the expiration of the session `id`, unless another connection took it over.
+    pub fn detach(&self, id: Uuid, cursor: &Arc<AtomicU64>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(&id) {
+            if Arc::ptr_eq(&session.cursor, cursor) {
+                session.detached_at = Some(Instant::now());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(user_id: Option<Uuid>) -> Session {
+        Session::new(user_id, Default::default(), Default::default(), Default::default())
+    }
+
+    #[test]
+    fn only_resumes_sessions_of_the_same_user() -> anyhow::Result<()> {
+        let sessions = Sessions::default();
+        let (id, user_id) = (Uuid::new_v4(), Some(Uuid::new_v4()));
+        let first = session(user_id);
+        assert!(sessions.attach(id, None, first.clone())?.is_none());
+        sessions.detach(id, &first.cursor);
+
+        assert!(sessions.attach(id, Some(first.secret), session(None)).is_err());
+        let second = session(user_id);
+        let previous = sessions.attach(id, Some(first.secret), second.clone())?.unwrap();
+        assert!(Arc::ptr_eq(&previous.cursor, &first.cursor));
+
+        // The first connection closing late does not expire the second one.
+        sessions.detach(id, &first.cursor);
+        assert!(sessions.sessions.lock().unwrap()[&id].detached_at.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn only_resumes_sessions_with_their_secret() -> anyhow::Result<()> {
+        let sessions = Sessions::default();
+        let id = Uuid::new_v4();
+        let first = session(None);
+        sessions.attach(id, None, first.clone())?;
+
+        assert!(sessions.attach(id, None, session(None)).is_err());
+        assert!(sessions.attach(id, Some(Uuid::new_v4()), session(None)).is_err());
+        sessions.attach(id, Some(first.secret), session(None))?;
+        // The secret is kept for the next time the session is resumed.
+        assert_eq!(sessions.sessions.lock().unwrap()[&id].secret, first.secret);
+        Ok(())
+    }
+
+    #[test]
+    fn reaps_the_expired_sessions() -> anyhow::Result<()> {
+        let sessions = Sessions::default();
+        let (id, other) = (Uuid::new_v4(), Uuid::new_v4());
+        sessions.attach(id, None, session(None))?;
+        sessions.attach(other, None, session(None))?;
+        assert_eq!(sessions.reap(), 0);
+
+        let detached_at = Instant::now().checked_sub(SESSION_TTL).unwrap();
+        sessions.sessions.lock().unwrap().get_mut(&id).unwrap().detached_at = Some(detached_at);
+        assert_eq!(sessions.reap(), 1);
+        assert!(sessions.sessions.lock().unwrap().contains_key(&other));
+        Ok(())
+    }
+}
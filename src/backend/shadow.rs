@@ -0,0 +1,406 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rand::{thread_rng, Rng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::audio_manager::AudioManager;
+use crate::backend::audio_generation_backend::{
+    AudioGenerationBackend, BackendInboundMsg, BackendOutboundMsg, JobProcessor,
+};
+use crate::backend::audio_generation_fanout::{std_to_tokio_receiver, GenerationMessage};
+use crate::backend::auth::Access;
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::storage::Storage;
+
+const SHADOW_DIR: &str = "shadow";
+/// Comparisons kept on disk, the oldest ones are removed together with their audio.
+const MAX_COMPARISONS: usize = 500;
+
+pub struct ShadowOptions {
+    /// The alternative backend that receives a copy of the jobs.
+    pub processor: Box<dyn JobProcessor>,
+    /// Fraction of the jobs, between 0 and 1, duplicated onto the shadow backend.
+    pub fraction: f32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ShadowOutcome {
+    pub elapsed_ms: u64,
+    pub error: Option<String>,
+}
+
+/// How the same job went in the primary and in the shadow backend.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ShadowComparison {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub prompt: String,
+    pub secs: usize,
+    pub primary: Option<ShadowOutcome>,
+    pub shadow: Option<ShadowOutcome>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ShadowReport {
+    /// Jobs that finished in both backends.
+    pub completed: usize,
+    pub primary_failures: usize,
+    pub shadow_failures: usize,
+    pub primary_avg_ms: f64,
+    pub shadow_avg_ms: f64,
+    pub comparisons: Vec<ShadowComparison>,
+}
+
+impl ShadowReport {
+    fn new(comparisons: Vec<ShadowComparison>) -> Self {
+        let mut report = Self::default();
+        let (mut primary_ms, mut shadow_ms) = (0, 0);
+        for c in comparisons.iter() {
+            let (Some(primary), Some(shadow)) = (&c.primary, &c.shadow) else {
+                continue;
+            };
+            report.completed += 1;
+            report.primary_failures += primary.error.is_some() as usize;
+            report.shadow_failures += shadow.error.is_some() as usize;
+            primary_ms += primary.elapsed_ms;
+            shadow_ms += shadow.elapsed_ms;
+        }
+        if report.completed > 0 {
+            report.primary_avg_ms = primary_ms as f64 / report.completed as f64;
+            report.shadow_avg_ms = shadow_ms as f64 / report.completed as f64;
+        }
+        report.comparisons = comparisons;
+        report
+    }
+}
+
+/// Owner only endpoint serving the comparison between the primary and the shadow
+/// backend for the last shadowed jobs.
+pub async fn shadow_report<S: Storage>(storage: S, access: Access) -> Response {
+    if access != Access::Owner {
+        return (StatusCode::FORBIDDEN, "Only the owner can see the shadow report").into_response();
+    }
+    match load_comparisons(&storage).await {
+        Ok(comparisons) => Json(ShadowReport::new(comparisons)).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+async fn load_comparisons<S: Storage>(storage: &S) -> anyhow::Result<Vec<ShadowComparison>> {
+    let mut result = vec![];
+    for file in storage.list(SHADOW_DIR).await? {
+        if !file.ends_with(".json") {
+            continue;
+        }
+        if let Some(bytes) = storage.read(&file).await? {
+            result.push(serde_json::from_slice(&bytes)?);
+        }
+    }
+    Ok(result)
+}
+
+/// Removes the oldest of the `stored` comparisons, and their audio, until there are
+/// at most `max` of them.
+async fn rotate<S: Storage>(storage: &S, stored: &mut VecDeque<Uuid>, max: usize) {
+    while stored.len() > max {
+        let Some(id) = stored.pop_front() else { break };
+        for file in [format!("{SHADOW_DIR}/{id}.json"), format!("{SHADOW_DIR}/audios/{id}.wav")] {
+            if let Err(err) = storage.rm(&file).await {
+                warn!("Could not remove the shadow comparison file {file}: {err}");
+            }
+        }
+    }
+}
+
+/// Ids of the comparisons left on disk by previous runs.
+async fn stored_ids<S: Storage>(storage: &S) -> VecDeque<Uuid> {
+    let files = storage.list(SHADOW_DIR).await.unwrap_or_default();
+    files
+        .iter()
+        .filter_map(|v| v.rsplit('/').next()?.strip_suffix(".json")?.parse().ok())
+        .collect()
+}
+
+/// Runs the shadow backend, returning a sender that forwards everything to
+/// `primary_tx` and duplicates a fraction of the jobs onto the shadow backend.
+/// The shadow results are stored but never broadcast to the clients, and only
+/// the last [MAX_COMPARISONS] of them are kept.
+pub fn run_shadow<S: Storage + 'static>(
+    opts: ShadowOptions,
+    primary_tx: Sender<BackendInboundMsg>,
+    mut primary_rx: tokio::sync::broadcast::Receiver<GenerationMessage>,
+    storage: S,
+) -> Sender<BackendInboundMsg> {
+    info!(
+        "Shadowing {}% of the jobs onto {}",
+        opts.fraction * 100.0,
+        opts.processor.name()
+    );
+    let audio_manager = AudioManager::with_channels(opts.processor.channels());
+    let (shadow_tx, shadow_rx) = AudioGenerationBackend::new(opts.processor).run();
+    let shadowed = Arc::new(RwLock::new(HashSet::new()));
+
+    let (tee_tx, tee_rx) = std::sync::mpsc::channel();
+    let shadowed_clone = shadowed.clone();
+    std::thread::spawn(move || {
+        for msg in tee_rx {
+            match &msg {
+                BackendInboundMsg::Request(req) if thread_rng().gen::<f32>() < opts.fraction => {
+                    shadowed_clone.write().unwrap().insert(req.id.clone());
+                    let _ = shadow_tx.send(msg.clone());
+                }
+                BackendInboundMsg::Abort(_) => {
+                    let _ = shadow_tx.send(msg.clone());
+                }
+                _ => {}
+            }
+            if primary_tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut shadow_rx = std_to_tokio_receiver(shadow_rx);
+    tokio::spawn(async move {
+        let mut comparisons = HashMap::<Uuid, ShadowComparison>::new();
+        let mut started = HashMap::<(bool, Uuid), Instant>::new();
+        let mut stored = stored_ids(&storage).await;
+        loop {
+            // (is the shadow backend, job id, error if any)
+            let (is_shadow, id, error) = tokio::select! {
+                msg = shadow_rx.recv() => {
+                    let Some(msg) = msg else { break };
+                    match msg {
+                        BackendOutboundMsg::Start(req) => {
+                            let IdPair(chat_id, id) = req.id.into();
+                            started.insert((true, id), Instant::now());
+                            comparisons.entry(id).or_insert_with(|| ShadowComparison {
+                                id,
+                                chat_id,
+                                prompt: req.prompt,
+                                secs: req.secs,
+                                primary: None,
+                                shadow: None,
+                            });
+                            continue;
+                        }
+                        BackendOutboundMsg::Response((id, samples, _)) => {
+                            let IdPair(_, id) = id.into();
+                            let relpath = format!("{SHADOW_DIR}/audios/{id}.wav");
+                            let error = match audio_manager.to_wav(samples) {
+                                Ok(bytes) => storage.write(&relpath, bytes).await.err().map(|e| e.to_string()),
+                                Err(err) => Some(err.to_string()),
+                            };
+                            (true, id, error)
+                        }
+                        BackendOutboundMsg::Failure((id, error)) => {
+                            let IdPair(_, id) = id.into();
+                            (true, id, Some(error.message))
+                        }
+                        BackendOutboundMsg::Cancelled(id) => {
+                            let IdPair(_, id) = id.into();
+                            (true, id, Some("Cancelled".to_string()))
+                        }
+                        BackendOutboundMsg::Queued(_)
+                        | BackendOutboundMsg::Progress(_)
+                        | BackendOutboundMsg::Tokens(_)
+                        | BackendOutboundMsg::Audio(_) => continue,
+                    }
+                }
+                msg = primary_rx.recv() => {
+                    let msg = match msg {
+                        Ok(msg) => msg,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    };
+                    let id = match &msg {
+                        GenerationMessage::Start(m) => IdPair(m.chat_id, m.id),
+                        GenerationMessage::Result(m) => IdPair(m.chat_id, m.id),
+                        GenerationMessage::Error(m) => IdPair(m.chat_id, m.id),
+                        GenerationMessage::Cancelled(m) => IdPair(m.chat_id, m.id),
+                        GenerationMessage::Progress(_)
+                        | GenerationMessage::Tokens(_)
+                        | GenerationMessage::Audio(_) => continue,
+                    };
+                    if !shadowed.read().unwrap().contains(&id.to_string()) {
+                        continue;
+                    }
+                    match msg {
+                        GenerationMessage::Start(m) => {
+                            started.insert((false, m.id), Instant::now());
+                            comparisons.entry(m.id).or_insert_with(|| ShadowComparison {
+                                id: m.id,
+                                chat_id: m.chat_id,
+                                prompt: m.prompt,
+                                secs: m.secs,
+                                primary: None,
+                                shadow: None,
+                            });
+                            continue;
+                        }
+                        GenerationMessage::Result(m) => (false, m.id, None),
+                        GenerationMessage::Error(m) => (false, m.id, Some(m.error)),
+                        GenerationMessage::Cancelled(m) => (false, m.id, Some("Cancelled".to_string())),
+                        GenerationMessage::Progress(_)
+                        | GenerationMessage::Tokens(_)
+                        | GenerationMessage::Audio(_) => continue,
+                    }
+                }
+            };
+
+            let Some(comparison) = comparisons.get_mut(&id) else {
+                continue;
+            };
+            let elapsed_ms = started
+                .remove(&(is_shadow, id))
+                .map(|v| v.elapsed().as_millis() as u64)
+                .unwrap_or_default();
+            let outcome = Some(ShadowOutcome { elapsed_ms, error });
+            if is_shadow {
+                comparison.shadow = outcome;
+            } else {
+                comparison.primary = outcome;
+            }
+            let done = comparison.primary.is_some() && comparison.shadow.is_some();
+            let bytes = serde_json::to_vec(&comparison).expect("Could not serialize comparison");
+            if !stored.contains(&id) {
+                stored.push_back(id);
+            }
+            if let Err(err) = storage.write(&format!("{SHADOW_DIR}/{id}.json"), bytes).await {
+                warn!("Could not store shadow comparison for {id}: {err}");
+            }
+            rotate(&storage, &mut stored, MAX_COMPARISONS).await;
+            if done {
+                let id_pair = IdPair(comparison.chat_id, id);
+                comparisons.remove(&id);
+                shadowed.write().unwrap().remove(&id_pair.to_string());
+            }
+        }
+    });
+
+    tee_tx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::backend::_test_utils::DummyJobProcessor;
+    use crate::backend::audio_generation_backend::{AudioGenerationRequest, Priority};
+    use crate::backend::audio_generation_fanout::{audio_generation_fanout, EventLog};
+    use crate::music_gen_config::{FlacConfig, Mp3Config};
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    fn comparison(primary: Option<(u64, bool)>, shadow: Option<(u64, bool)>) -> ShadowComparison {
+        let outcome = |(elapsed_ms, failed): (u64, bool)| ShadowOutcome {
+            elapsed_ms,
+            error: failed.then(|| "failed".to_string()),
+        };
+        ShadowComparison {
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
+            prompt: "".to_string(),
+            secs: 1,
+            primary: primary.map(outcome),
+            shadow: shadow.map(outcome),
+        }
+    }
+
+    #[test]
+    fn report_aggregates_finished_comparisons() {
+        let report = ShadowReport::new(vec![
+            comparison(Some((100, false)), Some((300, true))),
+            comparison(Some((200, false)), Some((100, false))),
+            comparison(Some((1000, false)), None),
+        ]);
+        assert_eq!(report.completed, 2);
+        assert_eq!(report.primary_failures, 0);
+        assert_eq!(report.shadow_failures, 1);
+        assert_eq!(report.primary_avg_ms, 150.0);
+        assert_eq!(report.shadow_avg_ms, 200.0);
+        assert_eq!(report.comparisons.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn stores_shadow_results_without_broadcasting_them() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let (primary_tx, primary_rx) =
+            AudioGenerationBackend::new(DummyJobProcessor::default()).run();
+        let events = EventLog::default();
+        let (mp3, flac) = (Mp3Config::default(), FlacConfig::default());
+        let broadcast_tx =
+            audio_generation_fanout(primary_rx, storage.clone(), 1, 1, mp3, flac, events);
+        let mut rx = broadcast_tx.subscribe();
+        let opts = ShadowOptions {
+            processor: Box::new(DummyJobProcessor::default()),
+            fraction: 1.0,
+        };
+        let tx = run_shadow(opts, primary_tx, broadcast_tx.subscribe(), storage.clone());
+
+        let (chat_id, id) = (Uuid::new_v4(), Uuid::new_v4());
+        tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+            id: IdPair(chat_id, id).to_string(),
+            prompt: "".to_string(),
+            secs: 2,
+            config: Default::default(),
+            priority: Priority::Interactive,
+            owner: String::new(),
+        }))?;
+
+        let mut starts = 0;
+        loop {
+            match rx.recv().await? {
+                GenerationMessage::Start(_) => starts += 1,
+                GenerationMessage::Result(_) => break,
+                _ => {}
+            }
+        }
+        assert_eq!(starts, 1);
+
+        let report = loop {
+            let report = ShadowReport::new(load_comparisons(&storage).await?);
+            if report.completed == 1 {
+                break report;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(report.comparisons[0].id, id);
+        assert_eq!(report.comparisons[0].chat_id, chat_id);
+        assert_eq!(report.shadow_failures, 0);
+        assert!(storage.exists(&format!("shadow/audios/{id}.wav")).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotates_out_the_oldest_comparisons() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        for id in ids {
+            let bytes = serde_json::to_vec(&ShadowComparison { id, ..comparison(None, None) })?;
+            storage.write(&format!("shadow/{id}.json"), bytes).await?;
+        }
+        storage.write(&format!("shadow/audios/{}.wav", ids[0]), "").await?;
+
+        let mut stored = stored_ids(&storage).await;
+        assert_eq!(stored.len(), 3);
+        let oldest = stored[0];
+        rotate(&storage, &mut stored, 2).await;
+        assert_eq!(stored.len(), 2);
+        assert!(!storage.exists(&format!("shadow/{oldest}.json")).await?);
+        assert!(!storage.exists(&format!("shadow/audios/{oldest}.wav")).await?);
+        assert_eq!(load_comparisons(&storage).await?.len(), 2);
+        Ok(())
+    }
+}
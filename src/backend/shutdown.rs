@@ -0,0 +1,108 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::AudioGenerationBackend;
+use crate::backend::audio_generation_fanout::GenerationMessage;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long cancelled jobs are given to stop once the grace period is over.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Jobs that started and whose outcome was not broadcast yet. The results are only
+/// broadcast once their audio is saved, so none of these is half written.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    ids: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+impl InFlight {
+    pub fn track(ai_broadcast_tx: &tokio::sync::broadcast::Sender<GenerationMessage>) -> Self {
+        let in_flight = Self::default();
+        let ids = in_flight.ids.clone();
+        let mut rx = ai_broadcast_tx.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(GenerationMessage::Start(m)) => ids.lock().unwrap().insert(m.id),
+                    Ok(GenerationMessage::Result(m)) => ids.lock().unwrap().remove(&m.id),
+                    Ok(GenerationMessage::Error(m)) => ids.lock().unwrap().remove(&m.id),
+                    Ok(GenerationMessage::Cancelled(m)) => ids.lock().unwrap().remove(&m.id),
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+            }
+        });
+        in_flight
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.lock().unwrap().is_empty()
+    }
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM on Unix.
+pub async fn signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Stops taking jobs and waits for the running ones to finish, cancelling the ones
+/// still running after `grace_period`. Waiting jobs stay in the job store, and are
+/// picked up again on the next start.
+pub async fn drain(backend: &AudioGenerationBackend, in_flight: &InFlight, grace_period: Duration) {
+    backend.drain();
+    let is_idle = || backend.running_jobs() == 0 && in_flight.is_empty();
+    if is_idle() {
+        return;
+    }
+    info!(
+        "Shutting down, waiting up to {}s for {} running jobs",
+        grace_period.as_secs(),
+        backend.running_jobs().max(in_flight.len())
+    );
+    if wait_until(is_idle, grace_period).await {
+        return;
+    }
+    warn!("Cancelling the jobs that did not finish in time");
+    backend.cancel_running();
+    if !wait_until(is_idle, CANCEL_TIMEOUT).await {
+        warn!("Some jobs did not stop in time, shutting down anyway");
+    }
+}
+
+async fn wait_until(condition: impl Fn() -> bool, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    true
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Cursor;
+
+use ndarray::Axis;
+use rustfft::num_complex::Complex;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::audio_manager::{AudioManager, DEFAULT_SAMPLING_RATE};
+use crate::backend::errors::ErrorCode;
+use crate::dsp::{istft, stft};
+use crate::storage::{Storage, AUDIOS_DIR};
+
+const STEMS_DIR: &str = "stems";
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Splits a generation into its individual stems, like drums, bass or melody.
+pub trait StemSeparator: Send + Sync {
+    /// Identifies the model and its settings. Cached stems produced by other
+    /// versions are not reused.
+    fn version(&self) -> String;
+    fn separate(&self, samples: VecDeque<f32>) -> anyhow::Result<BTreeMap<String, VecDeque<f32>>>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct StemsManifest {
+    generation_id: Uuid,
+    version: String,
+    /// Stem name to its relative path in the storage.
+    stems: BTreeMap<String, String>,
+}
+
+/// Splits the audio into the low end, the mids and the highs. It is no source separation
+/// model, but it is cheap and the stems always add up to the original audio.
+pub struct BandSeparator {
+    pub sampling_rate: u32,
+}
+
+impl Default for BandSeparator {
+    fn default() -> Self {
+        Self {
+            sampling_rate: DEFAULT_SAMPLING_RATE,
+        }
+    }
+}
+
+const N_FFT: usize = 2048;
+const HOP_LENGTH: usize = 512;
+/// Name of each band with its lower and upper frequency in Hz.
+const BANDS: [(&str, f32, f32); 3] = [
+    ("bass", 0.0, 250.0),
+    ("mids", 250.0, 4000.0),
+    ("highs", 4000.0, f32::INFINITY),
+];
+
+impl StemSeparator for BandSeparator {
+    fn version(&self) -> String {
+        format!("bands-v1-{N_FFT}")
+    }
+
+    fn separate(&self, samples: VecDeque<f32>) -> anyhow::Result<BTreeMap<String, VecDeque<f32>>> {
+        let samples = Vec::from(samples);
+        let spec = stft(&samples, N_FFT, HOP_LENGTH);
+        let bin_hz = self.sampling_rate as f32 / N_FFT as f32;
+        let mut result = BTreeMap::new();
+        for (name, low, high) in BANDS {
+            let mut band = spec.clone();
+            for (f, mut row) in band.axis_iter_mut(Axis(0)).enumerate() {
+                let hz = f as f32 * bin_hz;
+                if hz < low || hz >= high {
+                    row.fill(Complex::default());
+                }
+            }
+            result.insert(name.to_string(), istft(&band, N_FFT, HOP_LENGTH, samples.len()).into());
+        }
+        Ok(result)
+    }
+}
+
+/// Returns the stems of a generation, keyed by stem name, as relative paths in the
+/// storage. They are only separated once per audio content and separator version.
+pub async fn stems<S: Storage>(
+    storage: &S,
+    separator: &dyn StemSeparator,
+    generation_id: Uuid,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let Some(bytes) = storage.read(&format!("{AUDIOS_DIR}/{generation_id}.wav")).await? else {
+        return Err(ErrorCode::NotFound.err(format!("Generation {generation_id} not found")));
+    };
+    let version = separator.version();
+    let dir = format!("{STEMS_DIR}/{:016x}/{}", content_hash(&bytes), sanitize(&version));
+
+    if let Some(manifest) = storage.read(&format!("{dir}/{MANIFEST_FILE}")).await? {
+        let manifest: StemsManifest = serde_json::from_slice(&manifest)?;
+        return Ok(manifest.stems);
+    }
+
+    info!("Separating stems for {generation_id} with {version}");
+    let samples = hound::WavReader::new(Cursor::new(bytes))?
+        .into_samples::<f32>()
+        .collect::<Result<VecDeque<_>, _>>()?;
+    let audio_manager = AudioManager::default();
+    let mut result = BTreeMap::new();
+    for (name, samples) in separator.separate(samples)? {
+        let relpath = format!("{dir}/{}.wav", sanitize(&name));
+        storage.write(&relpath, audio_manager.to_wav(samples)?).await?;
+        result.insert(name, relpath);
+    }
+    let manifest = StemsManifest {
+        generation_id,
+        version,
+        stems: result.clone(),
+    };
+    // The manifest goes last, so that half written entries are never served.
+    storage.write(&format!("{dir}/{MANIFEST_FILE}"), serde_json::to_vec(&manifest)?).await?;
+    Ok(result)
+}
+
+/// Removes the cached stems whose generation no longer exists or changed, and,
+/// if `current_version` is set, the ones produced by other separator versions.
+/// Returns the amount of removed entries.
+pub async fn gc_stems<S: Storage>(storage: &S, current_version: Option<&str>) -> anyhow::Result<usize> {
+    let mut removed = 0;
+    for hash_dir in storage.list(STEMS_DIR).await? {
+        for dir in storage.list(&hash_dir).await? {
+            let manifest = storage.read(&format!("{dir}/{MANIFEST_FILE}")).await?;
+            let manifest = manifest.and_then(|v| serde_json::from_slice::<StemsManifest>(&v).ok());
+            let keep = match manifest {
+                Some(manifest) => {
+                    let audio = format!("{AUDIOS_DIR}/{}.wav", manifest.generation_id);
+                    let hash_matches = match storage.read(&audio).await? {
+                        Some(bytes) => hash_dir.ends_with(&format!("{:016x}", content_hash(&bytes))),
+                        None => false,
+                    };
+                    let version_matches = current_version
+                        .map(|v| sanitize(v) == sanitize(&manifest.version))
+                        .unwrap_or(true);
+                    hash_matches && version_matches
+                }
+                None => false,
+            };
+            if !keep {
+                storage.rm_rf(&dir).await?;
+                removed += 1;
+            }
+        }
+        if storage.list(&hash_dir).await?.is_empty() {
+            storage.rm_rf(&hash_dir).await?;
+        }
+    }
+    Ok(removed)
+}
+
+/// FNV-1a hash of the audio content.
+fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct HalvingSeparator {
+        runs: AtomicUsize,
+        version: String,
+    }
+
+    impl StemSeparator for HalvingSeparator {
+        fn version(&self) -> String {
+            self.version.clone()
+        }
+
+        fn separate(&self, samples: VecDeque<f32>) -> anyhow::Result<BTreeMap<String, VecDeque<f32>>> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            let half = samples.iter().map(|v| v / 2.0).collect::<VecDeque<_>>();
+            Ok(BTreeMap::from([("drums".to_string(), half.clone()), ("bass".to_string(), half)]))
+        }
+    }
+
+    async fn generation(storage: &AppFs, samples: &[f32]) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        let wav = AudioManager::default().to_wav(samples.iter().copied().collect())?;
+        storage.write(&format!("{AUDIOS_DIR}/{id}.wav"), wav).await?;
+        Ok(id)
+    }
+
+    #[tokio::test]
+    async fn caches_stems_per_content_and_version() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let id = generation(&storage, &[0.5; 10]).await?;
+        let separator = HalvingSeparator {
+            version: "demucs-v1".to_string(),
+            ..Default::default()
+        };
+
+        let first = stems(&storage, &separator, id).await?;
+        let second = stems(&storage, &separator, id).await?;
+        assert_eq!(first, second);
+        assert_eq!(first.keys().collect::<Vec<_>>(), vec!["bass", "drums"]);
+        assert!(storage.exists(&first["drums"]).await?);
+        assert_eq!(separator.runs.load(Ordering::SeqCst), 1);
+
+        let upgraded = HalvingSeparator {
+            version: "demucs-v2".to_string(),
+            ..Default::default()
+        };
+        stems(&storage, &upgraded, id).await?;
+        assert_eq!(upgraded.runs.load(Ordering::SeqCst), 1);
+
+        assert!(stems(&storage, &separator, Uuid::new_v4()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn collects_stale_stems() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let kept = generation(&storage, &[0.5; 10]).await?;
+        let deleted = generation(&storage, &[0.25; 10]).await?;
+        let v1 = HalvingSeparator {
+            version: "v1".to_string(),
+            ..Default::default()
+        };
+        let v2 = HalvingSeparator {
+            version: "v2".to_string(),
+            ..Default::default()
+        };
+        stems(&storage, &v1, kept).await?;
+        let kept_stems = stems(&storage, &v2, kept).await?;
+        let deleted_stems = stems(&storage, &v2, deleted).await?;
+        storage.rm(&format!("{AUDIOS_DIR}/{deleted}.wav")).await?;
+
+        assert_eq!(gc_stems(&storage, None).await?, 1);
+        assert!(!storage.exists(&deleted_stems["drums"]).await?);
+        assert_eq!(gc_stems(&storage, Some("v2")).await?, 1);
+        assert!(storage.exists(&kept_stems["drums"]).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn splits_the_audio_into_bands() -> anyhow::Result<()> {
+        let separator = BandSeparator::default();
+        let rate = separator.sampling_rate as f32;
+        let samples = (0..16000)
+            .map(|i| (2.0 * std::f32::consts::PI * 100.0 * i as f32 / rate).sin() * 0.5)
+            .collect::<VecDeque<_>>();
+        let stems = separator.separate(samples.clone())?;
+        assert_eq!(stems.keys().collect::<Vec<_>>(), vec!["bass", "highs", "mids"]);
+
+        let energy = |v: &VecDeque<f32>| v.iter().map(|v| v * v).sum::<f32>();
+        assert!(energy(&stems["bass"]) > 0.99 * energy(&samples));
+        for (i, sample) in samples.iter().enumerate() {
+            let sum = stems.values().map(|v| v[i]).sum::<f32>();
+            assert!((sum - sample).abs() < 1e-2);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::backend::music_gpt_chat::{Chat, ChatEntry};
+use crate::config_profiles::parse_profile_command;
+use crate::storage::Storage;
+
+/// Popular prompts offered even when there's no history yet.
+const PRESETS: &[&str] = &[
+    "80s pop track with bassy drums and synth",
+    "90s rock song with loud guitars and heavy drums",
+    "a light and cheerly EDM track, with syncopated drums, aery pads, and strong emotions",
+    "lofi slow bpm electro chill with organic samples",
+    "an energetic hip-hop beat with punchy kicks and a deep bassline",
+    "cinematic orchestral score with epic strings and brass",
+    "acoustic folk song with fingerpicked guitar and soft vocals hum",
+    "smooth jazz with saxophone solo and walking bass",
+    "ambient soundscape with evolving pads and distant piano",
+    "reggaeton beat with dembow rhythm and bright synths",
+];
+
+/// Dimensions of the hashed character trigram embeddings.
+const EMBEDDING_DIMS: usize = 256;
+/// Candidates that neither match the prefix nor are this similar are discarded.
+const MIN_SIMILARITY: f32 = 0.3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SuggestionSource {
+    History,
+    Preset,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Suggestion {
+    pub prompt: String,
+    pub source: SuggestionSource,
+    pub score: f32,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct SuggestionsQuery {
+    /// What the user has typed so far.
+    #[serde(default)]
+    pub q: String,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_limit() -> usize {
+    10
+}
+
+/// Serves autocomplete suggestions for a partially typed prompt, based on the
+/// prompts in the chat history and some popular presets.
+pub async fn suggestions<S: Storage>(storage: S, user_id: Option<Uuid>, query: SuggestionsQuery) -> Response {
+    match prompt_history(&storage, user_id).await {
+        Ok(history) => Json(rank(&query.q, history, query.limit)).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// All the prompts sent by the user, with the amount of times each one was used.
+async fn prompt_history<S: Storage>(storage: &S, user_id: Option<Uuid>) -> anyhow::Result<HashMap<String, usize>> {
+    let mut result = HashMap::new();
+    for chat in Chat::load_all_for(storage, user_id).await? {
+        for entry in Chat::load_entries(storage, chat.chat_id).await? {
+            let ChatEntry::User(entry) = entry else {
+                continue;
+            };
+            let prompt = entry.text.trim();
+            if prompt.is_empty() || parse_profile_command(prompt).is_some() {
+                continue;
+            }
+            *result.entry(prompt.to_string()).or_default() += 1;
+        }
+    }
+    Ok(result)
+}
+
+fn rank(query: &str, history: HashMap<String, usize>, limit: usize) -> Vec<Suggestion> {
+    let query = query.trim().to_lowercase();
+    let query_embedding = embed(&query);
+
+    let presets = PRESETS
+        .iter()
+        .filter(|v| !history.contains_key(**v))
+        .map(|v| (v.to_string(), SuggestionSource::Preset, 0));
+    let history = history
+        .into_iter()
+        .map(|(k, v)| (k, SuggestionSource::History, v));
+
+    let mut result = vec![];
+    for (prompt, source, count) in history.chain(presets) {
+        let lower = prompt.to_lowercase();
+        let similarity = cosine(&query_embedding, &embed(&lower));
+        let is_prefix = lower.starts_with(&query);
+        if !is_prefix && similarity < MIN_SIMILARITY {
+            continue;
+        }
+        // Prefix matches go first, then the most similar ones, with frequently
+        // used prompts slightly boosted.
+        let score = is_prefix as u8 as f32 + similarity + 0.05 * (count as f32).ln_1p();
+        result.push(Suggestion {
+            prompt,
+            source,
+            score,
+        })
+    }
+    result.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.prompt.cmp(&b.prompt)));
+    result.truncate(limit);
+    result
+}
+
+/// Cheap text embedding made of hashed character trigrams. It captures spelling
+/// similarity between prompts without needing to run any model.
+fn embed(text: &str) -> [f32; EMBEDDING_DIMS] {
+    let mut result = [0.0; EMBEDDING_DIMS];
+    let chars = format!(" {text} ").chars().collect::<Vec<_>>();
+    for trigram in chars.windows(3) {
+        // FNV-1a
+        let mut hash: u32 = 0x811c9dc5;
+        for c in trigram {
+            hash ^= *c as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+        result[hash as usize % EMBEDDING_DIMS] += 1.0;
+    }
+    result
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::storage::AppFs;
+
+    use super::*;
+
+    #[test]
+    fn similar_texts_have_similar_embeddings() {
+        let a = embed("lofi hip hop beat");
+        let b = embed("lofi hiphop beats");
+        let c = embed("orchestral strings");
+        assert!(cosine(&a, &b) > cosine(&a, &c));
+        assert_eq!(cosine(&a, &[0.0; EMBEDDING_DIMS]), 0.0);
+    }
+
+    #[test]
+    fn ranks_prefix_matches_first() {
+        let history = HashMap::from([
+            ("lofi chill beat".to_string(), 3),
+            ("lofi piano".to_string(), 1),
+            ("chill lofi beats".to_string(), 1),
+            ("death metal".to_string(), 5),
+        ]);
+        let result = rank("lofi", history, 10);
+        let prompts = result.iter().map(|v| v.prompt.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            prompts,
+            vec![
+                "lofi piano",
+                "lofi chill beat",
+                "lofi slow bpm electro chill with organic samples",
+                "chill lofi beats",
+            ]
+        );
+        assert_eq!(result[0].source, SuggestionSource::History);
+        assert_eq!(result[2].source, SuggestionSource::Preset);
+    }
+
+    #[test]
+    fn respects_the_limit() {
+        assert_eq!(rank("", HashMap::new(), 3).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn loads_history_from_chats() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let chat_id = Uuid::new_v4();
+        Chat::load(&storage, chat_id).await?;
+        for text in ["lofi beat", "lofi beat", "/profile fast"] {
+            ChatEntry::new_user(chat_id, Uuid::new_v4(), text.to_string())
+                .save(&storage)
+                .await?;
+        }
+        ChatEntry::new_ai_success(chat_id, Uuid::new_v4(), "audios/foo.wav".to_string())
+            .save(&storage)
+            .await?;
+
+        let history = prompt_history(&storage, None).await?;
+        assert_eq!(history, HashMap::from([("lofi beat".to_string(), 2)]));
+        Ok(())
+    }
+}
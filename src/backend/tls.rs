@@ -0,0 +1,97 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::info;
+
+use crate::music_gen_config::ServerConfig;
+use crate::storage::{AppFs, Storage};
+
+const CERT_FILE: &str = "tls/self_signed_cert.pem";
+const KEY_FILE: &str = "tls/self_signed_key.pem";
+
+/// The TLS settings of the server, if it serves over HTTPS/WSS. The self-signed
+/// certificate is generated once and reused, so that the exception the browsers
+/// ask for is only added one time.
+pub async fn rustls_config(
+    server: &ServerConfig,
+    storage: &AppFs,
+    hostname: &str,
+) -> anyhow::Result<Option<RustlsConfig>> {
+    if let Some(tls) = &server.tls {
+        return Ok(Some(RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path).await?));
+    }
+    if !server.self_signed_tls {
+        return Ok(None);
+    }
+    let (cert_path, key_path) = self_signed(storage, hostname).await?;
+    Ok(Some(RustlsConfig::from_pem_file(cert_path, key_path).await?))
+}
+
+/// Paths of the self-signed certificate and key, generating them if needed.
+async fn self_signed(storage: &AppFs, hostname: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let paths = (storage.path_buf(CERT_FILE), storage.path_buf(KEY_FILE));
+    if storage.exists(CERT_FILE).await? && storage.exists(KEY_FILE).await? {
+        // Keys generated before they were written only readable by the owner.
+        restrict_to_owner(&paths.1)?;
+        return Ok(paths);
+    }
+    let names = vec![
+        hostname.to_string(),
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+    ];
+    let cert = rcgen::generate_simple_self_signed(names)?;
+    storage.write(CERT_FILE, cert.cert.pem()).await?;
+    write_private(&paths.1, cert.key_pair.serialize_pem().as_bytes())?;
+    info!("Generated a self-signed certificate for {hostname} in {}", paths.0.display());
+    Ok(paths)
+}
+
+/// Writes `bytes` to `path`, only readable and writable by the user running the server.
+fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(bytes)?;
+    restrict_to_owner(path)
+}
+
+#[cfg(unix)]
+fn restrict_to_owner(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict_to_owner(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reuses_the_self_signed_certificate() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let (cert_path, key_path) = self_signed(&storage, "musicgpt.local").await?;
+        let cert = std::fs::read_to_string(&cert_path)?;
+        assert!(cert.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(std::fs::read_to_string(&key_path)?.contains("PRIVATE KEY"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&key_path)?.permissions().mode() & 0o777, 0o600);
+        }
+
+        self_signed(&storage, "musicgpt.local").await?;
+        assert_eq!(std::fs::read_to_string(&cert_path)?, cert);
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use axum::extract::ConnectInfo;
+use axum::{Extension, Router};
+use futures_util::future::BoxFuture;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::UnixListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::backend::server::CLOSE_TIMEOUT;
+
+/// Binds a Unix socket at `path` and returns the future serving `app` on it until
+/// `shutdown` is cancelled. The connections come from the loopback address, which is not
+/// trusted as the local machine while serving on a socket, as reverse proxies connect
+/// through it on behalf of anyone.
+pub fn serve_unix(
+    path: &Path,
+    app: Router,
+    shutdown: CancellationToken,
+) -> anyhow::Result<BoxFuture<'static, ()>> {
+    // Left behind if the previous run did not stop cleanly.
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let path = path.to_path_buf();
+    info!("MusicGPT listening on {}", path.display());
+    let local = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let app = app.layer(Extension(ConnectInfo(local)));
+    Ok(Box::pin(async move {
+        let builder = auto::Builder::new(TokioExecutor::new());
+        let graceful = GracefulShutdown::new();
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        warn!("Could not accept a connection on {}: {err}", path.display());
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                _ = shutdown.cancelled() => break,
+            };
+            let service = TowerToHyperService::new(app.clone());
+            let conn = builder
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            let conn = graceful.watch(conn);
+            tokio::spawn(async move {
+                if let Err(err) = conn.await {
+                    debug!("Unix socket connection closed with an error: {err}");
+                }
+            });
+        }
+        drop(listener);
+        let _ = std::fs::remove_file(&path);
+        tokio::select! {
+            _ = graceful.shutdown() => {},
+            _ = tokio::time::sleep(CLOSE_TIMEOUT) => {},
+        }
+    }))
+}
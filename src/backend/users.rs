@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::backend::auth::{cookie, Access};
+
+/// Header with the token of the user, for clients that do not keep cookies.
+pub const USER_HEADER: &str = "x-musicgpt-user";
+/// Cookie with the token of the user, set when registering.
+const USER_COOKIE: &str = "musicgpt_user";
+const TOKEN_LEN: usize = 32;
+const MAX_NAME_LEN: usize = 64;
+
+/// Someone using the server. Users only see their own chats, and the jobs and audio
+/// in them. Clients that are not registered share the chats that have no user, apart
+/// from invite guests, which get a [User::guest] of their own.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct User {
+    pub id: Uuid,
+    pub name: String,
+    /// Identifies the user in the requests, as the [USER_HEADER] header or cookie.
+    pub token: String,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct RegisterRequest {
+    pub name: String,
+}
+
+impl User {
+    /// The user of a guest with no registered user, with an id derived from its invite.
+    pub fn guest(invite: &str) -> User {
+        let hash = openssl::sha::sha256(invite.as_bytes());
+        let mut id = [0; 16];
+        id.copy_from_slice(&hash[..16]);
+        User {
+            id: Uuid::from_bytes(id),
+            name: "Guest".to_string(),
+            token: String::new(),
+        }
+    }
+}
+
+/// The registered users, saved as JSON in the data dir.
+#[derive(Clone)]
+pub struct Users {
+    path: PathBuf,
+    users: Arc<RwLock<HashMap<String, User>>>,
+}
+
+impl Users {
+    pub fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let users: Vec<User> = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        let users = users.into_iter().map(|v| (v.token.clone(), v)).collect();
+        Ok(Self {
+            path,
+            users: Arc::new(RwLock::new(users)),
+        })
+    }
+
+    pub fn register(&self, name: &str) -> anyhow::Result<User> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(anyhow!("The name must have between 1 and {MAX_NAME_LEN} characters"));
+        }
+        let token: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LEN)
+            .map(char::from)
+            .collect();
+        let user = User {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            token: token.clone(),
+        };
+        let mut users = self.users.write().unwrap();
+        users.insert(token, user.clone());
+        let all: Vec<_> = users.values().collect();
+        std::fs::write(&self.path, serde_json::to_vec_pretty(&all)?)?;
+        Ok(user)
+    }
+
+    pub fn by_token(&self, token: &str) -> Option<User> {
+        self.users.read().unwrap().get(token).cloned()
+    }
+
+    /// Middleware that puts the user of every request, if any, into its extensions.
+    /// Unknown tokens, like the ones of a wiped data dir, are treated as no user.
+    pub async fn middleware(self, mut req: Request, next: Next) -> Response {
+        let user = user_token(req.headers()).and_then(|v| self.by_token(&v));
+        // The chats without a user are the ones of the owner, so guests always get one.
+        let user = user.or_else(|| match req.extensions().get::<Access>() {
+            Some(Access::Guest(token)) => Some(User::guest(token)),
+            _ => None,
+        });
+        req.extensions_mut().insert(user);
+        next.run(req).await
+    }
+}
+
+/// Endpoint registering a new user, whose token is also set as a cookie so that
+/// the web app is bound to it from then on.
+pub async fn register_user(users: Users, req: RegisterRequest) -> Response {
+    let user = match users.register(&req.name) {
+        Ok(user) => user,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    let cookie = format!(
+        "{USER_COOKIE}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age=31536000",
+        user.token
+    );
+    let mut res = Json(user).into_response();
+    if let Ok(value) = HeaderValue::from_str(&cookie) {
+        res.headers_mut().append(header::SET_COOKIE, value);
+    }
+    res
+}
+
+fn user_token(headers: &HeaderMap) -> Option<String> {
+    match headers.get(USER_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(token) => Some(token.trim().to_string()),
+        None => cookie(headers, USER_COOKIE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gives_each_invite_its_own_guest() {
+        assert_eq!(User::guest("invite").id, User::guest("invite").id);
+        assert_ne!(User::guest("invite").id, User::guest("other").id);
+    }
+
+    #[test]
+    fn registers_users_and_keeps_them() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("musicgpt-users-{}.json", Uuid::new_v4()));
+        let users = Users::open(path.clone())?;
+        let alice = users.register(" Alice ")?;
+        assert_eq!(alice.name, "Alice");
+        assert!(users.register("").is_err());
+        assert_eq!(users.by_token(&alice.token), Some(alice.clone()));
+        assert_eq!(users.by_token("wrong"), None);
+
+        let reopened = Users::open(path.clone())?;
+        assert_eq!(reopened.by_token(&alice.token), Some(alice));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+
+use axum::http::{header, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use rust_embed::RustEmbed;
+
+const INDEX: &str = "index.html";
+/// Vite puts the files with a content hash in their name here, so they never change.
+const HASHED_DIR: &str = "assets/";
+
+#[derive(RustEmbed)]
+#[folder = "web/dist"]
+struct Embedded;
+
+/// Where the files of the web app are served from.
+#[derive(Clone, Debug)]
+pub enum WebAssets {
+    /// The frontend build embedded in the binary.
+    Embedded,
+    Dir(PathBuf),
+}
+
+impl WebAssets {
+    pub fn new(dir: Option<&str>) -> Self {
+        match dir {
+            Some(dir) => WebAssets::Dir(PathBuf::from(dir)),
+            None => WebAssets::Embedded,
+        }
+    }
+
+    async fn load(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        match self {
+            WebAssets::Embedded => Embedded::get(path).map(|v| v.data),
+            WebAssets::Dir(dir) => {
+                // Nothing outside of the dir is served.
+                if !Path::new(path).components().all(|v| matches!(v, Component::Normal(_))) {
+                    return None;
+                }
+                tokio::fs::read(dir.join(path)).await.ok().map(Cow::Owned)
+            }
+        }
+    }
+
+    /// Serves the file at `uri`. Paths that do not look like files get the index, so
+    /// that the routes of the web app can be opened directly.
+    pub async fn serve(&self, uri: Uri) -> Response {
+        let path = match uri.path().trim_start_matches('/') {
+            "" => INDEX,
+            path => path,
+        };
+        if let Some(content) = self.load(path).await {
+            return file_response(path, content);
+        }
+        let is_file = Path::new(path).extension().is_some();
+        match self.load(INDEX).await {
+            Some(content) if !is_file => file_response(INDEX, content),
+            _ => (StatusCode::NOT_FOUND, format!("{path} not found")).into_response(),
+        }
+    }
+}
+
+fn file_response(path: &str, content: Cow<'static, [u8]>) -> Response {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    (
+        [
+            (header::CONTENT_TYPE, mime.to_string()),
+            (header::CACHE_CONTROL, cache_control(path).to_string()),
+        ],
+        content,
+    )
+        .into_response()
+}
+
+/// Hashed files are cached for good, the rest are checked on every load so that
+/// a new version of the web app is picked up right away.
+fn cache_control(path: &str) -> &'static str {
+    if path.starts_with(HASHED_DIR) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_the_app_with_a_fallback_to_the_index() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("musicgpt-web-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("assets"))?;
+        std::fs::write(dir.join(INDEX), "<html></html>")?;
+        std::fs::write(dir.join("assets/app-1234.js"), "console.log()")?;
+        let assets = WebAssets::new(Some(dir.to_str().unwrap()));
+
+        let res = assets.serve(Uri::from_static("/assets/app-1234.js")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "public, max-age=31536000, immutable");
+
+        for uri in ["/", "/chats/1234"] {
+            let res = assets.serve(Uri::from_static(uri)).await;
+            assert_eq!(res.headers()[header::CACHE_CONTROL], "no-cache");
+            assert_eq!(to_bytes(res.into_body(), usize::MAX).await?, "<html></html>");
+        }
+        for uri in ["/missing.js", "/../secret.txt"] {
+            let res = assets.serve(Uri::from_static(uri)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        let res = WebAssets::Embedded.serve(Uri::from_static("/")).await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/html");
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::auth::Access;
+use crate::backend::rest_api::audio_url;
+use crate::music_gen_config::Secret;
+
+/// Header with the HMAC-SHA256 of the body, as `sha256=<hex>`, keyed with the
+/// webhook secret.
+pub const SIGNATURE_HEADER: &str = "x-musicgpt-signature";
+/// Header with the [WebhookEventKind] of the delivery.
+pub const EVENT_HEADER: &str = "x-musicgpt-event";
+const MAX_ATTEMPTS: u32 = 3;
+/// Doubled after every failed attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    /// Declared in the config file, it cannot be removed through the API.
+    #[serde(default)]
+    pub configured: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct WebhookRequest {
+    pub url: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    /// The job has to wait for others to finish, only sent if the server is busy.
+    Queued,
+    Started,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Body of the POST requests sent to the webhooks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookEvent {
+    pub event: WebhookEventKind,
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u128,
+    /// Where the audio can be downloaded from, only for completed jobs.
+    pub download_url: Option<String>,
+    /// Why the job failed, only for failed jobs.
+    pub error: Option<String>,
+}
+
+/// URLs notified of the lifecycle of every job. The ones registered through the
+/// API are saved as JSON in the data dir, the ones in the config file are not.
+#[derive(Clone)]
+pub struct Webhooks {
+    path: PathBuf,
+    webhooks: Arc<RwLock<Vec<Webhook>>>,
+    secret: Option<Secret>,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn open(path: PathBuf, configured: &[String], secret: Option<Secret>) -> anyhow::Result<Self> {
+        let mut webhooks: Vec<Webhook> = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        webhooks.extend(configured.iter().map(|url| Webhook {
+            id: Uuid::new_v4(),
+            url: url.clone(),
+            configured: true,
+        }));
+        if !webhooks.is_empty() && secret.is_none() {
+            warn!("Webhooks are sent unsigned, set a webhook secret for signing them");
+        }
+        Ok(Self {
+            path,
+            webhooks: Arc::new(RwLock::new(webhooks)),
+            secret,
+            client: reqwest::Client::builder().timeout(DELIVERY_TIMEOUT).build()?,
+        })
+    }
+
+    pub fn register(&self, url: &str) -> anyhow::Result<Webhook> {
+        let parsed = reqwest::Url::parse(url)?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(anyhow!("Webhook URLs must be http or https"));
+        }
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            url: url.to_string(),
+            configured: false,
+        };
+        let mut webhooks = self.webhooks.write().unwrap();
+        webhooks.push(webhook.clone());
+        self.save(&webhooks)?;
+        Ok(webhook)
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        self.webhooks.read().unwrap().clone()
+    }
+
+    pub fn remove(&self, id: Uuid) -> anyhow::Result<bool> {
+        let mut webhooks = self.webhooks.write().unwrap();
+        let before = webhooks.len();
+        webhooks.retain(|v| v.id != id || v.configured);
+        if webhooks.len() == before {
+            return Ok(false);
+        }
+        self.save(&webhooks)?;
+        Ok(true)
+    }
+
+    fn save(&self, webhooks: &[Webhook]) -> anyhow::Result<()> {
+        let registered: Vec<_> = webhooks.iter().filter(|v| !v.configured).collect();
+        std::fs::write(&self.path, serde_json::to_vec_pretty(&registered)?)?;
+        Ok(())
+    }
+
+    /// `sha256=<hex>` of the HMAC-SHA256 of `body`, if there is a secret.
+    fn signature(&self, body: &[u8]) -> anyhow::Result<Option<String>> {
+        let Some(secret) = &self.secret else {
+            return Ok(None);
+        };
+        let key = PKey::hmac(secret.expose().as_bytes())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(body)?;
+        let hex: String = signer.sign_to_vec()?.iter().map(|b| format!("{b:02x}")).collect();
+        Ok(Some(format!("sha256={hex}")))
+    }
+
+    /// Sends `event` to all the webhooks, retrying the failed deliveries in the background.
+    fn deliver(&self, event: WebhookEvent) {
+        let body = serde_json::to_vec(&event).expect("Could not serialize webhook event");
+        let signature = match self.signature(&body) {
+            Ok(signature) => signature,
+            Err(err) => return warn!("Could not sign webhook event: {err}"),
+        };
+        let kind = serde_json::to_value(event.event)
+            .ok()
+            .and_then(|v| v.as_str().map(|v| v.to_string()))
+            .unwrap_or_default();
+        for webhook in self.list() {
+            let (client, body) = (self.client.clone(), body.clone());
+            let (signature, kind) = (signature.clone(), kind.clone());
+            tokio::spawn(async move {
+                let mut delay = RETRY_DELAY;
+                for attempt in 1..=MAX_ATTEMPTS {
+                    let mut req = client
+                        .post(&webhook.url)
+                        .header("Content-Type", "application/json")
+                        .header(EVENT_HEADER, &kind)
+                        .body(body.clone());
+                    if let Some(signature) = &signature {
+                        req = req.header(SIGNATURE_HEADER, signature);
+                    }
+                    match req.send().await.and_then(|v| v.error_for_status()) {
+                        Ok(_) => return,
+                        Err(err) if attempt == MAX_ATTEMPTS => {
+                            warn!("Giving up on webhook {} after {attempt} attempts: {err}", webhook.url)
+                        }
+                        Err(err) => {
+                            info!("Webhook {} failed, retrying in {}s: {err}", webhook.url, delay.as_secs());
+                            tokio::time::sleep(delay).await;
+                            delay *= 2;
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    /// Delivers the lifecycle events of the jobs until the broadcast is closed. The
+    /// download URLs point to `base_url`.
+    pub fn run(self, ai_broadcast_tx: &tokio::sync::broadcast::Sender<GenerationMessage>, base_url: String) {
+        let mut rx = ai_broadcast_tx.subscribe();
+        tokio::spawn(async move {
+            // Jobs are reported as queued once, even though their position is updated.
+            let mut queued = HashSet::new();
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if self.webhooks.read().unwrap().is_empty() {
+                    continue;
+                }
+                let event = |event, id, chat_id| WebhookEvent {
+                    event,
+                    id,
+                    chat_id,
+                    timestamp: now(),
+                    download_url: None,
+                    error: None,
+                };
+                let event = match msg {
+                    GenerationMessage::Progress(m) if m.queue_position.is_some() => {
+                        if !queued.insert(m.id) {
+                            continue;
+                        }
+                        event(WebhookEventKind::Queued, m.id, m.chat_id)
+                    }
+                    GenerationMessage::Start(m) => event(WebhookEventKind::Started, m.id, m.chat_id),
+                    GenerationMessage::Result(m) => {
+                        queued.remove(&m.id);
+                        WebhookEvent {
+                            download_url: Some(base_url.clone() + &audio_url(m.id, &m.relpath)),
+                            ..event(WebhookEventKind::Completed, m.id, m.chat_id)
+                        }
+                    }
+                    GenerationMessage::Error(m) => {
+                        queued.remove(&m.id);
+                        WebhookEvent {
+                            error: Some(m.error),
+                            ..event(WebhookEventKind::Failed, m.id, m.chat_id)
+                        }
+                    }
+                    GenerationMessage::Cancelled(m) => {
+                        queued.remove(&m.id);
+                        event(WebhookEventKind::Cancelled, m.id, m.chat_id)
+                    }
+                    GenerationMessage::Progress(_)
+                    | GenerationMessage::Tokens(_)
+                    | GenerationMessage::Audio(_) => continue,
+                };
+                self.deliver(event);
+            }
+        });
+    }
+}
+
+/// Owner only endpoint registering a webhook.
+pub async fn register_webhook(webhooks: Webhooks, access: Access, req: WebhookRequest) -> Response {
+    if access != Access::Owner {
+        return (StatusCode::FORBIDDEN, "Only the owner can register webhooks").into_response();
+    }
+    match webhooks.register(&req.url) {
+        Ok(webhook) => Json(webhook).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+/// Owner only endpoint listing the webhooks.
+pub async fn list_webhooks(webhooks: Webhooks, access: Access) -> Response {
+    if access != Access::Owner {
+        return (StatusCode::FORBIDDEN, "Only the owner can list webhooks").into_response();
+    }
+    Json(webhooks.list()).into_response()
+}
+
+/// Owner only endpoint removing a webhook registered through the API.
+pub async fn remove_webhook(webhooks: Webhooks, access: Access, id: Uuid) -> Response {
+    if access != Access::Owner {
+        return (StatusCode::FORBIDDEN, "Only the owner can remove webhooks").into_response();
+    }
+    match webhooks.remove(id) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Webhook not found").into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::music_gen_config::SecretRef;
+
+    use super::*;
+
+    #[test]
+    fn keeps_the_registered_webhooks() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("musicgpt-webhooks-{}.json", Uuid::new_v4()));
+        let configured = vec!["http://localhost:9000/configured".to_string()];
+        let webhooks = Webhooks::open(path.clone(), &configured, None)?;
+        assert!(webhooks.register("ftp://localhost/hook").is_err());
+        let webhook = webhooks.register("http://localhost:9000/hook")?;
+        assert_eq!(webhooks.list().len(), 2);
+
+        let reopened = Webhooks::open(path.clone(), &configured, None)?;
+        assert_eq!(reopened.list().len(), 2);
+        let configured_id = reopened.list().iter().find(|v| v.configured).unwrap().id;
+        assert!(!reopened.remove(configured_id)?);
+        assert!(reopened.remove(webhook.id)?);
+        assert_eq!(Webhooks::open(path.clone(), &[], None)?.list(), vec![]);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn signs_the_body() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("musicgpt-webhooks-{}.json", Uuid::new_v4()));
+        std::env::set_var("MUSICGPT_TEST_WEBHOOK_SECRET", "It's a Secret to Everybody");
+        let secret = SecretRef::Env("MUSICGPT_TEST_WEBHOOK_SECRET".to_string()).resolve()?;
+        let webhooks = Webhooks::open(path, &[], Some(secret))?;
+        // Known HMAC-SHA256 vector.
+        assert_eq!(
+            webhooks.signature(b"Hello, World!")?,
+            Some("sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17".to_string())
+        );
+        Ok(())
+    }
+}
@@ -1,12 +1,33 @@
-use std::fmt::Display;
+use std::io::Write;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 use futures_util::{pin_mut, SinkExt, StreamExt};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::limits::{InvalidMessage, InvalidReason, MAX_WS_MESSAGE_BYTES};
+
+/// First bytes of the binary frames with a zlib compressed JSON message.
+pub const COMPRESSED_FRAME_MAGIC: &[u8; 4] = b"MGZL";
+/// Messages shorter than this are not worth compressing.
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// How a connection is kept alive, and when it is given up on.
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    /// Pings are sent this often, browsers answer them on their own.
+    pub ping_interval: Duration,
+    /// Connections that sent nothing for this long, not even a pong, are closed.
+    pub idle_timeout: Duration,
+}
 
 #[async_trait]
 pub trait WsHandler: Sized {
@@ -16,53 +37,219 @@ pub trait WsHandler: Sized {
     async fn handle_init(&self) -> Vec<Self::Outbound>;
     async fn handle_inbound_msg(&self, msg: Self::Inbound) -> Option<Self::Outbound>;
     fn handle_subscription(&self) -> impl StreamExt<Item = Self::Outbound> + Send + 'static;
-    async fn handle_error(&self, _: impl Display + Send) -> Option<Self::Outbound>;
+    /// Called for the messages that are too big or do not deserialize, the connection
+    /// stays open.
+    async fn handle_error(&self, err: InvalidMessage) -> Option<Self::Outbound>;
+
+    /// Whether outbound messages are currently sent as binary frames rather than
+    /// text frames. Checked for every message, as it can change mid connection.
+    fn binary_frames(&self) -> impl Fn() -> bool + Send + Sync + 'static {
+        || false
+    }
+
+    /// Whether big outbound messages are currently compressed, like [Self::binary_frames].
+    fn compression(&self) -> impl Fn() -> bool + Send + Sync + 'static {
+        || false
+    }
+
+    /// Bytes sent as is in a binary frame instead of the serialized message, for
+    /// messages that are not worth encoding as JSON, like audio.
+    fn raw_frame(_: &Self::Outbound) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Cancelled when the server shuts down, closing the connection with a close frame.
+    fn shutdown(&self) -> CancellationToken {
+        CancellationToken::new()
+    }
+
+    /// Pings the client and closes the connection once it goes quiet, so that the
+    /// connections of vanished clients do not stay around.
+    fn keepalive(&self) -> Option<Keepalive> {
+        None
+    }
+
+    /// Checked after every response, closes the connection with this frame if set.
+    fn close_frame(&self) -> Option<CloseFrame<'static>> {
+        None
+    }
+
+    /// Called once the connection is closed.
+    async fn handle_close(&self) {}
 
     async fn handle(self, ws: WebSocket) {
         let (tx, mut rx) = ws.split();
         let tx = Arc::new(Mutex::new(tx));
+        let framing = Arc::new(Framing {
+            binary: Box::new(self.binary_frames()),
+            compression: Box::new(self.compression()),
+            raw_frame: Self::raw_frame,
+        });
 
         // Initialization messages.
         {
             let mut tx = tx.lock().await;
             for msg in self.handle_init().await {
-                let msg = serde_json::to_string(&msg).expect("Could not serialize msg");
-                let _ = tx.send(Message::Text(msg)).await;
+                let _ = tx.send(framing.encode(&msg)).await;
             }
             // <- drop tx
         }
 
         // Subscriptions messages.
         let tx_clone = tx.clone();
+        let framing_clone = framing.clone();
         let subscription = self.handle_subscription();
         let task = tokio::spawn(async move {
             pin_mut!(subscription);
             while let Some(msg) = subscription.next().await {
-                let msg = serde_json::to_string(&msg).expect("Could not serialize msg");
-                let _ = tx_clone.lock().await.send(Message::Text(msg)).await;
+                let msg = framing_clone.encode(&msg);
+                // The connection is gone, nothing else will get through.
+                if tx_clone.lock().await.send(msg).await.is_err() {
+                    break;
+                }
             }
         });
 
         // Inbound messages.
-        while let Some(Ok(msg)) = rx.next().await {
-            let msg = match msg {
-                Message::Text(text) => serde_json::from_str(&text),
-                Message::Binary(bin) => serde_json::from_slice(&bin),
+        let shutdown = self.shutdown();
+        let keepalive = self.keepalive();
+        let mut pings = keepalive.map(|v| {
+            tokio::time::interval_at(Instant::now() + v.ping_interval, v.ping_interval)
+        });
+        let mut last_seen = Instant::now();
+        loop {
+            let ping = async {
+                match &mut pings {
+                    Some(pings) => pings.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let next = tokio::select! {
+                next = rx.next() => next,
+                _ = shutdown.cancelled() => {
+                    let frame = CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "The server is shutting down".into(),
+                    };
+                    let _ = tx.lock().await.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+                _ = ping => {
+                    let idle_timeout = keepalive.map_or(Duration::MAX, |v| v.idle_timeout);
+                    if last_seen.elapsed() >= idle_timeout {
+                        let frame = CloseFrame {
+                            code: close_code::AWAY,
+                            reason: "The connection was idle for too long".into(),
+                        };
+                        let _ = tx.lock().await.send(Message::Close(Some(frame))).await;
+                        break;
+                    }
+                    let _ = tx.lock().await.send(Message::Ping(vec![])).await;
+                    continue;
+                }
+            };
+            last_seen = Instant::now();
+            let Some(Ok(msg)) = next else {
+                break;
+            };
+            let bytes = match &msg {
+                Message::Text(text) => text.as_bytes(),
+                Message::Binary(bin) => bin.as_slice(),
                 Message::Close(_) => break,
                 _ => continue,
             };
+            let msg = match bytes.len() {
+                len if len > MAX_WS_MESSAGE_BYTES => Err(InvalidMessage::new(
+                    InvalidReason::TooLarge,
+                    format!("The message has {len} bytes, the maximum is {MAX_WS_MESSAGE_BYTES}"),
+                )),
+                _ => serde_json::from_slice(bytes)
+                    .map_err(|err| InvalidMessage::new(InvalidReason::Malformed, err)),
+            };
             let maybe_response = match msg {
                 Ok(msg) => self.handle_inbound_msg(msg).await,
                 Err(err) => self.handle_error(err).await,
             };
             if let Some(response) = maybe_response {
                 let mut tx = tx.lock().await;
-                let msg = serde_json::to_string(&response).expect("Could not serialize msg");
-                let _ = tx.send(Message::Text(msg)).await;
+                let _ = tx.send(framing.encode(&response)).await;
                 // <- drop tx
             }
+            if let Some(frame) = self.close_frame() {
+                let _ = tx.lock().await.send(Message::Close(Some(frame))).await;
+                break;
+            }
         }
         // TODO: use a cancellation token?
-        task.abort()
+        task.abort();
+        self.handle_close().await
+    }
+}
+
+/// How the outbound messages of a connection are turned into frames.
+struct Framing<T> {
+    binary: Box<dyn Fn() -> bool + Send + Sync>,
+    compression: Box<dyn Fn() -> bool + Send + Sync>,
+    raw_frame: fn(&T) -> Option<Vec<u8>>,
+}
+
+impl<T: Serialize> Framing<T> {
+    fn encode(&self, msg: &T) -> Message {
+        if let Some(frame) = (self.raw_frame)(msg) {
+            return Message::Binary(frame);
+        }
+        let msg = serde_json::to_string(msg).expect("Could not serialize msg");
+        if msg.len() >= COMPRESSION_THRESHOLD && (self.compression)() {
+            if let Ok(frame) = compress(msg.as_bytes()) {
+                return Message::Binary(frame);
+            }
+        }
+        if (self.binary)() {
+            Message::Binary(msg.into_bytes())
+        } else {
+            Message::Text(msg)
+        }
+    }
+}
+
+/// A binary frame with [COMPRESSED_FRAME_MAGIC] followed by the zlib compressed `msg`.
+fn compress(msg: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(COMPRESSED_FRAME_MAGIC.to_vec(), Compression::fast());
+    encoder.write_all(msg)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn framing(compression: bool) -> Framing<Value> {
+        Framing {
+            binary: Box::new(|| false),
+            compression: Box::new(move || compression),
+            raw_frame: |_| None,
+        }
+    }
+
+    #[test]
+    fn compresses_big_messages() -> anyhow::Result<()> {
+        let big = json!({ "Chats": vec!["a chat with a long name"; 100] });
+        let Message::Binary(frame) = framing(true).encode(&big) else {
+            panic!("expected a binary frame")
+        };
+        assert_eq!(&frame[0..4], COMPRESSED_FRAME_MAGIC);
+        let mut decompressed = String::new();
+        ZlibDecoder::new(&frame[4..]).read_to_string(&mut decompressed)?;
+        assert_eq!(serde_json::from_str::<Value>(&decompressed)?, big);
+        assert!(frame.len() < decompressed.len());
+
+        assert!(matches!(framing(false).encode(&big), Message::Text(_)));
+        assert!(matches!(framing(true).encode(&json!("small")), Message::Text(_)));
+        Ok(())
     }
 }
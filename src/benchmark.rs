@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use sysinfo::{Pid, System};
+
+/// Prompts of the benchmark, always the same so that the results of different machines
+/// can be compared.
+pub const PROMPTS: [&str; 3] = [
+    "80s pop track with bassy drums and synth",
+    "Lo-fi hip hop beat with a mellow piano",
+    "Epic orchestral score with strings and brass",
+];
+
+/// Seconds of audio generated for each prompt.
+pub const SECS: usize = 10;
+
+#[derive(Debug, Serialize)]
+pub struct BenchmarkResult {
+    pub model: String,
+    pub device: String,
+    pub prompt: String,
+    /// Decode steps per second, each one generates a token per codebook.
+    pub tokens_per_sec: f32,
+    /// Seconds of audio generated per second, including the text encoding and the
+    /// decoding of the audio. Over 1 is faster than real time.
+    pub audio_secs_per_sec: f32,
+    /// Most memory the process took, in bytes.
+    pub peak_memory: u64,
+}
+
+impl BenchmarkResult {
+    /// The result of generating [SECS] seconds of audio made of `tokens` tokens, which
+    /// took `decoding` to decode and `total` overall.
+    pub fn new(
+        model: String,
+        device: String,
+        prompt: &str,
+        tokens: usize,
+        decoding: Duration,
+        total: Duration,
+        peak_memory: u64,
+    ) -> Self {
+        Self {
+            model,
+            device,
+            prompt: prompt.to_string(),
+            tokens_per_sec: tokens as f32 / decoding.as_secs_f32().max(f32::EPSILON),
+            audio_secs_per_sec: SECS as f32 / total.as_secs_f32().max(f32::EPSILON),
+            peak_memory,
+        }
+    }
+}
+
+/// Samples the memory of the process, keeping the most it took.
+pub struct PeakMemory {
+    system: System,
+    pid: Pid,
+    peak: u64,
+}
+
+impl PeakMemory {
+    pub fn new() -> anyhow::Result<Self> {
+        let pid = sysinfo::get_current_pid().map_err(|err| anyhow::anyhow!(err))?;
+        Ok(Self {
+            system: System::new(),
+            pid,
+            peak: 0,
+        })
+    }
+
+    pub fn sample(&mut self) -> u64 {
+        self.system.refresh_process(self.pid);
+        let used = self.system.process(self.pid).map(|p| p.memory()).unwrap_or_default();
+        self.peak = self.peak.max(used);
+        self.peak
+    }
+}
+
+/// The arguments of a benchmark on a single device, run in its own process as the
+/// execution providers are set up once per process. These are the `args` of the current
+/// process without the flags that benchmark on several devices.
+pub fn single_device_args(args: &[String]) -> Vec<String> {
+    let mut result = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--benchmark" => {}
+            "--benchmark-devices" | "--benchmark-output" => {
+                args.next();
+            }
+            v if v.starts_with("--benchmark-devices=") || v.starts_with("--benchmark-output=") => {}
+            _ => result.push(arg.clone()),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_the_pace_of_the_generation() {
+        let (model, device, secs) = ("Small".to_string(), "Cpu".to_string(), Duration::from_secs);
+        let result = BenchmarkResult::new(model, device, PROMPTS[0], 500, secs(4), secs(5), 1);
+        assert_eq!(result.tokens_per_sec, 125.0);
+        assert_eq!(result.audio_secs_per_sec, SECS as f32 / 5.0);
+    }
+
+    #[test]
+    fn leaves_out_the_flags_of_several_devices() {
+        let args = ["A song", "--benchmark-devices", "cpu,cuda", "--benchmark", "--model=medium"];
+        let args = args.map(String::from);
+        assert_eq!(single_device_args(&args), vec!["A song", "--model=medium"]);
+        let args = ["--benchmark-devices=cpu", "--benchmark-output", "out.json"].map(String::from);
+        assert!(single_device_args(&args).is_empty());
+    }
+}
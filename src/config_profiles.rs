@@ -0,0 +1,165 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::music_gen_config::{GenerationConfig, MusicGenConfig};
+use crate::storage::Storage;
+
+/// A named group of settings that are applied together to every job while the
+/// profile is active, like a "fast-preview" or a "high-quality" preset.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ConfigProfile {
+    pub name: String,
+    pub config: GenerationConfig,
+}
+
+const PROFILES_DIR: &str = "profiles";
+
+impl ConfigProfile {
+    /// The profiles declared in the `profiles` section of the config file.
+    pub fn from_config(config: &MusicGenConfig) -> Vec<Self> {
+        config
+            .profiles
+            .iter()
+            .map(|(name, config)| Self {
+                name: name.clone(),
+                config: config.clone(),
+            })
+            .collect()
+    }
+
+    /// Loads a profile by name. The ones declared in the config file, passed
+    /// in `from_config`, take precedence over the ones saved in the storage.
+    pub async fn load<S: Storage>(storage: &S, from_config: &[Self], name: &str) -> anyhow::Result<Self> {
+        validate_name(name)?;
+        if let Some(profile) = from_config.iter().find(|p| p.name == name) {
+            return Ok(profile.clone());
+        }
+        let Some(content) = storage.read(&format!("{PROFILES_DIR}/{name}.json")).await? else {
+            return Err(anyhow!("Profile {name} does not exist"));
+        };
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    pub async fn load_all<S: Storage>(storage: &S, from_config: &[Self]) -> anyhow::Result<Vec<Self>> {
+        let mut result = from_config.to_vec();
+        for file in storage.list(PROFILES_DIR).await? {
+            if let Ok(Some(content)) = storage.read(&file).await {
+                if let Ok(profile) = serde_json::from_slice::<Self>(&content) {
+                    if !from_config.iter().any(|p| p.name == profile.name) {
+                        result.push(profile)
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Stores the profile. The ones declared in the config file, passed in
+    /// `from_config`, are read-only.
+    pub async fn save<S: Storage>(&self, storage: &S, from_config: &[Self]) -> anyhow::Result<()> {
+        validate_name(&self.name)?;
+        if from_config.iter().any(|p| p.name == self.name) {
+            return Err(anyhow!("Profile {} is declared in the config file", self.name));
+        }
+        self.config.validate()?;
+        let path = format!("{PROFILES_DIR}/{}.json", self.name);
+        Ok(storage.write(&path, serde_json::to_vec(self)?).await?)
+    }
+}
+
+/// Parses chat commands in the form of `/profile <name>`, returning the name
+/// of the requested profile.
+pub fn parse_profile_command(prompt: &str) -> Option<&str> {
+    let name = prompt.trim().strip_prefix("/profile ")?.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+pub(crate) fn validate_name(name: &str) -> anyhow::Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid profile name {name:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn saves_and_loads_profiles() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let fast = ConfigProfile {
+            name: "fast-preview".to_string(),
+            config: GenerationConfig { top_k: Some(10), ..Default::default() },
+        };
+        fast.save(&storage, &[]).await?;
+        let quality = ConfigProfile {
+            name: "high-quality".to_string(),
+            config: GenerationConfig { top_k: Some(250), ..Default::default() },
+        };
+        quality.save(&storage, &[]).await?;
+
+        assert_eq!(ConfigProfile::load(&storage, &[], "fast-preview").await?, fast);
+        assert_eq!(ConfigProfile::load_all(&storage, &[]).await?, vec![quality, fast]);
+        assert!(ConfigProfile::load(&storage, &[], "missing").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn config_file_profiles_take_precedence() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let stored = ConfigProfile {
+            name: "fast".to_string(),
+            config: GenerationConfig { top_k: Some(10), ..Default::default() },
+        };
+        stored.save(&storage, &[]).await?;
+
+        let mut config = MusicGenConfig::default();
+        config.profiles.insert(
+            "fast".to_string(),
+            GenerationConfig { top_k: Some(5), ..Default::default() },
+        );
+        config.profiles.insert(
+            "quality".to_string(),
+            GenerationConfig { top_k: Some(250), ..Default::default() },
+        );
+        let from_config = ConfigProfile::from_config(&config);
+
+        let fast = ConfigProfile::load(&storage, &from_config, "fast").await?;
+        assert_eq!(fast.config.top_k, Some(5));
+        let all = ConfigProfile::load_all(&storage, &from_config).await?;
+        assert_eq!(all, from_config);
+        assert!(stored.save(&storage, &from_config).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_names() {
+        let storage = AppFs::new_tmp();
+        assert!(ConfigProfile::load(&storage, &[], "../chats").await.is_err());
+        let profile = ConfigProfile {
+            name: "a/b".to_string(),
+            config: Default::default(),
+        };
+        assert!(profile.save(&storage, &[]).await.is_err());
+    }
+
+    #[test]
+    fn parses_profile_commands() {
+        assert_eq!(parse_profile_command("/profile fast"), Some("fast"));
+        assert_eq!(parse_profile_command("  /profile fast  "), Some("fast"));
+        assert_eq!(parse_profile_command("/profile "), None);
+        assert_eq!(parse_profile_command("a cool song"), None);
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::de::{Error, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A duration written in config files as `"500ms"`, `"30s"`, `"2m"`, `"1h30m"`,
+/// or as a plain integer amount of seconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HumanDuration(pub Duration);
+
+/// An amount of bytes written in config files as `"512MB"`, `"4GiB"`, or as a
+/// plain integer amount of bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+const DURATION_UNITS: [(&str, u64); 4] = [("h", 3_600_000), ("m", 60_000), ("s", 1000), ("ms", 1)];
+
+const SIZE_UNITS: [(&str, u64); 9] = [
+    ("tib", 1 << 40),
+    ("gib", 1 << 30),
+    ("mib", 1 << 20),
+    ("kib", 1 << 10),
+    ("tb", 1_000_000_000_000),
+    ("gb", 1_000_000_000),
+    ("mb", 1_000_000),
+    ("kb", 1000),
+    ("b", 1),
+];
+
+const BINARY_SIZE_UNITS: [(&str, u64); 5] = [
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+    ("B", 1),
+];
+
+/// Splits `s` into `(number, unit)` pairs, like `"1h30m"` into `[(1, "h"), (30, "m")]`.
+fn split_amounts(s: &str) -> Result<Vec<(f64, String)>, String> {
+    let mut result = vec![];
+    let mut chars = s.trim().chars().peekable();
+    while chars.peek().is_some() {
+        let mut number = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+            number.push(c);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut unit = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+            unit.push(c.to_ascii_lowercase());
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let number = number.parse::<f64>().map_err(|_| format!("Invalid amount in {s:?}"))?;
+        result.push((number, unit));
+    }
+    if result.is_empty() {
+        return Err("Empty value".to_string());
+    }
+    Ok(result)
+}
+
+fn parse_units(s: &str, units: &[(&str, u64)], default_unit: &str) -> Result<u64, String> {
+    let mut total = 0.0;
+    for (number, unit) in split_amounts(s)? {
+        let unit = if unit.is_empty() { default_unit } else { &unit };
+        let Some((_, factor)) = units.iter().find(|(name, _)| *name == unit) else {
+            let names = units.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            return Err(format!("Unknown unit {unit:?} in {s:?}, use one of {names:?}"));
+        };
+        total += number * *factor as f64;
+    }
+    if total > u64::MAX as f64 {
+        return Err(format!("{s:?} is too big"));
+    }
+    Ok(total.round() as u64)
+}
+
+/// Formats `value` with the biggest unit that represents it exactly.
+fn format_units(value: u64, units: &[(&str, u64)], f: &mut Formatter<'_>) -> std::fmt::Result {
+    for (name, factor) in units {
+        if value % factor == 0 && (value > 0 || *factor == 1) {
+            return write!(f, "{}{name}", value / factor);
+        }
+    }
+    write!(f, "{value}")
+}
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ms = parse_units(s, &DURATION_UNITS, "s")?;
+        Ok(Self(Duration::from_millis(ms)))
+    }
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        format_units(self.0.as_millis() as u64, &DURATION_UNITS, f)
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(parse_units(s, &SIZE_UNITS, "b")?))
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Binary units are preferred, as they are the ones used for memory.
+        format_units(self.0, &BINARY_SIZE_UNITS, f)
+    }
+}
+
+/// Deserializes either a human-readable string or a plain integer.
+struct HumanVisitor<T>(fn(u64) -> T);
+
+impl<T: FromStr<Err = String>> Visitor<'_> for HumanVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "a string like \"30s\" or \"4GiB\", or an integer")
+    }
+
+    fn visit_u64<E: Error>(self, v: u64) -> Result<T, E> {
+        Ok(self.0(v))
+    }
+
+    fn visit_i64<E: Error>(self, v: i64) -> Result<T, E> {
+        u64::try_from(v)
+            .map(self.0)
+            .map_err(|_| E::custom(format!("{v} cannot be negative")))
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<T, E> {
+        T::from_str(v).map_err(E::custom)
+    }
+}
+
+macro_rules! human_serde {
+    ($ty:ident, $from_int:expr) => {
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.to_string())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_any(HumanVisitor($from_int))
+            }
+        }
+
+        impl JsonSchema for $ty {
+            fn schema_name() -> String {
+                stringify!($ty).to_string()
+            }
+
+            fn json_schema(_: &mut SchemaGenerator) -> Schema {
+                SchemaObject {
+                    instance_type: Some(vec![InstanceType::String, InstanceType::Integer].into()),
+                    ..Default::default()
+                }
+                .into()
+            }
+        }
+    };
+}
+
+human_serde!(HumanDuration, |v| HumanDuration(Duration::from_secs(v)));
+human_serde!(ByteSize, ByteSize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        let parse = |s: &str| s.parse::<HumanDuration>().map(|v| v.0);
+        assert_eq!(parse("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse("45"), Ok(Duration::from_secs(45)));
+        assert!(parse("").is_err());
+        assert!(parse("3 weeks").is_err());
+        assert!(parse("s").is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        let parse = |s: &str| s.parse::<ByteSize>().map(|v| v.0);
+        assert_eq!(parse("4GiB"), Ok(4 << 30));
+        assert_eq!(parse("512MB"), Ok(512_000_000));
+        assert_eq!(parse("1.5 KiB"), Ok(1536));
+        assert_eq!(parse("100"), Ok(100));
+        assert!(parse("4 GiBs").is_err());
+    }
+
+    #[test]
+    fn round_trips_through_serde() -> anyhow::Result<()> {
+        let duration: HumanDuration = serde_json::from_str(r#""1h30m""#)?;
+        assert_eq!(serde_json::to_string(&duration)?, r#""90m""#);
+        let duration: HumanDuration = serde_json::from_str("30")?;
+        assert_eq!(serde_json::to_string(&duration)?, r#""30s""#);
+
+        let size: ByteSize = serde_json::from_str(r#""4GiB""#)?;
+        assert_eq!(serde_json::to_string(&size)?, r#""4GiB""#);
+        let size: ByteSize = serde_json::from_str("1000")?;
+        assert_eq!(serde_json::to_string(&size)?, r#""1000B""#);
+
+        assert!(serde_json::from_str::<ByteSize>("-1").is_err());
+        assert!(serde_json::from_str::<HumanDuration>(r#""soon""#).is_err());
+        Ok(())
+    }
+}
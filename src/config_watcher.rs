@@ -0,0 +1,89 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+
+use crate::music_gen_config::MusicGenConfig;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Polls the config file at `path` for changes, applying into `config` the fields
+/// that are safe to change at runtime. Changes to fields that need the models to
+/// be loaded again are ignored with a warning.
+///
+/// # Arguments
+///
+/// * `path`: The config file to watch
+/// * `config`: The config shared with the running pipeline
+/// * `on_reload`: Called with the resulting config each time a change is applied
+///
+/// returns: JoinHandle<()> the handle of the watching task
+pub fn watch_config(
+    path: String,
+    config: Arc<RwLock<MusicGenConfig>>,
+    on_reload: impl Fn(&MusicGenConfig) + Send + 'static,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last_modified = modified(&path).await;
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let curr_modified = modified(&path).await;
+            if curr_modified == last_modified {
+                continue;
+            }
+            last_modified = curr_modified;
+
+            let new_config = match MusicGenConfig::from_file(&path) {
+                Ok(v) => v,
+                Err(err) => {
+                    warn!("Ignoring invalid config change in {path}: {err}");
+                    continue;
+                }
+            };
+            {
+                let mut config = config.write().unwrap();
+                for blocker in config.reload_blockers(&new_config) {
+                    warn!("{blocker}");
+                }
+                config.apply_hot_reloadable(&new_config);
+                on_reload(&config);
+                // <- drop config
+            }
+            info!("Config reloaded from {path}");
+        }
+    })
+}
+
+async fn modified(path: &str) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn applies_changes_to_the_config_file() -> anyhow::Result<()> {
+        let path = format!("/tmp/musicgpt-config-watcher-{}.json", uuid::Uuid::new_v4());
+        MusicGenConfig::default().save_to_file(&path)?;
+        let config = Arc::new(RwLock::new(MusicGenConfig::default()));
+        let handle = watch_config(path.clone(), config.clone(), |_| {});
+
+        // The time is paused, so this only lets the watcher read the original file.
+        tokio::time::sleep(POLL_INTERVAL / 2).await;
+        let mut changed = MusicGenConfig::default();
+        changed.decoder.top_k = 7;
+        changed.save_to_file(&path)?;
+        // Make sure that the modification time differs from the original one.
+        let file = std::fs::File::options().write(true).open(&path)?;
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))?;
+
+        tokio::time::sleep(POLL_INTERVAL * 2).await;
+        handle.abort();
+        assert_eq!(config.read().unwrap().decoder.top_k, 7);
+        Ok(())
+    }
+}
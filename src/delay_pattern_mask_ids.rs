@@ -1,27 +1,65 @@
 #[derive(Debug)]
-pub struct DelayedPatternMaskIds<const N: usize> {
-    batches: [Vec<i64>; N],
+pub struct DelayedPatternMaskIds {
+    batches: Vec<Vec<i64>>,
+    channels: usize,
 }
 
-impl<const N: usize> DelayedPatternMaskIds<N> {
-    pub fn new() -> Self {
-        assert!(N > 0, "N needs to be greater than 0");
+impl DelayedPatternMaskIds {
+    /// The ids of `codebooks` codebooks, interleaved by channel for the stereo models
+    /// (left 0, right 0, left 1, right 1...). The codebooks of every channel are delayed
+    /// like the ones of a mono model.
+    pub fn new(codebooks: usize, channels: usize) -> Self {
+        assert!(codebooks > 0, "codebooks needs to be greater than 0");
+        assert!(
+            channels > 0 && codebooks % channels == 0,
+            "codebooks needs to be a multiple of channels"
+        );
         Self {
-            batches: [(); N].map(|()| vec![]),
+            batches: vec![vec![]; codebooks],
+            channels,
         }
     }
 
+    /// How many steps codebook `i` is delayed.
+    fn delay(&self, i: usize) -> usize {
+        i / self.channels
+    }
+
+    /// Steps between a frame being started and being complete.
+    fn depth(&self) -> usize {
+        self.batches.len() / self.channels
+    }
+
     pub fn push(&mut self, token_ids: impl IntoIterator<Item = i64>) {
+        let n = self.batches.len();
         let mut i = 0;
         for token_id in token_ids.into_iter() {
-            assert!(i < N, "Expected exactly {N} token_ids");
+            assert!(i < n, "Expected exactly {n} token_ids");
             self.batches[i].push(token_id);
             i += 1;
         }
-        assert_eq!(i, N, "Expected exactly {N} token_ids");
+        assert_eq!(i, n, "Expected exactly {n} token_ids");
+    }
+
+    /// Same as [Self::push], but taking the tokens of `prompt` instead of the `sampled`
+    /// ones while it lasts, so that the generation continues it. Each codebook is delayed
+    /// one more step, so it takes the prompt frame of its own position.
+    pub fn push_prompted(&mut self, prompt: &[Vec<i64>], sampled: impl IntoIterator<Item = i64>) {
+        let step = self.batches[0].len();
+        let token_ids = sampled
+            .into_iter()
+            .enumerate()
+            .map(|(i, token_id)| {
+                match step.checked_sub(self.delay(i)).and_then(|f| prompt.get(f)) {
+                    Some(frame) => frame[i],
+                    None => token_id,
+                }
+            })
+            .collect::<Vec<_>>();
+        self.push(token_ids);
     }
 
-    pub fn last_delayed_masked(&self, pad_token_id: i64) -> [i64; N] {
+    pub fn last_delayed_masked(&self, pad_token_id: i64) -> Vec<i64> {
         // We want to apply the Ps to the last
         //   0 1 2 3 4 5 6 7 8 9 10
         // 0 x x x x x x x x x x ...
@@ -29,18 +67,18 @@ impl<const N: usize> DelayedPatternMaskIds<N> {
         // 2 P P x x x x x x x x ...
         // 3 P P P x x x x x x x ...
         let seq_len = self.batches[0].len();
-        let mut result = [0; N];
-        for (i, item) in result.iter_mut().enumerate() {
-            if (seq_len as i64 - i as i64) <= 0 {
-                *item = pad_token_id
-            } else {
-                *item = *self.batches[i].last().expect("There are no input_ids");
-            }
-        }
-        result
+        (0..self.batches.len())
+            .map(|i| {
+                if seq_len <= self.delay(i) {
+                    pad_token_id
+                } else {
+                    *self.batches[i].last().expect("There are no input_ids")
+                }
+            })
+            .collect()
     }
 
-    pub fn last_de_delayed(&self) -> Option<[i64; N]> {
+    pub fn last_de_delayed(&self) -> Option<Vec<i64>> {
         // We want to gather the last diagonal set of numbers avoiding Ps
         // (e.g. [(0,0), (1,1), (2,2), (3,3)])
         //   0 1 2 3 4 5 6 7 8 9
@@ -48,14 +86,25 @@ impl<const N: usize> DelayedPatternMaskIds<N> {
         // 1 P x x x x x x x P P
         // 2 P P x x x x x x x P
         // 3 P P P x x x x x x x
-        if self.batches[0].len() < N {
+        let depth = self.depth();
+        let seq_len = self.batches[0].len();
+        if seq_len < depth {
             return None;
         }
-        let mut result = [0; N];
-        for (i, item) in result.iter_mut().enumerate() {
-            *item = self.batches[i][self.batches[i].len() - N + i]
+        Some(
+            (0..self.batches.len())
+                .map(|i| self.batches[i][seq_len - depth + self.delay(i)])
+                .collect(),
+        )
+    }
+
+    /// Same as [Self::last_de_delayed], but `None` for the first `prompt_len` frames,
+    /// which were taken from the prompt with [Self::push_prompted] and not generated.
+    pub fn last_generated(&self, prompt_len: usize) -> Option<Vec<i64>> {
+        if self.batches[0].len() < self.depth() + prompt_len {
+            return None;
         }
-        Some(result)
+        self.last_de_delayed()
     }
 }
 
@@ -65,23 +114,23 @@ mod tests {
 
     #[test]
     fn last_delayed_masked() {
-        let mut input_ids = DelayedPatternMaskIds::<4>::new();
-        assert_eq!(input_ids.last_delayed_masked(0), [0, 0, 0, 0]);
+        let mut input_ids = DelayedPatternMaskIds::new(4, 1);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![0, 0, 0, 0]);
         input_ids.push([1, 2, 3, 4]);
-        assert_eq!(input_ids.last_delayed_masked(0), [1, 0, 0, 0]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![1, 0, 0, 0]);
         input_ids.push([5, 6, 7, 8]);
-        assert_eq!(input_ids.last_delayed_masked(0), [5, 6, 0, 0]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![5, 6, 0, 0]);
         input_ids.push([9, 10, 11, 12]);
-        assert_eq!(input_ids.last_delayed_masked(0), [9, 10, 11, 0]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![9, 10, 11, 0]);
         input_ids.push([13, 14, 15, 16]);
-        assert_eq!(input_ids.last_delayed_masked(0), [13, 14, 15, 16]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![13, 14, 15, 16]);
         input_ids.push([17, 18, 19, 20]);
-        assert_eq!(input_ids.last_delayed_masked(0), [17, 18, 19, 20]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![17, 18, 19, 20]);
     }
 
     #[test]
     fn last_de_delayed() {
-        let mut input_ids = DelayedPatternMaskIds::<4>::new();
+        let mut input_ids = DelayedPatternMaskIds::new(4, 1);
         assert_eq!(input_ids.last_de_delayed(), None);
         input_ids.push([1, 2, 3, 4]);
         assert_eq!(input_ids.last_de_delayed(), None);
@@ -90,8 +139,35 @@ mod tests {
         input_ids.push([9, 10, 11, 12]);
         assert_eq!(input_ids.last_de_delayed(), None);
         input_ids.push([13, 14, 15, 16]);
-        assert_eq!(input_ids.last_de_delayed(), Some([1, 6, 11, 16]));
+        assert_eq!(input_ids.last_de_delayed(), Some(vec![1, 6, 11, 16]));
         input_ids.push([17, 18, 19, 20]);
-        assert_eq!(input_ids.last_de_delayed(), Some([5, 10, 15, 20]));
+        assert_eq!(input_ids.last_de_delayed(), Some(vec![5, 10, 15, 20]));
+    }
+
+    #[test]
+    fn continues_a_prompt() {
+        let prompt = [vec![1, 2], vec![3, 4]];
+        let mut input_ids = DelayedPatternMaskIds::new(2, 1);
+        input_ids.push_prompted(&prompt, [9, 9]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![1, 0]);
+        input_ids.push_prompted(&prompt, [9, 9]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![3, 2]);
+        assert_eq!(input_ids.last_generated(prompt.len()), None);
+        input_ids.push_prompted(&prompt, [5, 9]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![5, 4]);
+        assert_eq!(input_ids.last_generated(prompt.len()), None);
+        input_ids.push_prompted(&prompt, [7, 6]);
+        assert_eq!(input_ids.last_generated(prompt.len()), Some(vec![5, 6]));
+    }
+
+    #[test]
+    fn delays_the_codebooks_of_every_channel() {
+        let mut input_ids = DelayedPatternMaskIds::new(4, 2);
+        input_ids.push([1, 2, 3, 4]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![1, 2, 0, 0]);
+        assert_eq!(input_ids.last_de_delayed(), None);
+        input_ids.push([5, 6, 7, 8]);
+        assert_eq!(input_ids.last_delayed_masked(0), vec![5, 6, 7, 8]);
+        assert_eq!(input_ids.last_de_delayed(), Some(vec![1, 2, 7, 8]));
     }
 }
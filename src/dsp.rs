@@ -0,0 +1,368 @@
+use std::f32::consts::PI;
+
+use ndarray::{Array2, Axis};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+/// Periodic Hann window of length `n`, the same one used by default in librosa and torch.
+pub fn hann_window(n: usize) -> Vec<f32> {
+    (0..n)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos())
+        .collect()
+}
+
+/// Short-time Fourier transform of a mono signal using a Hann window.
+///
+/// The signal is zero padded by `n_fft / 2` samples on both sides, so frame `t` is
+/// centered around sample `t * hop_length`.
+///
+/// # Arguments
+///
+/// * `signal`: The input samples
+/// * `n_fft`: The size of each FFT frame
+/// * `hop_length`: The amount of samples between consecutive frames
+///
+/// returns: Array2<Complex<f32>> of shape (n_fft / 2 + 1, n_frames)
+pub fn stft(signal: &[f32], n_fft: usize, hop_length: usize) -> Array2<Complex<f32>> {
+    assert!(n_fft > 0, "n_fft must be greater than 0");
+    assert!(hop_length > 0, "hop_length must be greater than 0");
+    let window = hann_window(n_fft);
+    let pad = n_fft / 2;
+    let mut padded = vec![0.0; (signal.len() + 2 * pad).max(n_fft)];
+    padded[pad..pad + signal.len()].copy_from_slice(signal);
+
+    let n_bins = n_fft / 2 + 1;
+    let n_frames = 1 + (padded.len() - n_fft) / hop_length;
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(n_fft);
+
+    let mut result = Array2::zeros((n_bins, n_frames));
+    let mut buffer = vec![Complex::default(); n_fft];
+    for t in 0..n_frames {
+        let frame = &padded[t * hop_length..t * hop_length + n_fft];
+        for (b, (s, w)) in buffer.iter_mut().zip(frame.iter().zip(window.iter())) {
+            *b = Complex::new(s * w, 0.0);
+        }
+        fft.process(&mut buffer);
+        for (f, value) in buffer[..n_bins].iter().enumerate() {
+            result[(f, t)] = *value;
+        }
+    }
+    result
+}
+
+/// Inverse of [stft], reconstructing the signal with windowed overlap-add.
+///
+/// # Arguments
+///
+/// * `spec`: The one-sided spectrum as returned by [stft]
+/// * `n_fft`: The size of each FFT frame
+/// * `hop_length`: The amount of samples between consecutive frames
+/// * `length`: The length of the original signal
+///
+/// returns: Vec<f32> the reconstructed samples
+pub fn istft(
+    spec: &Array2<Complex<f32>>,
+    n_fft: usize,
+    hop_length: usize,
+    length: usize,
+) -> Vec<f32> {
+    let (n_bins, n_frames) = spec.dim();
+    assert_eq!(n_bins, n_fft / 2 + 1, "spectrum does not match n_fft");
+    let window = hann_window(n_fft);
+    let pad = n_fft / 2;
+    let ifft = FftPlanner::<f32>::new().plan_fft_inverse(n_fft);
+
+    let total_len = n_fft + hop_length * n_frames.saturating_sub(1);
+    let mut output = vec![0.0; total_len];
+    let mut norm = vec![0.0; total_len];
+    let mut buffer = vec![Complex::default(); n_fft];
+    for t in 0..n_frames {
+        // Rebuild the full spectrum from the one-sided one using its hermitian symmetry.
+        for (f, b) in buffer.iter_mut().enumerate() {
+            *b = if f < n_bins {
+                spec[(f, t)]
+            } else {
+                spec[(n_fft - f, t)].conj()
+            };
+        }
+        ifft.process(&mut buffer);
+        let offset = t * hop_length;
+        for (i, (b, w)) in buffer.iter().zip(window.iter()).enumerate() {
+            output[offset + i] += b.re / n_fft as f32 * w;
+            norm[offset + i] += w * w;
+        }
+    }
+
+    output
+        .into_iter()
+        .zip(norm)
+        .skip(pad)
+        .take(length)
+        .map(|(o, n)| if n > 1e-8 { o / n } else { 0.0 })
+        .collect()
+}
+
+/// Squared magnitude of each bin of a complex spectrum.
+pub fn power_spectrogram(spec: &Array2<Complex<f32>>) -> Array2<f32> {
+    spec.mapv(|c| c.norm_sqr())
+}
+
+/// Converts a frequency in Hz to the HTK mel scale.
+pub fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+/// Converts a frequency in the HTK mel scale to Hz.
+pub fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// Builds a bank of triangular filters equally spaced in the mel scale.
+///
+/// # Arguments
+///
+/// * `sampling_rate`: The sampling rate of the analyzed signal
+/// * `n_fft`: The FFT size used for computing the spectrum
+/// * `n_mels`: The amount of mel bands
+/// * `f_min`: The lowest frequency in Hz
+/// * `f_max`: The highest frequency in Hz
+///
+/// returns: Array2<f32> of shape (n_mels, n_fft / 2 + 1)
+pub fn mel_filterbank(
+    sampling_rate: usize,
+    n_fft: usize,
+    n_mels: usize,
+    f_min: f32,
+    f_max: f32,
+) -> Array2<f32> {
+    let n_bins = n_fft / 2 + 1;
+    let (mel_min, mel_max) = (hz_to_mel(f_min), hz_to_mel(f_max));
+    let edges = (0..n_mels + 2)
+        .map(|i| mel_to_hz(mel_min + (mel_max - mel_min) * i as f32 / (n_mels + 1) as f32))
+        .collect::<Vec<_>>();
+
+    let mut filters = Array2::zeros((n_mels, n_bins));
+    for m in 0..n_mels {
+        let (lower, center, upper) = (edges[m], edges[m + 1], edges[m + 2]);
+        for f in 0..n_bins {
+            let freq = f as f32 * sampling_rate as f32 / n_fft as f32;
+            let rising = (freq - lower) / (center - lower);
+            let falling = (upper - freq) / (upper - center);
+            filters[(m, f)] = rising.min(falling).max(0.0);
+        }
+    }
+    filters
+}
+
+/// Computes the mel power spectrogram of a mono signal.
+///
+/// returns: Array2<f32> of shape (n_mels, n_frames)
+pub fn mel_spectrogram(
+    signal: &[f32],
+    sampling_rate: usize,
+    n_fft: usize,
+    hop_length: usize,
+    n_mels: usize,
+) -> Array2<f32> {
+    let power = power_spectrogram(&stft(signal, n_fft, hop_length));
+    let filters = mel_filterbank(sampling_rate, n_fft, n_mels, 0.0, sampling_rate as f32 / 2.0);
+    filters.dot(&power)
+}
+
+/// Computes the chromagram of a mono signal, the energy of each of the 12 pitch
+/// classes starting at C. Every bin between A0 and C8 goes to its closest pitch class.
+///
+/// returns: Array2<f32> of shape (12, n_frames)
+pub fn chromagram(
+    signal: &[f32],
+    sampling_rate: usize,
+    n_fft: usize,
+    hop_length: usize,
+) -> Array2<f32> {
+    let power = power_spectrogram(&stft(signal, n_fft, hop_length));
+    let mut filters = Array2::zeros((12, n_fft / 2 + 1));
+    for f in 1..n_fft / 2 + 1 {
+        let freq = f as f32 * sampling_rate as f32 / n_fft as f32;
+        if !(27.5..=4186.0).contains(&freq) {
+            continue;
+        }
+        // Semitones from the C below A4.
+        let pitch = (12.0 * (freq / 440.0).log2()).round() as i32 + 9;
+        filters[(pitch.rem_euclid(12) as usize, f)] = 1.0;
+    }
+    filters.dot(&power)
+}
+
+/// Joins `b` after `a`, overlapping the last `overlap` samples of `a` with the first ones
+/// of `b` using an equal power crossfade, so that the seam is not heard.
+pub fn crossfade(a: &[f32], b: &[f32], overlap: usize) -> Vec<f32> {
+    let overlap = overlap.min(a.len()).min(b.len());
+    let start = a.len() - overlap;
+    let mut result = Vec::with_capacity(start + b.len());
+    result.extend_from_slice(&a[..start]);
+    for i in 0..overlap {
+        let angle = (i as f32 + 0.5) / overlap as f32 * PI / 2.0;
+        result.push(a[start + i] * angle.cos() + b[i] * angle.sin());
+    }
+    result.extend_from_slice(&b[overlap..]);
+    result
+}
+
+/// Slowest tempo told apart by [estimate_bpm], which reports tempos within an octave of it.
+pub const MIN_BPM: f32 = 70.0;
+
+/// Estimates the tempo of a mono signal in beats per minute, from the periodicity of its
+/// onsets: the autocorrelation of the spectral flux peaks at the length of a beat. Tempos
+/// are folded into `MIN_BPM..2 * MIN_BPM`, as half and double the tempo are as periodic.
+///
+/// returns: None if the signal has no onsets
+pub fn estimate_bpm(signal: &[f32], sampling_rate: usize) -> Option<f32> {
+    let (n_fft, hop_length) = (1024, 256);
+    let power = power_spectrogram(&stft(signal, n_fft, hop_length)).mapv(|v| v.ln_1p());
+    let frames: Vec<_> = power.axis_iter(Axis(1)).collect();
+    // How much louder each frame got, which peaks at every onset.
+    let flux: Vec<f32> = frames
+        .windows(2)
+        .map(|w| w[1].iter().zip(w[0].iter()).map(|(b, a)| (b - a).max(0.0)).sum())
+        .collect();
+    let mean = flux.iter().sum::<f32>() / flux.len().max(1) as f32;
+    let flux: Vec<f32> = flux.iter().map(|v| v - mean).collect();
+
+    let frame_rate = sampling_rate as f32 / hop_length as f32;
+    let min_lag = (frame_rate * 30.0 / MIN_BPM).ceil() as usize;
+    let max_lag = (frame_rate * 60.0 / MIN_BPM).floor() as usize;
+    let (lag, score) = (min_lag..=max_lag.min(flux.len().saturating_sub(1)))
+        .map(|lag| {
+            let sum: f32 = flux.iter().zip(&flux[lag..]).map(|(a, b)| a * b).sum();
+            (lag, sum / (flux.len() - lag) as f32)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    (score > 0.0).then(|| 60.0 * frame_rate / lag as f32)
+}
+
+/// Whether the tempos `a` and `b` are within `tolerance` of each other, as a share of
+/// them, once in the same octave.
+pub fn same_tempo(a: f32, b: f32, tolerance: f32) -> bool {
+    let octaves = (a / b).log2();
+    (octaves - octaves.round()).abs() <= (1.0 + tolerance).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!((a - b).abs() < tolerance, "{a} is not close to {b}");
+    }
+
+    #[test]
+    fn hann_window_matches_reference() {
+        // torch.hann_window(4) == [0.0, 0.5, 1.0, 0.5]
+        let window = hann_window(4);
+        for (a, b) in window.iter().zip([0.0, 0.5, 1.0, 0.5]) {
+            assert_close(*a, b, 1e-6);
+        }
+    }
+
+    #[test]
+    fn stft_shape() {
+        let spec = stft(&[0.0; 100], 16, 4);
+        // torch.stft(torch.zeros(100), 16, 4, center=True).shape == (9, 26)
+        assert_eq!(spec.dim(), (9, 26));
+    }
+
+    #[test]
+    fn stft_of_a_sine_peaks_at_its_bin() {
+        let (n_fft, bin) = (64, 5);
+        let signal = (0..1024)
+            .map(|i| (2.0 * PI * bin as f32 * i as f32 / n_fft as f32).sin())
+            .collect::<Vec<_>>();
+        let power = power_spectrogram(&stft(&signal, n_fft, 16));
+        let frame = power.index_axis(Axis(1), 10);
+        let (peak, _) = frame
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+            .unwrap();
+        assert_eq!(peak, bin);
+    }
+
+    #[test]
+    fn istft_reconstructs_the_signal() {
+        let signal = (0..300)
+            .map(|i| (i as f32 * 0.1).sin() + 0.3 * (i as f32 * 0.77).cos())
+            .collect::<Vec<_>>();
+        let spec = stft(&signal, 32, 8);
+        let reconstructed = istft(&spec, 32, 8, signal.len());
+        assert_eq!(reconstructed.len(), signal.len());
+        for (a, b) in reconstructed.iter().zip(signal.iter()) {
+            assert_close(*a, *b, 1e-4);
+        }
+    }
+
+    #[test]
+    fn mel_scale_matches_reference() {
+        // librosa.hz_to_mel(1000, htk=True) == 999.98553
+        assert_close(hz_to_mel(1000.0), 999.98553, 1e-2);
+        assert_close(mel_to_hz(hz_to_mel(440.0)), 440.0, 1e-2);
+    }
+
+    #[test]
+    fn mel_filterbank_is_made_of_triangles() {
+        let filters = mel_filterbank(16000, 512, 40, 0.0, 8000.0);
+        assert_eq!(filters.dim(), (40, 257));
+        for filter in filters.axis_iter(Axis(0)) {
+            let max = filter.fold(0.0f32, |acc, v| acc.max(*v));
+            assert!(max > 0.0 && max <= 1.0);
+            assert!(filter.iter().all(|v| *v >= 0.0));
+        }
+    }
+
+    #[test]
+    fn crossfade_overlaps_the_seam() {
+        let joined = crossfade(&[1.0; 10], &[-1.0; 10], 4);
+        assert_eq!(joined.len(), 16);
+        assert_eq!(joined[..6], [1.0; 6]);
+        assert_eq!(joined[10..], [-1.0; 6]);
+        assert!(joined[6] > 0.0 && joined[9] < 0.0);
+        assert_eq!(crossfade(&[1.0; 3], &[], 4), vec![1.0; 3]);
+    }
+
+    #[test]
+    fn chromagram_of_a_sine_peaks_at_its_pitch_class() {
+        // A4 and the C below it.
+        for (freq, pitch) in [(440.0, 9), (261.63, 0)] {
+            let signal = (0..16000)
+                .map(|i| (2.0 * PI * freq * i as f32 / 16000.0).sin())
+                .collect::<Vec<_>>();
+            let chroma = chromagram(&signal, 16000, 2048, 512);
+            assert_eq!(chroma.dim().0, 12);
+            let frame = chroma.index_axis(Axis(1), 10);
+            let (peak, _) = frame
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+                .unwrap();
+            assert_eq!(peak, pitch);
+        }
+    }
+
+    #[test]
+    fn estimates_the_tempo_of_a_click_track() {
+        let sampling_rate = 16000;
+        for bpm in [100.0, 150.0] {
+            let beat = (60.0 / bpm * sampling_rate as f32) as usize;
+            let signal = (0..8 * sampling_rate)
+                .map(|i| match i % beat {
+                    i if i < 400 => (2.0 * PI * 1000.0 * i as f32 / sampling_rate as f32).sin(),
+                    _ => 0.0,
+                })
+                .collect::<Vec<_>>();
+            let estimated = estimate_bpm(&signal, sampling_rate).unwrap();
+            assert!((MIN_BPM..2.0 * MIN_BPM).contains(&estimated));
+            assert!(same_tempo(estimated, bpm, 0.03), "{estimated} is not {bpm}");
+        }
+        assert_eq!(estimate_bpm(&[0.0; 16000], sampling_rate), None);
+        assert!(!same_tempo(100.0, 120.0, 0.05));
+    }
+}
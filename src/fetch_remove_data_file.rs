@@ -1,11 +1,39 @@
+use std::collections::HashSet;
 use std::error;
 use std::path::PathBuf;
+use std::sync::Mutex;
 
 use axum::http::StatusCode;
 use futures_util::StreamExt;
+use lazy_static::lazy_static;
 use tokio::io::AsyncWriteExt;
 
-use crate::storage::{AppFs, Storage};
+use crate::storage::{AppFs, Storage, TEMP_DIR};
+
+lazy_static! {
+    static ref DOWNLOADS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// The temporary files of the downloads in progress, which must not be cleaned up.
+pub fn downloads_in_flight() -> Vec<String> {
+    DOWNLOADS.lock().unwrap().iter().cloned().collect()
+}
+
+/// Keeps a temporary file in [downloads_in_flight] until dropped.
+struct InFlight(String);
+
+impl InFlight {
+    fn new(temp_file: &str) -> Self {
+        DOWNLOADS.lock().unwrap().insert(temp_file.to_string());
+        Self(temp_file.to_string())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        DOWNLOADS.lock().unwrap().remove(&self.0);
+    }
+}
 
 /// Loads a remote from the local data directory, downloading it from
 /// the remote endpoint if necessary
@@ -15,6 +43,7 @@ use crate::storage::{AppFs, Storage};
 /// * `url`: The URL of the remote file
 /// * `file_name`: The filename in the local data directory
 /// * `force`: Force the download even if the file exists
+/// * `bearer`: Token sent as a `Bearer` Authorization header, for private or gated files
 /// * `cbk`: A callback for tracking progress of the download (elapsed, total)
 ///
 /// returns: Result<PathBuf, Error>
@@ -24,6 +53,7 @@ impl AppFs {
         url: &str,
         local_file: &str,
         force: bool,
+        bearer: Option<&str>,
         cbk: Cb,
     ) -> std::io::Result<PathBuf> {
         // At this point, the file might already exist on disk, so nothing else to do.
@@ -32,7 +62,11 @@ impl AppFs {
         }
 
         // If the file was not in disk, we need to download it.
-        let resp = reqwest::get(url).await.map_err(io_err)?;
+        let mut req = reqwest::Client::new().get(url);
+        if let Some(token) = bearer {
+            req = req.bearer_auth(token);
+        }
+        let resp = req.send().await.map_err(io_err)?;
         let status_code = resp.status();
         if status_code != StatusCode::OK {
             return Err(io_err(format!("Error downloading {url}. Invalid status code {status_code}")));
@@ -40,7 +74,8 @@ impl AppFs {
         let total_bytes = resp.content_length().unwrap_or_default() as usize;
 
         // The file will be first downloaded to a temporary file, to avoid corruptions.
-        let temp_file = format!("{local_file}.temp");
+        let temp_file = format!("{TEMP_DIR}/{local_file}.temp");
+        let _in_flight = InFlight::new(&temp_file);
         let mut file = self.create(&temp_file).await?;
 
         // Stream the HTTP response to the file stream.
@@ -99,13 +134,13 @@ mod tests {
 
         let time = SystemTime::now();
         app_fs
-            .fetch_remote_data_file(remote_file, &file_name, false, |_, _| {})
+            .fetch_remote_data_file(remote_file, &file_name, false, None, |_, _| {})
             .await?;
         let download_elapsed = SystemTime::now().duration_since(time).unwrap().as_micros();
 
         let time = SystemTime::now();
         app_fs
-            .fetch_remote_data_file(remote_file, &file_name, false, |_, _| {})
+            .fetch_remote_data_file(remote_file, &file_name, false, None, |_, _| {})
             .await?;
         let cached_elapsed = SystemTime::now().duration_since(time).unwrap().as_micros();
 
@@ -0,0 +1,125 @@
+use std::fmt::Debug;
+
+use anyhow::anyhow;
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, Stream};
+use flacenc::error::Verify;
+use flacenc::source::MemSource;
+
+use crate::music_gen_config::FlacConfig;
+use crate::pcm::{to_pcm, BitDepth};
+
+/// Seconds between the points of the seek table, the same as the reference encoder.
+const SEEK_POINT_SECS: u64 = 10;
+
+/// Type of the SEEKTABLE metadata block.
+const SEEK_TABLE: u8 = 3;
+
+/// Encodes `samples`, with `channels` interleaved channels at `sampling_rate`, as a 24 bit
+/// flac with a seek table.
+pub fn to_flac(
+    samples: impl IntoIterator<Item = f32>,
+    channels: u16,
+    sampling_rate: u32,
+    config: &FlacConfig,
+) -> anyhow::Result<Vec<u8>> {
+    let level = config.compression_level;
+    let mut encoder = flacenc::config::Encoder::default();
+    encoder.block_size = if level <= 2 { 1152 } else { 4096 };
+    let stereo = !matches!(level, 0 | 3);
+    encoder.stereo_coding.use_leftside = stereo;
+    encoder.stereo_coding.use_rightside = stereo;
+    encoder.stereo_coding.use_midside = stereo;
+    encoder.subframe_coding.use_lpc = level >= 3;
+    encoder.subframe_coding.qlpc.lpc_order = match level {
+        ..=3 => 6,
+        4 | 5 => 8,
+        _ => 12,
+    };
+    let block_size = encoder.block_size;
+    let encoder = encoder.into_verified().map_err(|(_, err)| flac_error(err))?;
+
+    let (samples, _) = to_pcm(samples, BitDepth::I24, true);
+    let (channels, rate) = (channels.max(1) as usize, sampling_rate as usize);
+    let source = MemSource::from_samples(&samples, channels, 24, rate);
+    let stream = flacenc::encode_with_fixed_block_size(&encoder, source, block_size)
+        .map_err(flac_error)?;
+    let mut sink = ByteSink::new();
+    stream.write(&mut sink).map_err(flac_error)?;
+    let total = (samples.len() / channels) as u64;
+    let points = seek_points(&stream, block_size as u64, total, sampling_rate as u64);
+    Ok(with_seek_table(sink.as_slice().to_vec(), &points))
+}
+
+fn flac_error(err: impl Debug) -> anyhow::Error {
+    anyhow!("Could not encode the flac: {err:?}")
+}
+
+/// A point every [SEEK_POINT_SECS] seconds, at the first frame starting from it, made of
+/// the first sample of the frame, its offset from the first frame and its samples.
+fn seek_points(stream: &Stream, block_size: u64, total: u64, rate: u64) -> Vec<(u64, u64, u16)> {
+    let mut points = vec![];
+    let (mut offset, mut next) = (0, 0);
+    for i in 0..stream.frame_count() {
+        let sample = i as u64 * block_size;
+        if sample >= next {
+            points.push((sample, offset, block_size.min(total - sample) as u16));
+            next = sample - sample % (SEEK_POINT_SECS * rate) + SEEK_POINT_SECS * rate;
+        }
+        offset += stream.frame(i).map_or(0, |frame| frame.count_bits() as u64 / 8);
+    }
+    points
+}
+
+/// Adds a seek table with `points` after the metadata blocks of the encoded `flac`, so that
+/// players seek in it without reading it from the start.
+fn with_seek_table(mut flac: Vec<u8>, points: &[(u64, u64, u16)]) -> Vec<u8> {
+    let mut block = vec![0x80 | SEEK_TABLE];
+    block.extend_from_slice(&(points.len() as u32 * 18).to_be_bytes()[1..]);
+    for (sample, offset, samples) in points {
+        block.extend(sample.to_be_bytes());
+        block.extend(offset.to_be_bytes());
+        block.extend(samples.to_be_bytes());
+    }
+    // Right after the "fLaC" marker, each block starts with whether it is the last one, its
+    // type and its length in 24 bits.
+    let mut pos = 4;
+    while pos + 4 <= flac.len() {
+        let len = u32::from_be_bytes([0, flac[pos + 1], flac[pos + 2], flac[pos + 3]]) as usize;
+        if flac[pos] & 0x80 != 0 {
+            flac[pos] &= 0x7f;
+            let frames = flac.split_off(pos + 4 + len);
+            flac.extend(block);
+            flac.extend(frames);
+            break;
+        }
+        pos += 4 + len;
+    }
+    flac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seek_points_land_on_frames() -> anyhow::Result<()> {
+        let tone = (0..21 * 32000).map(|i| (i as f32 * 0.05).sin() * 0.5);
+        let config = FlacConfig { compression_level: 0 };
+        let flac = to_flac(tone, 1, 32000, &config)?;
+        assert_eq!(&flac[..4], b"fLaC");
+        // The stream info, no longer the last block, then the seek table.
+        assert_eq!(flac[4], 0);
+        let table = 4 + 4 + 34;
+        assert_eq!(flac[table..table + 4], [0x80 | SEEK_TABLE, 0, 0, 3 * 18]);
+        let frames = table + 4 + 3 * 18;
+        for point in flac[table + 4..frames].chunks(18) {
+            let sample = u64::from_be_bytes(point[..8].try_into()?);
+            let offset = u64::from_be_bytes(point[8..16].try_into()?) as usize;
+            assert_eq!(sample % 1152, 0);
+            // Each frame starts with the sync code of the fixed block size frames.
+            assert_eq!(flac[frames + offset..frames + offset + 2], [0xff, 0xf8]);
+        }
+        Ok(())
+    }
+}
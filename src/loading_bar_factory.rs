@@ -4,6 +4,8 @@ use std::time::Duration;
 
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
 
+use crate::progress::{ProgressSink, StepProgress};
+
 pub struct LoadingBarFactor;
 
 pub struct Bar(ProgressBar);
@@ -19,6 +21,13 @@ impl Bar {
     }
 }
 
+impl ProgressSink for Bar {
+    fn on_step(&self, progress: &StepProgress) {
+        self.update_elapsed_total(progress.step, progress.total);
+        self.set_message(format!("{:.0} tokens/s", progress.tokens_per_sec));
+    }
+}
+
 impl Deref for Bar {
     type Target = ProgressBar;
 
@@ -77,7 +86,8 @@ impl LoadingBarFactor {
         pb.set_style(
             ProgressStyle::with_template(
                 &(prefix.into()
-                    + " {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] ({eta})"),
+                    + " {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] ({eta})"
+                    + " {msg}"),
             )
             .unwrap()
             .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
@@ -7,7 +7,9 @@ use num_traits::FloatConst;
 use ort::tensor::ArrayExtensions;
 use ort::value::{DynValue};
 use rand::distributions::WeightedIndex;
-use rand::{thread_rng, Rng};
+use rand::Rng;
+
+use crate::music_gen_config::LogitBias;
 
 pub struct Logits(Array2<f32>);
 
@@ -75,7 +77,9 @@ impl Logits {
         Ok(Self(arr))
     }
 
-    pub fn apply_free_guidance(self, guidance_scale: usize) -> Self {
+    /// Mixes the conditional logits, in the first half of the batch, with the unconditional
+    /// ones in the second half, see [crate::music_gen_config::DecoderConfig::guidance_scale].
+    pub fn apply_free_guidance(self, guidance_scale: f32) -> Self {
         if self.0.dim().0 % 2 != 0 {
             panic!("In order to apply free guidance to the logits, the first size of the first dimension must be even")
         }
@@ -86,7 +90,27 @@ impl Logits {
 
         // Based on transformers.js, src/generation/logits_process.js#L603:
         // scores = uncond_logits + (cond_logits - uncond_logits) * guidance_scale
-        Self((cond_logits.into_owned() - uncond_logits) * guidance_scale as f32 + uncond_logits)
+        Self((cond_logits.into_owned() - uncond_logits) * guidance_scale + uncond_logits)
+    }
+
+    /// Adds the biases of the tokens to their logits, banning the ones without a bias, see
+    /// [crate::music_gen_config::GenerationConfig::logit_bias]. Each row is a codebook.
+    pub fn apply_bias(mut self, biases: &[LogitBias]) -> Self {
+        for v in biases {
+            if let Some(logit) = self.0.get_mut((v.codebook, v.token)) {
+                *logit = v.bias.map_or(f32::NEG_INFINITY, |bias| *logit + bias);
+            }
+        }
+        self
+    }
+
+    /// Divides the logits by `temperature`, see
+    /// [crate::music_gen_config::DecoderConfig::temperature].
+    pub fn apply_temperature(self, temperature: f32) -> Self {
+        if temperature == 1.0 {
+            return self;
+        }
+        Self(self.0 / temperature)
     }
 
     /// Samples the logits across the batch dimension (the first one), and returns a vector
@@ -96,9 +120,12 @@ impl Logits {
     /// # Arguments
     ///
     /// * `k`: Take into account only top k logits in each batch
+    /// * `p`: Out of those, take into account only the most probable ones whose
+    ///   probabilities add up to p, and at least one
+    /// * `rng`: Source of randomness, seeded for reproducible samples
     ///
     /// returns: Vec<(i64, f32), Global> the per-batch sample
-    pub fn sample(&self, k: usize) -> Vec<(i64, f32)> {
+    pub fn sample(&self, k: usize, p: f32, rng: &mut impl Rng) -> Vec<(i64, f32)> {
         let mut result = vec![];
         let softmax_logits = self.0.softmax(Axis(1));
         for batch in softmax_logits.axis_iter(Axis(0)) {
@@ -118,11 +145,22 @@ impl Logits {
             });
             // Trim based on provided k.
             softmax_logits_batch = softmax_logits_batch[0..k].to_vec();
+            // Trim based on provided p, the nucleus.
+            let mut cumulative = 0.0;
+            let nucleus = softmax_logits_batch
+                .iter()
+                .take_while(|e| {
+                    let taken = cumulative < p;
+                    cumulative += e.1;
+                    taken
+                })
+                .count();
+            softmax_logits_batch.truncate(nucleus.max(1));
             // Create a distribution based on the softmax probabilities.
             let distribution = WeightedIndex::new(softmax_logits_batch.iter().map(|e| e.1))
                 .expect("Could not create WeightedIndex distribution");
             // Sample a random index based on the softmax probabilities.
-            let (idx, softmax_prob) = softmax_logits_batch[thread_rng().sample(distribution)];
+            let (idx, softmax_prob) = softmax_logits_batch[rng.sample(distribution)];
             // based on JS implementation:
             //  Math.log(probabilities[sampledIndex])
             // In JS, Math.log uses euler's number base.
@@ -134,12 +172,57 @@ impl Logits {
 
 #[cfg(test)]
 mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
     use super::*;
 
     #[test]
     fn free_guidance() {
-        let logits = Logits::from(Array::from(vec![[10., -1., 3.], [-1., 1., 11.]]).into_dyn());
-        let logits = logits.apply_free_guidance(3);
-        assert_eq!(logits.shape(), &[1, 3]);
+        let logits = || Logits::from(Array::from(vec![[10., -1., 3.], [-1., 1., 11.]]).into_dyn());
+        let guided = logits().apply_free_guidance(3.0);
+        assert_eq!(guided.shape(), &[1, 3]);
+        assert_eq!(guided.0.row(0).to_vec(), vec![32., -5., -13.]);
+        // A scale of 1 leaves the conditional logits as they are.
+        let conditional = logits().apply_free_guidance(1.0);
+        assert_eq!(conditional.0.row(0).to_vec(), vec![10., -1., 3.]);
+    }
+
+    #[test]
+    fn samples_from_the_nucleus() {
+        let logits = Logits::from(Array::from(vec![[1., 10., 2., 9.5]]).into_dyn());
+        let rng = &mut StdRng::seed_from_u64(42);
+        for _ in 0..20 {
+            // The two most likely tokens add up to almost all of the probability.
+            let [(token, _)] = logits.sample(4, 0.5, rng)[..] else { panic!() };
+            assert_eq!(token, 1);
+            let [(token, _)] = logits.sample(4, 0.9, rng)[..] else { panic!() };
+            assert!(token == 1 || token == 3);
+        }
+        let cold = logits.apply_temperature(0.5);
+        assert_eq!(cold.0.row(0).to_vec(), vec![2., 20., 4., 19.]);
+    }
+
+    #[test]
+    fn biases_the_tokens_of_each_codebook() {
+        let logits = Logits::from(Array::from(vec![[1., 10., 2.], [3., 4., 5.]]).into_dyn());
+        let biases = [
+            LogitBias { codebook: 0, token: 1, bias: None },
+            LogitBias { codebook: 1, token: 0, bias: Some(2.0) },
+        ];
+        let biased = logits.apply_bias(&biases);
+        assert_eq!(biased.0.row(0).to_vec(), vec![1., f32::NEG_INFINITY, 2.]);
+        assert_eq!(biased.0.row(1).to_vec(), vec![5., 4., 5.]);
+        let rng = &mut StdRng::seed_from_u64(42);
+        let [(token, _), _] = biased.sample(3, 0.01, rng)[..] else { panic!() };
+        assert_eq!(token, 2);
+    }
+
+    #[test]
+    fn samples_the_same_with_the_same_seed() {
+        let logits = Logits::from(Array::from(vec![[1.0; 64], [2.0; 64]]).into_dyn());
+        let sample = |seed| logits.sample(64, 1.0, &mut StdRng::seed_from_u64(seed));
+        assert_eq!(sample(7), sample(7));
+        assert_ne!((0..10).map(sample).collect::<Vec<_>>(), vec![sample(7); 10]);
     }
 }
@@ -0,0 +1,100 @@
+use crate::music_gen_config::TimelineSection;
+
+/// Splits a generation longer than the decoder context into segments. Each segment is
+/// prompted with the last tokens of the previous one, so that it continues it.
+#[derive(Clone, Copy, Debug)]
+pub struct LongForm {
+    /// Most tokens in a segment, counting the ones it is prompted with.
+    pub context: usize,
+    /// Tokens of the previous segment that a segment is prompted with.
+    pub overlap: usize,
+}
+
+impl LongForm {
+    /// The next segment once there are `tokens` tokens, the first `prompt_len` of which
+    /// are the prompt of the job: how many of the last tokens it is prompted with, and
+    /// how many new ones it generates. `None` once the `max_len` new tokens are there.
+    pub fn next(&self, tokens: usize, prompt_len: usize, max_len: usize) -> Option<(usize, usize)> {
+        let remaining = (max_len + prompt_len).checked_sub(tokens).filter(|v| *v > 0)?;
+        let prompted = match tokens == prompt_len {
+            true => prompt_len,
+            false => self.overlap.min(tokens),
+        };
+        let new = self.context.saturating_sub(prompted).max(1).min(remaining);
+        Some((prompted, new))
+    }
+}
+
+/// Prompts that condition the audio of a job, each one from where the previous one ends.
+pub struct Timeline<'a> {
+    /// The prompt of each section, with the token where it ends.
+    sections: Vec<(&'a str, usize)>,
+}
+
+impl<'a> Timeline<'a> {
+    /// The `sections` of a job, of `per_sec` tokens per second, or all of it on `prompt`
+    /// if there are none.
+    pub fn new(prompt: &'a str, sections: Option<&'a [TimelineSection]>, per_sec: usize) -> Self {
+        let mut end = 0;
+        let sections = match sections {
+            Some(sections) if !sections.is_empty() => sections
+                .iter()
+                .map(|v| {
+                    end += v.secs * per_sec;
+                    (v.prompt.as_str(), end)
+                })
+                .collect(),
+            _ => vec![(prompt, usize::MAX)],
+        };
+        Self { sections }
+    }
+
+    /// The prompt of the new `token`, and how many tokens are left before the next
+    /// section starts. The last section has no end.
+    pub fn at(&self, token: usize) -> (&'a str, Option<usize>) {
+        let last = self.sections.len() - 1;
+        let i = self.sections.iter().position(|(_, end)| *end > token).unwrap_or(last);
+        let (prompt, end) = self.sections[i];
+        (prompt, (i < last).then(|| end - token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continues_the_end_of_each_segment() {
+        let long_form = LongForm {
+            context: 30,
+            overlap: 10,
+        };
+        assert_eq!(long_form.next(0, 0, 20), Some((0, 20)));
+        assert_eq!(long_form.next(20, 0, 20), None);
+
+        assert_eq!(long_form.next(0, 0, 70), Some((0, 30)));
+        assert_eq!(long_form.next(30, 0, 70), Some((10, 20)));
+        assert_eq!(long_form.next(50, 0, 70), Some((10, 20)));
+        assert_eq!(long_form.next(70, 0, 70), None);
+
+        // The first segment takes all of the prompt of the job.
+        assert_eq!(long_form.next(5, 5, 70), Some((5, 25)));
+        assert_eq!(long_form.next(30, 5, 70), Some((10, 20)));
+    }
+
+    #[test]
+    fn switches_the_prompt_at_each_section() {
+        let sections = [
+            TimelineSection { prompt: "Intro".to_string(), secs: 2 },
+            TimelineSection { prompt: "Drop".to_string(), secs: 3 },
+        ];
+        let timeline = Timeline::new("Song", Some(&sections), 10);
+        assert_eq!(timeline.at(0), ("Intro", Some(20)));
+        assert_eq!(timeline.at(15), ("Intro", Some(5)));
+        assert_eq!(timeline.at(20), ("Drop", None));
+        assert_eq!(timeline.at(80), ("Drop", None));
+
+        let timeline = Timeline::new("Song", None, 10);
+        assert_eq!(timeline.at(0), ("Song", None));
+    }
+}
@@ -3,44 +3,68 @@ use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Instant;
 
 use crate::audio_manager::{AudioManager, AudioStream};
+use crate::benchmark::{BenchmarkResult, PeakMemory};
+use crate::config_profiles::ConfigProfile;
+use crate::config_units::ByteSize;
 use crate::loading_bar_factory::LoadingBarFactor;
+use crate::long_form::LongForm;
 use crate::music_gen_audio_encodec::MusicGenAudioEncodec;
+use crate::music_gen_config::{
+    ConfigSourceOptions, DeviceScheduling, MusicGenConfig, Secret, SecretRef, ThreadingConfig,
+};
 use crate::music_gen_decoder::{MusicGenDecoder, MusicGenMergedDecoder, MusicGenSplitDecoder};
 use crate::music_gen_text_encoder::MusicGenTextEncoder;
+use crate::progress::ProgressReporter;
 use crate::storage::{AppFs, Storage};
 use anyhow::anyhow;
 use clap::{Parser, ValueEnum};
 use directories::ProjectDirs;
 use half::f16;
-use lazy_static::lazy_static;
 use log::{error, info};
 use ort::execution_providers::{
-    CUDAExecutionProvider, CoreMLExecutionProvider, ExecutionProvider, ExecutionProviderDispatch,
-    TensorRTExecutionProvider,
+    CUDAExecutionProvider, CoreMLExecutionProvider, DirectMLExecutionProvider, ExecutionProvider,
+    ExecutionProviderDispatch, ROCmExecutionProvider, TensorRTExecutionProvider,
 };
+use ort::memory::AllocationDevice;
+use ort::session::builder::SessionBuilder;
 use ort::session::Session;
+use ort::tensor::TensorElementType;
+use ort::value::ValueType;
 use regex::Regex;
 use text_io::read;
 use tokenizers::Tokenizer;
+use tokio_util::sync::CancellationToken;
 use tracing::warn;
 use tracing_subscriber::fmt::time::UtcTime;
 use tracing_subscriber::{fmt, EnvFilter};
 
 mod audio_manager;
 mod backend;
+mod benchmark;
+mod config_profiles;
+mod config_units;
+mod config_watcher;
 mod delay_pattern_mask_ids;
+mod dsp;
 mod fetch_remove_data_file;
+mod flac;
 mod loading_bar_factory;
 mod logits;
+mod long_form;
+mod memory_preflight;
+mod mp3;
 mod music_gen_audio_encodec;
 mod music_gen_config;
 mod music_gen_decoder;
 mod music_gen_inputs;
 mod music_gen_outputs;
 mod music_gen_text_encoder;
+mod pcm;
+mod progress;
 mod storage;
 mod tensor_ops;
 
@@ -57,6 +81,85 @@ enum Model {
     MediumFp16,
     MediumQuant,
     Large,
+    /// Takes a melody to follow besides the text prompt.
+    Melody,
+    /// Generates stereo audio.
+    SmallStereo,
+    /// Generates stereo audio.
+    MediumStereo,
+    /// Generates sound effects instead of music, better at short clips.
+    #[value(name = "audiogen")]
+    AudioGen,
+}
+
+impl Model {
+    /// The export of this model in `precision`, the exports of the fp16 models are already
+    /// in fp16.
+    fn with_precision(self, precision: Precision) -> anyhow::Result<Self> {
+        match (self, precision) {
+            (model, Precision::Fp32) => Ok(model),
+            (Model::Small | Model::SmallFp16, Precision::Fp16) => Ok(Model::SmallFp16),
+            (Model::Medium | Model::MediumFp16, Precision::Fp16) => Ok(Model::MediumFp16),
+            (model, Precision::Fp16) => Err(anyhow!("{model} has no fp16 export")),
+        }
+    }
+
+    /// The export of this model with an int8 quantized decoder, if there is one.
+    fn quantized(self) -> Option<Self> {
+        match self {
+            Model::Small | Model::SmallFp16 => Some(Model::SmallQuant),
+            Model::Medium | Model::MediumFp16 => Some(Model::MediumQuant),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Precision {
+    Fp32,
+    /// Halves the memory of the model and, on GPUs, about doubles its throughput.
+    Fp16,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Device {
+    Cpu,
+    /// NVIDIA GPUs, needs a build with the cuda feature.
+    Cuda,
+    /// NVIDIA GPUs through TensorRT, needs a build with the tensorrt feature. Building
+    /// the engines of a model takes minutes the first time, they are cached afterwards.
+    #[value(name = "tensorrt")]
+    TensorRT,
+    /// Apple Silicon, needs a build with the coreml feature.
+    #[value(name = "coreml")]
+    CoreML,
+    /// Any GPU on Windows, including AMD and Intel ones, needs a build with the directml
+    /// feature.
+    #[value(name = "directml")]
+    DirectML,
+    /// AMD GPUs on Linux, needs a build with the rocm feature.
+    #[value(name = "rocm")]
+    ROCm,
+}
+
+/// Where a copy of the models runs.
+#[derive(Clone)]
+struct Placement {
+    /// Name of the device, like "Cuda" or "Cpu".
+    device: &'static str,
+    /// Index of the GPU.
+    device_id: i32,
+    /// Execution providers of the sessions, none to use the ones of the environment.
+    providers: Vec<ExecutionProviderDispatch>,
+}
+
+/// The units of Apple Silicon that CoreML runs the models on.
+#[derive(Clone, Copy, ValueEnum)]
+enum ComputeUnits {
+    /// The GPU and the Neural Engine, and the CPU for what they can not run.
+    All,
+    /// Only the Neural Engine, which takes the least power.
+    Ane,
 }
 
 impl Display for Model {
@@ -69,11 +172,15 @@ impl Display for Model {
             Model::MediumFp16 => write!(f, "MusicGen Medium Fp16"),
             Model::MediumQuant => write!(f, "MusicGen Medium Quantized"),
             Model::Large => write!(f, "MusicGen Large"),
+            Model::Melody => write!(f, "MusicGen Melody"),
+            Model::SmallStereo => write!(f, "MusicGen Small Stereo"),
+            Model::MediumStereo => write!(f, "MusicGen Medium Stereo"),
+            Model::AudioGen => write!(f, "AudioGen Medium"),
         }
     }
 }
 
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[command(name = "MusicGPT")]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -91,6 +198,11 @@ struct Args {
     #[arg(long, default_value = "small")]
     model: Model,
 
+    /// Precision of the weights and the tensors of the models, only some of them have
+    /// an fp16 export.
+    #[arg(long, default_value = "fp32")]
+    precision: Precision,
+
     /// The LLM models are exported using https://github.com/huggingface/optimum,
     /// and they export transformer-based decoders either in two files, or a single
     /// merged one.
@@ -105,7 +217,31 @@ struct Args {
     #[arg(long, default_value = "false")]
     gpu: bool,
 
-    /// [CLI mode] The seconds of audio to generate.
+    /// Device to run the models on, instead of the one detected with --gpu. If it can not
+    /// be used, the models run on the CPU.
+    #[arg(long)]
+    device: Option<Device>,
+
+    /// Index of the GPU that the models run on with --device cuda, tensorrt, directml or
+    /// rocm.
+    #[arg(long, default_value = "0")]
+    gpu_device_id: i32,
+
+    /// Directory where the TensorRT engines built for the models are cached, by default
+    /// one in the cache dir.
+    #[arg(long)]
+    tensorrt_cache_dir: Option<PathBuf>,
+
+    /// Most memory that the models can take on the GPU, like "6GiB". Unlimited by default.
+    #[arg(long)]
+    gpu_memory_limit: Option<ByteSize>,
+
+    /// Units of Apple Silicon that the models run on with --device coreml.
+    #[arg(long, default_value = "all")]
+    coreml_compute_units: ComputeUnits,
+
+    /// [CLI mode] The seconds of audio to generate. Over the context of the model, the
+    /// audio is generated in segments that continue each other.
     #[arg(long, default_value = "10")]
     secs: usize,
 
@@ -125,13 +261,95 @@ struct Args {
     #[arg(long, default_value = "false")]
     ui_no_open: bool,
 
-    /// [UI mode] Port in which the MusicGPT web app will run.
-    #[arg(long, default_value = "8642")]
-    ui_port: usize,
+    /// [UI mode] Port in which the MusicGPT web app will run [default: 8642].
+    #[arg(long)]
+    ui_port: Option<usize>,
 
     /// [UI mode] Exposes the MusicGPT web app in 0.0.0.0 instead of 127.0.0.1.
     #[arg(long, default_value = "false")]
     ui_expose: bool,
+
+    /// [UI mode] Serves the MusicGPT web app over HTTPS with a self-signed certificate,
+    /// unless the config file sets one.
+    #[arg(long, default_value = "false")]
+    ui_https: bool,
+
+    /// [UI mode] Also serves the MusicGPT web app on a Unix socket at this path, for
+    /// reverse proxies and other local processes.
+    #[arg(long)]
+    ui_unix_socket: Option<PathBuf>,
+
+    /// [UI mode] Only serves the MusicGPT web app on --ui-unix-socket, without opening
+    /// a TCP port.
+    #[arg(long, default_value = "false")]
+    ui_unix_socket_only: bool,
+
+    /// Name of a config profile to apply to every generation, either declared in the
+    /// `profiles` section of the config file or stored in the data dir.
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Path or http(s) URL of a config file that replaces the one shipped with the
+    /// model. Remote configs are fetched once on startup and cached, and the cache is
+    /// used if they can't be fetched. They are not polled for changes afterwards.
+    /// [UI mode] Fields that are safe to change at runtime, like sampling
+    /// parameters or the log level, are reloaded when the file changes.
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Value of the Authorization header sent when --config is an http(s) URL.
+    #[arg(long, env = "MUSICGPT_CONFIG_AUTH_HEADER", hide_env_values = true)]
+    config_auth_header: Option<String>,
+
+    /// [UI mode] Duplicates a fraction of the jobs onto this model, storing the
+    /// results for comparison at /api/shadow/report without returning them.
+    #[arg(long)]
+    shadow_model: Option<Model>,
+
+    /// [UI mode] Fraction of the jobs, between 0 and 1, sent to --shadow-model.
+    #[arg(long, default_value = "0.1")]
+    shadow_fraction: f32,
+
+    /// Loads the model even if it does not seem to fit in the available memory.
+    #[arg(long, default_value = "false")]
+    skip_memory_check: bool,
+
+    /// [UI mode] Models that jobs can switch to by naming them, besides --model, like
+    /// "medium,melody". Jobs cannot switch models by default.
+    #[arg(long, value_delimiter = ',')]
+    switchable_models: Vec<Model>,
+
+    /// The `huggingface_token` of the config file, sent when downloading the models.
+    #[arg(skip)]
+    huggingface_token: Option<Secret>,
+
+    /// [UI mode] Models that jobs switched to stay loaded, besides --model. Past this
+    /// amount, the least recently used one is unloaded.
+    #[arg(long, default_value = "1")]
+    max_loaded_models: usize,
+
+    /// Generates the benchmark prompts with --model in each of --benchmark-precisions,
+    /// prints the tokens per second, seconds of audio per second and peak memory of each
+    /// one as JSON, and exits.
+    #[arg(long, default_value = "false")]
+    benchmark: bool,
+
+    /// Runs the benchmark on each of these devices, like "cpu,cuda", instead of on the
+    /// one of --device.
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["device", "gpu"])]
+    benchmark_devices: Vec<Device>,
+
+    /// Precisions that the benchmark runs in, like "fp32,fp16", by default --precision.
+    #[arg(long, value_delimiter = ',')]
+    benchmark_precisions: Vec<Precision>,
+
+    /// Writes the results of the benchmark to this file instead of printing them.
+    #[arg(long)]
+    benchmark_output: Option<PathBuf>,
+
+    /// Prints the JSON Schema of the configuration file and exits.
+    #[arg(long, default_value = "false")]
+    config_schema: bool,
 }
 
 impl Args {
@@ -139,24 +357,92 @@ impl Args {
         if self.secs < 1 {
             return Err(anyhow!("--secs must > 0"));
         }
-        if self.secs > 30 {
-            return Err(anyhow!("--secs must <= 30"));
+        if self.secs > MAX_CLI_SECS {
+            return Err(anyhow!("--secs must <= {MAX_CLI_SECS}"));
+        }
+        if !(0.0..=1.0).contains(&self.shadow_fraction) {
+            return Err(anyhow!("--shadow-fraction must be between 0 and 1"));
+        }
+        if self.ui_unix_socket_only && self.ui_unix_socket.is_none() {
+            return Err(anyhow!("--ui-unix-socket-only needs --ui-unix-socket"));
         }
         Ok(())
     }
 }
 
-lazy_static! {
-    static ref PROJECT_FS: AppFs = AppFs::new(
-        ProjectDirs::from("com", "gabotechs", "musicgpt")
-            .expect("Could not load project directory")
-            .data_dir()
-    );
+/// The data dir, with the storage locations of the config file passed with --config.
+/// Set up on startup, before anything is downloaded or stored.
+static PROJECT_FS: OnceLock<AppFs> = OnceLock::new();
+
+fn project_fs() -> &'static AppFs {
+    PROJECT_FS.get().expect("The data dir is set up on startup")
+}
+
+fn config_cache_dir() -> PathBuf {
+    ProjectDirs::from("com", "gabotechs", "musicgpt")
+        .expect("Could not load project directory")
+        .cache_dir()
+        .join("config")
 }
 
-async fn _main() -> anyhow::Result<()> {
-    let args = Args::parse();
+fn tensorrt_cache_dir() -> PathBuf {
+    ProjectDirs::from("com", "gabotechs", "musicgpt")
+        .expect("Could not load project directory")
+        .cache_dir()
+        .join("tensorrt")
+}
+
+/// Remote configs passed with --config are read from their local cached copy.
+fn local_config_path(source: &str) -> String {
+    if MusicGenConfig::is_remote(source) {
+        let path = MusicGenConfig::cached_source_path(&config_cache_dir(), source);
+        path.to_string_lossy().to_string()
+    } else {
+        source.to_string()
+    }
+}
+
+type SetLogLevel = Box<dyn Fn(&str) -> anyhow::Result<()> + Send + Sync>;
+
+async fn _main(set_log_level: SetLogLevel) -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    if args.config_schema {
+        let schema = MusicGenConfig::json_schema();
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    }
     args.validate()?;
+    // Remote configs are fetched once on startup, and from then on they are
+    // used from the local cache as any other config file.
+    if let Some(source) = &args.config {
+        if MusicGenConfig::is_remote(source) {
+            info!("Fetching config from {source}");
+            let opts = ConfigSourceOptions {
+                auth_header: args.config_auth_header.clone(),
+                cache_dir: Some(config_cache_dir()),
+            };
+            MusicGenConfig::from_source(source, &opts).await?;
+            args.config = Some(local_config_path(source));
+        }
+    }
+
+    let file_config = args.config.as_deref().map(MusicGenConfig::from_file).transpose()?;
+    let storage = file_config.as_ref().map(|v| v.storage.clone()).unwrap_or_default();
+    let data_dir = ProjectDirs::from("com", "gabotechs", "musicgpt")
+        .ok_or_else(|| anyhow!("Could not load project directory"))?
+        .data_dir()
+        .to_path_buf();
+    let _ = PROJECT_FS.set(AppFs::from_config(data_dir, &storage));
+
+    if !args.benchmark_devices.is_empty() {
+        return benchmark_devices(&args);
+    }
+    // The benchmark applies its own precisions.
+    let benchmarked_model = args.model;
+    // Jobs name the models like the command line, whatever their precision.
+    let default_model = model_name(args.model);
+    args.model = args.model.with_precision(args.precision)?;
+    args.shadow_model = args.shadow_model.map(|v| v.with_precision(args.precision)).transpose()?;
 
     #[cfg(feature = "onnxruntime-from-source")]
     let mut ort_builder = ort::init_from(
@@ -168,35 +454,130 @@ async fn _main() -> anyhow::Result<()> {
     #[cfg(not(feature = "onnxruntime-from-source"))]
     let mut ort_builder = ort::init();
 
-    let mut device = "Cpu";
-    if args.gpu {
-        warn!("GPU support is experimental, it might not work on most platforms");
-        let (gpu_device, provider) = init_gpu()?;
-        device = gpu_device;
-        ort_builder = ort_builder.with_execution_providers(&[provider]);
-    }
+    args.huggingface_token = file_config
+        .as_ref()
+        .and_then(|v| v.secrets.huggingface_token.as_ref())
+        .map(SecretRef::resolve)
+        .transpose()?;
+    let (devices, scheduling) = match &file_config {
+        Some(config) => (config.devices.clone(), config.device_scheduling),
+        None => (vec![], DeviceScheduling::default()),
+    };
+    let placements = if devices.is_empty() {
+        let (device, providers) = init_device(&args)?;
+        if !providers.is_empty() {
+            ort_builder = ort_builder.with_execution_providers(providers);
+        }
+        let device_id = args.gpu_device_id;
+        vec![Placement { device, device_id, providers: vec![] }]
+    } else {
+        // Each copy of the models runs on its own device, so the sessions get the
+        // execution providers instead of the environment.
+        init_placements(&args, &devices)?
+    };
+    let names = placements.iter().map(|v| v.device).collect::<Vec<_>>();
+    info!("Running the models on {}", names.join(", "));
     ort_builder.commit()?;
 
+    if args.benchmark {
+        return benchmark(&args, benchmarked_model, &placements[0]).await;
+    }
+
+    let config_profiles = match &file_config {
+        Some(config) => ConfigProfile::from_config(config),
+        None => vec![],
+    };
+    let profile = match &args.profile {
+        Some(name) => Some(ConfigProfile::load(project_fs(), &config_profiles, name).await?),
+        None => None,
+    };
+
     if args.prompt.is_empty() {
-        let (text_encoder, decoder, audio_encodec) = build_music_gen_parts(&args).await?;
+        let replicas = build_replicas(&args, args.model, &placements).await?;
+        let config = replicas[0].config.clone();
+        let configs = replicas.iter().map(|v| v.config.clone()).collect::<Vec<_>>();
+        // Command line flags take precedence over the config file.
+        let mut server = config.read().unwrap().server.clone();
+        let mut pipeline = config.read().unwrap().pipeline.clone();
+        // Each device runs the stages of its own jobs.
+        if placements.len() > 1 {
+            pipeline.max_concurrent_jobs = Some(pipeline.max_in_flight() * placements.len());
+        }
+        let secrets = config.read().unwrap().secrets.resolve()?;
+        // Kept when the cached models are evicted through the admin API, including the
+        // ones that can be switched to.
+        let model_files = [Some(args.model), args.shadow_model]
+            .into_iter()
+            .flatten()
+            .chain(args.switchable_models.iter().copied())
+            .flat_map(|model| model_files(model, args.use_split_decoder))
+            .map(|(_, local_file)| local_file.to_string())
+            .collect();
+        if let Some(port) = args.ui_port {
+            server.port = port;
+        }
+        server.expose |= args.ui_expose;
+        server.self_signed_tls |= args.ui_https;
+        server.auto_open &= !args.ui_no_open;
+        if let Some(path) = &args.config {
+            set_log_level(&config.read().unwrap().log_level)?;
+            config_watcher::watch_config(path.clone(), config.clone(), move |config| {
+                if let Err(err) = set_log_level(&config.log_level) {
+                    warn!("Could not apply log level {}: {err}", config.log_level);
+                }
+            });
+            for config in &configs[1..] {
+                config_watcher::watch_config(path.clone(), config.clone(), |_| {});
+            }
+        }
+        let shadow = match args.shadow_model {
+            Some(model) => Some(backend::ShadowOptions {
+                processor: into_processor(
+                    build_replicas(&args, model, &placements).await?,
+                    scheduling,
+                ),
+                fraction: args.shadow_fraction,
+            }),
+            None => None,
+        };
+        // The models that jobs switch to are loaded from the worker that runs the job.
+        let (loader_args, runtime) = (args.clone(), tokio::runtime::Handle::current());
+        let loader_placements = placements.clone();
+        let loader: backend::ModelLoader = Box::new(move |name| {
+            let model = <Model as ValueEnum>::from_str(name, true)
+                .map_err(|_| anyhow!("Unknown model {name}"))?
+                .with_precision(loader_args.precision)?;
+            let replicas =
+                runtime.block_on(build_replicas(&loader_args, model, &loader_placements))?;
+            Ok(into_processor(replicas, scheduling))
+        });
+        let processor = backend::ModelSwitchingProcessor::new(
+            default_model,
+            into_processor(replicas, scheduling),
+            loader,
+            args.switchable_models.iter().map(|v| model_name(*v)).collect(),
+            args.max_loaded_models,
+        );
         backend::run(
-            PROJECT_FS.clone(),
-            backend::MusicGenJobProcessor {
-                name: args.model.to_string(),
-                device: device.to_string(),
-                text_encoder,
-                decoder,
-                audio_encodec,
-            },
+            project_fs().clone(),
+            processor,
             backend::RunOptions {
-                port: args.ui_port,
-                auto_open: true,
-                expose: args.ui_expose,
+                server,
+                profile,
+                config_profiles,
+                shadow,
+                pipeline,
+                api_key: secrets.api_key,
+                webhook_secret: secrets.webhook_secret,
+                admin_token: secrets.admin_token,
+                model_files,
+                unix_socket: args.ui_unix_socket.clone(),
+                unix_socket_only: args.ui_unix_socket_only,
             },
         )
         .await
     } else {
-        cli_interface(&args).await
+        cli_interface(&args, profile, &placements[0]).await
     }
 }
 
@@ -211,32 +592,54 @@ async fn main() {
         .with_timer(UtcTime::new(time_format));
     let filter = EnvFilter::new("info,ort=off");
 
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .event_format(format)
         .with_max_level(tracing::Level::INFO)
         .with_env_filter(filter)
-        .init();
-    if let Err(err) = _main().await {
+        .with_filter_reloading();
+    let filter_handle = subscriber.reload_handle();
+    subscriber.init();
+    let set_log_level: SetLogLevel = Box::new(move |level| {
+        Ok(filter_handle.reload(EnvFilter::new(format!("{level},ort=off")))?)
+    });
+    if let Err(err) = _main(set_log_level).await {
         error!("{err}");
         exit(1)
     }
 }
 
 const INPUT_IDS_BATCH_PER_SECOND: usize = 50;
+/// Longest audio generated from the command line, long ones are made of segments.
+const MAX_CLI_SECS: usize = 300;
 
 #[allow(unused_assignments, unused_variables)]
-async fn cli_interface(args: &Args) -> anyhow::Result<()> {
-    let (text_encoder, decoder, audio_encodec) = build_music_gen_parts(args).await?;
+async fn cli_interface(
+    args: &Args,
+    profile: Option<ConfigProfile>,
+    placement: &Placement,
+) -> anyhow::Result<()> {
+    let model = fitting_model(args, args.model, placement.device).await?;
+    let (text_encoder, decoder, _, audio_encodec, config) =
+        build_music_gen_parts(args, model, placement).await?;
     let secs_re = Regex::new("--secs[ =](\\d+)")?;
     let output_re = Regex::new(r"--output[ =]([.a-zA-Z_-]+)")?;
 
-    let audio_player = AudioManager::default();
+    let channels = config.read().unwrap().decoder.audio_channels as u16;
+    let audio_player = AudioManager::with_channels(channels);
+    let long_form = {
+        let decoder = &config.read().unwrap().decoder;
+        LongForm {
+            context: decoder.context_secs * INPUT_IDS_BATCH_PER_SECOND,
+            overlap: decoder.long_form_overlap_secs * INPUT_IDS_BATCH_PER_SECOND,
+        }
+    };
     // This variable holds the audio stream. The stream stops when this is dropped,
     // so we need to maintain it referenced here.
     let mut curr_stream: Option<AudioStream> = None;
     let mut prompt = args.prompt.clone();
     let mut secs = args.secs;
     let mut output = args.output.clone();
+    let generation_config = profile.map(|p| p.config).unwrap_or_default();
 
     loop {
         if prompt.is_empty() {
@@ -265,20 +668,34 @@ async fn cli_interface(args: &Args) -> anyhow::Result<()> {
                 }
             }
         }
-        // First, encode the text.
-        let (last_hidden_state, attention_mask) = text_encoder.encode(&prompt)?;
-
-        // Second, generate tokens.
+        // First, generate the tokens, in segments that continue each other if the audio
+        // is longer than the context of the model. The text is encoded for each one.
         let max_len = secs * INPUT_IDS_BATCH_PER_SECOND;
-        let token_stream = decoder.generate_tokens(last_hidden_state, attention_mask, max_len)?;
         let bar = LoadingBarFactor::bar("Generating audio");
-        let mut data = VecDeque::new();
-        while let Ok(tokens) = token_stream.recv() {
-            data.push_back(tokens?);
-            bar.update_elapsed_total(data.len(), max_len)
+        let progress = ProgressReporter::new(Some(&bar), &prompt, max_len);
+        let mut data = VecDeque::<Vec<i64>>::new();
+        while let Some((prompted, new)) = long_form.next(data.len(), 0, max_len) {
+            let (last_hidden_state, attention_mask) = text_encoder.encode(&prompt, None)?;
+            let token_stream = decoder.generate_tokens(
+                last_hidden_state,
+                attention_mask,
+                None,
+                data.range(data.len() - prompted..).cloned().collect(),
+                new,
+                &generation_config,
+                CancellationToken::new(),
+            )?;
+            let before = data.len();
+            while let Ok(tokens) = token_stream.recv() {
+                data.push_back(tokens?);
+                progress.step(data.len(), None);
+            }
+            if data.len() == before {
+                break;
+            }
         }
 
-        // Third, encode the tokens into audio.
+        // Then, encode the tokens into audio.
         let samples = audio_encodec.encode(data)?;
 
         // Last, play the audio.
@@ -304,6 +721,98 @@ async fn cli_interface(args: &Args) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Generates the benchmark prompts with `model` on `placement`, in each of the precisions
+/// of --benchmark-precisions.
+async fn benchmark(args: &Args, model: Model, placement: &Placement) -> anyhow::Result<()> {
+    let device = placement.device;
+    let precisions = match args.benchmark_precisions.is_empty() {
+        true => vec![args.precision],
+        false => args.benchmark_precisions.clone(),
+    };
+    let max_len = benchmark::SECS * INPUT_IDS_BATCH_PER_SECOND;
+    let mut results = vec![];
+    for precision in precisions {
+        let model = match model.with_precision(precision) {
+            Ok(model) => fitting_model(args, model, device).await?,
+            Err(err) => {
+                warn!("{err}, skipping it");
+                continue;
+            }
+        };
+        let (text_encoder, decoder, _, audio_encodec, _) =
+            build_music_gen_parts(args, model, placement).await?;
+        let mut memory = PeakMemory::new()?;
+        for prompt in benchmark::PROMPTS {
+            info!("Benchmarking {model} with \"{prompt}\"");
+            let started = Instant::now();
+            let (last_hidden_state, attention_mask) = text_encoder.encode(prompt, None)?;
+            let decoding = Instant::now();
+            let token_stream = decoder.generate_tokens(
+                last_hidden_state,
+                attention_mask,
+                None,
+                vec![],
+                max_len,
+                &Default::default(),
+                CancellationToken::new(),
+            )?;
+            let mut data = vec![];
+            while let Ok(tokens) = token_stream.recv() {
+                data.push(tokens?);
+                if data.len() % INPUT_IDS_BATCH_PER_SECOND == 0 {
+                    memory.sample();
+                }
+            }
+            let decoding = decoding.elapsed();
+            let tokens = data.len();
+            audio_encodec.encode(data)?;
+            results.push(BenchmarkResult::new(
+                model.to_string(),
+                device.to_string(),
+                prompt,
+                tokens,
+                decoding,
+                started.elapsed(),
+                memory.sample(),
+            ));
+        }
+    }
+    write_benchmark(args, &results)
+}
+
+/// Runs the benchmark on each of --benchmark-devices, each one in a process of its own.
+fn benchmark_devices(args: &Args) -> anyhow::Result<()> {
+    let current_args = std::env::args().skip(1).collect::<Vec<_>>();
+    let mut results = vec![];
+    for device in &args.benchmark_devices {
+        let name = device.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+        let output = std::env::temp_dir()
+            .join(format!("musicgpt-benchmark-{name}-{}.json", std::process::id()));
+        let status = std::process::Command::new(std::env::current_exe()?)
+            .args(benchmark::single_device_args(&current_args))
+            .args(["--benchmark", "--device", &name, "--benchmark-output"])
+            .arg(&output)
+            .status()?;
+        if !status.success() {
+            warn!("The benchmark on {name} failed");
+            continue;
+        }
+        let device_results = std::fs::read(&output)?;
+        let _ = std::fs::remove_file(&output);
+        results.extend(serde_json::from_slice::<Vec<serde_json::Value>>(&device_results)?);
+    }
+    write_benchmark(args, &results)
+}
+
+fn write_benchmark<T: serde::Serialize>(args: &Args, results: &[T]) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(results)?;
+    match &args.benchmark_output {
+        Some(path) => std::fs::write(path, json)?,
+        None => println!("{json}"),
+    }
+    Ok(())
+}
+
 #[cfg(feature = "onnxruntime-from-source")]
 async fn lookup_dyn_onnxruntime_lib() -> anyhow::Result<PathBuf> {
     // If running with Cargo, build.rs have set this ONNXRUNTIME_LOCAL_FILES env to the
@@ -332,11 +841,12 @@ async fn lookup_dyn_onnxruntime_lib() -> anyhow::Result<PathBuf> {
     download(
         remote_file_spec,
         false,
+        None,
         &format!("Dynamic libraries not found in path set by ONNXRUNTIME_LOCAL_FILES env variable. Downloading them from GitHub release {PKG_VERSION}..."),
         "Dynamic libraries downloaded successfully",
     )
     .await?;
-    let main_dynlib_file = PROJECT_FS.path_buf(&format!(
+    let main_dynlib_file = project_fs().path_buf(&format!(
         "dynlibs/{ONNXRUNTIME_VERSION}/{MAIN_DYNLIB_FILENAME}"
     ));
     if !tokio::fs::try_exists(&main_dynlib_file).await? {
@@ -345,6 +855,175 @@ async fn lookup_dyn_onnxruntime_lib() -> anyhow::Result<PathBuf> {
     Ok(main_dynlib_file)
 }
 
+/// The device that the models run on, and the execution providers that run them there
+/// in order of preference, none for the CPU.
+fn init_device(args: &Args) -> anyhow::Result<(&'static str, Vec<ExecutionProviderDispatch>)> {
+    let device = match args.device {
+        Some(device) => device,
+        None if args.gpu => {
+            warn!("GPU support is experimental, it might not work on most platforms");
+            let (device, provider) = init_gpu()?;
+            return Ok((device, vec![provider]));
+        }
+        None => Device::Cpu,
+    };
+    Ok(init_device_or_cpu(args, device, args.gpu_device_id))
+}
+
+/// The execution providers of the `device_id`th GPU of `device`, or none if it can not
+/// be used, in which case the models run on the CPU.
+fn init_device_or_cpu(
+    args: &Args,
+    device: Device,
+    device_id: i32,
+) -> (&'static str, Vec<ExecutionProviderDispatch>) {
+    let result = match device {
+        Device::Cpu => return ("Cpu", vec![]),
+        Device::Cuda => init_cuda(args, device_id).map(|provider| ("Cuda", vec![provider])),
+        Device::TensorRT => init_tensorrt(args, device_id),
+        Device::CoreML => init_coreml(args).map(|provider| ("CoreML", vec![provider])),
+        Device::DirectML => {
+            init_directml(args, device_id).map(|provider| ("DirectML", vec![provider]))
+        }
+        Device::ROCm => init_rocm(device_id).map(|provider| ("ROCm", vec![provider])),
+    };
+    result.unwrap_or_else(|err| {
+        warn!("{err}, falling back to the CPU");
+        ("Cpu", vec![])
+    })
+}
+
+/// Where each of the `devices` of the config, like "cuda:1", runs its copy of the models.
+fn init_placements(args: &Args, devices: &[String]) -> anyhow::Result<Vec<Placement>> {
+    let mut placements = vec![];
+    for spec in devices {
+        let (name, device_id) = spec.split_once(':').unwrap_or((spec, "0"));
+        let device = <Device as ValueEnum>::from_str(name, true)
+            .map_err(|_| anyhow!("Unknown device {spec}"))?;
+        let device_id = device_id
+            .parse()
+            .map_err(|_| anyhow!("Invalid GPU index in device {spec}"))?;
+        let (device, providers) = init_device_or_cpu(args, device, device_id);
+        placements.push(Placement {
+            device,
+            device_id,
+            providers,
+        });
+    }
+    Ok(placements)
+}
+
+fn init_cuda(args: &Args, device_id: i32) -> anyhow::Result<ExecutionProviderDispatch> {
+    if !cfg!(feature = "cuda") {
+        return Err(anyhow!("This build has no CUDA support"));
+    }
+    let mut provider = CUDAExecutionProvider::default().with_device_id(device_id);
+    if let Some(limit) = args.gpu_memory_limit {
+        provider = provider.with_memory_limit(limit.0 as usize);
+    }
+    provider
+        .register(&mut Session::builder()?)
+        .map_err(|err| anyhow!("Could not load {}: {err}", provider.as_str()))?;
+    Ok(provider.build())
+}
+
+fn init_coreml(args: &Args) -> anyhow::Result<ExecutionProviderDispatch> {
+    if !cfg!(feature = "coreml") {
+        return Err(anyhow!("This build has no CoreML support"));
+    }
+    let provider = match args.coreml_compute_units {
+        ComputeUnits::All => CoreMLExecutionProvider::default(),
+        ComputeUnits::Ane => CoreMLExecutionProvider::default().with_ane_only(),
+    };
+    provider
+        .register(&mut Session::builder()?)
+        .map_err(|err| anyhow!("Could not load {}: {err}", provider.as_str()))?;
+    Ok(provider.build())
+}
+
+fn init_directml(args: &Args, device_id: i32) -> anyhow::Result<ExecutionProviderDispatch> {
+    if !cfg!(feature = "directml") {
+        return Err(anyhow!("This build has no DirectML support"));
+    }
+    // Which index is which GPU is not obvious in machines with an integrated one.
+    match directml_adapters() {
+        Ok(adapters) => {
+            for (i, adapter) in adapters.iter().enumerate() {
+                info!("DirectML adapter {i}: {adapter}");
+            }
+        }
+        Err(err) => warn!("Could not list the DirectML adapters: {err}"),
+    }
+    let provider = DirectMLExecutionProvider::default().with_device_id(device_id);
+    provider
+        .register(&mut Session::builder()?)
+        .map_err(|err| anyhow!("Could not load {}: {err}", provider.as_str()))?;
+    Ok(provider.build())
+}
+
+fn init_rocm(device_id: i32) -> anyhow::Result<ExecutionProviderDispatch> {
+    if !cfg!(feature = "rocm") {
+        return Err(anyhow!("This build has no ROCm support"));
+    }
+    let provider = ROCmExecutionProvider::default().with_device_id(device_id);
+    provider
+        .register(&mut Session::builder()?)
+        .map_err(|err| anyhow!("Could not load {}: {err}", provider.as_str()))?;
+    Ok(provider.build())
+}
+
+/// The names of the GPUs that DirectML can run on, by their --gpu-device-id.
+#[cfg(windows)]
+fn directml_adapters() -> anyhow::Result<Vec<String>> {
+    use windows::Win32::Graphics::Dxgi::{CreateDXGIFactory1, IDXGIFactory1};
+
+    let factory: IDXGIFactory1 = unsafe { CreateDXGIFactory1()? };
+    let mut adapters = vec![];
+    // Enumerating fails past the last adapter.
+    while let Ok(adapter) = unsafe { factory.EnumAdapters1(adapters.len() as u32) } {
+        let description = unsafe { adapter.GetDesc1()? }.Description;
+        let len = description.iter().position(|&c| c == 0).unwrap_or(description.len());
+        adapters.push(String::from_utf16_lossy(&description[..len]));
+    }
+    Ok(adapters)
+}
+
+#[cfg(not(windows))]
+fn directml_adapters() -> anyhow::Result<Vec<String>> {
+    Err(anyhow!("DirectML is only available on Windows"))
+}
+
+/// TensorRT, followed by CUDA for the nodes that TensorRT does not support.
+fn init_tensorrt(
+    args: &Args,
+    device_id: i32,
+) -> anyhow::Result<(&'static str, Vec<ExecutionProviderDispatch>)> {
+    if !cfg!(feature = "tensorrt") {
+        return Err(anyhow!("This build has no TensorRT support"));
+    }
+    let cache_dir = args.tensorrt_cache_dir.clone().unwrap_or_else(tensorrt_cache_dir);
+    std::fs::create_dir_all(&cache_dir)?;
+    let cache_dir = cache_dir.to_string_lossy().to_string();
+    let mut provider = TensorRTExecutionProvider::default()
+        .with_device_id(device_id)
+        .with_engine_cache(true)
+        .with_engine_cache_path(&cache_dir)
+        .with_timing_cache(true)
+        .with_timing_cache_path(&cache_dir);
+    if let Some(limit) = args.gpu_memory_limit {
+        provider = provider.with_max_workspace_size(limit.0 as usize);
+    }
+    provider
+        .register(&mut Session::builder()?)
+        .map_err(|err| anyhow!("Could not load {}: {err}", provider.as_str()))?;
+    info!("TensorRT engines are cached in {cache_dir}, the first run builds them");
+    let mut providers = vec![provider.build()];
+    if let Ok(cuda) = init_cuda(args, device_id) {
+        providers.push(cuda);
+    }
+    Ok(("TensorRT", providers))
+}
+
 fn init_gpu() -> anyhow::Result<(&'static str, ExecutionProviderDispatch)> {
     let mut dummy_builder = Session::builder()?;
 
@@ -368,6 +1047,16 @@ fn init_gpu() -> anyhow::Result<(&'static str, ExecutionProviderDispatch)> {
             Err(err) => error!("Could not load {}: {}", provider.as_str(), err),
         }
     }
+    if cfg!(feature = "rocm") {
+        let provider = ROCmExecutionProvider::default();
+        match provider.register(&mut dummy_builder) {
+            Ok(_) => {
+                info!("{} detected", provider.as_str());
+                return Ok(("ROCm", provider.build()));
+            }
+            Err(err) => error!("Could not load {}: {}", provider.as_str(), err),
+        }
+    }
     if cfg!(feature = "coreml") {
         let provider = CoreMLExecutionProvider::default().with_ane_only();
         match provider.register(&mut dummy_builder) {
@@ -384,13 +1073,8 @@ fn init_gpu() -> anyhow::Result<(&'static str, ExecutionProviderDispatch)> {
     ))
 }
 
-async fn build_music_gen_parts(
-    args: &Args,
-) -> anyhow::Result<(
-    MusicGenTextEncoder,
-    Box<dyn MusicGenDecoder>,
-    MusicGenAudioEncodec,
-)> {
+/// The files of `model`, as (remote url, local file in the data dir) pairs.
+fn model_files(model: Model, use_split_decoder: bool) -> Vec<(&'static str, &'static str)> {
     macro_rules! hf_url {
         ($t: expr) => {
             (
@@ -402,7 +1086,7 @@ async fn build_music_gen_parts(
             )
         };
     }
-    let remote_file_spec = match (args.model, args.use_split_decoder) {
+    let mut files = match (model, use_split_decoder) {
         (Model::Small, true) => vec![
             hf_url!("small/config.json"),
             hf_url!("small/tokenizer.json"),
@@ -465,6 +1149,49 @@ async fn build_music_gen_parts(
             hf_url!("large_fp32/decoder_model.onnx_data"),
             hf_url!("large_fp32/decoder_with_past_model.onnx_data"),
         ],
+        (Model::Melody, true) => vec![
+            hf_url!("melody/config.json"),
+            hf_url!("melody/tokenizer.json"),
+            hf_url!("melody_fp32/text_encoder.onnx"),
+            hf_url!("melody_fp32/decoder_model.onnx"),
+            hf_url!("melody_fp32/decoder_with_past_model.onnx"),
+            hf_url!("melody_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("melody_fp32/decoder_model.onnx_data"),
+            hf_url!("melody_fp32/decoder_with_past_model.onnx_data"),
+        ],
+        (Model::SmallStereo, true) => vec![
+            hf_url!("small_stereo/config.json"),
+            hf_url!("small_stereo/tokenizer.json"),
+            hf_url!("small_stereo_fp32/text_encoder.onnx"),
+            hf_url!("small_stereo_fp32/decoder_model.onnx"),
+            hf_url!("small_stereo_fp32/decoder_with_past_model.onnx"),
+            hf_url!("small_stereo_fp32/encodec_decode.onnx"),
+        ],
+        (Model::MediumStereo, true) => vec![
+            hf_url!("medium_stereo/config.json"),
+            hf_url!("medium_stereo/tokenizer.json"),
+            hf_url!("medium_stereo_fp32/text_encoder.onnx"),
+            hf_url!("medium_stereo_fp32/decoder_model.onnx"),
+            hf_url!("medium_stereo_fp32/decoder_with_past_model.onnx"),
+            hf_url!("medium_stereo_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("medium_stereo_fp32/decoder_model.onnx_data"),
+            hf_url!("medium_stereo_fp32/decoder_with_past_model.onnx_data"),
+        ],
+        // Same architecture as MusicGen, with a 16kHz EnCodec. Its config has the sampling
+        // rate and the generation defaults that suit sound effects.
+        (Model::AudioGen, true) => vec![
+            hf_url!("audiogen_medium/config.json"),
+            hf_url!("audiogen_medium/tokenizer.json"),
+            hf_url!("audiogen_medium_fp32/text_encoder.onnx"),
+            hf_url!("audiogen_medium_fp32/decoder_model.onnx"),
+            hf_url!("audiogen_medium_fp32/decoder_with_past_model.onnx"),
+            hf_url!("audiogen_medium_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("audiogen_medium_fp32/decoder_model.onnx_data"),
+            hf_url!("audiogen_medium_fp32/decoder_with_past_model.onnx_data"),
+        ],
         (Model::Small, false) => vec![
             hf_url!("small/config.json"),
             hf_url!("small/tokenizer.json"),
@@ -520,15 +1247,62 @@ async fn build_music_gen_parts(
             // Files below will just be downloaded,
             hf_url!("large_fp32/decoder_model_merged.onnx_data"),
         ],
+        (Model::Melody, false) => vec![
+            hf_url!("melody/config.json"),
+            hf_url!("melody/tokenizer.json"),
+            hf_url!("melody_fp32/text_encoder.onnx"),
+            hf_url!("melody_fp32/decoder_model_merged.onnx"),
+            hf_url!("melody_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("melody_fp32/decoder_model_merged.onnx_data"),
+        ],
+        (Model::SmallStereo, false) => vec![
+            hf_url!("small_stereo/config.json"),
+            hf_url!("small_stereo/tokenizer.json"),
+            hf_url!("small_stereo_fp32/text_encoder.onnx"),
+            hf_url!("small_stereo_fp32/decoder_model_merged.onnx"),
+            hf_url!("small_stereo_fp32/encodec_decode.onnx"),
+        ],
+        (Model::MediumStereo, false) => vec![
+            hf_url!("medium_stereo/config.json"),
+            hf_url!("medium_stereo/tokenizer.json"),
+            hf_url!("medium_stereo_fp32/text_encoder.onnx"),
+            hf_url!("medium_stereo_fp32/decoder_model_merged.onnx"),
+            hf_url!("medium_stereo_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("medium_stereo_fp32/decoder_model_merged.onnx_data"),
+        ],
+        (Model::AudioGen, false) => vec![
+            hf_url!("audiogen_medium/config.json"),
+            hf_url!("audiogen_medium/tokenizer.json"),
+            hf_url!("audiogen_medium_fp32/text_encoder.onnx"),
+            hf_url!("audiogen_medium_fp32/decoder_model_merged.onnx"),
+            hf_url!("audiogen_medium_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("audiogen_medium_fp32/decoder_model_merged.onnx_data"),
+        ],
     };
+    // All the MusicGen models share the same EnCodec, only needed for continuing existing
+    // audio.
+    files.push(match model {
+        Model::AudioGen => hf_url!("encodec_16khz/encodec_encode.onnx"),
+        _ => hf_url!("encodec_32khz/encodec_encode.onnx"),
+    });
+    files
+}
 
-    let mut results = download(
-        remote_file_spec,
-        args.force_download,
-        "Some AI models need to be downloaded, this only needs to be done once",
-        "AI models downloaded correctly",
-    )
-    .await?;
+async fn build_music_gen_parts(
+    args: &Args,
+    model: Model,
+    placement: &Placement,
+) -> anyhow::Result<(
+    MusicGenTextEncoder,
+    Box<dyn MusicGenDecoder>,
+    backend::DecoderFactory,
+    MusicGenAudioEncodec,
+    Arc<RwLock<MusicGenConfig>>,
+)> {
+    let mut results = download_model(args, model).await?;
 
     // First result is the decoder config.
     let config = results.pop_front().unwrap();
@@ -540,70 +1314,174 @@ async fn build_music_gen_parts(
         .with_truncation(None)
         .expect("Could not configure tokenizer");
 
-    let mut sessions = build_sessions(results).await?;
+    let mut onnx_files = results
+        .into_iter()
+        .filter(|file| file.extension() == Some("onnx".as_ref()))
+        .collect::<Vec<_>>();
+    // The very last result is the EnCodec encoder.
+    let encodec_encode = onnx_files.pop().unwrap();
+    // Third result is the text encoder, the last one is the audio encodec, and
+    // the decoder parts are in between. The decoder is loaded separately, as it
+    // might need to be recreated if it stalls.
+    let decoder_files = onnx_files[1..onnx_files.len() - 1].to_vec();
+
+    let config = match &args.config {
+        Some(path) => MusicGenConfig::from_file(path)?,
+        None => {
+            let config = tokio::fs::read_to_string(config)
+                .await
+                .expect("Error reading config file from disk");
+            serde_json::from_str(&config).expect("Could not deserialize config file")
+        }
+    };
+
+    let mut sessions = build_sessions(
+        [
+            onnx_files[0].clone(),
+            onnx_files[onnx_files.len() - 1].clone(),
+            encodec_encode,
+        ],
+        &config.threading,
+        &placement.providers,
+    )
+    .await?;
 
     let text_encoder = MusicGenTextEncoder {
         tokenizer,
-        // third result is the text encoder.
         text_encoder: sessions.pop_front().unwrap(),
     };
+    let audio_encodec = MusicGenAudioEncodec {
+        audio_encodec_decode: sessions.pop_front().unwrap(),
+        audio_encodec_encode: sessions.pop_front().unwrap(),
+    };
 
-    let config = tokio::fs::read_to_string(config)
-        .await
-        .expect("Error reading config file from disk");
-    let config = serde_json::from_str(&config).expect("Could not deserialize config file");
-    #[allow(clippy::collapsible_else_if)]
-    let decoder: Box<dyn MusicGenDecoder> = if args.use_split_decoder {
-        macro_rules! load {
-            ($ty: ty) => {
-                Box::new(MusicGenSplitDecoder::<$ty> {
-                    // forth and fifth result are the decoder parts if split.
-                    decoder_model: sessions.pop_front().unwrap(),
-                    decoder_with_past_model: Arc::new(sessions.pop_front().unwrap()),
-                    config,
-                    _phantom_data: Default::default(),
-                })
-            };
-        }
-        if matches!(args.model, Model::SmallFp16 | Model::MediumFp16) {
-            load!(f16)
-        } else {
-            load!(f32)
+    let config = Arc::new(RwLock::new(config));
+
+    let decoder_config = config.clone();
+    let device = allocation_device(placement.device);
+    let (device_id, providers) = (placement.device_id, placement.providers.clone());
+    let decoder_factory: backend::DecoderFactory = Box::new(move || {
+        let config = decoder_config.clone();
+        build_decoder(&decoder_files, config, &providers, device, device_id)
+    });
+    let bar = LoadingBarFactor::spinner("Loading decoder...");
+    let decoder = decoder_factory()?;
+    bar.finish_and_clear();
+
+    Ok((text_encoder, decoder, decoder_factory, audio_encodec, config))
+}
+
+/// The files of `model`, downloading the ones that are not yet.
+async fn download_model(args: &Args, model: Model) -> anyhow::Result<VecDeque<PathBuf>> {
+    download(
+        model_files(model, args.use_split_decoder),
+        args.force_download,
+        args.huggingface_token.as_ref(),
+        "Some AI models need to be downloaded, this only needs to be done once",
+        "AI models downloaded correctly",
+    )
+    .await
+}
+
+/// `model`, or its export with an int8 quantized decoder if it does not fit in the
+/// available memory and that one does. The quantized decoders take and give f32 tensors
+/// like the fp32 ones, so nothing else changes.
+async fn fitting_model(args: &Args, mut model: Model, device: &str) -> anyhow::Result<Model> {
+    loop {
+        // Big models are spread over the .onnx files and their external .onnx_data files,
+        // which the sessions load from next to them.
+        let files = download_model(args, model).await?;
+        let needed = memory_preflight::estimated_memory(&Vec::from(files))?;
+        if device != "Cpu" {
+            info!("{model} needs about {} of GPU memory", memory_preflight::gib(needed));
+            return Ok(model);
         }
-    } else {
-        macro_rules! load {
-            ($ty: ty) => {
-                Box::new(MusicGenMergedDecoder::<$ty> {
-                    // forth result is the decoder.
-                    decoder_model_merged: Arc::new(sessions.pop_front().unwrap()),
-                    config,
-                    _phantom_data: Default::default(),
-                })
-            };
+        if args.skip_memory_check {
+            return Ok(model);
         }
-        if matches!(args.model, Model::SmallFp16 | Model::MediumFp16) {
-            load!(f16)
-        } else {
-            load!(f32)
+        let available = memory_preflight::available_memory();
+        match memory_preflight::check_memory(&model.to_string(), needed, available) {
+            Ok(()) => return Ok(model),
+            Err(err) => match model.quantized() {
+                Some(quantized) => {
+                    warn!("{err}. Falling back to {quantized}");
+                    model = quantized;
+                }
+                None => return Err(err),
+            },
         }
-    };
-    let audio_encodec = MusicGenAudioEncodec {
-        // last result is the audio encodec.
-        audio_encodec_decode: sessions.pop_front().unwrap(),
-    };
+    }
+}
+
+/// The job processor of `model`, running on `placement`.
+async fn build_processor(
+    args: &Args,
+    model: Model,
+    placement: &Placement,
+) -> anyhow::Result<backend::MusicGenJobProcessor> {
+    let device = placement.device;
+    let model = fitting_model(args, model, device).await?;
+    let (text_encoder, decoder, decoder_factory, audio_encodec, config) =
+        build_music_gen_parts(args, model, placement).await?;
+    let pipeline = backend::Pipeline::new(&config.read().unwrap().pipeline);
+    Ok(backend::MusicGenJobProcessor {
+        name: model.to_string(),
+        device: device.to_string(),
+        text_encoder,
+        decoder: RwLock::new(decoder),
+        decoder_factory,
+        audio_encodec,
+        pipeline,
+        config,
+        data_dir: project_fs().root.clone(),
+        progress_sink: None,
+    })
+}
 
-    Ok((text_encoder, decoder, audio_encodec))
+/// A job processor of `model` on each of `placements`.
+async fn build_replicas(
+    args: &Args,
+    model: Model,
+    placements: &[Placement],
+) -> anyhow::Result<Vec<backend::MusicGenJobProcessor>> {
+    let mut replicas = vec![];
+    for placement in placements {
+        replicas.push(build_processor(args, model, placement).await?);
+    }
+    Ok(replicas)
+}
+
+/// Spreads the jobs across the `replicas` with `scheduling`, if there are several.
+fn into_processor(
+    mut replicas: Vec<backend::MusicGenJobProcessor>,
+    scheduling: DeviceScheduling,
+) -> Box<dyn backend::JobProcessor> {
+    if replicas.len() == 1 {
+        return Box::new(replicas.remove(0));
+    }
+    let replicas = replicas
+        .into_iter()
+        .map(|v| Box::new(v) as Box<dyn backend::JobProcessor>)
+        .collect();
+    Box::new(backend::MultiDeviceProcessor::new(replicas, scheduling))
+}
+
+/// The name of `model` in the command line, which is also the one jobs switch to it by.
+fn model_name(model: Model) -> String {
+    model.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
 }
 
 async fn download<T: Display>(
     remote_file_spec: Vec<(T, T)>,
     force_download: bool,
+    bearer: Option<&Secret>,
     on_download_msg: &str,
     on_finished_msg: &str,
 ) -> anyhow::Result<VecDeque<PathBuf>> {
     let mut has_to_download = force_download;
     for (_, local_filename) in remote_file_spec.iter() {
-        has_to_download = has_to_download || !PROJECT_FS.exists(&local_filename.to_string()).await?
+        let local_filename = local_filename.to_string();
+        has_to_download = has_to_download || !project_fs().exists(&local_filename).await?
     }
 
     if has_to_download {
@@ -614,13 +1492,15 @@ async fn download<T: Display>(
     for (remote_file, local_filename) in remote_file_spec {
         let remote_file = remote_file.to_string();
         let local_filename = local_filename.to_string();
+        let bearer = bearer.map(|v| v.expose().to_string());
         let bar = m.add(LoadingBarFactor::download_bar(&local_filename));
         tasks.push(tokio::spawn(async move {
-            PROJECT_FS
+            project_fs()
                 .fetch_remote_data_file(
                     &remote_file,
                     &local_filename,
                     force_download,
+                    bearer.as_deref(),
                     bar.into_update_callback(),
                 )
                 .await
@@ -637,8 +1517,80 @@ async fn download<T: Display>(
     Ok(results)
 }
 
+/// Where the tensors that the sessions running on `device`, as named by [init_gpu],
+/// give back are allocated.
+fn allocation_device(device: &str) -> AllocationDevice {
+    match device {
+        "TensorRT" | "Cuda" => AllocationDevice::CUDA,
+        // CoreML has no memory of its own that ONNX Runtime can allocate on.
+        _ => AllocationDevice::CPU,
+    }
+}
+
+/// Builds the decoder from its ONNX files, which are either the merged decoder
+/// or the two parts of the split one, keeping its cache in the memory of `device`.
+fn build_decoder(
+    files: &[PathBuf],
+    config: Arc<RwLock<MusicGenConfig>>,
+    providers: &[ExecutionProviderDispatch],
+    device: AllocationDevice,
+    device_id: i32,
+) -> anyhow::Result<Box<dyn MusicGenDecoder>> {
+    let threading = config.read().unwrap().threading.clone();
+    let mut sessions = files
+        .iter()
+        .map(|file| session_builder(&threading, providers)?.commit_from_file(file))
+        .collect::<ort::Result<VecDeque<_>>>()?;
+    // The precision of the decoder is the one of its inputs. The int8 quantized decoders
+    // take f32 inputs, the quantization only applies to their weights.
+    let is_fp16 = sessions[0].inputs.iter().any(|input| {
+        input.name == "encoder_hidden_states"
+            && matches!(input.input_type, ValueType::Tensor { ty: TensorElementType::Float16, .. })
+    });
+    #[allow(clippy::collapsible_else_if)]
+    let decoder: Box<dyn MusicGenDecoder> = if sessions.len() == 2 {
+        macro_rules! load {
+            ($ty: ty) => {
+                Box::new(MusicGenSplitDecoder::<$ty> {
+                    decoder_model: sessions.pop_front().unwrap(),
+                    decoder_with_past_model: Arc::new(sessions.pop_front().unwrap()),
+                    config,
+                    device,
+                    device_id,
+                    _phantom_data: Default::default(),
+                })
+            };
+        }
+        if is_fp16 {
+            load!(f16)
+        } else {
+            load!(f32)
+        }
+    } else {
+        macro_rules! load {
+            ($ty: ty) => {
+                Box::new(MusicGenMergedDecoder::<$ty> {
+                    decoder_model_merged: Arc::new(sessions.pop_front().unwrap()),
+                    config,
+                    device,
+                    device_id,
+                    _phantom_data: Default::default(),
+                })
+            };
+        }
+        if is_fp16 {
+            load!(f16)
+        } else {
+            load!(f32)
+        }
+    };
+    Ok(decoder)
+}
+
 async fn build_sessions(
     files: impl IntoIterator<Item = PathBuf>,
+    threading: &ThreadingConfig,
+    providers: &[ExecutionProviderDispatch],
 ) -> anyhow::Result<VecDeque<Session>> {
     let mut results = VecDeque::new();
     for file in files {
@@ -649,9 +1601,34 @@ async fn build_sessions(
             format!("Loading {:?}...", file.file_name().unwrap_or_default()).as_str(),
         );
 
-        let result = Session::builder()?.commit_from_file(file)?;
+        let result = session_builder(threading, providers)?.commit_from_file(file)?;
         bar.finish_and_clear();
         results.push_back(result);
     }
     Ok(results)
 }
+
+/// A session builder with the threads of `threading`, running on `providers` if there
+/// are any instead of the ones of the environment.
+fn session_builder(
+    threading: &ThreadingConfig,
+    providers: &[ExecutionProviderDispatch],
+) -> ort::Result<SessionBuilder> {
+    let mut builder = Session::builder()?;
+    if !providers.is_empty() {
+        builder = builder.with_execution_providers(providers.to_vec())?;
+    }
+    if let Some(threads) = threading.intra_op_thread_count() {
+        builder = builder.with_intra_threads(threads)?;
+    }
+    if let Some(threads) = threading.inter_op_threads {
+        builder = builder.with_parallel_execution(true)?.with_inter_threads(threads)?;
+    }
+    if let Some(spin) = threading.spin {
+        builder = builder.with_intra_op_spinning(spin)?.with_inter_op_spinning(spin)?;
+    }
+    if let Some(affinities) = threading.intra_op_thread_affinities() {
+        builder = builder.with_config_entry("session.intra_op_thread_affinities", affinities)?;
+    }
+    Ok(builder)
+}
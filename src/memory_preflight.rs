@@ -0,0 +1,69 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use sysinfo::System;
+
+/// Memory taken on top of the weights by the ONNX Runtime sessions, the tokenizer
+/// and the buffers of the audio.
+const BASE_MEMORY: u64 = 512 << 20;
+
+/// Memory the sessions of a model need, estimated from the size of its files. The weights
+/// are kept in memory as they are in the files, including their external data, and the
+/// graph optimizations, the activations and the kv cache of the decoder take about half
+/// of it on top.
+pub fn estimated_memory(files: &[impl AsRef<Path>]) -> anyhow::Result<u64> {
+    let mut weights = 0;
+    for file in files {
+        weights += std::fs::metadata(file)?.len();
+    }
+    Ok(weights + weights / 2 + BASE_MEMORY)
+}
+
+/// Fails right away if `model`, which needs `needed` bytes, does not fit in the
+/// `available` ones, instead of running out of memory in the middle of a generation.
+pub fn check_memory(model: &str, needed: u64, available: u64) -> anyhow::Result<()> {
+    if needed <= available {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{model} needs about {} of memory, but only {} are available. Try a smaller \
+         model, a quantized one like --model small-quant, or --skip-memory-check if the \
+         estimate is wrong",
+        gib(needed),
+        gib(available)
+    ))
+}
+
+pub fn gib(bytes: u64) -> String {
+    format!("{:.1}GiB", bytes as f64 / (1u64 << 30) as f64)
+}
+
+/// Memory that can be taken right now without swapping.
+pub fn available_memory() -> u64 {
+    let mut system = System::new();
+    system.refresh_memory();
+    system.available_memory()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails_if_the_model_does_not_fit() {
+        assert!(check_memory("MusicGen Small", 1 << 30, 4 << 30).is_ok());
+        let err = check_memory("MusicGen Large", 12 << 30, 4 << 30).unwrap_err();
+        assert!(err.to_string().contains("needs about 12.0GiB"), "{err}");
+    }
+
+    #[test]
+    fn estimates_from_the_size_of_the_files() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join("musicgpt-preflight-test");
+        std::fs::create_dir_all(&dir)?;
+        let (model, data) = (dir.join("decoder.onnx"), dir.join("decoder.onnx_data"));
+        std::fs::write(&model, vec![0; 1000])?;
+        std::fs::write(&data, vec![0; 3000])?;
+        assert_eq!(estimated_memory(&[model, data])?, 6000 + BASE_MEMORY);
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt::Debug;
+
+use anyhow::anyhow;
+use mp3lame_encoder::{Bitrate, Builder, FlushNoGap, InterleavedPcm, MonoPcm, Quality, VbrMode};
+
+use crate::music_gen_config::Mp3Config;
+use crate::pcm::{to_pcm, BitDepth};
+
+/// Encodes `samples`, with `channels` interleaved channels at `sampling_rate`, as mp3.
+pub fn to_mp3(
+    samples: impl IntoIterator<Item = f32>,
+    channels: u16,
+    sampling_rate: u32,
+    config: &Mp3Config,
+) -> anyhow::Result<Vec<u8>> {
+    let mut builder = Builder::new().ok_or_else(|| anyhow!("Could not create the mp3 encoder"))?;
+    builder.set_num_channels(channels as u8).map_err(lame_error)?;
+    builder.set_sample_rate(sampling_rate).map_err(lame_error)?;
+    match config.vbr_quality {
+        Some(quality) => {
+            builder.set_vbr_mode(VbrMode::Mtrh).map_err(lame_error)?;
+            builder.set_vbr_quality(quality_of(quality)).map_err(lame_error)?;
+        }
+        None => builder.set_brate(bitrate_of(config.bitrate_kbps)).map_err(lame_error)?,
+    }
+    builder.set_quality(Quality::Good).map_err(lame_error)?;
+    let mut encoder = builder.build().map_err(lame_error)?;
+
+    let (samples, _) = to_pcm(samples, BitDepth::I16, true);
+    let samples = samples.into_iter().map(|v| v as i16).collect::<Vec<_>>();
+    let frames = samples.len() / channels.max(1) as usize;
+    let mut buffer = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(frames));
+    match channels {
+        1 => encoder.encode_to_vec(MonoPcm(&samples), &mut buffer),
+        _ => encoder.encode_to_vec(InterleavedPcm(&samples), &mut buffer),
+    }
+    .map_err(lame_error)?;
+    encoder.flush_to_vec::<FlushNoGap>(&mut buffer).map_err(lame_error)?;
+    Ok(buffer)
+}
+
+fn lame_error(err: impl Debug) -> anyhow::Error {
+    anyhow!("Could not encode the mp3: {err:?}")
+}
+
+/// The highest bitrate of the mp3 standard that is not over `kbps`.
+fn bitrate_of(kbps: u32) -> Bitrate {
+    match kbps {
+        ..=15 => Bitrate::Kbps8,
+        16..=23 => Bitrate::Kbps16,
+        24..=31 => Bitrate::Kbps24,
+        32..=39 => Bitrate::Kbps32,
+        40..=47 => Bitrate::Kbps40,
+        48..=63 => Bitrate::Kbps48,
+        64..=79 => Bitrate::Kbps64,
+        80..=95 => Bitrate::Kbps80,
+        96..=111 => Bitrate::Kbps96,
+        112..=127 => Bitrate::Kbps112,
+        128..=159 => Bitrate::Kbps128,
+        160..=191 => Bitrate::Kbps160,
+        192..=223 => Bitrate::Kbps192,
+        224..=255 => Bitrate::Kbps224,
+        256..=319 => Bitrate::Kbps256,
+        _ => Bitrate::Kbps320,
+    }
+}
+
+/// The quality of LAME numbered like in its command line, from 0, the best, to 9.
+fn quality_of(quality: u8) -> Quality {
+    match quality {
+        0 => Quality::Best,
+        1 => Quality::SecondBest,
+        2 => Quality::NearBest,
+        3 => Quality::VeryNice,
+        4 => Quality::Nice,
+        5 => Quality::Good,
+        6 => Quality::Decent,
+        7 => Quality::Ok,
+        8 => Quality::SecondWorst,
+        _ => Quality::Worst,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_far_smaller_than_the_wav() -> anyhow::Result<()> {
+        let tone = (0..32000).map(|i| (i as f32 * 0.05).sin() * 0.5).collect::<Vec<_>>();
+        let mp3 = to_mp3(tone.clone(), 1, 32000, &Mp3Config::default())?;
+        // A second at 192kbps is about 24KB.
+        assert!(!mp3.is_empty() && mp3.len() < 32000, "{}", mp3.len());
+        let vbr = Mp3Config { vbr_quality: Some(2), ..Default::default() };
+        let stereo = tone.iter().flat_map(|v| [*v, -*v]).collect::<Vec<_>>();
+        assert!(!to_mp3(stereo, 2, 32000, &vbr)?.is_empty());
+        Ok(())
+    }
+}
@@ -5,21 +5,43 @@ use ndarray::{Array, Axis};
 use ort::session::Session;
 use ort::value::DynValue;
 
+/// Codebooks of each channel, encodec only ever takes mono audio.
+const CODEBOOKS: usize = 4;
+
 pub struct MusicGenAudioEncodec {
     pub audio_encodec_decode: Session,
+    pub audio_encodec_encode: Session,
 }
 
 impl MusicGenAudioEncodec {
-    pub fn encode(&self, tokens: impl IntoIterator<Item = [i64; 4]>) -> ort::Result<VecDeque<f32>> {
-        let mut data = vec![];
-        for ids in tokens {
-            for id in ids {
-                data.push(id)
-            }
+    /// The audio of `tokens`. The frames of the stereo models have the codebooks of both
+    /// channels interleaved, and their audio comes out with the samples interleaved too.
+    pub fn encode(
+        &self,
+        tokens: impl IntoIterator<Item = Vec<i64>>,
+    ) -> ort::Result<VecDeque<f32>> {
+        let frames = tokens.into_iter().collect::<Vec<_>>();
+        let channels = frames.first().map_or(1, |frame| frame.len() / CODEBOOKS).max(1);
+        if channels == 1 {
+            return self.encode_channel(frames.into_iter().flatten().collect());
+        }
+        let mut decoded = vec![];
+        for channel in 0..channels {
+            let data = frames
+                .iter()
+                .flat_map(|frame| (0..CODEBOOKS).map(move |k| frame[k * channels + channel]))
+                .collect();
+            decoded.push(self.encode_channel(data)?);
         }
+        let len = decoded.iter().map(|v| v.len()).min().unwrap_or_default();
+        Ok((0..len)
+            .flat_map(|i| decoded.iter().map(move |v| v[i]))
+            .collect())
+    }
 
-        let seq_len = data.len() / 4;
-        let arr = Array::from_shape_vec((seq_len, 4), data).expect("Programming error");
+    fn encode_channel(&self, data: Vec<i64>) -> ort::Result<VecDeque<f32>> {
+        let seq_len = data.len() / CODEBOOKS;
+        let arr = Array::from_shape_vec((seq_len, CODEBOOKS), data).expect("Programming error");
         let arr = arr.t().insert_axis(Axis(0)).insert_axis(Axis(0));
         let mut outputs = self.audio_encodec_decode.run(ort::inputs![arr]?)?;
         let audio_values: DynValue = outputs
@@ -37,4 +59,30 @@ impl MusicGenAudioEncodec {
             "Token stream must be either f16 or f32",
         ))
     }
+
+    /// The opposite of [MusicGenAudioEncodec::encode], the tokens of some mono audio, so
+    /// that a generation can continue it. For `channels` > 1 the same tokens are used for
+    /// every channel.
+    pub fn tokens(&self, audio: &[f32], channels: usize) -> ort::Result<Vec<Vec<i64>>> {
+        let arr = Array::from_shape_vec((1, 1, audio.len()), audio.to_vec())
+            .expect("Programming error");
+        let mut outputs = self.audio_encodec_encode.run(ort::inputs![arr]?)?;
+        let audio_codes: DynValue = outputs
+            .remove("audio_codes")
+            .expect("audio_codes not found in output");
+
+        // Shaped (chunks, batch, codebooks, frames), with a single chunk and batch.
+        let (shape, codes) = audio_codes.try_extract_raw_tensor::<i64>()?;
+        let frames = shape.last().copied().unwrap_or_default() as usize;
+        if codes.len() != CODEBOOKS * frames {
+            return Err(ort::Error::new(format!("Unexpected audio codes shape {shape:?}")));
+        }
+        Ok((0..frames)
+            .map(|t| {
+                (0..CODEBOOKS * channels)
+                    .map(|i| codes[(i / channels) * frames + t])
+                    .collect()
+            })
+            .collect())
+    }
 }
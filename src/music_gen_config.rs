@@ -1,9 +1,17 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use specta::Type;
 use thiserror::Error;
+use uuid::Uuid;
 use validator::Validate;
 
+use crate::config_units::{ByteSize, HumanDuration};
+
 /// Configuration for the complete MusicGen pipeline
-#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Validate, Clone)]
 pub struct MusicGenConfig {
     #[serde(default = "default_audio_encoder")]
     #[validate]
@@ -22,10 +30,47 @@ pub struct MusicGenConfig {
     
     #[serde(default = "default_device")]
     pub device: String,
+
+    /// Devices that the models run on, like `["cuda:0", "cuda:1"]`, each one with its own
+    /// copy of the models. Jobs are spread across them, see `device_scheduling`. The
+    /// device of the command line is used when empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+
+    #[serde(default)]
+    pub device_scheduling: DeviceScheduling,
+
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    #[serde(default = "default_server")]
+    #[validate]
+    pub server: ServerConfig,
+
+    /// Named groups of overrides on top of this config, selectable at startup
+    /// or at runtime
+    #[serde(default)]
+    pub profiles: BTreeMap<String, GenerationConfig>,
+
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    #[serde(default)]
+    #[validate]
+    pub pipeline: PipelineConfig,
+
+    #[serde(default)]
+    pub threading: ThreadingConfig,
 }
 
 /// Audio encoder configuration
-#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Validate, Clone)]
 pub struct AudioEncoderConfig {
     #[serde(default = "default_sampling_rate")]
     #[validate(range(min = 8000, max = 192000))]
@@ -39,7 +84,7 @@ pub struct AudioEncoderConfig {
 }
 
 /// Transformer decoder configuration
-#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Validate, Clone)]
 pub struct DecoderConfig {
     #[serde(default = "default_num_attention_heads")]
     #[validate(range(min = 1, max = 32))]
@@ -51,16 +96,63 @@ pub struct DecoderConfig {
     
     #[serde(default = "default_top_k")]
     pub top_k: usize,
+
+    /// How much the audio sticks to the prompt, with classifier free guidance. 1 takes
+    /// the conditional logits as they are, higher values push them away from the
+    /// unconditional ones.
+    #[serde(default = "default_guidance_scale")]
+    #[validate(range(min = 0.0, max = 20.0))]
+    pub guidance_scale: f32,
+
+    /// Divides the logits before sampling, lower values make the likely tokens even more
+    /// likely, higher ones flatten the distribution.
+    #[serde(default = "default_temperature")]
+    #[validate(range(min = 0.01, max = 10.0))]
+    pub temperature: f32,
+
+    /// Samples only from the most likely tokens whose probabilities add up to this, after
+    /// the `top_k` ones are taken. 1 takes all of them.
+    #[serde(default = "default_top_p")]
+    #[validate(range(min = 0.0, max = 1.0))]
+    pub top_p: f32,
     
     #[serde(default = "default_pad_token_id")]
     pub pad_token_id: i64,
     
     #[serde(default = "default_hidden_size")]
     pub hidden_size: usize,
+
+    /// Token streams generated at each step, 4 for each audio channel.
+    #[serde(default = "default_num_codebooks")]
+    #[validate(range(min = 1, max = 16))]
+    pub num_codebooks: usize,
+
+    /// 2 for the stereo models, whose codebooks alternate between the left and right channels.
+    #[serde(default = "default_audio_channels")]
+    #[validate(range(min = 1, max = 2))]
+    pub audio_channels: usize,
+
+    /// Longest audio generated in one go, the length the model was trained on. Longer
+    /// jobs are generated in segments, each one continuing the end of the previous one.
+    #[serde(default = "default_context_secs")]
+    #[validate(range(min = 2, max = 120))]
+    pub context_secs: usize,
+
+    /// Audio at the end of a segment that the next one continues from.
+    #[serde(default = "default_long_form_overlap_secs")]
+    #[validate(range(min = 1, max = 60))]
+    pub long_form_overlap_secs: usize,
+
+    /// Jobs stop early once their audio ends in this many seconds of near silence, which
+    /// is trimmed. MusicGen has no end of audio token, so otherwise it always generates
+    /// the whole length asked for
+    #[serde(default)]
+    #[validate(range(min = 1, max = 60))]
+    pub stop_after_silence_secs: Option<usize>,
 }
 
 /// Text encoder configuration
-#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Validate, Clone)]
 pub struct TextEncoderConfig {
     #[serde(default = "default_d_kv")]
     pub d_kv: usize,
@@ -72,6 +164,833 @@ pub struct TextEncoderConfig {
     pub max_position_embeddings: usize,
 }
 
+/// Web server settings used in UI mode
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Validate, Clone, PartialEq)]
+pub struct ServerConfig {
+    #[serde(default = "default_port")]
+    #[validate(range(min = 1, max = 65535))]
+    pub port: usize,
+
+    /// Listen in 0.0.0.0 instead of 127.0.0.1
+    #[serde(default)]
+    pub expose: bool,
+
+    /// Open the web app in a browser on startup
+    #[serde(default = "default_auto_open")]
+    pub auto_open: bool,
+
+    /// Address to listen in, takes precedence over `expose`
+    #[serde(default)]
+    pub bind_address: Option<String>,
+
+    /// Serve over HTTPS/WSS with these certificates
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    /// Serve over HTTPS/WSS with a self-signed certificate kept in the data dir,
+    /// ignored if `tls` is set
+    #[serde(default)]
+    pub self_signed_tls: bool,
+
+    /// Origins allowed to make cross-origin requests, `*` allows any
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+
+    /// Methods allowed in cross-origin requests, any if empty
+    #[serde(default)]
+    pub cors_methods: Vec<String>,
+
+    /// Only the local machine and guests with an invite link can use the server. Kept
+    /// for older configs, as remote clients always need the API key or an invite now
+    #[serde(default)]
+    pub invite_only: bool,
+
+    /// The server is reached through a reverse proxy on this machine, whose requests all
+    /// come from the loopback address. They need the API key or an invite like the remote
+    /// ones, instead of being trusted as the local machine. Implied by a Unix socket
+    #[serde(default)]
+    pub behind_proxy: bool,
+
+    /// Limits on the jobs submitted by each client
+    #[serde(default)]
+    #[validate]
+    pub rate_limit: RateLimitConfig,
+
+    /// How long the running jobs are given to finish when shutting down, like `"30s"`,
+    /// before they are cancelled
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: HumanDuration,
+
+    /// Compress the big WebSocket messages for the clients that support it
+    #[serde(default = "default_ws_compression")]
+    pub ws_compression: bool,
+
+    /// How often the WebSocket clients are pinged, like `"20s"`
+    #[serde(default = "default_ws_ping_interval")]
+    pub ws_ping_interval: HumanDuration,
+
+    /// WebSocket connections that send nothing for this long, not even the answer to
+    /// a ping, are closed
+    #[serde(default = "default_ws_idle_timeout")]
+    pub ws_idle_timeout: HumanDuration,
+
+    /// URLs that receive a POST on every job lifecycle event, on top of the ones
+    /// registered through the API
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+
+    /// Serve the web app from this directory instead of the build embedded in the
+    /// binary, for trying out a frontend without rebuilding the server
+    #[serde(default)]
+    pub web_dir: Option<String>,
+
+    /// Also serve the gRPC API in this port, on the same address as the rest. It is
+    /// always plain HTTP/2, put it behind a proxy for TLS
+    #[serde(default)]
+    #[validate(range(min = 1, max = 65535))]
+    pub grpc_port: Option<usize>,
+
+    /// Advertise the server on the local network with mDNS when it is exposed, so
+    /// that clients can find it without typing its address
+    #[serde(default = "default_mdns")]
+    pub mdns: bool,
+}
+
+/// PEM encoded certificate chain and private key
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// Jobs each client, identified by its IP address, can submit. Unlimited when not set
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Validate, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    /// Jobs that can be submitted in any 60 seconds
+    #[serde(default)]
+    #[validate(range(min = 1))]
+    pub jobs_per_minute: Option<usize>,
+
+    /// Jobs that can be queued or running at the same time
+    #[serde(default)]
+    #[validate(range(min = 1))]
+    pub concurrent_jobs: Option<usize>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        default_server()
+    }
+}
+
+impl ServerConfig {
+    pub fn scheme(&self) -> &'static str {
+        if self.tls.is_some() || self.self_signed_tls {
+            "https"
+        } else {
+            "http"
+        }
+    }
+}
+
+/// Bounds for the jobs processed in UI mode
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct LimitsConfig {
+    /// Longest audio that can be requested, like `"30s"`, the same 5 minutes as in CLI
+    /// mode by default
+    #[serde(default = "default_max_generation_length")]
+    pub max_generation_length: Option<HumanDuration>,
+
+    /// Jobs running for longer than this, like `"5m"`, are failed
+    #[serde(default)]
+    pub job_timeout: Option<HumanDuration>,
+
+    /// Jobs are failed if the memory of the whole process, the models and the other
+    /// jobs included, goes over this size, like `"4GiB"`. It is checked before a job
+    /// starts and then once per second of generated audio
+    #[serde(default)]
+    pub max_memory: Option<ByteSize>,
+
+    /// Jobs whose decode loop makes no progress for this long are failed as
+    /// stalled, and the decoder session is recreated
+    #[serde(default = "default_stall_timeout")]
+    pub stall_timeout: HumanDuration,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_generation_length: default_max_generation_length(),
+            job_timeout: None,
+            max_memory: None,
+            stall_timeout: default_stall_timeout(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let checks = [
+            ("limits.max_generation_length", self.max_generation_length.map(|v| v.0.as_secs())),
+            ("limits.job_timeout", self.job_timeout.map(|v| v.0.as_millis() as u64)),
+            ("limits.max_memory", self.max_memory.map(|v| v.0)),
+            ("limits.stall_timeout", Some(self.stall_timeout.0.as_millis() as u64)),
+        ];
+        for (field, value) in checks {
+            if value == Some(0) {
+                return Err(ConfigError::ValidationError(format!("{field} cannot be zero")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How many jobs can be in each stage of the generation at the same time. The
+/// stages have very different resource profiles, so they are bounded independently
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Validate, Clone, PartialEq)]
+pub struct PipelineConfig {
+    /// Jobs encoding their prompt
+    #[serde(default = "default_stage_concurrency")]
+    #[validate(range(min = 1, max = 64))]
+    pub text_encoder: usize,
+
+    /// Jobs generating tokens, the most memory hungry stage
+    #[serde(default = "default_stage_concurrency")]
+    #[validate(range(min = 1, max = 64))]
+    pub decoder: usize,
+
+    /// Jobs turning their tokens into audio samples
+    #[serde(default = "default_stage_concurrency")]
+    #[validate(range(min = 1, max = 64))]
+    pub audio_encoder: usize,
+
+    /// Generated audios being encoded as wav and written to disk
+    #[serde(default = "default_stage_concurrency")]
+    #[validate(range(min = 1, max = 64))]
+    pub post_processing: usize,
+
+    /// Jobs generated at the same time. By default one, or as many as fit in the model
+    /// stages if any of them takes more than one job
+    #[serde(default)]
+    #[validate(range(min = 1, max = 256))]
+    pub max_concurrent_jobs: Option<usize>,
+
+    /// Jobs that can be waiting to start, new ones are rejected with a `QueueFull`
+    /// over it. Unlimited when not set
+    #[serde(default)]
+    #[validate(range(min = 1))]
+    pub max_waiting_jobs: Option<usize>,
+
+    /// Completed jobs whose audio is kept, so that identical jobs are answered with it
+    /// instead of being generated again. 0 disables it
+    #[serde(default = "default_result_cache")]
+    pub result_cache: usize,
+
+    /// Runs a short generation once the models are loaded, so that the first job does
+    /// not pay for the graph optimizations and the memory allocations. The server is
+    /// not ready until it is done
+    #[serde(default)]
+    pub warmup: bool,
+
+    /// Saves the tokens of the running jobs every this many seconds of generated audio,
+    /// so that the jobs interrupted by a crash or a restart resume from there instead of
+    /// starting over. Disabled when not set
+    #[serde(default)]
+    #[validate(range(min = 1))]
+    pub checkpoint_secs: Option<usize>,
+
+    /// Encoding of the jobs that ask for their audio as `"mp3"`
+    #[serde(default)]
+    #[validate]
+    pub mp3: Mp3Config,
+
+    /// Encoding of the jobs that ask for their audio as `"flac"`
+    #[serde(default)]
+    #[validate]
+    pub flac: FlacConfig,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            text_encoder: default_stage_concurrency(),
+            decoder: default_stage_concurrency(),
+            audio_encoder: default_stage_concurrency(),
+            post_processing: default_stage_concurrency(),
+            max_concurrent_jobs: None,
+            max_waiting_jobs: None,
+            result_cache: default_result_cache(),
+            warmup: false,
+            checkpoint_secs: None,
+            mp3: Mp3Config::default(),
+            flac: FlacConfig::default(),
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Max amount of jobs taken from the queue at the same time. Each job is in one
+    /// model stage at a time, so the jobs waiting between stages are bounded by this.
+    pub fn max_in_flight(&self) -> usize {
+        let stages = [self.text_encoder, self.decoder, self.audio_encoder];
+        let default = match stages.iter().all(|v| *v == 1) {
+            true => 1,
+            false => stages.iter().sum(),
+        };
+        self.max_concurrent_jobs.unwrap_or(default)
+    }
+}
+
+/// Encoding of the mp3 files, a constant bitrate one unless `vbr_quality` is set
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Validate, Clone, PartialEq)]
+pub struct Mp3Config {
+    /// Kilobits per second, rounded down to one of the standard bitrates
+    #[serde(default = "default_mp3_bitrate")]
+    #[validate(range(min = 8, max = 320))]
+    pub bitrate_kbps: u32,
+
+    /// Quality of a variable bitrate encoding, from 0, the best and largest, to 9
+    #[serde(default)]
+    #[validate(range(max = 9))]
+    pub vbr_quality: Option<u8>,
+}
+
+impl Default for Mp3Config {
+    fn default() -> Self {
+        Self {
+            bitrate_kbps: default_mp3_bitrate(),
+            vbr_quality: None,
+        }
+    }
+}
+
+/// Encoding of the flac files, which are lossless whatever the level
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Validate, Clone, PartialEq)]
+pub struct FlacConfig {
+    /// From 0, the fastest, to 8, the smallest files, like the levels of the `flac` tool
+    #[serde(default = "default_flac_compression_level")]
+    #[validate(range(max = 8))]
+    pub compression_level: u8,
+}
+
+impl Default for FlacConfig {
+    fn default() -> Self {
+        Self {
+            compression_level: default_flac_compression_level(),
+        }
+    }
+}
+
+/// Container of the generated audio
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Type, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioFormat {
+    #[default]
+    Wav,
+    /// Far smaller than a wav, for sharing. The wav is kept too
+    Mp3,
+    /// Lossless and about half the size of a wav, for archiving. The wav is kept too
+    Flac,
+}
+
+impl AudioFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Flac => "flac",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::Mp3 => "audio/mpeg",
+            AudioFormat::Flac => "audio/flac",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "wav" => Some(AudioFormat::Wav),
+            "mp3" => Some(AudioFormat::Mp3),
+            "flac" => Some(AudioFormat::Flac),
+            _ => None,
+        }
+    }
+}
+
+/// How the jobs are spread across the `devices`
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceScheduling {
+    /// Each job on the next device
+    #[default]
+    RoundRobin,
+    /// Each job on the device running the fewest jobs
+    LeastLoaded,
+}
+
+/// Threads of the ONNX Runtime sessions. By default each session takes one thread per
+/// physical core, which oversubscribes small machines running something else and might
+/// not make the most of big ones
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct ThreadingConfig {
+    /// Threads that run each operator, by default one per physical core, or one per
+    /// core in `cores` if set
+    #[serde(default)]
+    pub intra_op_threads: Option<usize>,
+
+    /// Threads that run independent operators at the same time. Operators run one after
+    /// the other when not set
+    #[serde(default)]
+    pub inter_op_threads: Option<usize>,
+
+    /// Whether idle threads spin waiting for work, which shortens each step at the cost
+    /// of keeping the cores busy. On by default
+    #[serde(default)]
+    pub spin: Option<bool>,
+
+    /// Logical cores, numbered from 0, that the intra op threads are pinned to, one per
+    /// thread. The first one is the thread running the session, which is not pinned
+    #[serde(default)]
+    pub cores: Vec<usize>,
+}
+
+impl ThreadingConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let err = |msg: String| Err(ConfigError::ValidationError(msg));
+        if self.intra_op_threads == Some(0) {
+            return err("threading.intra_op_threads cannot be zero".to_string());
+        }
+        if self.inter_op_threads == Some(0) {
+            return err("threading.inter_op_threads cannot be zero".to_string());
+        }
+        match self.intra_op_threads {
+            Some(threads) if !self.cores.is_empty() && self.cores.len() != threads => err(format!(
+                "threading.cores must have one core per intra op thread ({threads})"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Threads that run each operator, none for the default.
+    pub fn intra_op_thread_count(&self) -> Option<usize> {
+        match self.cores.len() {
+            0 => self.intra_op_threads,
+            cores => Some(cores),
+        }
+    }
+
+    /// The cores of the threads that the sessions start, in the format of ONNX Runtime,
+    /// which numbers them from 1.
+    pub fn intra_op_thread_affinities(&self) -> Option<String> {
+        let pinned = self.cores.get(1..).filter(|v| !v.is_empty())?;
+        Some(pinned.iter().map(|v| (v + 1).to_string()).collect::<Vec<_>>().join(";"))
+    }
+}
+
+/// Locations on disk used by MusicGPT
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct StorageConfig {
+    /// Directory for models, dynamic libraries and chats. Defaults to the OS data dir
+    #[serde(default)]
+    pub data_dir: Option<String>,
+
+    /// Directory where the generated audio is stored. Defaults to `<data_dir>/audios`
+    #[serde(default)]
+    pub output_dir: Option<String>,
+
+    /// Directory for in progress downloads. Defaults to `<data_dir>/tmp`
+    #[serde(default)]
+    pub temp_dir: Option<String>,
+
+    /// Writes that would make the stored files exceed this size, like `"20GiB"`, are rejected
+    #[serde(default)]
+    pub max_disk_usage: Option<ByteSize>,
+}
+
+/// Where to read a secret from. Only the reference is stored in the config file,
+/// never the secret itself
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretRef {
+    /// Name of the environment variable holding the secret
+    Env(String),
+    /// Path to a file whose contents are the secret
+    File(String),
+}
+
+impl SecretRef {
+    /// Read the secret value
+    pub fn resolve(&self) -> Result<Secret, ConfigError> {
+        let value = match self {
+            SecretRef::Env(name) => std::env::var(name).map_err(|_| {
+                ConfigError::ValidationError(format!("Environment variable {name} is not set"))
+            })?,
+            SecretRef::File(path) => std::fs::read_to_string(path)?.trim().to_string(),
+        };
+        Ok(Secret(value))
+    }
+}
+
+/// A resolved secret value. It is intentionally not serializable and redacted
+/// in debug output, so that it cannot end up in config files or logs
+#[derive(Clone, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+/// Credentials for remote services, and for the clients of this server
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+pub struct SecretsConfig {
+    /// Token sent to Hugging Face when downloading the models
+    #[serde(default)]
+    pub huggingface_token: Option<SecretRef>,
+
+    /// Key that remote clients must send for using the server, as a `Bearer`
+    /// Authorization header or, over WebSocket, as their first message
+    #[serde(default)]
+    pub api_key: Option<SecretRef>,
+
+    /// Key of the HMAC-SHA256 signature sent with the webhooks
+    #[serde(default)]
+    pub webhook_secret: Option<SecretRef>,
+
+    /// Token for the `/api/admin` endpoints, sent in the `x-musicgpt-admin-token`
+    /// header. The admin endpoints are disabled if not set
+    #[serde(default)]
+    pub admin_token: Option<SecretRef>,
+}
+
+/// The values of the [SecretsConfig] references, read at runtime
+#[derive(Debug, Default, Clone)]
+pub struct ResolvedSecrets {
+    pub huggingface_token: Option<Secret>,
+    pub api_key: Option<Secret>,
+    pub webhook_secret: Option<Secret>,
+    pub admin_token: Option<Secret>,
+}
+
+impl SecretsConfig {
+    /// Read all the referenced secrets, failing if any of them is not available
+    pub fn resolve(&self) -> Result<ResolvedSecrets, ConfigError> {
+        let resolve = |v: &Option<SecretRef>| v.as_ref().map(SecretRef::resolve).transpose();
+        Ok(ResolvedSecrets {
+            huggingface_token: resolve(&self.huggingface_token)?,
+            api_key: resolve(&self.api_key)?,
+            webhook_secret: resolve(&self.webhook_secret)?,
+            admin_token: resolve(&self.admin_token)?,
+        })
+    }
+}
+
+/// Partial configuration that overlays the server config for a single job
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Type, Clone, PartialEq)]
+pub struct GenerationConfig {
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    /// See [DecoderConfig::guidance_scale].
+    #[serde(default)]
+    pub guidance_scale: Option<f32>,
+    /// See [DecoderConfig::temperature].
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// See [DecoderConfig::top_p].
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// Seed of the sampling, the same job with the same seed generates the same audio.
+    /// Jobs without one get a random one, reported when they complete.
+    #[serde(default)]
+    pub seed: Option<u32>,
+    /// Characteristics to steer the audio away from, like "distorted drums". The classifier
+    /// free guidance contrasts the prompt with it instead of with an empty prompt.
+    #[serde(default)]
+    pub negative_prompt: Option<String>,
+    /// Parts of the audio conditioned on their own prompt instead of the one of the job,
+    /// one after the other, like an intro, a drop and an outro. Each one continues the end
+    /// of the previous one, and the last one lasts until the end of the audio.
+    #[serde(default)]
+    pub sections: Option<Vec<TimelineSection>>,
+    /// Tempo, key and time signature that the audio is asked for.
+    #[serde(default)]
+    pub hints: Option<MusicalHints>,
+    /// Model the job runs on, named like the values of `--model`, like "medium" or
+    /// "melody", among the ones in `--switchable-models`. The one the server was started
+    /// with if not set.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Uploaded melody, or generated audio by the id of its job, that the audio follows.
+    /// Only taken by the melody models.
+    #[serde(default)]
+    pub melody: Option<Uuid>,
+    /// Generated audio, by the id of its job, or uploaded melody that the audio continues.
+    /// The result is the whole clip followed by the new audio.
+    #[serde(default)]
+    pub continuation: Option<Uuid>,
+    /// Generated or uploaded audio that the new audio leads into, crossfading with its
+    /// start. Together with `continuation`, it fills the gap between two clips.
+    #[serde(default)]
+    pub leads_into: Option<Uuid>,
+    /// Added to the logits of some tokens at every step, for suppressing the ones that
+    /// make an artifact, like a recurring click, found in previous generations.
+    #[serde(default)]
+    pub logit_bias: Option<Vec<LogitBias>>,
+    /// Container that the audio is stored and served in, a wav if not set.
+    #[serde(default)]
+    pub format: Option<AudioFormat>,
+    /// Tokens that the job generated before it was interrupted, which it resumes from.
+    /// Set by the backend from the checkpoint of the job.
+    #[serde(skip)]
+    pub resumed_tokens: Option<Vec<Vec<i64>>>,
+}
+
+/// Bias of a token of a codebook, see [GenerationConfig::logit_bias].
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Type, Clone, PartialEq)]
+pub struct LogitBias {
+    /// Codebook the token is sampled in. The stereo models interleave the codebooks of the
+    /// left channel with the ones of the right one.
+    pub codebook: usize,
+    pub token: usize,
+    /// Added to the logit of the token, negative makes it less likely. The token is never
+    /// sampled if not set.
+    #[serde(default)]
+    pub bias: Option<f32>,
+}
+
+impl GenerationConfig {
+    /// Overwrites in `config` the fields that are set in this partial config
+    pub fn apply(&self, config: &mut MusicGenConfig) {
+        if let Some(top_k) = self.top_k {
+            config.decoder.top_k = top_k;
+        }
+        if let Some(guidance_scale) = self.guidance_scale {
+            config.decoder.guidance_scale = guidance_scale;
+        }
+        if let Some(temperature) = self.temperature {
+            config.decoder.temperature = temperature;
+        }
+        if let Some(top_p) = self.top_p {
+            config.decoder.top_p = top_p;
+        }
+    }
+
+    /// Returns a partial config where the fields set in `other` take precedence
+    pub fn merged(&self, other: &Self) -> Self {
+        Self {
+            top_k: other.top_k.or(self.top_k),
+            guidance_scale: other.guidance_scale.or(self.guidance_scale),
+            temperature: other.temperature.or(self.temperature),
+            top_p: other.top_p.or(self.top_p),
+            seed: other.seed.or(self.seed),
+            negative_prompt: other.negative_prompt.clone().or(self.negative_prompt.clone()),
+            sections: other.sections.clone().or(self.sections.clone()),
+            hints: other.hints.clone().or(self.hints.clone()),
+            model: other.model.clone().or(self.model.clone()),
+            melody: other.melody.or(self.melody),
+            continuation: other.continuation.or(self.continuation),
+            leads_into: other.leads_into.or(self.leads_into),
+            logit_bias: other.logit_bias.clone().or(self.logit_bias.clone()),
+            format: other.format.or(self.format),
+            resumed_tokens: other.resumed_tokens.clone().or(self.resumed_tokens.clone()),
+        }
+    }
+
+    /// Validate the overridden values
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.top_k == Some(0) {
+            return Err(ConfigError::ValidationError("top_k cannot be zero".to_string()));
+        }
+        if let Some(guidance_scale) = self.guidance_scale {
+            if !(0.0..=MAX_GUIDANCE_SCALE).contains(&guidance_scale) {
+                return Err(ConfigError::ValidationError(format!(
+                    "guidance_scale must be between 0 and {MAX_GUIDANCE_SCALE}"
+                )));
+            }
+        }
+        if let Some(temperature) = self.temperature {
+            if !(temperature > 0.0 && temperature <= MAX_TEMPERATURE) {
+                return Err(ConfigError::ValidationError(format!(
+                    "temperature must be greater than 0 and at most {MAX_TEMPERATURE}"
+                )));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(ConfigError::ValidationError(
+                    "top_p must be greater than 0 and at most 1".to_string(),
+                ));
+            }
+        }
+        if let Some(negative_prompt) = &self.negative_prompt {
+            if negative_prompt.chars().count() > MAX_EXTRA_PROMPT_CHARS {
+                return Err(ConfigError::ValidationError(format!(
+                    "negative_prompt cannot be longer than {MAX_EXTRA_PROMPT_CHARS} characters"
+                )));
+            }
+        }
+        if let Some(sections) = &self.sections {
+            if sections.is_empty() || sections.len() > MAX_SECTIONS {
+                return Err(ConfigError::ValidationError(format!(
+                    "sections must have between 1 and {MAX_SECTIONS} sections"
+                )));
+            }
+            for section in sections {
+                if section.secs == 0 {
+                    return Err(ConfigError::ValidationError(
+                        "secs of a section must be greater than 0".to_string(),
+                    ));
+                }
+                let chars = section.prompt.chars().count();
+                if chars == 0 || chars > MAX_EXTRA_PROMPT_CHARS {
+                    return Err(ConfigError::ValidationError(format!(
+                        "The prompt of a section must have between 1 and \
+                         {MAX_EXTRA_PROMPT_CHARS} characters"
+                    )));
+                }
+            }
+        }
+        if let Some(hints) = &self.hints {
+            hints.validate()?;
+        }
+        if let Some(logit_bias) = &self.logit_bias {
+            if logit_bias.len() > MAX_LOGIT_BIASES {
+                return Err(ConfigError::ValidationError(format!(
+                    "logit_bias cannot have more than {MAX_LOGIT_BIASES} tokens"
+                )));
+            }
+            for bias in logit_bias {
+                if bias.token >= CODEBOOK_SIZE {
+                    return Err(ConfigError::ValidationError(format!(
+                        "The tokens of logit_bias must be lower than {CODEBOOK_SIZE}"
+                    )));
+                }
+                if bias.bias.is_some_and(|v| !v.is_finite()) {
+                    return Err(ConfigError::ValidationError(
+                        "The biases of logit_bias must be finite, leave them out to ban the \
+                         token"
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Musical properties of the audio of a job. The models only take text, so they are
+/// folded into the prompt with the phrasing of the descriptions they were trained on.
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Type, Clone, PartialEq)]
+pub struct MusicalHints {
+    #[serde(default)]
+    pub bpm: Option<u32>,
+    /// Like "A minor" or "F# major".
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Like "4/4" or "6/8".
+    #[serde(default)]
+    pub time_signature: Option<String>,
+    /// Fails the job if the tempo of the audio is not the one of `bpm`, or half or double
+    /// it. Meant for audio that is layered with other tracks.
+    #[serde(default)]
+    pub check_bpm: bool,
+}
+
+impl MusicalHints {
+    /// `prompt` followed by the hints, like "lofi beat, 90 bpm, in A minor, in 4/4 time".
+    pub fn describe(&self, prompt: &str) -> String {
+        let mut description = prompt.to_string();
+        if let Some(bpm) = self.bpm {
+            description += &format!(", {bpm} bpm");
+        }
+        if let Some(key) = &self.key {
+            description += &format!(", in {key}");
+        }
+        if let Some(time_signature) = &self.time_signature {
+            description += &format!(", in {time_signature} time");
+        }
+        description
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(bpm) = self.bpm {
+            if !(MIN_HINTED_BPM..=MAX_HINTED_BPM).contains(&bpm) {
+                return Err(ConfigError::ValidationError(format!(
+                    "bpm must be between {MIN_HINTED_BPM} and {MAX_HINTED_BPM}"
+                )));
+            }
+        }
+        if let Some(key) = &self.key {
+            let valid = key.split_once(' ').is_some_and(|(tonic, mode)| {
+                let mut chars = tonic.chars();
+                let note = chars.next().is_some_and(|v| ('A'..='G').contains(&v));
+                let accidental = matches!(chars.as_str(), "" | "#" | "b");
+                note && accidental && matches!(mode, "major" | "minor")
+            });
+            if !valid {
+                return Err(ConfigError::ValidationError(format!(
+                    "key must be like \"A minor\" or \"F# major\", got \"{key}\""
+                )));
+            }
+        }
+        if let Some(time_signature) = &self.time_signature {
+            let valid = time_signature.split_once('/').is_some_and(|(beats, unit)| {
+                let beats = beats.parse::<u32>().is_ok_and(|v| (1..=32).contains(&v));
+                let unit = unit.parse::<u32>().is_ok_and(|v| v.is_power_of_two() && v <= 32);
+                beats && unit
+            });
+            if !valid {
+                return Err(ConfigError::ValidationError(format!(
+                    "time_signature must be like \"4/4\" or \"6/8\", got \"{time_signature}\""
+                )));
+            }
+        }
+        if self.check_bpm && self.bpm.is_none() {
+            return Err(ConfigError::ValidationError(
+                "check_bpm needs a bpm to check against".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Part of the audio of a job, conditioned on its own prompt.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Type, Clone, PartialEq)]
+pub struct TimelineSection {
+    pub prompt: String,
+    pub secs: usize,
+}
+
+/// Highest guidance scale taken, past it the audio is mostly noise.
+const MAX_GUIDANCE_SCALE: f32 = 20.0;
+/// Highest temperature taken, past it every token is about as likely.
+const MAX_TEMPERATURE: f32 = 10.0;
+/// Longest negative or section prompt taken, they go through the text encoder like the prompt.
+const MAX_EXTRA_PROMPT_CHARS: usize = 500;
+/// Most sections in the timeline of a job.
+const MAX_SECTIONS: usize = 16;
+/// Tokens in each codebook of EnCodec.
+const CODEBOOK_SIZE: usize = 2048;
+/// Most tokens biased in a job, well under the size of a codebook so that there are always
+/// tokens left to sample.
+const MAX_LOGIT_BIASES: usize = 256;
+/// Tempos that can be asked for, past them the models ignore the hint.
+const MIN_HINTED_BPM: u32 = 40;
+const MAX_HINTED_BPM: u32 = 240;
+
+/// Sampling rates of the available EnCodec audio encoder exports
+const ENCODEC_SAMPLING_RATES: [usize; 4] = [16000, 24000, 32000, 48000];
+
 // Default value implementations
 fn default_audio_encoder() -> AudioEncoderConfig {
     AudioEncoderConfig {
@@ -86,8 +1005,40 @@ fn default_decoder() -> DecoderConfig {
         num_attention_heads: default_num_attention_heads(),
         num_hidden_layers: default_num_hidden_layers(),
         top_k: default_top_k(),
+        guidance_scale: default_guidance_scale(),
+        temperature: default_temperature(),
+        top_p: default_top_p(),
         pad_token_id: default_pad_token_id(),
         hidden_size: default_hidden_size(),
+        num_codebooks: default_num_codebooks(),
+        audio_channels: default_audio_channels(),
+        context_secs: default_context_secs(),
+        long_form_overlap_secs: default_long_form_overlap_secs(),
+        stop_after_silence_secs: None,
+    }
+}
+
+fn default_server() -> ServerConfig {
+    ServerConfig {
+        port: default_port(),
+        expose: false,
+        auto_open: default_auto_open(),
+        bind_address: None,
+        tls: None,
+        self_signed_tls: false,
+        cors_origins: vec![],
+        cors_methods: vec![],
+        invite_only: false,
+        behind_proxy: false,
+        rate_limit: RateLimitConfig::default(),
+        shutdown_grace_period: default_shutdown_grace_period(),
+        ws_compression: default_ws_compression(),
+        ws_ping_interval: default_ws_ping_interval(),
+        ws_idle_timeout: default_ws_idle_timeout(),
+        webhooks: vec![],
+        web_dir: None,
+        grpc_port: None,
+        mdns: default_mdns(),
     }
 }
 
@@ -100,19 +1051,43 @@ fn default_text_encoder() -> TextEncoderConfig {
 }
 
 // Individual default values
-fn default_sampling_rate() -> usize { 44100 }
+fn default_sampling_rate() -> usize { 32000 }
 fn default_hop_length() -> usize { 512 }
 fn default_n_fft() -> usize { 2048 }
-fn default_num_attention_heads() -> usize { 8 }
+fn default_num_attention_heads() -> usize { 12 }
 fn default_num_hidden_layers() -> usize { 6 }
 fn default_top_k() -> usize { 50 }
+fn default_guidance_scale() -> f32 { 3.0 }
+fn default_temperature() -> f32 { 1.0 }
+fn default_top_p() -> f32 { 1.0 }
 fn default_pad_token_id() -> i64 { 0 }
 fn default_hidden_size() -> usize { 768 }
+fn default_num_codebooks() -> usize { 4 }
+fn default_audio_channels() -> usize { 1 }
+fn default_context_secs() -> usize { 30 }
+fn default_long_form_overlap_secs() -> usize { 10 }
 fn default_d_kv() -> usize { 64 }
 fn default_d_model() -> usize { 768 }
 fn default_max_position_embeddings() -> usize { 512 }
 fn default_batch_size() -> usize { 1 }
 fn default_device() -> String { "cpu".to_string() }
+fn default_log_level() -> String { "info".to_string() }
+fn default_port() -> usize { 8642 }
+fn default_auto_open() -> bool { true }
+fn default_stage_concurrency() -> usize { 1 }
+fn default_result_cache() -> usize { 16 }
+fn default_mp3_bitrate() -> u32 { 192 }
+fn default_flac_compression_level() -> u8 { 5 }
+fn default_max_generation_length() -> Option<HumanDuration> {
+    Some(HumanDuration(std::time::Duration::from_secs(300)))
+}
+fn default_stall_timeout() -> HumanDuration { HumanDuration(std::time::Duration::from_secs(60)) }
+fn default_shutdown_grace_period() -> HumanDuration { HumanDuration(std::time::Duration::from_secs(30)) }
+fn default_ws_compression() -> bool { true }
+fn default_ws_ping_interval() -> HumanDuration { HumanDuration(std::time::Duration::from_secs(20)) }
+fn default_ws_idle_timeout() -> HumanDuration { HumanDuration(std::time::Duration::from_secs(60)) }
+
+fn default_mdns() -> bool { true }
 
 /// Configuration error types
 #[derive(Error, Debug)]
@@ -125,6 +1100,19 @@ pub enum ConfigError {
     
     #[error("Serialization error: {0}")]
     SerializationError(#[from] serde_json::Error),
+
+    #[error("Could not fetch remote configuration: {0}")]
+    FetchError(String),
+}
+
+/// How to load configuration files served over http(s)
+#[derive(Clone, Default)]
+pub struct ConfigSourceOptions {
+    /// Value for the `Authorization` header, like `Bearer <token>`
+    pub auth_header: Option<String>,
+    /// Where the last fetched copy of remote configs is kept, used when the
+    /// remote endpoint is unreachable
+    pub cache_dir: Option<PathBuf>,
 }
 
 impl MusicGenConfig {
@@ -136,7 +1124,67 @@ impl MusicGenConfig {
         Ok(config)
     }
     
-    /// Save configuration to JSON file
+    /// Whether `source` is an http(s) URL rather than a local path
+    pub fn is_remote(source: &str) -> bool {
+        source.starts_with("http://") || source.starts_with("https://")
+    }
+
+    /// Where the cached copy of the remote `source` is stored within `cache_dir`
+    pub fn cached_source_path(cache_dir: &Path, source: &str) -> PathBuf {
+        let name = source
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect::<String>();
+        cache_dir.join(name)
+    }
+
+    /// Load configuration from a local path or an http(s) URL. Remote configs are
+    /// cached, and the cached copy is used if the remote endpoint cannot be reached.
+    /// They are fetched on every call, nothing polls them for changes
+    pub async fn from_source(source: &str, opts: &ConfigSourceOptions) -> Result<Self, ConfigError> {
+        if !Self::is_remote(source) {
+            return Self::from_file(source);
+        }
+        let cache_path = opts
+            .cache_dir
+            .as_ref()
+            .map(|dir| Self::cached_source_path(dir, source));
+
+        let content = match Self::fetch(source, opts.auth_header.as_deref()).await {
+            Ok(content) => content,
+            Err(err) => match &cache_path {
+                Some(path) if path.exists() => {
+                    tracing::warn!("{err}, using the cached copy at {}", path.display());
+                    std::fs::read_to_string(path)?
+                }
+                _ => return Err(err),
+            },
+        };
+        let config: Self = serde_json::from_str(&content)?;
+        config.validate()?;
+
+        if let Some(path) = cache_path {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, content)?;
+        }
+        Ok(config)
+    }
+
+    async fn fetch(url: &str, auth_header: Option<&str>) -> Result<String, ConfigError> {
+        let fetch_err = |e: reqwest::Error| ConfigError::FetchError(format!("{url}: {e}"));
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(auth_header) = auth_header {
+            request = request.header(reqwest::header::AUTHORIZATION, auth_header);
+        }
+        let response = request.send().await.map_err(fetch_err)?;
+        let response = response.error_for_status().map_err(fetch_err)?;
+        response.text().await.map_err(fetch_err)
+    }
+
+    /// Save configuration to JSON file. Secrets are only stored as references
+    /// to where they can be read from, so their values are never written
     pub fn save_to_file(&self, path: &str) -> Result<(), ConfigError> {
         let content = serde_json::to_string_pretty(self)?;
         std::fs::write(path, content)?;
@@ -151,14 +1199,91 @@ impl MusicGenConfig {
             .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
         self.text_encoder.validate()
             .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+        self.server.validate()
+            .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+        self.pipeline.validate()
+            .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
         
         if self.batch_size == 0 {
             return Err(ConfigError::ValidationError("Batch size cannot be zero".to_string()));
         }
-        
+        self.limits.validate()?;
+        self.threading.validate()?;
+        for (name, profile) in self.profiles.iter() {
+            crate::config_profiles::validate_name(name)
+                .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+            profile.validate()?;
+        }
+
+        self.validate_cross_fields()
+    }
+
+    /// Validate the relationships between fields, which would otherwise surface
+    /// as shape errors in the middle of a generation
+    fn validate_cross_fields(&self) -> Result<(), ConfigError> {
+        let (audio, decoder, text) = (&self.audio_encoder, &self.decoder, &self.text_encoder);
+        let err = |msg: String| Err(ConfigError::ValidationError(msg));
+
+        if decoder.hidden_size % decoder.num_attention_heads != 0 {
+            return err(format!(
+                "decoder.hidden_size ({}) must be divisible by decoder.num_attention_heads ({})",
+                decoder.hidden_size, decoder.num_attention_heads
+            ));
+        }
+        // The past key values are shaped as [batch, num_attention_heads, seq, d_kv].
+        if text.d_kv == 0 || decoder.hidden_size / decoder.num_attention_heads != text.d_kv {
+            return err(format!(
+                "decoder.hidden_size ({}) / decoder.num_attention_heads ({}) must be equal to text_encoder.d_kv ({})",
+                decoder.hidden_size, decoder.num_attention_heads, text.d_kv
+            ));
+        }
+        // Each channel has its own set of codebooks.
+        if decoder.num_codebooks % decoder.audio_channels != 0 {
+            return err(format!(
+                "decoder.num_codebooks ({}) must be divisible by decoder.audio_channels ({})",
+                decoder.num_codebooks, decoder.audio_channels
+            ));
+        }
+        // Every segment of a long generation needs to add some new audio.
+        if decoder.long_form_overlap_secs >= decoder.context_secs {
+            return err(format!(
+                "decoder.long_form_overlap_secs ({}) must be less than decoder.context_secs ({})",
+                decoder.long_form_overlap_secs, decoder.context_secs
+            ));
+        }
+        if text.d_model % text.d_kv != 0 {
+            return err(format!(
+                "text_encoder.d_model ({}) must be divisible by text_encoder.d_kv ({})",
+                text.d_model, text.d_kv
+            ));
+        }
+        if audio.n_fft < audio.hop_length {
+            return err(format!(
+                "audio_encoder.n_fft ({}) must be greater or equal than audio_encoder.hop_length ({})",
+                audio.n_fft, audio.hop_length
+            ));
+        }
+        if !ENCODEC_SAMPLING_RATES.contains(&audio.sampling_rate) {
+            return err(format!(
+                "audio_encoder.sampling_rate ({}) is not supported by the audio encoder, use one of {:?}",
+                audio.sampling_rate, ENCODEC_SAMPLING_RATES
+            ));
+        }
         Ok(())
     }
     
+    /// JSON Schema describing the configuration file format
+    pub fn json_schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(MusicGenConfig)
+    }
+
+    /// Start building a configuration from the default values
+    pub fn builder() -> MusicGenConfigBuilder {
+        MusicGenConfigBuilder {
+            config: Self::default(),
+        }
+    }
+
     /// Create configuration with default values
     pub fn default() -> Self {
         Self {
@@ -167,6 +1292,485 @@ impl MusicGenConfig {
             text_encoder: default_text_encoder(),
             batch_size: default_batch_size(),
             device: default_device(),
+            devices: vec![],
+            device_scheduling: DeviceScheduling::default(),
+            log_level: default_log_level(),
+            secrets: SecretsConfig::default(),
+            storage: StorageConfig::default(),
+            server: default_server(),
+            profiles: BTreeMap::new(),
+            limits: LimitsConfig::default(),
+            pipeline: PipelineConfig::default(),
+            threading: ThreadingConfig::default(),
+        }
+    }
+
+    /// Describes the differences with `other` that cannot be applied at runtime
+    /// because they require the models to be loaded again
+    pub fn reload_blockers(&self, other: &Self) -> Vec<String> {
+        let (a, b) = (self, other);
+        let checks = [
+            ("audio_encoder.sampling_rate", a.audio_encoder.sampling_rate != b.audio_encoder.sampling_rate),
+            ("audio_encoder.hop_length", a.audio_encoder.hop_length != b.audio_encoder.hop_length),
+            ("audio_encoder.n_fft", a.audio_encoder.n_fft != b.audio_encoder.n_fft),
+            ("decoder.num_attention_heads", a.decoder.num_attention_heads != b.decoder.num_attention_heads),
+            ("decoder.num_hidden_layers", a.decoder.num_hidden_layers != b.decoder.num_hidden_layers),
+            ("decoder.pad_token_id", a.decoder.pad_token_id != b.decoder.pad_token_id),
+            ("decoder.hidden_size", a.decoder.hidden_size != b.decoder.hidden_size),
+            ("decoder.num_codebooks", a.decoder.num_codebooks != b.decoder.num_codebooks),
+            ("decoder.audio_channels", a.decoder.audio_channels != b.decoder.audio_channels),
+            ("text_encoder.d_kv", a.text_encoder.d_kv != b.text_encoder.d_kv),
+            ("text_encoder.d_model", a.text_encoder.d_model != b.text_encoder.d_model),
+            ("text_encoder.max_position_embeddings", a.text_encoder.max_position_embeddings != b.text_encoder.max_position_embeddings),
+            ("device", a.device != b.device),
+            ("devices", a.devices != b.devices),
+            ("device_scheduling", a.device_scheduling != b.device_scheduling),
+            ("storage", a.storage != b.storage),
+            ("server", a.server != b.server),
+            ("profiles", a.profiles != b.profiles),
+            ("pipeline", a.pipeline != b.pipeline),
+            ("threading", a.threading != b.threading),
+        ];
+        checks
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(field, _)| format!("Changing {field} requires restarting MusicGPT, ignoring it"))
+            .collect()
+    }
+
+    /// Copies from `other` the fields that are safe to change without reloading the models
+    pub fn apply_hot_reloadable(&mut self, other: &Self) {
+        self.decoder.top_k = other.decoder.top_k;
+        self.decoder.guidance_scale = other.decoder.guidance_scale;
+        self.decoder.temperature = other.decoder.temperature;
+        self.decoder.top_p = other.decoder.top_p;
+        self.decoder.context_secs = other.decoder.context_secs;
+        self.decoder.long_form_overlap_secs = other.decoder.long_form_overlap_secs;
+        self.decoder.stop_after_silence_secs = other.decoder.stop_after_silence_secs;
+        self.batch_size = other.batch_size;
+        self.log_level = other.log_level.clone();
+        self.secrets = other.secrets.clone();
+        self.limits = other.limits.clone();
+    }
+}
+
+/// Fluent builder for [MusicGenConfig] that validates the result on build
+#[derive(Debug, Clone)]
+pub struct MusicGenConfigBuilder {
+    config: MusicGenConfig,
+}
+
+macro_rules! setter {
+    ($name:ident: $ty:ty => $($field:ident).+) => {
+        pub fn $name(mut self, value: $ty) -> Self {
+            self.config.$($field).+ = value;
+            self
+        }
+    };
+}
+
+impl MusicGenConfigBuilder {
+    setter!(sampling_rate: usize => audio_encoder.sampling_rate);
+    setter!(hop_length: usize => audio_encoder.hop_length);
+    setter!(n_fft: usize => audio_encoder.n_fft);
+    setter!(num_attention_heads: usize => decoder.num_attention_heads);
+    setter!(num_hidden_layers: usize => decoder.num_hidden_layers);
+    setter!(top_k: usize => decoder.top_k);
+    setter!(guidance_scale: f32 => decoder.guidance_scale);
+    setter!(temperature: f32 => decoder.temperature);
+    setter!(top_p: f32 => decoder.top_p);
+    setter!(pad_token_id: i64 => decoder.pad_token_id);
+    setter!(hidden_size: usize => decoder.hidden_size);
+    setter!(num_codebooks: usize => decoder.num_codebooks);
+    setter!(audio_channels: usize => decoder.audio_channels);
+    setter!(context_secs: usize => decoder.context_secs);
+    setter!(long_form_overlap_secs: usize => decoder.long_form_overlap_secs);
+    setter!(d_kv: usize => text_encoder.d_kv);
+    setter!(d_model: usize => text_encoder.d_model);
+    setter!(max_position_embeddings: usize => text_encoder.max_position_embeddings);
+    setter!(batch_size: usize => batch_size);
+
+    pub fn device(mut self, device: impl Into<String>) -> Self {
+        self.config.device = device.into();
+        self
+    }
+
+    pub fn log_level(mut self, log_level: impl Into<String>) -> Self {
+        self.config.log_level = log_level.into();
+        self
+    }
+
+    /// Validate and return the built configuration
+    pub fn build(self) -> Result<MusicGenConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_schema_describes_all_sections() -> anyhow::Result<()> {
+        let schema = serde_json::to_value(MusicGenConfig::json_schema())?;
+        let properties = &schema["properties"];
+        for section in ["audio_encoder", "decoder", "text_encoder", "batch_size", "device"] {
+            assert!(properties.get(section).is_some(), "{section} not in schema");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn hot_reload_only_applies_safe_fields() {
+        let mut config = MusicGenConfig::default();
+        let mut other = MusicGenConfig::default();
+        other.decoder.top_k = 10;
+        other.log_level = "debug".to_string();
+        other.device = "cuda".to_string();
+
+        let blockers = config.reload_blockers(&other);
+        assert_eq!(blockers.len(), 1);
+        assert!(blockers[0].contains("device"));
+
+        config.apply_hot_reloadable(&other);
+        assert_eq!(config.decoder.top_k, 10);
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.device, "cpu");
+    }
+
+    #[test]
+    fn generation_config_overlays_only_set_fields() {
+        let mut config = MusicGenConfig::default();
+        GenerationConfig::default().apply(&mut config);
+        assert_eq!(config.decoder.top_k, default_top_k());
+
+        let overrides = GenerationConfig { top_k: Some(5), ..Default::default() };
+        assert!(overrides.validate().is_ok());
+        overrides.apply(&mut config);
+        assert_eq!(config.decoder.top_k, 5);
+
+        assert!(GenerationConfig { top_k: Some(0), ..Default::default() }.validate().is_err());
+
+        let overrides = GenerationConfig { guidance_scale: Some(1.5), ..Default::default() };
+        assert!(overrides.validate().is_ok());
+        overrides.apply(&mut config);
+        assert_eq!(config.decoder.guidance_scale, 1.5);
+        assert_eq!(config.decoder.top_k, 5);
+        let too_high = GenerationConfig { guidance_scale: Some(25.0), ..Default::default() };
+        assert!(too_high.validate().is_err());
+
+        let overrides = GenerationConfig {
+            temperature: Some(0.7),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+        assert!(overrides.validate().is_ok());
+        overrides.apply(&mut config);
+        assert_eq!((config.decoder.temperature, config.decoder.top_p), (0.7, 0.9));
+        let frozen = GenerationConfig { temperature: Some(0.0), ..Default::default() };
+        assert!(frozen.validate().is_err());
+        assert!(GenerationConfig { top_p: Some(1.5), ..Default::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn generation_config_validates_negative_prompt() {
+        let negative = |prompt: String| GenerationConfig {
+            negative_prompt: Some(prompt),
+            ..Default::default()
+        };
+        assert!(negative("distorted drums".to_string()).validate().is_ok());
+        assert!(negative("a".repeat(MAX_EXTRA_PROMPT_CHARS + 1)).validate().is_err());
+
+        let base = negative("vocals".to_string());
+        assert_eq!(base.merged(&GenerationConfig::default()), base);
+    }
+
+    #[test]
+    fn folds_the_musical_hints_into_the_prompt() {
+        let hints = MusicalHints {
+            bpm: Some(90),
+            key: Some("A minor".to_string()),
+            time_signature: Some("4/4".to_string()),
+            check_bpm: true,
+        };
+        assert!(hints.validate().is_ok());
+        assert_eq!(hints.describe("lofi beat"), "lofi beat, 90 bpm, in A minor, in 4/4 time");
+        assert_eq!(MusicalHints::default().describe("lofi beat"), "lofi beat");
+
+        let invalid = [
+            MusicalHints { bpm: Some(500), ..Default::default() },
+            MusicalHints { key: Some("H major".to_string()), ..Default::default() },
+            MusicalHints { key: Some("C#".to_string()), ..Default::default() },
+            MusicalHints { time_signature: Some("4/3".to_string()), ..Default::default() },
+            MusicalHints { check_bpm: true, ..Default::default() },
+        ];
+        for hints in invalid {
+            assert!(hints.validate().is_err(), "{hints:?} is valid");
         }
     }
+
+    #[test]
+    fn generation_config_validates_sections() {
+        let section = |(prompt, secs): &(&str, usize)| TimelineSection {
+            prompt: prompt.to_string(),
+            secs: *secs,
+        };
+        let sections = |sections: &[(&str, usize)]| GenerationConfig {
+            sections: Some(sections.iter().map(section).collect()),
+            ..Default::default()
+        };
+        assert!(sections(&[("Intro", 10), ("Drop", 20)]).validate().is_ok());
+        assert!(sections(&[]).validate().is_err());
+        assert!(sections(&[("Intro", 0)]).validate().is_err());
+        assert!(sections(&[("", 10)]).validate().is_err());
+        assert!(sections(&[("Drop", 1); MAX_SECTIONS + 1]).validate().is_err());
+    }
+
+    #[test]
+    fn generation_config_merge_prefers_other() {
+        let base = GenerationConfig { top_k: Some(5), ..Default::default() };
+        assert_eq!(base.merged(&GenerationConfig::default()), base);
+        let other = GenerationConfig { top_k: Some(7), ..Default::default() };
+        assert_eq!(base.merged(&other), other);
+
+        let melody = GenerationConfig { melody: Some(Uuid::new_v4()), ..Default::default() };
+        assert_eq!(melody.merged(&other).melody, melody.melody);
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(MusicGenConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn parses_server_section() -> anyhow::Result<()> {
+        let config: MusicGenConfig = serde_json::from_str(
+            r#"{ "server": { "port": 9000, "cors_origins": ["https://example.com"] } }"#,
+        )?;
+        assert_eq!(config.server.port, 9000);
+        assert!(config.server.auto_open);
+        assert!(!config.server.expose);
+        assert_eq!(config.server.cors_origins, vec!["https://example.com"]);
+
+        let mut config = MusicGenConfig::default();
+        config.server.port = 0;
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_human_readable_limits() -> anyhow::Result<()> {
+        let config: MusicGenConfig = serde_json::from_str(
+            r#"{
+                "limits": { "max_generation_length": "30s", "job_timeout": "2m", "max_memory": "4GiB" },
+                "storage": { "max_disk_usage": 1024 }
+            }"#,
+        )?;
+        assert!(config.validate().is_ok());
+        let limits = &config.limits;
+        assert_eq!(limits.max_generation_length.unwrap().0.as_secs(), 30);
+        assert_eq!(limits.job_timeout.unwrap().0.as_secs(), 120);
+        assert_eq!(limits.max_memory.unwrap().0, 4 << 30);
+        assert_eq!(config.storage.max_disk_usage.unwrap().0, 1024);
+        assert_eq!(limits.stall_timeout, default_stall_timeout());
+
+        let config: MusicGenConfig = serde_json::from_str(r#"{ "limits": { "stall_timeout": "90s" } }"#)?;
+        assert_eq!(config.limits.max_generation_length, default_max_generation_length());
+        assert_eq!(config.limits.stall_timeout.0.as_secs(), 90);
+
+        let invalid = r#"{ "limits": { "job_timeout": "2 fortnights" } }"#;
+        assert!(serde_json::from_str::<MusicGenConfig>(invalid).is_err());
+        let zero: MusicGenConfig = serde_json::from_str(r#"{ "limits": { "job_timeout": "0s" } }"#)?;
+        assert!(zero.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_pipeline_concurrency() -> anyhow::Result<()> {
+        let config: MusicGenConfig = serde_json::from_str(r#"{ "pipeline": { "decoder": 2, "post_processing": 4 } }"#)?;
+        assert!(config.validate().is_ok());
+        assert_eq!(config.pipeline.text_encoder, 1);
+        assert_eq!(config.pipeline.decoder, 2);
+        assert_eq!(config.pipeline.max_in_flight(), 4);
+        assert_eq!(PipelineConfig::default().max_in_flight(), 1);
+
+        let zero: MusicGenConfig = serde_json::from_str(r#"{ "pipeline": { "decoder": 0 } }"#)?;
+        assert!(zero.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pins_the_threads_to_the_cores() -> anyhow::Result<()> {
+        let config: MusicGenConfig = serde_json::from_str(r#"{ "threading": { "cores": [2, 3, 4] } }"#)?;
+        assert!(config.validate().is_ok());
+        assert_eq!(config.threading.intra_op_thread_count(), Some(3));
+        assert_eq!(config.threading.intra_op_thread_affinities().as_deref(), Some("4;5"));
+        assert_eq!(ThreadingConfig::default().intra_op_thread_affinities(), None);
+
+        let mismatch = r#"{ "threading": { "intra_op_threads": 2, "cores": [2, 3, 4] } }"#;
+        assert!(serde_json::from_str::<MusicGenConfig>(mismatch)?.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_and_validates_profiles() -> anyhow::Result<()> {
+        let config: MusicGenConfig = serde_json::from_str(
+            r#"{ "profiles": { "fast": { "top_k": 10 }, "quality": { "top_k": 250 } } }"#,
+        )?;
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.profiles["fast"],
+            GenerationConfig { top_k: Some(10), ..Default::default() },
+        );
+        assert_eq!(
+            config.profiles["quality"],
+            GenerationConfig { top_k: Some(250), ..Default::default() },
+        );
+
+        let mut config = MusicGenConfig::default();
+        config.profiles.insert(
+            "fast".to_string(),
+            GenerationConfig { top_k: Some(0), ..Default::default() },
+        );
+        assert!(config.validate().is_err());
+
+        let mut config = MusicGenConfig::default();
+        config.profiles.insert("../fast".to_string(), GenerationConfig::default());
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validates_relationships_between_fields() {
+        let invalid = [
+            MusicGenConfig::builder().hidden_size(770),
+            MusicGenConfig::builder().num_attention_heads(8),
+            MusicGenConfig::builder().d_kv(0),
+            MusicGenConfig::builder().d_model(700),
+            MusicGenConfig::builder().n_fft(256).hop_length(512),
+            MusicGenConfig::builder().sampling_rate(44100),
+            MusicGenConfig::builder().num_codebooks(7).audio_channels(2),
+            MusicGenConfig::builder().context_secs(10).long_form_overlap_secs(10),
+        ];
+        for builder in invalid {
+            assert!(builder.clone().build().is_err(), "{builder:?} should be invalid");
+        }
+
+        // MusicGen small.
+        let valid = MusicGenConfig::builder()
+            .hidden_size(1024)
+            .num_attention_heads(16)
+            .d_kv(64)
+            .d_model(768)
+            .sampling_rate(32000)
+            .build();
+        assert!(valid.is_ok());
+        // MusicGen stereo.
+        let stereo = MusicGenConfig::builder().num_codebooks(8).audio_channels(2).build();
+        assert!(stereo.is_ok());
+    }
+
+    #[test]
+    fn builder_sets_fields_and_validates() -> anyhow::Result<()> {
+        let config = MusicGenConfig::builder()
+            .device("cuda")
+            .top_k(20)
+            .sampling_rate(32000)
+            .build()?;
+        assert_eq!(config.device, "cuda");
+        assert_eq!(config.decoder.top_k, 20);
+        assert_eq!(config.audio_encoder.sampling_rate, 32000);
+
+        assert!(MusicGenConfig::builder().sampling_rate(10).build().is_err());
+        assert!(MusicGenConfig::builder().batch_size(0).build().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_secrets_from_env_and_files() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("musicgpt-secrets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let file = dir.join("admin_token");
+        std::fs::write(&file, "admin-token\n")?;
+        std::env::set_var("MUSICGPT_TEST_HF_TOKEN", "hf-token");
+
+        let secrets = SecretsConfig {
+            huggingface_token: Some(SecretRef::Env("MUSICGPT_TEST_HF_TOKEN".to_string())),
+            api_key: None,
+            webhook_secret: None,
+            admin_token: Some(SecretRef::File(file.to_string_lossy().to_string())),
+        };
+        let resolved = secrets.resolve()?;
+        assert_eq!(resolved.huggingface_token.unwrap().expose(), "hf-token");
+        assert_eq!(resolved.api_key, None);
+        assert_eq!(resolved.admin_token.as_ref().unwrap().expose(), "admin-token");
+        assert!(!format!("{:?}", resolved.admin_token).contains("admin-token"));
+
+        let missing = SecretRef::Env("MUSICGPT_TEST_MISSING".to_string());
+        assert!(missing.resolve().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn saved_config_only_contains_secret_references() -> anyhow::Result<()> {
+        std::env::set_var("MUSICGPT_TEST_SAVED_TOKEN", "super-secret");
+        let mut config = MusicGenConfig::default();
+        config.secrets.huggingface_token =
+            Some(SecretRef::Env("MUSICGPT_TEST_SAVED_TOKEN".to_string()));
+
+        let path = std::env::temp_dir().join(format!("musicgpt-config-{}.json", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        config.save_to_file(&path)?;
+        let content = std::fs::read_to_string(&path)?;
+        assert!(content.contains("MUSICGPT_TEST_SAVED_TOKEN"));
+        assert!(!content.contains("super-secret"));
+
+        let loaded = MusicGenConfig::from_file(&path)?;
+        assert_eq!(loaded.secrets, config.secrets);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn loads_remote_configs_and_caches_them() -> anyhow::Result<()> {
+        use axum::http::{HeaderMap, StatusCode};
+        use axum::routing::get;
+
+        let app = axum::Router::new().route(
+            "/config.json",
+            get(|headers: HeaderMap| async move {
+                match headers.get("authorization").and_then(|v| v.to_str().ok()) {
+                    Some("Bearer token") => (StatusCode::OK, r#"{ "batch_size": 4 }"#),
+                    _ => (StatusCode::UNAUTHORIZED, ""),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/config.json", listener.local_addr()?);
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let cache_dir = format!("musicgpt-config-cache-{}", uuid::Uuid::new_v4());
+        let cache_dir = std::env::temp_dir().join(cache_dir);
+        let mut opts = ConfigSourceOptions {
+            auth_header: None,
+            cache_dir: Some(cache_dir.clone()),
+        };
+        assert!(MusicGenConfig::from_source(&url, &opts).await.is_err());
+
+        opts.auth_header = Some("Bearer token".to_string());
+        let config = MusicGenConfig::from_source(&url, &opts).await?;
+        assert_eq!(config.batch_size, 4);
+        assert!(MusicGenConfig::cached_source_path(&cache_dir, &url).exists());
+
+        // Once the remote is gone, the cached copy is used.
+        server.abort();
+        let _ = server.await;
+        let config = MusicGenConfig::from_source(&url, &opts).await?;
+        assert_eq!(config.batch_size, 4);
+
+        opts.cache_dir = None;
+        assert!(MusicGenConfig::from_source(&url, &opts).await.is_err());
+        std::fs::remove_dir_all(&cache_dir)?;
+        Ok(())
+    }
 }
@@ -1,40 +1,90 @@
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::sync::mpsc::Receiver;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use crate::delay_pattern_mask_ids::DelayedPatternMaskIds;
-use crate::music_gen_config::MusicGenConfig;
+use crate::music_gen_config::{GenerationConfig, LogitBias, MusicGenConfig};
 use crate::music_gen_inputs::MusicGenInputs;
 use crate::music_gen_outputs::MusicGenOutputs;
-use crate::tensor_ops::{dupe_zeros_along_first_dim, zeros_tensor};
-use num_traits::Zero;
+use crate::tensor_ops::{
+    cast_tensor, concat_padded_along_first_dim, dupe_zeros_along_first_dim, zeros_tensor,
+};
+use num_traits::{NumCast, Zero};
+use ort::memory::{AllocationDevice, AllocatorType, MemoryInfo, MemoryType};
 use ort::session::Session;
 use ort::tensor::PrimitiveTensorElementType;
 use ort::value::{DynValue, Tensor};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use tokio_util::sync::CancellationToken;
 
-pub trait MusicGenType: PrimitiveTensorElementType + Debug + Clone + Zero {}
+pub trait MusicGenType: PrimitiveTensorElementType + Debug + Clone + Zero + NumCast {}
 
 impl MusicGenType for u8 {}
 impl MusicGenType for i8 {}
 impl MusicGenType for f32 {}
 impl MusicGenType for half::f16 {}
 
-// TODO: is this configurable?
-const GUIDANCE_SCALE: usize = 3;
-
 pub trait MusicGenDecoder: Send + Sync {
+    /// Generates up to `max_len` tokens in a background thread, which stops as soon
+    /// as `cancel` is cancelled or the returned receiver is dropped. The generation
+    /// continues the tokens in `prompt`, which are not sent again. Each frame has a token
+    /// per codebook, interleaved by channel for the stereo models. The tokens are the
+    /// same every time for the same `overrides.seed`. The classifier free guidance steers
+    /// the generation away from the `negative` prompt, encoded like the positive one, or
+    /// from no prompt at all.
     fn generate_tokens(
         &self,
         last_hidden_state: DynValue,
         encoder_attention_mask: DynValue,
+        negative: Option<(DynValue, DynValue)>,
+        prompt: Vec<Vec<i64>>,
         max_len: usize,
-    ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>>;
+        overrides: &GenerationConfig,
+        cancel: CancellationToken,
+    ) -> ort::Result<Receiver<ort::Result<Vec<i64>>>>;
+}
+
+/// The conditional `states` followed by the unconditional ones, the batch of the classifier
+/// free guidance. Apparently, there's a setting in huggingface's transformers that says that
+/// if `guidance_scale` > 1 then you should concatenate 0 along the first axis, which are the
+/// unconditional ones unless there is a `negative` prompt.
+fn guidance_batch<T: PrimitiveTensorElementType + Debug + Clone + Zero + 'static>(
+    states: Tensor<T>,
+    negative: Option<Tensor<T>>,
+) -> ort::Result<Tensor<T>> {
+    match negative {
+        None => dupe_zeros_along_first_dim(states),
+        Some(negative) => concat_padded_along_first_dim(states, negative),
+    }
+}
+
+/// The [GenerationConfig::logit_bias] of `overrides`, which must be of the `num_codebooks`
+/// codebooks of the model.
+fn logit_bias(overrides: &GenerationConfig, num_codebooks: usize) -> ort::Result<Vec<LogitBias>> {
+    let logit_bias = overrides.logit_bias.clone().unwrap_or_default();
+    if let Some(v) = logit_bias.iter().find(|v| v.codebook >= num_codebooks) {
+        return Err(ort::Error::new(format!(
+            "The model has {num_codebooks} codebooks, there is no codebook {}",
+            v.codebook
+        )));
+    }
+    Ok(logit_bias)
+}
+
+/// Memory of the `device_id`th `device`, where the decoders keep the past key values
+/// between steps.
+fn key_values_memory(device: AllocationDevice, device_id: i32) -> ort::Result<MemoryInfo> {
+    MemoryInfo::new(device, device_id, AllocatorType::Device, MemoryType::Default)
 }
 
 pub struct MusicGenMergedDecoder<T: MusicGenType> {
     pub decoder_model_merged: Arc<Session>,
-    pub config: MusicGenConfig,
+    pub config: Arc<RwLock<MusicGenConfig>>,
+    /// Where the past key values are kept between the steps, see [MusicGenInputs::bind].
+    pub device: AllocationDevice,
+    pub device_id: i32,
     pub _phantom_data: PhantomData<T>,
 }
 
@@ -46,37 +96,60 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
         &self,
         last_hidden_state: DynValue,
         encoder_attention_mask: DynValue,
+        negative: Option<(DynValue, DynValue)>,
+        prompt: Vec<Vec<i64>>,
         max_len: usize,
-    ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
-        // Apparently, there's a setting in huggingface's transformers that says that
-        // if `guidance_scale` > 1 then you should concatenate 0 along the first axis.
-        let encoder_hidden_states = dupe_zeros_along_first_dim::<T>(last_hidden_state.downcast()?)?;
-        let encoder_attention_mask =
-            dupe_zeros_along_first_dim::<i64>(encoder_attention_mask.downcast()?)?;
-
-        let mut delay_pattern_mask_ids = DelayedPatternMaskIds::<4>::new();
+        overrides: &GenerationConfig,
+        cancel: CancellationToken,
+    ) -> ort::Result<Receiver<ort::Result<Vec<i64>>>> {
+        let (negative_hidden_state, negative_attention_mask) = negative.unzip();
+        // The text encoder might be exported in another precision than the decoder.
+        let encoder_hidden_states = guidance_batch::<T>(
+            cast_tensor(last_hidden_state)?,
+            negative_hidden_state.map(cast_tensor).transpose()?,
+        )?;
+        let encoder_attention_mask = guidance_batch::<i64>(
+            encoder_attention_mask.downcast()?,
+            negative_attention_mask.map(|v| v.downcast()).transpose()?,
+        )?;
 
         let decoder_model_merged = self.decoder_model_merged.clone();
+        let (device, device_id) = (self.device, self.device_id);
 
         let mut inputs = MusicGenInputs::new();
         inputs.encoder_attention_mask(encoder_attention_mask)?;
         inputs.encoder_hidden_states(encoder_hidden_states)?;
 
-        let num_hidden_layers = self.config.decoder.num_hidden_layers;
-        let num_attention_heads = self.config.decoder.num_attention_heads;
-        let pad_token_id = self.config.decoder.pad_token_id;
-        let d_kv = self.config.text_encoder.d_kv;
-        let top_k = self.config.decoder.top_k;
+        let mut config = self.config.read().unwrap().clone();
+        overrides.apply(&mut config);
+        let num_hidden_layers = config.decoder.num_hidden_layers;
+        let num_attention_heads = config.decoder.num_attention_heads;
+        let num_codebooks = config.decoder.num_codebooks;
+        let pad_token_id = config.decoder.pad_token_id;
+        let d_kv = config.text_encoder.d_kv;
+        let top_k = config.decoder.top_k;
+        let guidance_scale = config.decoder.guidance_scale;
+        let temperature = config.decoder.temperature;
+        let top_p = config.decoder.top_p;
+        let logit_bias = logit_bias(overrides, num_codebooks)?;
+        let mut rng = match overrides.seed {
+            Some(seed) => StdRng::seed_from_u64(seed as u64),
+            None => StdRng::from_entropy(),
+        };
         let decoder_dims = [1, num_attention_heads, 0, d_kv];
+        let mut delay_pattern_mask_ids =
+            DelayedPatternMaskIds::new(num_codebooks, config.decoder.audio_channels);
         let encoder_dims = [1, num_attention_heads, 0, d_kv];
 
         // TODO: 100?
-        let (tx, rx) = std::sync::mpsc::channel::<ort::Result<[i64; 4]>>();
+        let (tx, rx) = std::sync::mpsc::channel::<ort::Result<Vec<i64>>>();
         let tx2 = tx.clone();
 
         std::thread::spawn(move || {
             let result = {
-                inputs.input_ids(Tensor::from_array(([8, 1], vec![pad_token_id; 8]))?)?;
+                let device = key_values_memory(device, device_id)?;
+                let pads = vec![pad_token_id; 2 * num_codebooks];
+                inputs.input_ids(Tensor::from_array(([2 * num_codebooks, 1], pads))?)?;
 
                 for i in 0..num_hidden_layers {
                     inputs.past_key_value_decoder_key(i, zeros_tensor::<T>(&decoder_dims))?;
@@ -85,48 +158,47 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
                     inputs.past_key_value_encoder_value(i, zeros_tensor::<T>(&encoder_dims))?;
                 }
                 inputs.use_cache_branch(false);
-                for _ in 0..max_len {
-                    let outputs = decoder_model_merged.run(inputs.ort())?;
-                    let mut outputs = MusicGenOutputs::new(outputs);
-
-                    delay_pattern_mask_ids.push(
-                        outputs
-                            .take_logits()?
-                            .apply_free_guidance(GUIDANCE_SCALE)
-                            .sample(top_k)
+                for _ in 0..prompt.len() + max_len {
+                    // Stops before running the next step, releasing the session.
+                    if cancel.is_cancelled() {
+                        break;
+                    }
+                    let (logits, present) = {
+                        let mut binding = inputs.bind(&decoder_model_merged, &device)?;
+                        let mut outputs = MusicGenOutputs::new(binding.run()?);
+                        // Optimization introduced by optimum to reuse past key values. So, we
+                        // just replace the constant outputs with the previous past key values.
+                        // https://github.com/huggingface/optimum/blob/0bf2c05fb7e1182b52d21b703cfc95fd9e4ea3dc/optimum/onnxruntime/base.py#L677-L704
+                        let encoder = !inputs.use_cache_branch;
+                        let present = outputs.take_present_key_values(num_hidden_layers, encoder);
+                        (outputs.take_logits()?, present)
+                    };
+
+                    delay_pattern_mask_ids.push_prompted(
+                        &prompt,
+                        logits
+                            .apply_free_guidance(guidance_scale)
+                            .apply_bias(&logit_bias)
+                            .apply_temperature(temperature)
+                            .sample(top_k, top_p, &mut rng)
                             .iter()
                             .map(|e| e.0),
                     );
 
-                    let [a, b, c, d] = delay_pattern_mask_ids.last_delayed_masked(pad_token_id);
+                    let masked = delay_pattern_mask_ids.last_delayed_masked(pad_token_id);
                     inputs.input_ids(ort::value::Value::from_array((
-                        [8, 1],
-                        vec![a, b, c, d, a, b, c, d],
+                        [2 * num_codebooks, 1],
+                        masked.repeat(2),
                     ))?)?;
 
-                    if let Some(last_de_delayed) = delay_pattern_mask_ids.last_de_delayed() {
-                        let sent = tx.send(Ok(last_de_delayed));
+                    if let Some(generated) = delay_pattern_mask_ids.last_generated(prompt.len()) {
+                        let sent = tx.send(Ok(generated));
                         if sent.is_err() {
                             break;
                         }
                     }
 
-                    for j in 0..num_hidden_layers {
-                        let v = outputs.take_present_decoder_key(j);
-                        inputs.past_key_value_decoder_key(j, v)?;
-                        let v = outputs.take_present_decoder_value(j);
-                        inputs.past_key_value_decoder_value(j, v)?;
-                        if !inputs.use_cache_branch {
-                            // Optimization introduced by optimum to reuse past key values. So, we just replace the constant
-                            // outputs with the previous past key values.
-                            // https://github.com/huggingface/optimum/blob/0bf2c05fb7e1182b52d21b703cfc95fd9e4ea3dc/optimum/onnxruntime/base.py#L677-L704
-                            let v = outputs.take_present_encoder_key(j);
-                            inputs.past_key_value_encoder_key(j, v)?;
-                            let v = outputs.take_present_encoder_value(j);
-                            inputs.past_key_value_encoder_value(j, v)?;
-                        }
-                    }
-
+                    inputs.past_key_values(present)?;
                     inputs.use_cache_branch(true);
                 }
                 Ok::<(), ort::Error>(())
@@ -144,7 +216,10 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
 pub struct MusicGenSplitDecoder<T: MusicGenType> {
     pub decoder_model: Session,
     pub decoder_with_past_model: Arc<Session>,
-    pub config: MusicGenConfig,
+    pub config: Arc<RwLock<MusicGenConfig>>,
+    /// Where the past key values are kept between the steps, see [MusicGenInputs::bind].
+    pub device: AllocationDevice,
+    pub device_id: i32,
     pub _phantom_data: PhantomData<T>,
 }
 
@@ -156,92 +231,111 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenSplitDecoder<T> {
         &self,
         last_hidden_state: DynValue,
         encoder_attention_mask: DynValue,
+        negative: Option<(DynValue, DynValue)>,
+        prompt: Vec<Vec<i64>>,
         max_len: usize,
-    ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
-        // Apparently, there's a setting in huggingface's transformers that says that
-        // if `guidance_scale` > 1 then you should concatenate 0 along the first axis.
-        let encoder_hidden_states = dupe_zeros_along_first_dim::<T>(last_hidden_state.downcast()?)?;
-        let encoder_attention_mask =
-            dupe_zeros_along_first_dim::<i64>(encoder_attention_mask.downcast()?)?;
-
-        let mut delay_pattern_mask_ids = DelayedPatternMaskIds::<4>::new();
-
-        let num_hidden_layers = self.config.decoder.num_hidden_layers;
-        let pad_token_id = self.config.decoder.pad_token_id;
-        let top_k = self.config.decoder.top_k;
+        overrides: &GenerationConfig,
+        cancel: CancellationToken,
+    ) -> ort::Result<Receiver<ort::Result<Vec<i64>>>> {
+        let (negative_hidden_state, negative_attention_mask) = negative.unzip();
+        // The text encoder might be exported in another precision than the decoder.
+        let encoder_hidden_states = guidance_batch::<T>(
+            cast_tensor(last_hidden_state)?,
+            negative_hidden_state.map(cast_tensor).transpose()?,
+        )?;
+        let encoder_attention_mask = guidance_batch::<i64>(
+            encoder_attention_mask.downcast()?,
+            negative_attention_mask.map(|v| v.downcast()).transpose()?,
+        )?;
+
+        let mut config = self.config.read().unwrap().clone();
+        overrides.apply(&mut config);
+        let num_hidden_layers = config.decoder.num_hidden_layers;
+        let num_codebooks = config.decoder.num_codebooks;
+        let pad_token_id = config.decoder.pad_token_id;
+        let top_k = config.decoder.top_k;
+        let guidance_scale = config.decoder.guidance_scale;
+        let temperature = config.decoder.temperature;
+        let top_p = config.decoder.top_p;
+        let logit_bias = logit_bias(overrides, num_codebooks)?;
+        let mut rng = match overrides.seed {
+            Some(seed) => StdRng::seed_from_u64(seed as u64),
+            None => StdRng::from_entropy(),
+        };
+        let mut delay_pattern_mask_ids =
+            DelayedPatternMaskIds::new(num_codebooks, config.decoder.audio_channels);
 
         let mut inputs = MusicGenInputs::new();
         inputs.encoder_attention_mask(encoder_attention_mask)?;
-        inputs.input_ids(Tensor::from_array(([8, 1], vec![pad_token_id; 8]))?)?;
+        let pads = vec![pad_token_id; 2 * num_codebooks];
+        inputs.input_ids(Tensor::from_array(([2 * num_codebooks, 1], pads))?)?;
         inputs.encoder_hidden_states(encoder_hidden_states)?;
 
         let outputs = self.decoder_model.run(inputs.ort())?;
         let mut outputs = MusicGenOutputs::new(outputs);
 
-        delay_pattern_mask_ids.push(
+        delay_pattern_mask_ids.push_prompted(
+            &prompt,
             outputs
                 .take_logits()?
-                .apply_free_guidance(GUIDANCE_SCALE)
-                .sample(top_k)
+                .apply_free_guidance(guidance_scale)
+                .apply_bias(&logit_bias)
+                .apply_temperature(temperature)
+                .sample(top_k, top_p, &mut rng)
                 .iter()
                 .map(|e| e.0),
         );
 
-        for j in 0..num_hidden_layers {
-            let v = outputs.take_present_decoder_key(j);
-            inputs.past_key_value_decoder_key(j, v)?;
-            let v = outputs.take_present_decoder_value(j);
-            inputs.past_key_value_decoder_value(j, v)?;
-            let v = outputs.take_present_encoder_key(j);
-            inputs.past_key_value_encoder_key(j, v)?;
-            let v = outputs.take_present_encoder_value(j);
-            inputs.past_key_value_encoder_value(j, v)?;
-        }
+        inputs.past_key_values(outputs.take_present_key_values(num_hidden_layers, true))?;
 
         inputs.remove_encoder_hidden_states();
 
         let decoder_with_past = self.decoder_with_past_model.clone();
+        let (device, device_id) = (self.device, self.device_id);
 
         // TODO: 100?
-        let (tx, rx) = std::sync::mpsc::channel::<ort::Result<[i64; 4]>>();
+        let (tx, rx) = std::sync::mpsc::channel::<ort::Result<Vec<i64>>>();
         let tx2 = tx.clone();
         std::thread::spawn(move || {
             let result = {
-                for _ in 0..max_len {
-                    let [a, b, c, d] = delay_pattern_mask_ids.last_delayed_masked(pad_token_id);
-                    inputs
-                        .input_ids(Tensor::from_array(([8, 1], vec![a, b, c, d, a, b, c, d]))?)?;
-                    let outputs = decoder_with_past.run(inputs.ort())?;
-                    let mut outputs = MusicGenOutputs::new(outputs);
-
-                    delay_pattern_mask_ids.push(
-                        outputs
-                            .take_logits()?
-                            .apply_free_guidance(GUIDANCE_SCALE)
-                            .sample(top_k)
+                let device = key_values_memory(device, device_id)?;
+                for _ in 0..prompt.len() + max_len {
+                    // Stops before running the next step, releasing the session.
+                    if cancel.is_cancelled() {
+                        break;
+                    }
+                    let masked = delay_pattern_mask_ids.last_delayed_masked(pad_token_id);
+                    inputs.input_ids(Tensor::from_array((
+                        [2 * num_codebooks, 1],
+                        masked.repeat(2),
+                    ))?)?;
+                    // NOTE: No need to propagate encoder values.
+                    let (logits, present) = {
+                        let mut binding = inputs.bind(&decoder_with_past, &device)?;
+                        let mut outputs = MusicGenOutputs::new(binding.run()?);
+                        let present = outputs.take_present_key_values(num_hidden_layers, false);
+                        (outputs.take_logits()?, present)
+                    };
+
+                    delay_pattern_mask_ids.push_prompted(
+                        &prompt,
+                        logits
+                            .apply_free_guidance(guidance_scale)
+                            .apply_bias(&logit_bias)
+                            .apply_temperature(temperature)
+                            .sample(top_k, top_p, &mut rng)
                             .iter()
                             .map(|e| e.0),
                     );
 
-                    if let Some(last_de_delayed) = delay_pattern_mask_ids.last_de_delayed() {
-                        let sent = tx.send(Ok(last_de_delayed));
+                    if let Some(generated) = delay_pattern_mask_ids.last_generated(prompt.len()) {
+                        let sent = tx.send(Ok(generated));
                         if sent.is_err() {
                             break;
                         }
                     }
 
-                    for j in 0..num_hidden_layers {
-                        let v = outputs.take_present_decoder_key(j);
-                        inputs.past_key_value_decoder_key(j, v)?;
-                        let v = outputs.take_present_decoder_value(j);
-                        inputs.past_key_value_decoder_value(j, v)?;
-                        // NOTE: No need to propagate encoder values.
-                        //
-                        // let v = outputs.take_present_encoder_key(j);
-                        // inputs.past_key_value_encoder_key(j, v)?;
-                        // let v = outputs.take_present_encoder_value(j);
-                        // inputs.past_key_value_encoder_value(j, v)?;
-                    }
+                    inputs.past_key_values(present)?;
                 }
                 Ok::<(), ort::Error>(())
             };
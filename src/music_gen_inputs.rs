@@ -1,6 +1,9 @@
+use ort::memory::{AllocationDevice, AllocatorType, MemoryInfo, MemoryType};
+use ort::session::{IoBinding, Session, SessionInputs};
 use ort::value::{DynValue, Tensor};
 use std::collections::HashMap;
-use ort::session::SessionInputs;
+
+use crate::music_gen_outputs::PresentKeyValues;
 
 pub struct MusicGenInputs {
     inputs: HashMap<String, DynValue>,
@@ -80,6 +83,18 @@ impl MusicGenInputs {
         Ok(())
     }
 
+    pub fn past_key_values(&mut self, present: PresentKeyValues) -> ort::Result<()> {
+        for (i, (key, value)) in present.decoder.into_iter().enumerate() {
+            self.past_key_value_decoder_key(i, key)?;
+            self.past_key_value_decoder_value(i, value)?;
+        }
+        for (i, (key, value)) in present.encoder.into_iter().enumerate() {
+            self.past_key_value_encoder_key(i, key)?;
+            self.past_key_value_encoder_value(i, value)?;
+        }
+        Ok(())
+    }
+
     pub fn use_cache_branch(&mut self, value: bool) {
         self.use_cache_branch = value;
         self.inputs.insert(
@@ -90,6 +105,34 @@ impl MusicGenInputs {
         );
     }
 
+    /// Binds the inputs to a run of `session` that leaves the present key values in the
+    /// memory of `device`. Fed back as the past key values of the next run, they stay on
+    /// the GPU for the whole generation instead of going back and forth to the host on
+    /// every step. Only the logits are copied to the host, where the tokens are sampled.
+    pub fn bind<'s>(
+        &'s self,
+        session: &'s Session,
+        device: &MemoryInfo,
+    ) -> ort::Result<IoBinding<'s>> {
+        let host = MemoryInfo::new(
+            AllocationDevice::CPU,
+            0,
+            AllocatorType::Device,
+            MemoryType::CPUOutput,
+        )?;
+        let mut binding = session.create_binding()?;
+        for (name, value) in &self.inputs {
+            binding.bind_input(name.as_str(), value)?;
+        }
+        for output in &session.outputs {
+            match output.name.as_str() {
+                "logits" => binding.bind_output_to_device("logits", &host)?,
+                name => binding.bind_output_to_device(name, device)?,
+            }
+        }
+        Ok(binding)
+    }
+
     pub fn ort(&self) -> SessionInputs {
         SessionInputs::ValueMap(
             self.inputs
@@ -2,6 +2,14 @@ use ort::session::SessionOutputs;
 use ort::value::DynValue;
 use crate::logits::Logits;
 
+/// The present key values of a step, which are the past key values of the next one.
+pub struct PresentKeyValues {
+    /// Key and value of each layer.
+    pub decoder: Vec<(DynValue, DynValue)>,
+    /// Key and value of each layer, empty if they were not taken.
+    pub encoder: Vec<(DynValue, DynValue)>,
+}
+
 pub struct MusicGenOutputs<'r, 's> {
     outputs: SessionOutputs<'r, 's>,
 }
@@ -15,6 +23,23 @@ impl<'r, 's> MusicGenOutputs<'r, 's> {
         Logits::from_3d_dyn_value(&self.outputs.remove("logits").unwrap())
     }
 
+    /// Takes the present key values of the `layers`, the encoder ones only if `encoder`.
+    pub fn take_present_key_values(&mut self, layers: usize, encoder: bool) -> PresentKeyValues {
+        let mut present = PresentKeyValues {
+            decoder: vec![],
+            encoder: vec![],
+        };
+        for i in 0..layers {
+            let key = self.take_present_decoder_key(i);
+            present.decoder.push((key, self.take_present_decoder_value(i)));
+            if encoder {
+                let key = self.take_present_encoder_key(i);
+                present.encoder.push((key, self.take_present_encoder_value(i)));
+            }
+        }
+        present
+    }
+
     pub fn take_present_decoder_key(&mut self, i: usize) -> DynValue {
         let key = format!("present.{i}.decoder.key");
         self.outputs
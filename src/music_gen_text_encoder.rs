@@ -1,16 +1,51 @@
+use half::f16;
+use ndarray::{Array2, Axis};
 use ort::session::Session;
-use ort::value::{DynValue, Tensor};
+use ort::tensor::TensorElementType;
+use ort::value::{DynValue, Tensor, ValueType};
 use tokenizers::Tokenizer;
 
+use crate::dsp::chromagram;
 use crate::tensor_ops::ones_tensor;
 
+/// Analysis settings of the melody, the same ones MusicGen-melody was trained with.
+const MELODY_N_FFT: usize = 16384;
+const MELODY_HOP_LENGTH: usize = 4096;
+/// Chroma frames taken by the melody models, about 30 seconds at 32kHz.
+const MELODY_FRAMES: usize = 235;
+
 pub struct MusicGenTextEncoder {
     pub tokenizer: Tokenizer,
     pub text_encoder: Session,
 }
 
 impl MusicGenTextEncoder {
-    pub fn encode(&self, text: &str) -> ort::Result<(DynValue, DynValue)> {
+    /// Whether the model can be conditioned on a melody besides the text.
+    pub fn takes_melody(&self) -> bool {
+        self.text_encoder
+            .inputs
+            .iter()
+            .any(|input| input.name == "input_features")
+    }
+
+    /// Whether the model takes the melody in fp16, the features are computed in f32.
+    fn takes_fp16_melody(&self) -> bool {
+        self.text_encoder.inputs.iter().any(|input| {
+            input.name == "input_features"
+                && matches!(
+                    input.input_type,
+                    ValueType::Tensor { ty: TensorElementType::Float16, .. }
+                )
+        })
+    }
+
+    /// Encodes `text`, together with the chroma of `melody` for the models that take one.
+    /// See [melody_features].
+    pub fn encode(
+        &self,
+        text: &str,
+        melody: Option<Array2<f32>>,
+    ) -> ort::Result<(DynValue, DynValue)> {
         let tokens = self
             .tokenizer
             .encode(text, true)
@@ -24,9 +59,33 @@ impl MusicGenTextEncoder {
         let input_ids = Tensor::from_array(([1, tokens_len], tokens))?;
         let attention_mask = ones_tensor::<i64>(&[1, tokens_len]);
 
-        let mut output = self
-            .text_encoder
-            .run(ort::inputs![input_ids, attention_mask]?)?;
+        let (mut output, seq_len) = match melody {
+            None => (
+                self.text_encoder
+                    .run(ort::inputs![input_ids, attention_mask]?)?,
+                tokens_len,
+            ),
+            Some(melody) => {
+                if !self.takes_melody() {
+                    return Err(ort::Error::new(
+                        "This model cannot be conditioned on a melody",
+                    ));
+                }
+                // The chroma frames are attended to together with the text tokens.
+                let frames = melody.nrows();
+                let melody = melody.insert_axis(Axis(0));
+                let input_features = match self.takes_fp16_melody() {
+                    true => Tensor::from_array(melody.mapv(f16::from_f32))?.into_dyn(),
+                    false => Tensor::from_array(melody)?.into_dyn(),
+                };
+                let output = self.text_encoder.run(ort::inputs![
+                    "input_ids" => input_ids,
+                    "attention_mask" => attention_mask,
+                    "input_features" => input_features,
+                ]?)?;
+                (output, tokens_len + frames)
+            }
+        };
 
         let last_hidden_state = output
             .remove("last_hidden_state")
@@ -34,7 +93,48 @@ impl MusicGenTextEncoder {
 
         Ok((
             last_hidden_state,
-            ones_tensor::<i64>(&[1, tokens_len]).into_dyn(),
+            ones_tensor::<i64>(&[1, seq_len]).into_dyn(),
         ))
     }
 }
+
+/// The dominant pitch class of each frame of a melody, one-hot encoded and padded with
+/// silence up to [MELODY_FRAMES], which is how the melody models take it.
+///
+/// returns: Array2<f32> of shape (MELODY_FRAMES, 12)
+pub fn melody_features(samples: &[f32], sampling_rate: usize) -> Array2<f32> {
+    let chroma = chromagram(samples, sampling_rate, MELODY_N_FFT, MELODY_HOP_LENGTH);
+    let mut features = Array2::zeros((MELODY_FRAMES, 12));
+    for (t, frame) in chroma.axis_iter(Axis(1)).take(MELODY_FRAMES).enumerate() {
+        let (pitch, max) = frame
+            .iter()
+            .enumerate()
+            .fold((0, 0.0), |acc, (i, v)| if *v > acc.1 { (i, *v) } else { acc });
+        if max > 0.0 {
+            features[(t, pitch)] = 1.0;
+        }
+    }
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn melody_features_follow_the_dominant_pitch() {
+        let a4 = (0..32000)
+            .map(|i| (2.0 * PI * 440.0 * i as f32 / 32000.0).sin())
+            .collect::<Vec<_>>();
+        let features = melody_features(&a4, 32000);
+        assert_eq!(features.dim(), (MELODY_FRAMES, 12));
+        // One second of audio makes 8 frames, the rest is silence.
+        for t in 0..8 {
+            assert_eq!(features[(t, 9)], 1.0);
+            assert_eq!(features.row(t).sum(), 1.0);
+        }
+        assert_eq!(features.row(8).sum(), 0.0);
+    }
+}
@@ -0,0 +1,100 @@
+use rand::{thread_rng, Rng};
+
+/// Integer sample formats supported when exporting audio.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitDepth {
+    I16,
+    I24,
+}
+
+impl BitDepth {
+    pub fn bits(&self) -> u16 {
+        match self {
+            BitDepth::I16 => 16,
+            BitDepth::I24 => 24,
+        }
+    }
+
+    /// The biggest positive value representable with this bit depth.
+    fn max(&self) -> f32 {
+        ((1 << (self.bits() - 1)) - 1) as f32
+    }
+}
+
+/// Statistics about the samples that did not fit in the [-1, 1] range.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClipStats {
+    /// Amount of samples that needed to be clipped.
+    pub clipped: usize,
+    /// Biggest absolute value found in the input.
+    pub peak: f32,
+}
+
+/// Converts f32 samples in the [-1, 1] range into integer PCM samples. All the export
+/// paths should go through here instead of casting samples by hand.
+///
+/// # Arguments
+///
+/// * `samples`: The f32 samples
+/// * `bit_depth`: The target bit depth
+/// * `dither`: Whether to apply TPDF dithering before quantizing, which trades
+///   quantization distortion on quiet material for a tiny amount of white noise
+///
+/// returns: (Vec<i32>, ClipStats) the PCM samples stored in i32s and the clipping stats
+pub fn to_pcm(
+    samples: impl IntoIterator<Item = f32>,
+    bit_depth: BitDepth,
+    dither: bool,
+) -> (Vec<i32>, ClipStats) {
+    let max = bit_depth.max();
+    let mut rng = thread_rng();
+    let mut stats = ClipStats::default();
+    let mut result = vec![];
+    for sample in samples {
+        stats.peak = stats.peak.max(sample.abs());
+        if sample.abs() > 1.0 {
+            stats.clipped += 1;
+        }
+        let mut value = sample.clamp(-1.0, 1.0) * max;
+        if dither {
+            // The sum of two uniform distributions of 1 LSB width results in a
+            // triangular distribution of 2 LSB width.
+            value += rng.gen_range(-0.5..0.5) + rng.gen_range(-0.5..0.5);
+        }
+        result.push(value.round().clamp(-max - 1.0, max) as i32);
+    }
+    (result, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_without_dither() {
+        let (pcm, stats) = to_pcm([0.0, 1.0, -1.0, 0.5], BitDepth::I16, false);
+        assert_eq!(pcm, vec![0, 32767, -32767, 16384]);
+        assert_eq!(stats, ClipStats { clipped: 0, peak: 1.0 });
+
+        let (pcm, _) = to_pcm([1.0, -1.0], BitDepth::I24, false);
+        assert_eq!(pcm, vec![8388607, -8388607]);
+    }
+
+    #[test]
+    fn clips_out_of_range_samples() {
+        let (pcm, stats) = to_pcm([1.5, -2.0, 0.2], BitDepth::I16, false);
+        assert_eq!(pcm[0], 32767);
+        assert_eq!(pcm[1], -32767);
+        assert_eq!(stats, ClipStats { clipped: 2, peak: 2.0 });
+    }
+
+    #[test]
+    fn dither_stays_within_one_lsb() {
+        let samples = (0..1000).map(|i| i as f32 / 1000.0 - 0.5).collect::<Vec<_>>();
+        let (dithered, _) = to_pcm(samples.clone(), BitDepth::I16, true);
+        let (plain, _) = to_pcm(samples, BitDepth::I16, false);
+        for (a, b) in dithered.iter().zip(plain.iter()) {
+            assert!((a - b).abs() <= 1);
+        }
+    }
+}
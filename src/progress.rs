@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Decode steps between the reports to a [ProgressSink].
+pub const PROGRESS_STEPS: usize = 10;
+
+/// Progress of the decoding of a job.
+pub struct StepProgress<'a> {
+    pub prompt: &'a str,
+    /// Decode steps so far, each one generates a token per codebook.
+    pub step: usize,
+    /// Decode steps of the whole job.
+    pub total: usize,
+    /// Steps per second since the decoding started.
+    pub tokens_per_sec: f32,
+    /// Audio decoded at this step, which follows the one of the previous reports.
+    pub audio: Option<&'a VecDeque<f32>>,
+}
+
+/// Gets the progress of the jobs as they are decoded, every [PROGRESS_STEPS] steps and
+/// whenever some of their audio is decoded. Unlike the callbacks of each job, it is set
+/// once for all of them, so it does not depend on how the jobs are submitted.
+pub trait ProgressSink: Send + Sync {
+    fn on_step(&self, progress: &StepProgress);
+}
+
+/// Reports the progress of a job to a [ProgressSink], if there is one.
+pub struct ProgressReporter<'a> {
+    sink: Option<&'a dyn ProgressSink>,
+    prompt: &'a str,
+    total: usize,
+    started: Instant,
+}
+
+impl<'a> ProgressReporter<'a> {
+    pub fn new(sink: Option<&'a dyn ProgressSink>, prompt: &'a str, total: usize) -> Self {
+        Self {
+            sink,
+            prompt,
+            total,
+            started: Instant::now(),
+        }
+    }
+
+    /// Reports the `step`th step if it is its turn, or if some `audio` was decoded at it.
+    pub fn step(&self, step: usize, audio: Option<&VecDeque<f32>>) {
+        let Some(sink) = self.sink else {
+            return;
+        };
+        if step % PROGRESS_STEPS != 0 && audio.is_none() {
+            return;
+        }
+        let secs = self.started.elapsed().as_secs_f32();
+        sink.on_step(&StepProgress {
+            prompt: self.prompt,
+            step,
+            total: self.total,
+            tokens_per_sec: if secs > 0.0 { step as f32 / secs } else { 0.0 },
+            audio,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Steps(Mutex<Vec<(usize, bool)>>);
+
+    impl ProgressSink for Steps {
+        fn on_step(&self, progress: &StepProgress) {
+            self.0.lock().unwrap().push((progress.step, progress.audio.is_some()));
+        }
+    }
+
+    #[test]
+    fn reports_every_few_steps_and_the_decoded_audio() {
+        let sink = Steps::default();
+        let reporter = ProgressReporter::new(Some(&sink), "A song", 30);
+        let audio = VecDeque::from([0.0; 4]);
+        for step in 1..=30 {
+            reporter.step(step, (step == 25).then_some(&audio));
+        }
+        let steps = sink.0.lock().unwrap().clone();
+        assert_eq!(steps, vec![(10, false), (20, false), (25, true), (30, false)]);
+    }
+}
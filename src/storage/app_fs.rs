@@ -1,17 +1,83 @@
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
 use async_trait::async_trait;
+use tokio::io::AsyncWrite;
 
+use crate::music_gen_config::StorageConfig;
 use crate::storage::{Storage, StorageFile};
 
+/// Top level dir where the generated audio is stored.
+pub const AUDIOS_DIR: &str = "audios";
+/// Top level dir for temporary files, like in progress downloads.
+pub const TEMP_DIR: &str = "tmp";
+/// How long the running total of the stored bytes is trusted for. The jobs database, the
+/// checkpoints and the users are written outside of [AppFs], so they are only counted
+/// once the total is measured again.
+const USAGE_TTL: Duration = Duration::from_secs(60);
+
+/// Running total of the bytes stored, so that the dirs are not walked on every write.
+#[derive(Default)]
+struct Usage {
+    bytes: u64,
+    /// When the dirs were last walked, the total needs to be measured if not set.
+    measured_at: Option<Instant>,
+}
+
 #[derive(Clone)]
 pub struct AppFs {
-    pub root: std::path::PathBuf,
+    pub root: PathBuf,
+    /// Relocates the files under [AUDIOS_DIR] to this dir.
+    pub output_dir: Option<PathBuf>,
+    /// Relocates the files under [TEMP_DIR] to this dir.
+    pub temp_dir: Option<PathBuf>,
+    /// Max amount of bytes that can be stored.
+    pub max_disk_usage: Option<u64>,
+    usage: Arc<Mutex<Usage>>,
+}
+
+/// A file created through [AppFs], whose writes count towards its max disk usage.
+pub struct AppFsFile {
+    file: tokio::fs::File,
+    /// The running total of the [AppFs] and its max, if it has one.
+    usage: Option<(Arc<Mutex<Usage>>, u64)>,
+}
+
+impl AsyncWrite for AppFsFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if let Some((usage, max)) = &self.usage {
+            if usage.lock().unwrap().bytes + buf.len() as u64 > *max {
+                return Poll::Ready(Err(exceeded(*max)));
+            }
+        }
+        let written = ready!(Pin::new(&mut self.file).poll_write(cx, buf))?;
+        if let Some((usage, _)) = &self.usage {
+            usage.lock().unwrap().bytes += written as u64;
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
 }
 
-impl StorageFile for tokio::fs::File {}
+impl StorageFile for AppFsFile {}
 
 #[async_trait]
 impl Storage for AppFs {
-    type File = tokio::fs::File;
+    type File = AppFsFile;
 
     async fn exists(&self, path: &str) -> std::io::Result<bool> {
         let (abs_filepath, _, _) = self.relative_file_to_path_buf(path);
@@ -34,15 +100,25 @@ impl Storage for AppFs {
 
     async fn write(&self, path: &str, content: impl AsRef<[u8]> + Send) -> std::io::Result<()> {
         let (abs_filepath, abs_filedir, _) = self.relative_file_to_path_buf(path);
+        let replaced = self.stored_len(&abs_filepath).await;
+        self.reserve(content.as_ref().len() as u64, replaced).await?;
         tokio::fs::create_dir_all(abs_filedir).await?;
-        tokio::fs::write(abs_filepath, content).await?;
+        if let Err(err) = tokio::fs::write(abs_filepath, content).await {
+            self.remeasure();
+            return Err(err);
+        }
         Ok(())
     }
 
     async fn create(&self, path: &str) -> std::io::Result<Self::File> {
         let (abs_filepath, abs_filedir, _) = self.relative_file_to_path_buf(path);
+        let replaced = self.stored_len(&abs_filepath).await;
+        self.reserve(0, replaced).await?;
         tokio::fs::create_dir_all(abs_filedir).await?;
-        tokio::fs::File::create(abs_filepath).await
+        Ok(AppFsFile {
+            file: tokio::fs::File::create(abs_filepath).await?,
+            usage: self.max_disk_usage.map(|max| (self.usage.clone(), max)),
+        })
     }
 
     async fn list(&self, path: &str) -> std::io::Result<Vec<String>> {
@@ -58,17 +134,10 @@ impl Storage for AppFs {
             }
         };
         while let Some(entry) = dir.next_entry().await? {
-            let entry_path = entry.path();
-            let Ok(rel) = entry_path.strip_prefix(&self.root) else {
+            let Some(rel) = self.path_buf_to_relative_file(&entry.path()) else {
                 continue;
             };
-            files.push(
-                rel.to_path_buf()
-                    .iter()
-                    .map(|e| e.to_str().unwrap_or_default())
-                    .collect::<Vec<_>>()
-                    .join("/"),
-            );
+            files.push(rel);
         }
         // TODO: doesn't the OS apis already return this sorted?
         files.sort();
@@ -78,14 +147,28 @@ impl Storage for AppFs {
     async fn mv(&self, from: &str, to: &str) -> std::io::Result<()> {
         let (from_filepath, _, _) = self.relative_file_to_path_buf(from);
         let (to_filepath, to_dirpath, _) = self.relative_file_to_path_buf(to);
+        let replaced = self.stored_len(&to_filepath).await;
         tokio::fs::create_dir_all(to_dirpath).await?;
-        tokio::fs::rename(from_filepath, to_filepath).await
+        if let Err(err) = tokio::fs::rename(&from_filepath, &to_filepath).await {
+            // Renaming does not work across file systems, which can happen if
+            // some dirs were relocated.
+            if tokio::fs::copy(&from_filepath, &to_filepath).await.is_err() {
+                return Err(err);
+            }
+            tokio::fs::remove_file(from_filepath).await?;
+        }
+        self.release(replaced);
+        Ok(())
     }
 
     async fn rm(&self, path: &str) -> std::io::Result<bool> {
         let (abs_filepath, _, _) = self.relative_file_to_path_buf(path);
+        let removed = self.stored_len(&abs_filepath).await;
         match tokio::fs::remove_file(abs_filepath).await {
-            Ok(_) => Ok(true),
+            Ok(_) => {
+                self.release(removed);
+                Ok(true)
+            }
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::NotFound {
                     Ok(false)
@@ -99,7 +182,10 @@ impl Storage for AppFs {
     async fn rm_rf(&self, path: &str) -> std::io::Result<bool> {
         let (abs_dirpath, _, _) = self.relative_file_to_path_buf(path);
         match tokio::fs::remove_dir_all(abs_dirpath).await {
-            Ok(_) => Ok(true),
+            Ok(_) => {
+                self.remeasure();
+                Ok(true)
+            }
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::NotFound {
                     Ok(false)
@@ -112,8 +198,129 @@ impl Storage for AppFs {
 }
 
 impl AppFs {
-    pub fn new(value: impl Into<std::path::PathBuf>) -> Self {
-        Self { root: value.into() }
+    pub fn new(value: impl Into<PathBuf>) -> Self {
+        Self {
+            root: value.into(),
+            output_dir: None,
+            temp_dir: None,
+            max_disk_usage: None,
+            usage: Default::default(),
+        }
+    }
+
+    /// Builds an [AppFs] with the locations set in the config, using `default_root`
+    /// if the config does not specify a data dir.
+    pub fn from_config(default_root: impl Into<PathBuf>, config: &StorageConfig) -> Self {
+        Self {
+            root: config.data_dir.as_ref().map(PathBuf::from).unwrap_or(default_root.into()),
+            output_dir: config.output_dir.as_ref().map(PathBuf::from),
+            temp_dir: config.temp_dir.as_ref().map(PathBuf::from),
+            max_disk_usage: config.max_disk_usage.map(|v| v.0),
+            usage: Default::default(),
+        }
+    }
+
+    /// Amount of bytes stored in the data dir and in the relocated dirs.
+    pub async fn disk_usage(&self) -> std::io::Result<u64> {
+        let mut dirs = vec![self.root.clone()];
+        for dir in [&self.output_dir, &self.temp_dir].into_iter().flatten() {
+            if !dir.starts_with(&self.root) {
+                dirs.push(dir.clone())
+            }
+        }
+        let mut total = 0;
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(v) => v,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    total += metadata.len();
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    /// The running total of the stored bytes, walking the dirs again if it is stale.
+    async fn usage(&self) -> std::io::Result<u64> {
+        {
+            let usage = self.usage.lock().unwrap();
+            if usage.measured_at.is_some_and(|v| v.elapsed() < USAGE_TTL) {
+                return Ok(usage.bytes);
+            }
+        }
+        let bytes = self.disk_usage().await?;
+        *self.usage.lock().unwrap() = Usage {
+            bytes,
+            measured_at: Some(Instant::now()),
+        };
+        Ok(bytes)
+    }
+
+    /// Counts `incoming` bytes that overwrite `replaced` ones in the running total,
+    /// failing if that would exceed the max disk usage.
+    async fn reserve(&self, incoming: u64, replaced: u64) -> std::io::Result<()> {
+        let Some(max) = self.max_disk_usage else {
+            return Ok(());
+        };
+        if self.usage().await?.saturating_sub(replaced) + incoming > max {
+            return Err(exceeded(max));
+        }
+        let mut usage = self.usage.lock().unwrap();
+        usage.bytes = usage.bytes.saturating_sub(replaced) + incoming;
+        Ok(())
+    }
+
+    /// Takes `bytes` that were removed out of the running total.
+    fn release(&self, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.bytes = usage.bytes.saturating_sub(bytes);
+    }
+
+    /// Makes the next write walk the dirs again, for when it is unknown how many bytes
+    /// were removed or written.
+    fn remeasure(&self) {
+        self.usage.lock().unwrap().measured_at = None;
+    }
+
+    /// Size of the file at `path`, 0 if it does not exist. Only needed for keeping the
+    /// running total, so it is not even looked up without a max disk usage.
+    async fn stored_len(&self, path: &Path) -> u64 {
+        if self.max_disk_usage.is_none() {
+            return 0;
+        }
+        tokio::fs::metadata(path).await.map(|v| v.len()).unwrap_or(0)
+    }
+
+    /// The dir to which the top level `dir` was relocated, if any.
+    fn relocated(&self, dir: &str) -> Option<&PathBuf> {
+        match dir {
+            AUDIOS_DIR => self.output_dir.as_ref(),
+            TEMP_DIR => self.temp_dir.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Inverse of [AppFs::relative_file_to_path_buf].
+    fn path_buf_to_relative_file(&self, path: &Path) -> Option<String> {
+        let join = |path: &Path| {
+            path.iter()
+                .map(|e| e.to_str().unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join("/")
+        };
+        for dir in [AUDIOS_DIR, TEMP_DIR] {
+            if let Some(rel) = self.relocated(dir).and_then(|v| path.strip_prefix(v).ok()) {
+                return Some(format!("{dir}/{}", join(rel)));
+            }
+        }
+        path.strip_prefix(&self.root).ok().map(join)
     }
 
     pub fn path_buf(&self, path: &str) -> std::path::PathBuf {
@@ -134,11 +341,19 @@ impl AppFs {
     fn relative_file_to_path_buf(
         &self,
         relative_file: &str,
-    ) -> (std::path::PathBuf, std::path::PathBuf, String) {
+    ) -> (PathBuf, PathBuf, String) {
         let mut abs_file_dir = self.root.to_path_buf();
 
         // The provided `relative_path` might contain directories separated with /
         let mut relative_file_elements = relative_file.split('/').collect::<Vec<_>>();
+        // If the top level dir was relocated, the rest of the path is relative to it.
+        if let Some(dir) = self.relocated(relative_file_elements[0]) {
+            abs_file_dir = dir.clone();
+            relative_file_elements.remove(0);
+            if relative_file_elements.is_empty() {
+                relative_file_elements.push("");
+            }
+        }
         // so take the file name...
         let file_name = relative_file_elements
             .pop()
@@ -155,13 +370,25 @@ impl AppFs {
     }
 }
 
+fn exceeded(max: u64) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Other,
+        format!("Max disk usage of {max} bytes exceeded"),
+    )
+}
+
 #[cfg(test)]
 mod tests {
+    use std::path::{Path, PathBuf};
+
     use rand::distributions::Alphanumeric;
     use rand::{thread_rng, Rng};
 
+    use crate::music_gen_config::StorageConfig;
     use crate::storage::tests::test_storage;
-    use crate::storage::AppFs;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::storage::{AppFs, Storage};
 
     fn rand_string() -> String {
         thread_rng()
@@ -176,4 +403,50 @@ mod tests {
         let app_fs = AppFs::new(format!("/tmp/{}", rand_string()));
         test_storage(app_fs).await
     }
+
+    #[tokio::test]
+    async fn relocates_dirs() -> std::io::Result<()> {
+        let base = format!("/tmp/{}", rand_string());
+        let config = StorageConfig {
+            data_dir: Some(format!("{base}/data")),
+            output_dir: Some(format!("{base}/output")),
+            temp_dir: Some(format!("{base}/temp")),
+            max_disk_usage: None,
+        };
+        let app_fs = AppFs::from_config("/unused", &config);
+        test_storage(app_fs.clone()).await?;
+
+        app_fs.write("audios/a.wav", "audio").await?;
+        app_fs.write("tmp/model.onnx.temp", "model").await?;
+        assert!(Path::new(&format!("{base}/output/a.wav")).exists());
+        assert!(Path::new(&format!("{base}/temp/model.onnx.temp")).exists());
+        assert_eq!(app_fs.list("audios").await?, vec!["audios/a.wav"]);
+        assert_eq!(app_fs.path_buf("audios/a.wav"), PathBuf::from(format!("{base}/output/a.wav")));
+
+        app_fs.mv("tmp/model.onnx.temp", "v1/model.onnx").await?;
+        assert!(Path::new(&format!("{base}/data/v1/model.onnx")).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn enforces_max_disk_usage() -> std::io::Result<()> {
+        let mut app_fs = AppFs::new(format!("/tmp/{}", rand_string()));
+        app_fs.max_disk_usage = Some(10);
+        app_fs.write("a.txt", "12345").await?;
+        app_fs.write("b.txt", "12345").await?;
+        assert_eq!(app_fs.disk_usage().await?, 10);
+        assert!(app_fs.write("c.txt", "1").await.is_err());
+        assert!(!app_fs.exists("c.txt").await?);
+
+        // Overwriting and removing files keep the running total right.
+        app_fs.write("a.txt", "123").await?;
+        app_fs.write("c.txt", "12").await?;
+        app_fs.rm("b.txt").await?;
+        let mut file = app_fs.create("d.txt").await?;
+        file.write_all(b"12345").await?;
+        file.flush().await?;
+        assert!(file.write_all(b"1").await.is_err());
+        assert_eq!(app_fs.disk_usage().await?, 10);
+        Ok(())
+    }
 }
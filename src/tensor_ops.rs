@@ -1,8 +1,9 @@
 use ndarray::{Array, IxDyn}; // [Incremental] for dynamic reshaping
 use ndarray::Array;
-use num_traits::{One, Zero};
+use half::f16;
+use num_traits::{NumCast, One, Zero};
 use ort::tensor::PrimitiveTensorElementType;
-use ort::value::Tensor;
+use ort::value::{DynValue, Tensor};
 use std::fmt::Debug;
 
 pub fn zeros_tensor<T: PrimitiveTensorElementType + Debug + Clone + Zero + 'static>(
@@ -24,6 +25,46 @@ pub fn dupe_zeros_along_first_dim<
     Tensor::from_array((shape, data))
 }
 
+/// Stacks `a` and `b`, both with a batch of 1, along the first dim. The second dim of the
+/// shorter one is padded with zeros, so that both have the same length.
+pub fn concat_padded_along_first_dim<
+    T: PrimitiveTensorElementType + Debug + Zero + Clone + 'static,
+>(
+    a: Tensor<T>,
+    b: Tensor<T>,
+) -> ort::Result<Tensor<T>> {
+    let (a_shape, a_data) = a.try_extract_raw_tensor()?;
+    let (b_shape, b_data) = b.try_extract_raw_tensor()?;
+    let len = a_shape[1].max(b_shape[1]);
+    let padded = |shape: &[i64], data: &[T]| {
+        let step: i64 = shape[2..].iter().product();
+        let pad = vec![T::zero(); ((len - shape[1]) * step) as usize];
+        [data.to_vec(), pad].concat()
+    };
+    let mut shape = a_shape.to_vec();
+    shape[0] = 2;
+    shape[1] = len;
+    let data = [padded(a_shape, a_data), padded(b_shape, b_data)].concat();
+    Tensor::from_array((shape, data))
+}
+
+/// `value`, a tensor of f32 or f16, the precisions the models are exported in, as a tensor
+/// of `T`. Lets the parts of a model exported in different precisions work together.
+pub fn cast_tensor<T: PrimitiveTensorElementType + Debug + Clone + NumCast + 'static>(
+    value: DynValue,
+) -> ort::Result<Tensor<T>> {
+    let cast = |v: f32| T::from(v).ok_or_else(|| ort::Error::new(format!("Cannot cast {v}")));
+    let (shape, data): (Vec<i64>, Vec<f32>) = match value.try_extract_raw_tensor::<f32>() {
+        Ok((shape, data)) => (shape.to_vec(), data.to_vec()),
+        Err(_) => {
+            let (shape, data) = value.try_extract_raw_tensor::<f16>()?;
+            (shape.to_vec(), data.iter().map(|v| v.to_f32()).collect())
+        }
+    };
+    let data = data.into_iter().map(cast).collect::<ort::Result<Vec<T>>>()?;
+    Tensor::from_array((shape, data))
+}
+
 pub fn ones_tensor<T: PrimitiveTensorElementType + Debug + Clone + One + 'static>(
     shape: &[usize],
 ) -> Tensor<T> {
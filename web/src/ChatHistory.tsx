@@ -25,6 +25,7 @@ export function ChatHistory ({ messages, className = '' }: ChatHistoryProps) {
             className={'mb-8'}
             key={key}
             progress={msg.progress}
+            eta={msg.eta}
           />
         } else if (msg.error !== undefined) {
           return <AudioFailure
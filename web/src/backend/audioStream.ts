@@ -0,0 +1,75 @@
+// Plays the audio that the server streams while a job is being generated. The
+// binary frames are laid out as described in `audio_frame` in the backend.
+
+const AUDIO_FRAME_MAGIC = 'MGAU'
+const HEADER_LEN = 50
+// Margin for the first chunk of a job, so that the next ones arrive in time.
+const START_DELAY_SECS = 0.2
+
+export interface AudioChunk {
+  id: string
+  chatId: string
+  offset: number
+  sampleRate: number
+  // The samples of the stereo models are interleaved.
+  channels: number
+  samples: Float32Array
+}
+
+function uuid (bytes: Uint8Array): string {
+  const hex = Array.from(bytes, b => b.toString(16).padStart(2, '0')).join('')
+  return `${hex.slice(0, 8)}-${hex.slice(8, 12)}-${hex.slice(12, 16)}-${hex.slice(16, 20)}-${hex.slice(20)}`
+}
+
+export function parseAudioFrame (data: ArrayBuffer): AudioChunk | undefined {
+  if (data.byteLength < HEADER_LEN) return undefined
+  const bytes = new Uint8Array(data)
+  if (String.fromCharCode(...bytes.slice(0, 4)) !== AUDIO_FRAME_MAGIC) return undefined
+  const view = new DataView(data)
+  const samples = new Float32Array((data.byteLength - HEADER_LEN) / 2)
+  for (let i = 0; i < samples.length; i++) {
+    samples[i] = view.getInt16(HEADER_LEN + 2 * i, true) / 32768
+  }
+  return {
+    id: uuid(bytes.slice(4, 20)),
+    chatId: uuid(bytes.slice(20, 36)),
+    offset: Number(view.getBigUint64(36, true)),
+    sampleRate: view.getUint32(44, true),
+    channels: Math.max(1, view.getUint16(48, true)),
+    samples,
+  }
+}
+
+export class StreamingPlayer {
+  private ctx?: AudioContext
+  // When the first sample of each job plays, in the clock of the audio context.
+  private starts = new Map<string, number>()
+  // Every hook sharing the connection hands the same chunks over.
+  private played = new Set<string>()
+
+  play (chunk: AudioChunk): void {
+    const key = `${chunk.id}:${chunk.offset}`
+    if (this.played.has(key)) return
+    this.played.add(key)
+    this.ctx ??= new AudioContext()
+    const ctx = this.ctx
+    const offsetSecs = chunk.offset / chunk.channels / chunk.sampleRate
+    let start = this.starts.get(chunk.id)
+    if (start === undefined) {
+      start = ctx.currentTime + START_DELAY_SECS - offsetSecs
+      this.starts.set(chunk.id, start)
+    }
+    const frames = Math.floor(chunk.samples.length / chunk.channels)
+    const buffer = ctx.createBuffer(chunk.channels, frames, chunk.sampleRate)
+    for (let c = 0; c < chunk.channels; c++) {
+      const channel = new Float32Array(frames)
+      for (let i = 0; i < frames; i++) channel[i] = chunk.samples[i * chunk.channels + c]
+      buffer.copyToChannel(channel, c)
+    }
+    const source = ctx.createBufferSource()
+    source.buffer = buffer
+    source.connect(ctx.destination)
+    // Chunks that arrive late are played right away rather than skipped.
+    source.start(Math.max(ctx.currentTime, start + offsetSecs))
+  }
+}
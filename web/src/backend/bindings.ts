@@ -2,33 +2,102 @@
 
 export type AiChatEntry = { id: string; chat_id: string; relpath: string; error: string }
 
-export type Chat = { chat_id: string; name: string; created_at: number }
+export type Chat = { chat_id: string; name: string; created_at: number; tags: string[]; user_id: string | null }
 
 export type UserChatEntry = { id: string; chat_id: string; text: string }
 
 export type SetChatMetadataRequest = { chat_id: string; name: string | null }
 
-export type AudioGenerationResult = { id: string; chat_id: string; relpath: string }
+export type AudioGenerationResult = { id: string; chat_id: string; relpath: string; cached: boolean; seed: number | null; hints: MusicalHints | null }
 
 export type AudioGenerationStart = { id: string; chat_id: string; prompt: string; secs: number }
 
-export type AudioGenerationError = { id: string; chat_id: string; error: string }
+export type AudioGenerationError = { id: string; chat_id: string; code: ErrorCode; error: string }
 
-export type GenerateAudioRequest = { id: string; chat_id: string; prompt: string; secs: number }
+export type AudioGenerationCancelled = { id: string; chat_id: string }
 
-export type GenerationMessage = { Start: AudioGenerationStart } | { Progress: AudioGenerationProgress } | { Error: AudioGenerationError } | { Result: AudioGenerationResult }
+export type GenerateAudioRequest = { id: string; chat_id: string; prompt: string; secs: number; config: GenerationConfig | null }
+
+export type GenerationMessage = { Start: AudioGenerationStart } | { Progress: AudioGenerationProgress } | { Error: AudioGenerationError } | { Result: AudioGenerationResult } | { Cancelled: AudioGenerationCancelled } | { Tokens: AudioGenerationTokens } | { Audio: AudioGenerationChunk }
 
 export type ChatEntry = { User: UserChatEntry } | { Ai: AiChatEntry }
 
-export type AudioGenerationProgress = { id: string; chat_id: string; progress: number }
+export type AudioGenerationProgress = { id: string; chat_id: string; progress: number; queue_position: number | null; tokens: number | null; total_tokens: number | null; percent: number; eta_secs: number | null }
 
 export type Info = { model: string; device: string }
 
-export type OutboundMsg = { Generation: GenerationMessage } | { Info: Info } | { Chat: [Chat, ChatEntry[]] } | { Chats: Chat[] } | { Error: string }
+export type OutboundMsg = { Welcome: Capabilities } | { Resumed: ResumeResponse } | { Generation: GenerationMessage } | { Info: Info } | { Chat: [Chat, ChatEntry[]] } | { Chats: Chat[] } | { Profiles: Profiles } | { BulkProgress: BulkProgress } | { Batch: BatchStatus } | { RateLimited: RateLimited } | { QueueFull: QueueFull } | { VersionMismatch: VersionMismatch } | { Invalid: InvalidMessage } | { Error: ApiError }
+
+export type InboundMsg = { Hello: Capabilities } | { Authenticate: AuthenticateRequest } | { Resume: ResumeRequest } | { GenerateAudioNewChat: GenerateAudioRequest } | { GenerateAudio: GenerateAudioRequest } | { GeneratePreview: GenerateAudioRequest } | { CommitPreview: CommitPreviewRequest } | { AbortGeneration: AbortGenerationRequest } | { GetChat: ChatRequest } | { SetChatMetadata: SetChatMetadataRequest } | { DelChat: ChatRequest } | { TapTokens: TokenTapRequest } | { UntapTokens: TokenTapRequest } | { SubscribeJob: SubscribeJobRequest } | "GetProfiles" | { SaveProfile: ConfigProfile } | { SetProfile: SetProfileRequest } | { Bulk: BulkRequest } | { GenerateBatch: GenerateBatchRequest } | { GetBatch: BatchRequest }
+
+export type ResumeRequest = { session_id: string; secret: string | null }
 
-export type InboundMsg = { GenerateAudioNewChat: GenerateAudioRequest } | { GenerateAudio: GenerateAudioRequest } | { AbortGeneration: AbortGenerationRequest } | { GetChat: ChatRequest } | { SetChatMetadata: SetChatMetadataRequest } | { DelChat: ChatRequest }
+export type ResumeResponse = { session_id: string; resumed: boolean; secret: string }
 
 export type ChatRequest = { chat_id: string }
 
+export type RateLimited = { reason: string; retry_after_secs: number }
+
+export type QueueFull = { position: number; estimated_wait_secs: number | null; retry_after_secs: number }
+
+export type VersionMismatch = { client_version: number; min_version: number; max_version: number }
+
+export type InvalidMessage = { reason: InvalidReason; message: string }
+
+export type InvalidReason = "TooLarge" | "Malformed" | "Invalid"
+
+export type ApiError = { code: ErrorCode; message: string }
+
+export type ErrorCode = "InvalidRequest" | "PromptTooLong" | "NotFound" | "Unauthorized" | "Forbidden" | "QuotaExceeded" | "RateLimited" | "QueueFull" | "Conflict" | "Gone" | "ModelNotLoaded" | "ShuttingDown" | "OutOfMemory" | "TimedOut" | "Cancelled" | "Internal"
+
+export type Capabilities = { protocol_version: number; binary_frames: boolean; compression: boolean; streaming_audio: boolean }
+
 export type AbortGenerationRequest = { id: string; chat_id: string }
 
+export type AudioGenerationTokens = { id: string; chat_id: string; tokens: number[] }
+
+export type AudioGenerationChunk = { id: string; chat_id: string; offset: number; sample_rate: number; channels: number; samples: number[] }
+
+export type TokenTapRequest = { id: string; chat_id: string }
+
+export type SubscribeJobRequest = { id: string; chat_id: string }
+
+export type GenerationConfig = { top_k: number | null; guidance_scale: number | null; temperature: number | null; top_p: number | null; seed: number | null; negative_prompt: string | null; sections: TimelineSection[] | null; hints: MusicalHints | null; model: string | null; melody: string | null; continuation: string | null; leads_into: string | null; logit_bias: LogitBias[] | null; format: AudioFormat | null }
+
+export type TimelineSection = { prompt: string; secs: number }
+
+export type MusicalHints = { bpm: number | null; key: string | null; time_signature: string | null; check_bpm: boolean }
+
+export type LogitBias = { codebook: number; token: number; bias: number | null }
+
+export type AudioFormat = "wav" | "mp3" | "flac"
+
+export type Melody = { id: string; secs: number }
+
+export type ConfigProfile = { name: string; config: GenerationConfig }
+
+export type AuthenticateRequest = { api_key: string }
+
+export type SetProfileRequest = { name: string | null }
+
+export type Profiles = { active: string | null; profiles: ConfigProfile[] }
+
+export type CommitPreviewRequest = { preview_id: string; id: string; chat_id: string }
+
+export type ChatFilter = { all: boolean; chat_ids: string[] | null; tag: string | null; untagged: boolean; older_than_days: number | null; name_contains: string | null }
+
+export type BulkAction = "Delete" | { Tag: string } | { Untag: string }
+
+export type BulkRequest = { filter: ChatFilter; action: BulkAction; dry_run: boolean }
+
+export type BulkProgress = { processed: number; total: number; dry_run: boolean }
+
+export type GenerateBatchRequest = { prompts: string[]; secs: number; chat_id: string | null; config: GenerationConfig | null; num_outputs: number | null }
+
+export type BatchRequest = { batch_id: string }
+
+export type JobState = "queued" | "running" | "completed" | "failed" | "cancelled"
+
+export type JobStatus = { id: string; chat_id: string; state: JobState; progress: number; audio_url: string | null; error: string | null }
+
+export type BatchStatus = { id: string; chat_id: string; state: JobState; progress: number; jobs: JobStatus[] }
@@ -1,23 +1,94 @@
 import useWebSocket from "react-use-websocket";
 import { useCallback, useEffect, useState } from "react";
-import { InboundMsg, Info, OutboundMsg } from "./bindings.ts";
+import { Capabilities, InboundMsg, Info, OutboundMsg } from "./bindings.ts";
+import { parseAudioFrame, StreamingPlayer } from "./audioStream.ts";
 
 const BACKEND_URL: string = import.meta.env.VITE_BACKEND_URL ?? window.location.origin
 export const WS_URL = `${BACKEND_URL.replace('http', 'ws')}/ws`
-export const FILES_URL = `${BACKEND_URL}/files`
+export const AUDIO_URL = `${BACKEND_URL}/api/audio`
+
+const CAPABILITIES: Capabilities = {
+  protocol_version: 1,
+  binary_frames: false,
+  compression: true,
+  streaming_audio: true,
+}
+
+const player = new StreamingPlayer()
+
+// Close code used by the server when it does not speak the protocol version of this app.
+const PROTOCOL_ERROR = 1002
+
+// Kept across reconnections of this tab, for receiving what was missed while away.
+const SESSION_STORAGE = 'musicgpt_session'
+// Given by the server with the first connection of the session, needed for resuming it.
+const SESSION_SECRET_STORAGE = 'musicgpt_session_secret'
+
+function sessionId (): string {
+  let id = sessionStorage.getItem(SESSION_STORAGE)
+  if (id == null) {
+    id = crypto.randomUUID()
+    sessionStorage.setItem(SESSION_STORAGE, id)
+  }
+  return id
+}
+
+// First bytes of the binary frames holding a zlib compressed JSON message.
+const COMPRESSED_FRAME_MAGIC = 'MGZL'
+
+async function decompressFrame (data: ArrayBuffer): Promise<OutboundMsg | undefined> {
+  const bytes = new Uint8Array(data)
+  if (String.fromCharCode(...bytes.slice(0, 4)) !== COMPRESSED_FRAME_MAGIC) return undefined
+  const stream = new Blob([bytes.slice(4)]).stream().pipeThrough(new DecompressionStream('deflate'))
+  return JSON.parse(await new Response(stream).text())
+}
+
+// Only needed when using a remote server that was configured with an API key.
+const API_KEY_STORAGE = 'musicgpt_api_key'
 
 export function useBackend () {
   const [info, setInfo] = useState<Info>()
 
   const [closeEvent, setCloseEvent] = useState<WebSocketEventMap['close']>()
 
+  const [last, setLast] = useState<OutboundMsg | null>(null)
+
   const { sendJsonMessage, lastJsonMessage, readyState } =
     useWebSocket<OutboundMsg>(WS_URL, {
       share: true,
       retryOnError: true,
+      onOpen: event => {
+        const ws = event.target as WebSocket
+        ws.binaryType = 'arraybuffer'
+        const hello: InboundMsg = { Hello: CAPABILITIES }
+        ws.send(JSON.stringify(hello))
+        const apiKey = localStorage.getItem(API_KEY_STORAGE)
+        if (apiKey != null) {
+          const authenticate: InboundMsg = { Authenticate: { api_key: apiKey } }
+          ws.send(JSON.stringify(authenticate))
+        }
+        const secret = sessionStorage.getItem(SESSION_SECRET_STORAGE)
+        const resume: InboundMsg = { Resume: { session_id: sessionId(), secret } }
+        ws.send(JSON.stringify(resume))
+      },
+      onMessage: event => {
+        if (event.data instanceof ArrayBuffer) {
+          const chunk = parseAudioFrame(event.data)
+          if (chunk != null) {
+            player.play(chunk)
+            return
+          }
+          decompressFrame(event.data)
+            .then(msg => { if (msg != null) setLast(msg) })
+            .catch(err => console.error('Could not decompress message', err))
+        }
+      },
+      // Only the JSON messages are kept as the last message.
+      filter: message => typeof message.data === 'string',
       shouldReconnect: close => {
         setCloseEvent(close)
-        return true
+        // Reconnecting would be rejected again until the app is updated.
+        return close.code !== PROTOCOL_ERROR
       }
     });
 
@@ -25,13 +96,32 @@ export function useBackend () {
     sendJsonMessage(msg);
   }, [sendJsonMessage]);
 
-  const last = lastJsonMessage;
+  useEffect(() => {
+    setLast(lastJsonMessage);
+  }, [lastJsonMessage]);
 
   useEffect(() => {
     if (last != null && 'Info' in last) {
       setInfo(last.Info);
     }
+    if (last != null && 'Resumed' in last) {
+      sessionStorage.setItem(SESSION_SECRET_STORAGE, last.Resumed.secret);
+    }
   }, [last]);
 
   return { send, last, readyState, closeEvent, info };
 }
+
+// Binds this browser to a new user, whose token the server keeps in a cookie.
+// The WebSocket needs to reconnect for only seeing the chats of the user.
+export async function registerUser (name: string): Promise<void> {
+  const res = await fetch(`${BACKEND_URL}/api/users`, {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    credentials: 'include',
+    body: JSON.stringify({ name }),
+  })
+  if (!res.ok) {
+    throw new Error(await res.text())
+  }
+}
@@ -1,7 +1,7 @@
 import { useEffect, useRef, useState } from "react";
 import { v4 as uuid } from "uuid";
 
-import { FILES_URL, useBackend } from "./useBackend.ts";
+import { AUDIO_URL, useBackend } from "./useBackend.ts";
 import {
   AudioGenerationError,
   AudioGenerationProgress,
@@ -11,6 +11,9 @@ import {
   ChatEntry
 } from './bindings.ts'
 
+// The default max generation length of the server, long audios are made of segments.
+export const MAX_SECS = 300
+
 export interface UserMessage {
   type: "user";
   id: string;
@@ -21,6 +24,8 @@ export interface AiMessage {
   type: "ai";
   id: string;
   progress: number;
+  // Seconds left, while it is known.
+  eta?: number;
   url?: string
   error?: string;
   justSucceeded: boolean
@@ -60,6 +65,9 @@ export function useChat (chat_id: string | undefined, onNewChat: (chat_id: strin
     } else if ('Generation' in last && 'Error' in last.Generation) {
       const msg = last.Generation.Error
       setHistory(prev => prev?.audioGenerationResultOrError(msg))
+    } else if ('Generation' in last && 'Cancelled' in last.Generation) {
+      const msg = last.Generation.Cancelled
+      setHistory(prev => prev?.audioGenerationResultOrError({ ...msg, code: 'Cancelled', error: 'Cancelled' }))
     } else if ('Chat' in last) {
       const [chat, history] = last.Chat
       setChatMetadata(chat)
@@ -70,10 +78,10 @@ export function useChat (chat_id: string | undefined, onNewChat: (chat_id: strin
   function sendMessage (prompt: string, secs: number) {
     const id = uuid();
     if (chat_id !== undefined) {
-      send({ GenerateAudio: { id, chat_id, prompt, secs: clamp(1, secs, 30) } });
+      send({ GenerateAudio: { id, chat_id, prompt, secs: clamp(1, secs, MAX_SECS), config: null } });
     } else {
       const chat_id = uuid()
-      send({ GenerateAudioNewChat: { id, chat_id, prompt, secs: clamp(1, secs, 30) } })
+      send({ GenerateAudioNewChat: { id, chat_id, prompt, secs: clamp(1, secs, MAX_SECS), config: null } })
       setHistory(new ChatHistory(chat_id))
       onNewChat(chat_id)
     }
@@ -124,12 +132,14 @@ class ChatHistory {
     if (msg.chat_id != this.chatId) return this
     if (msg.id in this.aiDict) {
       this.aiDict[msg.id].progress = msg.progress
+      this.aiDict[msg.id].eta = msg.eta_secs ?? undefined
       return this.shallowCopy()
     }
     const aiMsg: AiMessage = {
       type: "ai",
       id: msg.id,
       progress: msg.progress,
+      eta: msg.eta_secs ?? undefined,
       justSucceeded: false
     }
     this.aiDict[msg.id] = aiMsg
@@ -198,7 +208,8 @@ function clamp (min: number, num: number, max: number): number {
   return Math.max(Math.min(num, max), min);
 }
 
+// The audios are served by the API, which only lets each user fetch their own.
 function relpathToUrl (relpath: string): string {
-  if (!relpath.startsWith('/')) relpath = `/${relpath}`;
-  return FILES_URL + relpath
+  const file = relpath.split('/').pop() ?? relpath
+  return `${AUDIO_URL}/${file}`
 }
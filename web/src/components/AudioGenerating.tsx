@@ -4,10 +4,12 @@ import { LoadingIcon } from "../Icons/LoadingIcon.tsx";
 interface GeneratingAudioProps {
   className?: string;
   progress: number;
+  eta?: number;
 }
 
-const AudioGenerating: React.FC<GeneratingAudioProps> = ({ className = '', progress }) => {
+const AudioGenerating: React.FC<GeneratingAudioProps> = ({ className = '', progress, eta }) => {
   const percentProgress = Math.round(progress * 100)
+  const etaText = eta === undefined ? '' : ` · ~${Math.ceil(eta)}s left`
   return (
     <div className={`space-y-2 ${className}`}>
       <div className="flex items-center space-x-2 text-[var(--text-faded-color)]">
@@ -20,7 +22,7 @@ const AudioGenerating: React.FC<GeneratingAudioProps> = ({ className = '', progr
           style={{ width: `${percentProgress}%` }}
         />
       </div>
-      <div className="text-right text-[var(--text-faded-color)] text-sm">{percentProgress}%</div>
+      <div className="text-right text-[var(--text-faded-color)] text-sm">{percentProgress}%{etaText}</div>
     </div>
   );
 };
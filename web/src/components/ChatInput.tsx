@@ -2,6 +2,7 @@ import React, { useEffect, useRef, useState } from "react";
 import { LoadingIcon } from "../Icons/LoadingIcon.tsx";
 import { StopIcon } from "../Icons/StopIcon.tsx";
 import { SendIcon } from "../Icons/SendIcon.tsx";
+import { MAX_SECS } from "../backend/useChat.ts";
 
 export interface ChatInputProps {
   className?: string;
@@ -62,7 +63,7 @@ const ChatInput = ({ className = '', inputFocusToken, onSend, loading, onCancel
         type="number"
         id="audioDuration"
         min="1"
-        max="30"
+        max={MAX_SECS}
         placeholder={"Duration (s)"}
         value={audioDuration}
         onChange={handleAudioChange}